@@ -0,0 +1,421 @@
+use std::time::{Duration, Instant};
+use dl_client_lib::history::HistoryPager;
+use dl_client_lib::Message;
+
+// how many messages are loaded at a time when scrolling back through a conversation
+const HISTORY_PAGE: u32 = 25;
+
+/// A line in the message pane of a conversation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    /// a message sent or received, one with a ttl is deleted that many seconds after it is `read`
+    Message { sender: String, timestamp: String, text: String, ttl: u64, read: Option<Instant> },
+    /// something the client wants the user to know about the conversation, like a changed identity key
+    Notice(String),
+}
+
+impl From<Message> for Line {
+    fn from(message: Message) -> Self {
+        Line::Message { sender: message.sender, timestamp: message.timestamp, text: message.text, ttl: message.ttl, read: None }
+    }
+}
+
+pub struct Conversation {
+    /// the other user in the conversation, or the id of the group or channel
+    pub username: String,
+    /// what the conversation is listed as, the group's name for groups and the space's and channel's for channels
+    pub name: String,
+    pub is_group: bool,
+    /// the id of the space a channel is in, None for the other conversations
+    pub space: Option<String>,
+    pub lines: Vec<Line>,
+    /// messages received while the conversation was not open
+    pub unread: usize,
+    pub online: bool,
+    /// pages through the older messages of the conversation
+    pub pager: HistoryPager,
+    // where the messages received while the conversation was not open start, until it is left again
+    marker: Option<usize>,
+}
+
+impl Conversation {
+    fn new(username: &str) -> Self {
+        Self {
+            username: username.to_string(),
+            name: username.to_string(),
+            is_group: false,
+            space: None,
+            lines: Vec::new(),
+            unread: 0,
+            online: false,
+            pager: HistoryPager::new(username, HISTORY_PAGE),
+            marker: None,
+        }
+    }
+
+    /// Whether the conversation is with a single contact, rather than a group or channel
+    pub fn is_direct(&self) -> bool {
+        !self.is_group && self.space.is_none()
+    }
+
+    /// The index of the first line that was unread when the conversation was opened
+    pub fn marker(&self) -> Option<usize> {
+        self.marker
+    }
+}
+
+/// Fields of the login screen, in the order Tab moves through them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LoginField {
+    #[default]
+    Username,
+    Password,
+    Mode,
+}
+
+/// What was typed into the login screen
+#[derive(Default)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+    /// create a new account instead of logging in
+    pub signup: bool,
+    pub field: LoginField,
+    /// why the last attempt failed
+    pub error: Option<String>,
+}
+
+impl LoginForm {
+    /// A login screen with the username filled in if one is known, waiting for the password
+    pub fn new(username: Option<String>, signup: bool) -> Self {
+        let field = if username.is_some() { LoginField::Password } else { LoginField::Username };
+        Self { username: username.unwrap_or_default(), signup, field, ..Self::default() }
+    }
+
+    pub fn next_field(&mut self) {
+        self.field = match self.field {
+            LoginField::Username => LoginField::Password,
+            LoginField::Password => LoginField::Mode,
+            LoginField::Mode => LoginField::Username,
+        };
+    }
+
+    pub fn previous_field(&mut self) {
+        self.field = match self.field {
+            LoginField::Username => LoginField::Mode,
+            LoginField::Password => LoginField::Username,
+            LoginField::Mode => LoginField::Password,
+        };
+    }
+
+    pub fn type_char(&mut self, c: char) {
+        match self.field {
+            LoginField::Username => self.username.push(c),
+            LoginField::Password => self.password.push(c),
+            LoginField::Mode => if c == ' ' {
+                self.signup = !self.signup;
+            },
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        match self.field {
+            LoginField::Username => { self.username.pop(); }
+            LoginField::Password => { self.password.pop(); }
+            LoginField::Mode => {}
+        }
+    }
+}
+
+/// Everything shown on the chat screen
+pub struct App {
+    /// the user that is logged in
+    pub username: String,
+    pub conversations: Vec<Conversation>,
+    selected: usize,
+    /// what is being typed into the input line
+    pub input: String,
+    /// how many rows the message pane is scrolled up from the newest message
+    pub scroll: usize,
+    /// shown under the message pane until something else happens
+    pub status: String,
+}
+
+impl App {
+    pub fn new<S: Into<String>>(username: S) -> Self {
+        Self {
+            username: username.into(),
+            conversations: Vec::new(),
+            selected: 0,
+            input: String::new(),
+            scroll: 0,
+            status: String::new(),
+        }
+    }
+
+    /// The index of the conversation with a user, starting one if there is none yet
+    pub fn open(&mut self, username: &str) -> usize {
+        if let Some(index) = self.conversations.iter().position(|conversation| conversation.username == username) {
+            return index;
+        }
+        self.conversations.push(Conversation::new(username));
+        self.conversations.len() - 1
+    }
+
+    /// The index of the conversation of a group, starting one if there is none yet
+    /// the group's name is updated if the conversation is already there
+    pub fn open_group(&mut self, id: &str, name: &str) -> usize {
+        let index = self.open(id);
+        let conversation = &mut self.conversations[index];
+        conversation.name = name.to_string();
+        conversation.is_group = true;
+        index
+    }
+
+    /// The index of the conversation of a channel, starting one if there is none yet
+    /// it is listed as `space#channel`, which is updated if the conversation is already there
+    pub fn open_channel(&mut self, id: &str, name: &str, space_id: &str, space: &str) -> usize {
+        let index = self.open(id);
+        let conversation = &mut self.conversations[index];
+        conversation.name = format!("{}#{}", space, name);
+        conversation.space = Some(space_id.to_string());
+        index
+    }
+
+    /// The conversation shown in the message pane, None until there is one
+    pub fn current(&self) -> Option<&Conversation> {
+        self.conversations.get(self.selected)
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut Conversation> {
+        self.conversations.get_mut(self.selected)
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Show another conversation, marking its messages as read
+    pub fn select(&mut self, index: usize) {
+        if index >= self.conversations.len() {
+            return;
+        }
+        if index != self.selected {
+            // the unread marker of the conversation that is left is not needed anymore
+            if let Some(left) = self.conversations.get_mut(self.selected) {
+                left.marker = None;
+            }
+            self.scroll = 0;
+        }
+        self.selected = index;
+        self.conversations[index].unread = 0;
+    }
+
+    pub fn next(&mut self) {
+        if !self.conversations.is_empty() {
+            self.select((self.selected + 1) % self.conversations.len());
+        }
+    }
+
+    pub fn previous(&mut self) {
+        if !self.conversations.is_empty() {
+            self.select((self.selected + self.conversations.len() - 1) % self.conversations.len());
+        }
+    }
+
+    /// Add a new message to the end of a conversation
+    /// it is unread unless the conversation is open
+    pub fn receive(&mut self, username: &str, line: Line) {
+        let index = self.open(username);
+        let open = index == self.selected;
+        let conversation = &mut self.conversations[index];
+        if !open {
+            if conversation.marker.is_none() {
+                conversation.marker = Some(conversation.lines.len());
+            }
+            conversation.unread += 1;
+        }
+        conversation.lines.push(line);
+    }
+
+    /// Add a notice to the end of a conversation, notices are never unread
+    pub fn notice<S: Into<String>>(&mut self, username: &str, text: S) {
+        let index = self.open(username);
+        self.conversations[index].lines.push(Line::Notice(text.into()));
+    }
+
+    /// Add older messages to the start of a conversation
+    pub fn prepend(&mut self, username: &str, lines: Vec<Line>) {
+        let index = self.open(username);
+        let conversation = &mut self.conversations[index];
+        if let Some(marker) = &mut conversation.marker {
+            *marker += lines.len();
+        }
+        conversation.lines.splice(0..0, lines);
+    }
+
+    /// Start the timers of the disappearing messages in the open conversation, which are read now,
+    /// and delete the messages of every conversation whose timer ran out
+    /// @return: true if a message was deleted
+    pub fn expire(&mut self, now: Instant) -> bool {
+        if let Some(open) = self.conversations.get_mut(self.selected) {
+            for line in open.lines.iter_mut() {
+                if let Line::Message { ttl, read: read @ None, .. } = line {
+                    if *ttl > 0 {
+                        *read = Some(now);
+                    }
+                }
+            }
+        }
+
+        let mut deleted = false;
+        for conversation in self.conversations.iter_mut() {
+            let mut index = 0;
+            while index < conversation.lines.len() {
+                let expired = matches!(conversation.lines[index], Line::Message { ttl, read: Some(read), .. } if read + Duration::from_secs(ttl) <= now);
+                if !expired {
+                    index += 1;
+                    continue;
+                }
+                conversation.lines.remove(index);
+                // the unread marker stays in front of the same message
+                if let Some(marker) = &mut conversation.marker {
+                    if index < *marker {
+                        *marker -= 1;
+                    }
+                }
+                deleted = true;
+            }
+        }
+        deleted
+    }
+
+    pub fn set_online(&mut self, username: &str, online: bool) {
+        if let Some(conversation) = self.conversations.iter_mut().find(|conversation| conversation.username == username) {
+            conversation.online = online;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> Line {
+        Line::Message { sender: format!("test"), timestamp: String::new(), text: text.to_string(), ttl: 0, read: None }
+    }
+
+    fn disappearing(text: &str, ttl: u64) -> Line {
+        Line::Message { sender: format!("test"), timestamp: String::new(), text: text.to_string(), ttl, read: None }
+    }
+
+    fn texts(conversation: &Conversation) -> Vec<&str> {
+        conversation.lines.iter().map(|line| match line {
+            Line::Message { text, .. } | Line::Notice(text) => text.as_str(),
+        }).collect()
+    }
+
+    #[test]
+    fn unread_messages_are_marked() {
+        let mut app = App::new("skepz");
+        app.open("test");
+        app.open("bob");
+        assert_eq!(app.current().unwrap().username, "test");
+
+        // the open conversation is read as messages arrive
+        app.receive("test", message("hi"));
+        assert_eq!(app.current().unwrap().unread, 0);
+
+        app.receive("bob", message("one"));
+        app.receive("bob", message("two"));
+        app.notice("bob", "not a message");
+        assert_eq!(app.conversations[1].unread, 2);
+        assert_eq!(app.conversations[1].marker(), Some(0));
+
+        // the marker stays while the conversation is open, and older messages go above it
+        app.next();
+        assert_eq!(app.current().unwrap().unread, 0);
+        app.prepend("bob", vec![message("old")]);
+        assert_eq!(app.current().unwrap().marker(), Some(1));
+        assert_eq!(app.current().unwrap().lines[0], message("old"));
+
+        app.next();
+        assert_eq!(app.conversations[1].marker(), None);
+    }
+
+    #[test]
+    fn disappearing_messages_expire_after_they_are_read() {
+        let mut app = App::new("skepz");
+        app.open("test");
+        app.open("bob");
+        let start = Instant::now();
+        app.receive("test", disappearing("soon", 5));
+        app.receive("test", message("kept"));
+        app.receive("bob", disappearing("unread", 5));
+        app.receive("bob", message("after"));
+        assert!(!app.expire(start));
+
+        // only the open conversation was read, so bob's message is still there long after
+        let later = start + Duration::from_secs(60);
+        assert!(app.expire(later));
+        assert_eq!(texts(&app.conversations[0]), vec!["kept"]);
+        assert_eq!(texts(&app.conversations[1]), vec!["unread", "after"]);
+
+        // reading it starts its timer, and the unread marker moves with the messages
+        app.next();
+        assert_eq!(app.current().unwrap().marker(), Some(0));
+        assert!(!app.expire(later));
+        assert!(!app.expire(later + Duration::from_secs(4)));
+        assert!(app.expire(later + Duration::from_secs(5)));
+        assert_eq!(texts(&app.conversations[1]), vec!["after"]);
+        assert_eq!(app.current().unwrap().marker(), Some(0));
+    }
+
+    #[test]
+    fn conversations_are_opened_once() {
+        let mut app = App::new("skepz");
+        assert!(app.current().is_none());
+        app.next();
+        assert_eq!(app.open("test"), 0);
+        assert_eq!(app.open("bob"), 1);
+        assert_eq!(app.open("test"), 0);
+        app.previous();
+        assert_eq!(app.selected(), 1);
+        app.set_online("bob", true);
+        assert!(app.current().unwrap().online);
+
+        // groups are listed by name, and renaming one keeps its messages
+        assert_eq!(app.open_group("8b0f", "friends"), 2);
+        app.receive("8b0f", message("hi all"));
+        assert_eq!(app.open_group("8b0f", "pals"), 2);
+        let group = &app.conversations[2];
+        assert_eq!((group.name.as_str(), group.is_group, group.lines.len()), ("pals", true, 1));
+        assert!(!app.conversations[0].is_group);
+
+        // channels are listed with their space
+        assert_eq!(app.open_channel("c41a", "general", "5d2e", "club"), 3);
+        let channel = &app.conversations[3];
+        assert_eq!((channel.name.as_str(), channel.space.as_deref(), channel.is_direct()), ("club#general", Some("5d2e"), false));
+        assert!(app.conversations[0].is_direct());
+    }
+
+    #[test]
+    fn login_form() {
+        let mut form = LoginForm::default();
+        form.type_char('a');
+        form.next_field();
+        form.type_char('b');
+        form.type_char('c');
+        form.backspace();
+        form.next_field();
+        form.type_char('x');
+        assert!(!form.signup);
+        form.type_char(' ');
+        form.previous_field();
+        form.previous_field();
+        assert_eq!((form.username.as_str(), form.password.as_str(), form.signup, form.field), ("a", "b", true, LoginField::Username));
+
+        let form = LoginForm::new(Some(format!("skepz")), false);
+        assert_eq!((form.username.as_str(), form.field), ("skepz", LoginField::Password));
+    }
+}
@@ -0,0 +1,70 @@
+pub const USAGE: &str = "\
+Usage: dl_client [options]\
+\n\
+\nOptions:\
+\n  -p, --profile <name>    connect to a server profile from config/client.toml\
+\n  -H, --host <host>       the address or hostname of the server, overriding the profile\
+\n  -P, --port <port>       the port of the server, overriding the profile\
+\n  -u, --username <name>   fill in the username on the login screen\
+\n  -s, --signup            create a new account instead of logging in\
+\n  -h, --help              show this message";
+
+/// What was passed on the command line, anything left out comes from the config file
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub profile: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<String>,
+    pub username: Option<String>,
+    pub signup: bool,
+    pub help: bool,
+}
+
+/// Read the command line arguments, not including the program name
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // values can be given as `--flag value` or `--flag=value`
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || inline.clone().or_else(|| args.next())
+            .ok_or_else(|| format!("`{}` needs a value, run with --help to see how to use it", flag));
+        match flag.as_str() {
+            "-p" | "--profile" => parsed.profile = Some(value()?),
+            "-H" | "--host" => parsed.host = Some(value()?),
+            "-P" | "--port" => parsed.port = Some(value()?),
+            "-u" | "--username" => parsed.username = Some(value()?),
+            "-s" | "--signup" => parsed.signup = true,
+            "-h" | "--help" => parsed.help = true,
+            _ => return Err(format!("Unknown option `{}`, run with --help to see the options", flag)),
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_are_parsed() {
+        assert_eq!(parse(&[]), Ok(Args::default()));
+        let args = parse(&["--host", "10.0.0.1", "-P", "2300", "--username=skepz", "-s"]).unwrap();
+        assert_eq!(args.host.as_deref(), Some("10.0.0.1"));
+        assert_eq!(args.port.as_deref(), Some("2300"));
+        assert_eq!(args.username.as_deref(), Some("skepz"));
+        assert!(args.signup);
+        assert_eq!(parse(&["-p", "home"]).unwrap().profile.as_deref(), Some("home"));
+
+        assert_eq!(parse(&["--host=[::1]"]).unwrap().host.as_deref(), Some("[::1]"));
+        assert!(parse(&["--host"]).is_err());
+        assert!(parse(&["--hots", "10.0.0.1"]).is_err());
+    }
+}
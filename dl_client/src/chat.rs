@@ -0,0 +1,576 @@
+use std::time::{Duration, Instant};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use dl_client_lib::{Client, Event};
+use dl_network_common::{ContactState, Space};
+use crate::app::App;
+use crate::tui::{next_input, Input, Terminal};
+
+// how long to wait for a key press before checking what the server sent
+const INPUT_POLL: Duration = Duration::from_millis(50);
+// how many rows PageUp and PageDown move the message pane
+const SCROLL_ROWS: usize = 10;
+
+const HELP: &str = "/open <username>  /friend /accept /decline /unfriend <username>  /friends  /block /unblock <username>  /blocked  /group <name> <usernames>  /space <name>  /channel <name>  /invite /kick /ban /unban <username>  /rename <name>  /leave  /safety  /trust  /timer <30s|5m|2h|1d|off>  /quit    Tab: next conversation   PgUp/PgDn: scroll";
+
+/// Show the chat screen until the user quits
+/// @return: Err if the connection to the server was lost
+pub fn run(terminal: &mut Terminal, mut client: Client) -> Result<(), String> {
+    let mut app = App::new(client.username());
+    for contact in client.contacts() {
+        client.watch(&contact);
+        app.open(&contact);
+    }
+    for friend in client.friends().into_iter().filter(|friend| friend.state == ContactState::Accepted) {
+        client.watch(&friend.username);
+        app.open(&friend.username);
+    }
+    for group in client.groups() {
+        app.open_group(&group.id, &group.name);
+    }
+    for space in client.spaces() {
+        open_channels(&mut app, &space);
+    }
+    app.status = format!("Type /help for commands");
+    let requests: Vec<String> = client.friends().into_iter().filter(|friend| friend.state == ContactState::Incoming).map(|friend| friend.username).collect();
+    if !requests.is_empty() {
+        app.status = format!("Friend requests from {}, type /accept or /decline with their name", requests.join(", "));
+    }
+    load_older(&mut client, &mut app);
+
+    let mut dirty = true;
+    loop {
+        if dirty {
+            terminal.draw_chat(&mut app)?;
+            dirty = false;
+        }
+
+        match next_input(INPUT_POLL)? {
+            Some(Input::Key(key)) => {
+                if !handle_key(&mut client, &mut app, key) {
+                    client.disconnect();
+                    return Ok(());
+                }
+                dirty = true;
+            }
+            Some(Input::Resize) => {
+                terminal.clear()?;
+                dirty = true;
+            }
+            None => {}
+        }
+        if app.expire(Instant::now()) {
+            dirty = true;
+        }
+
+        // everything that happened in the meantime
+        while let Some(event) = client.next_event(Duration::ZERO) {
+            handle_event(&mut client, &mut app, event)?;
+            dirty = true;
+        }
+    }
+}
+
+/// Show something the client reported
+/// @return: Err once disconnected
+fn handle_event(client: &mut Client, app: &mut App, event: Event) -> Result<(), String> {
+    match event {
+        Event::MessageReceived(message) => {
+            let conversation = message.conversation.clone();
+            open_named(client, app, &conversation);
+            app.receive(&conversation, message.into());
+        }
+        Event::GroupChanged(group) => {
+            app.open_group(&group.id, &group.name);
+            if group.members.contains(&app.username) {
+                app.notice(&group.id, format!("Members of {}: {}", group.name, group.members.join(", ")));
+            } else {
+                app.notice(&group.id, format!("You are no longer in {}.", group.name));
+            }
+        }
+        Event::SpaceChanged(space) => {
+            let index = open_channels(app, &space);
+            let Some(id) = index.map(|index| app.conversations[index].username.clone()) else {
+                return Ok(());
+            };
+            if space.members.iter().any(|member| member.username == app.username) {
+                app.notice(&id, space_members(&space));
+            } else {
+                app.notice(&id, format!("You are no longer in {}.", space.name));
+            }
+        }
+        Event::FriendChanged(friend) => match friend.state {
+            ContactState::Incoming => {
+                app.status = format!("{} sent you a friend request, type /accept {} or /decline {}", friend.username, friend.username, friend.username);
+            }
+            ContactState::Accepted => {
+                client.watch(&friend.username);
+                app.notice(&friend.username, format!("You and {} are now friends.", friend.username));
+            }
+            // declined requests and removed friends keep their conversation, like a group that was left
+            _ => app.status = format!("You and {} are not friends anymore", friend.username),
+        },
+        Event::PresenceChanged { username, online } => app.set_online(&username, online),
+        Event::TimerChanged(timer) => {
+            open_named(client, app, &timer.conversation);
+            app.notice(&timer.conversation, match timer.ttl {
+                0 => format!("{} turned off disappearing messages.", timer.set_by),
+                ttl => format!("{} set messages to disappear {} after they are read.", timer.set_by, describe_timer(ttl)),
+            });
+        }
+        Event::IdentityChanged { username, old_safety_number, new_safety_number } => {
+            app.notice(&username, format!("WARNING: THE IDENTITY KEY OF {} HAS CHANGED!", username.to_uppercase()));
+            app.notice(&username, format!("This happens when {} reinstalls their client, or when someone is intercepting your messages.", username));
+            app.notice(&username, format!("Old safety number: {}", old_safety_number));
+            app.notice(&username, format!("New safety number: {}", new_safety_number));
+            app.notice(&username, format!("Compare the new safety number with {}, then type /trust to message them again.", username));
+        }
+        Event::Error(error) => app.status = error,
+        Event::Disconnected(reason) => return Err(reason),
+    }
+    Ok(())
+}
+
+/// React to a key press on the chat screen
+/// @return: false if the user quit
+fn handle_key(client: &mut Client, app: &mut App, key: KeyEvent) -> bool {
+    match key.code {
+        KeyCode::Esc => return false,
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+        KeyCode::Tab => {
+            app.next();
+            load_if_empty(client, app);
+        }
+        KeyCode::BackTab => {
+            app.previous();
+            load_if_empty(client, app);
+        }
+        KeyCode::PageUp => {
+            app.scroll += SCROLL_ROWS;
+            // older messages are loaded once the oldest ones are close to being shown
+            let lines = app.current().map_or(0, |conversation| conversation.lines.len());
+            if app.scroll + SCROLL_ROWS >= lines {
+                load_older(client, app);
+            }
+        }
+        KeyCode::PageDown => app.scroll = app.scroll.saturating_sub(SCROLL_ROWS),
+        KeyCode::Enter => {
+            let input = std::mem::take(&mut app.input);
+            return submit(client, app, input.trim());
+        }
+        KeyCode::Backspace => {
+            app.input.pop();
+        }
+        KeyCode::Char(c) => app.input.push(c),
+        _ => {}
+    }
+    true
+}
+
+/// Run a command typed into the input line, or send it as a message if it is not one
+/// @return: false if the user quit
+fn submit(client: &mut Client, app: &mut App, input: &str) -> bool {
+    if input.is_empty() {
+        return true;
+    }
+    let Some(command) = input.strip_prefix('/') else {
+        send_message(client, app, input);
+        return true;
+    };
+
+    let (command, argument) = command.split_once(' ').unwrap_or((command, ""));
+    match command {
+        "open" => open_conversation(client, app, argument.trim()),
+        "friend" => change_friend(client, app, "friend", argument.trim()),
+        "accept" => change_friend(client, app, "accept", argument.trim()),
+        "decline" => change_friend(client, app, "decline", argument.trim()),
+        "unfriend" => change_friend(client, app, "unfriend", argument.trim()),
+        "friends" => list_friends(client, app),
+        "block" => change_block(client, app, "block", argument.trim()),
+        "unblock" => change_block(client, app, "unblock", argument.trim()),
+        "blocked" => list_blocked(client, app),
+        "group" => create_group(client, app, argument.trim()),
+        "invite" => change_group(client, app, "invite", argument.trim()),
+        "kick" => change_group(client, app, "kick", argument.trim()),
+        "rename" => change_group(client, app, "rename", argument.trim()),
+        "leave" => change_group(client, app, "leave", ""),
+        "ban" => change_group(client, app, "ban", argument.trim()),
+        "unban" => change_group(client, app, "unban", argument.trim()),
+        "space" => create_space(client, app, argument.trim()),
+        "channel" => create_channel(client, app, argument.trim()),
+        "safety" => show_safety_number(client, app),
+        "trust" => trust_new_key(client, app),
+        "timer" => change_timer(client, app, argument.trim()),
+        "help" => app.status = format!("{}", HELP),
+        "quit" => return false,
+        _ => app.status = format!("Unknown command /{}, type /help for commands", command),
+    }
+    true
+}
+
+/// Start or switch to the conversation with a user
+fn open_conversation(client: &mut Client, app: &mut App, username: &str) {
+    if username.is_empty() {
+        app.status = format!("Usage: /open <username>");
+        return;
+    }
+    if username == client.username() {
+        app.status = format!("You can not start a conversation with yourself");
+        return;
+    }
+    match client.user_exists(username) {
+        Ok(true) => {}
+        Ok(false) => {
+            app.status = format!("There is no user named {}", username);
+            return;
+        }
+        Err(e) => {
+            app.status = format!("Failed to look up {}: {}", username, e);
+            return;
+        }
+    }
+    let index = app.open(username);
+    app.select(index);
+    client.watch(username);
+    load_if_empty(client, app);
+}
+
+/// Send, accept or decline a friend request, or stop being friends
+fn change_friend(client: &mut Client, app: &mut App, command: &str, username: &str) {
+    if username.is_empty() {
+        app.status = format!("Usage: /{} <username>", command);
+        return;
+    }
+    let changed = match command {
+        "friend" => client.send_friend_request(username),
+        "accept" => client.answer_friend_request(username, true),
+        "decline" => client.answer_friend_request(username, false),
+        _ => client.remove_friend(username).map(|()| ContactState::None),
+    };
+    match changed {
+        Ok(ContactState::Accepted) => {
+            client.watch(username);
+            app.notice(username, format!("You and {} are now friends.", username));
+        }
+        Ok(ContactState::Outgoing) => app.status = format!("Sent {} a friend request", username),
+        Ok(_) => app.status = format!("You and {} are not friends", username),
+        Err(e) => app.status = format!("Failed to change friends: {}", e),
+    }
+}
+
+/// Show the friends of the user and their unanswered requests in the status line
+fn list_friends(client: &Client, app: &mut App) {
+    let friends = client.friends();
+    if friends.is_empty() {
+        app.status = format!("No friends yet, type /friend <username> to send a request");
+        return;
+    }
+    let listed: Vec<String> = friends.into_iter().map(|friend| match friend.state {
+        ContactState::Incoming => format!("{} (wants to be friends)", friend.username),
+        ContactState::Outgoing => format!("{} (request sent)", friend.username),
+        _ => friend.username,
+    }).collect();
+    app.status = format!("Friends: {}", listed.join(", "));
+}
+
+/// Block a user or stop blocking them
+fn change_block(client: &mut Client, app: &mut App, command: &str, username: &str) {
+    if username.is_empty() {
+        app.status = format!("Usage: /{} <username>", command);
+        return;
+    }
+    let changed = if command == "block" { client.block(username) } else { client.unblock(username) };
+    match changed {
+        Ok(_) => app.status = format!("{} {}", if command == "block" { "Blocked" } else { "Unblocked" }, username),
+        Err(e) => app.status = format!("Failed to {} {}: {}", command, username, e),
+    }
+}
+
+/// Show the users the user blocked in the status line
+fn list_blocked(client: &mut Client, app: &mut App) {
+    match client.blocked() {
+        Ok(blocked) if blocked.is_empty() => app.status = format!("Nobody is blocked"),
+        Ok(blocked) => app.status = format!("Blocked: {}", blocked.join(", ")),
+        Err(e) => app.status = format!("Failed to get the blocked users: {}", e),
+    }
+}
+
+/// Create a group with the users listed after its name, and switch to it
+fn create_group(client: &mut Client, app: &mut App, argument: &str) {
+    let mut words = argument.split_whitespace();
+    let Some(name) = words.next() else {
+        app.status = format!("Usage: /group <name> <username> ...");
+        return;
+    };
+    let members: Vec<String> = words.map(|word| word.to_string()).collect();
+    match client.create_group(name, &members) {
+        Ok(group) => {
+            let index = app.open_group(&group.id, &group.name);
+            app.select(index);
+            app.notice(&group.id, format!("Members of {}: {}", group.name, group.members.join(", ")));
+        }
+        Err(e) => app.status = format!("Failed to create the group: {}", e),
+    }
+}
+
+/// Create a space owned by the user, and switch to its general channel
+fn create_space(client: &mut Client, app: &mut App, name: &str) {
+    if name.is_empty() {
+        app.status = format!("Usage: /space <name>");
+        return;
+    }
+    match client.create_space(name) {
+        Ok(space) => {
+            if let Some(index) = open_channels(app, &space) {
+                app.select(index);
+            }
+        }
+        Err(e) => app.status = format!("Failed to create the space: {}", e),
+    }
+}
+
+/// Add a channel to the space of the channel in the open conversation
+fn create_channel(client: &mut Client, app: &mut App, name: &str) {
+    let Some(space) = app.current().and_then(|conversation| conversation.space.clone()) else {
+        app.status = format!("Open a channel of the space first");
+        return;
+    };
+    if name.is_empty() {
+        app.status = format!("Usage: /channel <name>");
+        return;
+    }
+    match client.create_channel(&space, name) {
+        Ok(space) => {
+            open_channels(app, &space);
+            if let Some(channel) = space.channels.iter().find(|channel| channel.name == name) {
+                let index = app.open(&channel.id);
+                app.select(index);
+            }
+        }
+        Err(e) => app.status = format!("Failed to create the channel: {}", e),
+    }
+}
+
+/// Invite someone to, kick someone from, rename or leave the group in the open conversation
+/// in a channel, inviting, kicking, banning and leaving change its space instead
+fn change_group(client: &mut Client, app: &mut App, command: &str, argument: &str) {
+    if let Some(space) = app.current().and_then(|conversation| conversation.space.clone()) {
+        change_space(client, app, &space, command, argument);
+        return;
+    }
+    if command == "ban" || command == "unban" {
+        app.status = format!("Only members of a space can be banned, open one of its channels first");
+        return;
+    }
+    let Some(id) = app.current().filter(|conversation| conversation.is_group).map(|conversation| conversation.username.clone()) else {
+        app.status = format!("Open the conversation of a group first");
+        return;
+    };
+    if command != "leave" && argument.is_empty() {
+        let usage = if command == "rename" { "<name>" } else { "<username>" };
+        app.status = format!("Usage: /{} {}", command, usage);
+        return;
+    }
+    let changed = match command {
+        "invite" => client.invite_to_group(&id, argument),
+        "kick" => client.remove_from_group(&id, argument),
+        "rename" => client.rename_group(&id, argument),
+        _ => {
+            match client.leave_group(&id) {
+                Ok(()) => app.notice(&id, format!("You left the group.")),
+                Err(e) => app.status = format!("Failed to leave the group: {}", e),
+            }
+            return;
+        }
+    };
+    match changed {
+        Ok(group) => {
+            app.open_group(&group.id, &group.name);
+            app.notice(&group.id, format!("Members of {}: {}", group.name, group.members.join(", ")));
+        }
+        Err(e) => app.status = format!("Failed to change the group: {}", e),
+    }
+}
+
+/// Invite someone to, kick or ban someone from, or leave a space
+fn change_space(client: &mut Client, app: &mut App, space: &str, command: &str, argument: &str) {
+    if command != "leave" && argument.is_empty() {
+        app.status = format!("Usage: /{} <username>", command);
+        return;
+    }
+    let changed = match command {
+        "invite" => client.invite_to_space(space, argument),
+        "kick" => client.kick_from_space(space, argument),
+        "ban" => client.ban_from_space(space, argument, true),
+        "unban" => client.ban_from_space(space, argument, false),
+        "leave" => {
+            let name = client.space(space).map(|space| space.name.clone()).unwrap_or_default();
+            match client.leave_space(space) {
+                Ok(()) => app.status = format!("You left {}.", name),
+                Err(e) => app.status = format!("Failed to leave the space: {}", e),
+            }
+            return;
+        }
+        _ => {
+            app.status = format!("Spaces can not be renamed");
+            return;
+        }
+    };
+    match changed {
+        Ok(space) => {
+            if let Some(index) = open_channels(app, &space) {
+                let id = app.conversations[index].username.clone();
+                app.notice(&id, space_members(&space));
+            }
+        }
+        Err(e) => app.status = format!("Failed to change the space: {}", e),
+    }
+}
+
+/// Start or update the conversation of every channel of a space
+/// @return: the index of the first channel's conversation, None if the space has no channels
+fn open_channels(app: &mut App, space: &Space) -> Option<usize> {
+    let indices: Vec<usize> = space.channels.iter().map(|channel| app.open_channel(&channel.id, &channel.name, &space.id, &space.name)).collect();
+    indices.first().copied()
+}
+
+// start the conversation of a group or channel the client knows about, so it is listed under its name and not its id
+fn open_named(client: &Client, app: &mut App, conversation: &str) {
+    if let Some(group) = client.group(conversation) {
+        app.open_group(&group.id, &group.name);
+    }
+    if let Some(space) = client.channel_space(conversation) {
+        open_channels(app, space);
+    }
+}
+
+fn space_members(space: &Space) -> String {
+    let members: Vec<&str> = space.members.iter().map(|member| member.username.as_str()).collect();
+    format!("Members of {}: {}", space.name, members.join(", "))
+}
+
+/// Show the safety number of the open conversation so it can be compared with the contact
+fn show_safety_number(client: &mut Client, app: &mut App) {
+    let Some(username) = direct_conversation(app) else {
+        return;
+    };
+    match client.safety_number(&username) {
+        Ok(number) => app.notice(&username, format!("Safety number with {}: {}", username, number)),
+        Err(e) => app.status = format!("Failed to get the identity key of {}: {}", username, e),
+    }
+}
+
+/// Trust the new identity key of the contact in the open conversation after the user compared safety numbers
+fn trust_new_key(client: &mut Client, app: &mut App) {
+    let Some(username) = direct_conversation(app) else {
+        return;
+    };
+    match client.trust_new_key(&username) {
+        Ok(true) => app.notice(&username, format!("You now trust the new identity key of {}.", username)),
+        Ok(false) => app.status = format!("The identity key of {} has not changed", username),
+        Err(e) => app.status = format!("Failed to trust the new key of {}: {}", username, e),
+    }
+}
+
+/// Set how long after they are read the messages of the open conversation disappear, for everyone in it
+fn change_timer(client: &mut Client, app: &mut App, argument: &str) {
+    let Some(username) = app.current().map(|conversation| conversation.username.clone()) else {
+        app.status = format!("Open a conversation first");
+        return;
+    };
+    let Some(ttl) = parse_timer(argument) else {
+        app.status = format!("Usage: /timer <30s|5m|2h|1d|off>");
+        return;
+    };
+    match client.set_timer(&username, ttl) {
+        Ok(()) if ttl == 0 => app.notice(&username, format!("You turned off disappearing messages.")),
+        Ok(()) => app.notice(&username, format!("You set messages to disappear {} after they are read.", describe_timer(ttl))),
+        Err(e) => app.status = e,
+    }
+}
+
+/// Read a timer like 30s, 5m, 2h or 1d
+/// @return: the timer in seconds, 0 for off, or None if it is not a timer
+fn parse_timer(timer: &str) -> Option<u64> {
+    if timer == "off" {
+        return Some(0);
+    }
+    let unit = match timer.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let amount: u64 = timer[..timer.len() - 1].parse().ok()?;
+    amount.checked_mul(unit).filter(|ttl| *ttl > 0)
+}
+
+/// A timer in the largest unit it is a whole number of
+fn describe_timer(ttl: u64) -> String {
+    match ttl {
+        ttl if ttl % (24 * 60 * 60) == 0 => format!("{}d", ttl / (24 * 60 * 60)),
+        ttl if ttl % (60 * 60) == 0 => format!("{}h", ttl / (60 * 60)),
+        ttl if ttl % 60 == 0 => format!("{}m", ttl / 60),
+        ttl => format!("{}s", ttl),
+    }
+}
+
+/// The contact in the open conversation, telling the user if it is not the conversation with one
+fn direct_conversation(app: &mut App) -> Option<String> {
+    match app.current() {
+        Some(conversation) if conversation.is_direct() => Some(conversation.username.clone()),
+        // keys are kept per contact, so there is no safety number for a group or channel
+        Some(_) => {
+            app.status = format!("Open the conversation with one of the members first");
+            None
+        }
+        None => {
+            app.status = format!("Open a conversation first");
+            None
+        }
+    }
+}
+
+/// Send a message to the contact, group or channel in the open conversation
+fn send_message(client: &mut Client, app: &mut App, text: &str) {
+    let Some((username, is_group, is_channel)) = app.current().map(|conversation| (conversation.username.clone(), conversation.is_group, conversation.space.is_some())) else {
+        app.status = format!("Open a conversation with /open <username> first");
+        return;
+    };
+    let sent = if is_channel {
+        client.send_channel_message(&username, text)
+    } else if is_group {
+        client.send_group_message(&username, text)
+    } else {
+        client.send_message(&username, text)
+    };
+    match sent {
+        Ok(message) => {
+            app.receive(&username, message.into());
+            app.scroll = 0;
+        }
+        Err(e) => app.status = format!("Can not send a message to {}: {}", username, e),
+    }
+}
+
+/// Load the newest messages of the open conversation if none were loaded yet
+fn load_if_empty(client: &mut Client, app: &mut App) {
+    if app.current().is_some_and(|conversation| conversation.lines.is_empty()) {
+        load_older(client, app);
+    }
+}
+
+/// Load the page of messages before the oldest one shown in the open conversation
+fn load_older(client: &mut Client, app: &mut App) {
+    let Some(conversation) = app.current_mut() else {
+        return;
+    };
+    if conversation.pager.at_start() {
+        return;
+    }
+    let username = conversation.username.clone();
+    match client.history(&mut conversation.pager) {
+        Ok(page) => app.prepend(&username, page.into_iter().map(Into::into).collect()),
+        Err(e) => app.status = format!("Failed to get message history: {}", e),
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use serde::Deserialize;
+
+/// The `[server]` table of config files written before profiles were added
+#[derive(Debug, Deserialize)]
+pub struct Server {
+    pub address: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Tls {
+    pub enabled: Option<bool>,
+    pub server_name: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+/// A server the client can connect to
+#[derive(Debug, Default, Deserialize)]
+pub struct Profile {
+    pub host: Option<String>,
+    pub port: Option<String>,
+    /// filled in on the login screen
+    pub username: Option<String>,
+    pub tls: Option<Tls>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// the name of the profile used when none is picked on the command line
+    pub profile: Option<String>,
+    pub profiles: Option<BTreeMap<String, Profile>>,
+    pub server: Option<Server>,
+    pub tls: Option<Tls>,
+}
+
+impl Config {
+    /// Take the profile called `name`, or the default one if there is no name
+    /// config files without profiles are read as a single unnamed profile
+    pub fn select_profile(self, name: Option<&str>) -> Result<Profile, String> {
+        let mut profiles = self.profiles.unwrap_or_default();
+        let Some(name) = name.or(self.profile.as_deref()) else {
+            let (host, port) = match self.server.and_then(|server| server.address) {
+                Some(address) => match address.rsplit_once(':') {
+                    Some((host, port)) => (Some(host.to_string()), Some(port.to_string())),
+                    None => (Some(address), None),
+                },
+                None => (None, None),
+            };
+            return Ok(Profile { host, port, username: None, tls: self.tls });
+        };
+        match profiles.remove(name) {
+            Some(profile) => Ok(profile),
+            None if profiles.is_empty() => Err(format!("There is no server profile named `{}`, none are set up in the config file", name)),
+            None => Err(format!("There is no server profile named `{}`, the config file has {}",
+                name, profiles.keys().map(|name| format!("`{}`", name)).collect::<Vec<String>>().join(", "))),
+        }
+    }
+}
+
+pub const DEFAULT_CONFIG: &str = "\
+# profile: the server profile to use when none is picked with --profile\
+\nprofile = \"local\"\
+\n\
+\n# every [profiles.<name>] table is a server that can be picked with --profile <name>\
+\n[profiles.local]\
+\n# host: the server to connect to, an IPv4 address, an IPv6 address like [::1] or a hostname\
+\nhost = \"127.0.0.1\"\
+\n# port: the port the server listens on\
+\nport = \"2277\"\
+\n# username: filled in on the login screen, leave empty to type it every time\
+\nusername = \"\"\
+\n\
+\n[profiles.local.tls]\
+\n# enabled: connect with TLS, the server must have TLS enabled too\
+\nenabled = true\
+\n# server_name: the name the server's certificate was issued for\
+\nserver_name = \"localhost\"\
+\n# fingerprint: the SHA-256 fingerprint of the server's certificate, logged by the server when it starts\
+\n# pinning it accepts only that certificate, which is needed for the self-signed certificates servers generate\
+\n# when empty, the certificate must be issued by one of the web's certificate authorities\
+\nfingerprint = \"\"";
+
+/// Read the config file at `path`, writing the defaults to it first if it does not exist
+pub fn read_config(path: &Path) -> Result<Config, String> {
+    if let Some(dir) = path.parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(format!("Failed to create {}: {}", dir.display(), e));
+        }
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).read(true).open(path)
+        .map_err(|e| format!("Failed to open config file {}: {}", path.display(), e))?;
+    let mut data = String::new();
+    if let Err(e) = file.read_to_string(&mut data) {
+        return Err(format!("Failed to read config file {}: {}", path.display(), e));
+    }
+    if data.is_empty() {
+        if let Err(e) = file.write_all(DEFAULT_CONFIG.as_bytes()) {
+            return Err(format!("Failed to write defaults to config file {}: {}", path.display(), e));
+        }
+        data = format!("{}", DEFAULT_CONFIG);
+    }
+
+    toml::from_str(data.as_str()).map_err(|e| format!("Could not read config file {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_are_selected() {
+        let config = || toml::from_str::<Config>(DEFAULT_CONFIG).unwrap();
+        let local = config().select_profile(None).unwrap();
+        assert_eq!((local.host.as_deref(), local.port.as_deref()), (Some("127.0.0.1"), Some("2277")));
+        assert_eq!(local.tls.unwrap().enabled, Some(true));
+        assert!(config().select_profile(Some("local")).is_ok());
+        assert!(config().select_profile(Some("missing")).is_err());
+
+        // config files from before profiles
+        let old: Config = toml::from_str("[server]\naddress = \"10.0.0.1:2300\"\n[tls]\nenabled = false").unwrap();
+        let profile = old.select_profile(None).unwrap();
+        assert_eq!((profile.host.as_deref(), profile.port.as_deref()), (Some("10.0.0.1"), Some("2300")));
+        assert_eq!(profile.tls.unwrap().enabled, Some(false));
+    }
+}
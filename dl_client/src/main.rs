@@ -1,15 +1,72 @@
 // Delta Lima Client main file
+// `format!` is used for every owned string in this project, literal or not
+#![allow(clippy::useless_format)]
 
 use std::net::TcpStream;
-use dl_network_common::{Connection, ExpectedPacket, Packet};
+use std::path::Path;
+use crossterm::event::{KeyCode, KeyModifiers};
+use dl_client_lib::Handshake;
+use dl_network_common::{resolve_address, Connection};
+use dl_network_common::transport::Transport;
+use dl_network_common::tls::{client_config, TlsStream};
+use crate::app::{LoginField, LoginForm};
+use crate::args::{parse_args, USAGE};
+use crate::config::read_config;
+use crate::tui::{wait_input, Input, Terminal};
+
+mod app;
+mod args;
+mod chat;
+mod config;
+mod tui;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
-    // attempt connection
-    let ip = format!("127.0.0.1:2277");
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return;
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let config = match read_config(Path::new("config/client.toml")) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return;
+        }
+    };
+    let profile = match config.select_profile(args.profile.as_deref()) {
+        Ok(profile) => profile,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return;
+        }
+    };
+
+    // the command line overrides the profile
+    let host = args.host.or(profile.host).unwrap_or(format!("127.0.0.1"));
+    let port = args.port.or(profile.port).unwrap_or(format!("2277"));
+    let addresses = match resolve_address(&host, &port) {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            println!("ERROR: Invalid server address: {}", e);
+            return;
+        }
+    };
+    let username = args.username.or(profile.username).filter(|username| !username.is_empty());
+    let form = LoginForm::new(username, args.signup);
+    let tls = profile.tls.unwrap_or_default();
+
+    // attempt connection, trying every address the host resolved to in turn
     println!("Attempting to connect...");
-    let stream_result = TcpStream::connect(ip.clone());
+    let stream_result = TcpStream::connect(&addresses[..]);
     if let Err(e) = stream_result {
         println!("Failed to connect to server: {}", e);
         return;
@@ -17,109 +74,100 @@ fn main() {
 
     // now have a connection to the server
     let stream = stream_result.unwrap();
-    // turn the stream into a Connection structure
-    let mut connection = Connection::new(stream);
-
-    println!("Connected!");
 
-    // send a ping with version data to make the server happy
-    if let Err(_) = connection.send(Packet::Ping { version: VERSION.to_string(), disconnecting: false }) {
-        println!("ERROR: Failed to send version data to server! Disconnected.");
+    if !tls.enabled.unwrap_or(true) {
+        println!("WARNING: TLS is disabled, your password will be sent in cleartext!");
+        run(Connection::new(stream), form);
         return;
     }
 
-    // expect a PingResponse from the server
-    let response = connection.expect(ExpectedPacket::PingResponse);
-    if let Err(e) = response {
-        println!("ERROR: Failed to read version data from server: {}", e);
-        return;
-    }
-    match response.unwrap() {
-        Packet::PingResponse { valid, accepted_version } => {
-            if !valid {
-                println!("Invalid version! The server only accepts version {}, and you are on {}. Disconnected.", accepted_version, VERSION);
-                return;
-            }
-            println!("Valid version detected.");
+    // an empty fingerprint means nothing is pinned
+    let fingerprint = tls.fingerprint.filter(|fingerprint| !fingerprint.is_empty());
+    let tls_config = match client_config(fingerprint.as_deref()) {
+        Ok(tls_config) => tls_config,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            return;
         }
-        _ => unreachable!()
-    }
-
-    // DUMMY LOGIN INFO FOR TESTING
-    if connection.send(Packet::LoginRequest { username: format!("skepz"), password: format!("test"), signup: false }).is_err() {
-        println!("Failed to send dummy login info.");
-        return;
-    }
-
-    // expect a LoginResponse from the server
-    let response = connection.expect(ExpectedPacket::LoginResponse);
-    if let Err(e) = response {
-        println!("ERROR: Failed to login response data from server: {}", e);
-        return;
-    }
-    match response.unwrap() {
-        Packet::LoginResponse { valid, error } => {
-            if !valid {
-                let err = if let Some(e) = error {
-                    e
-                } else {
-                    format!("none")
-                };
-                println!("Invalid login: {}", err);
-                return;
+    };
+    let server_name = tls.server_name.unwrap_or(format!("localhost"));
+    match TlsStream::connect(tls_config, server_name.as_str(), stream) {
+        Ok(stream) => run(Connection::new(stream), form),
+        Err(e) => {
+            println!("Failed to connect to server securely: {}", e);
+            if fingerprint.is_none() {
+                println!("If the server uses a self-signed certificate, set `fingerprint` in the profile's `tls` table in `config/client.toml` to the one it logs when starting.");
             }
-            println!("Logged in!");
         }
-        _ => unreachable!()
     }
+}
 
-    // check if self is online
-    connection.send(Packet::UserOnlineRequest { username: format!("skepz") }).expect("Failed to send UserOnlineRequest to server!");
-
-    let online_response = connection.expect(ExpectedPacket::Message).expect("Failed to get UserResponse from server!");
+/// Talk to the server once connected
+fn run<T: Transport + 'static>(connection: Connection<T>, form: LoginForm) {
+    println!("Connected!");
 
-    match online_response {
-        Packet::UserResponse { response } => {
-            println!("I am {}", if response { "online!" } else { "offline" });
-        }
-        Packet::Disconnect => {
+    // send a ping with version data to make the server happy
+    let handshake = match Handshake::ping(connection, VERSION) {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            println!("ERROR: {} Disconnected.", e);
             return;
         }
-        Packet::Error {should_disconnect, error} => {
-            println!("Error from server: {}", error);
-            if should_disconnect {
-                return;
-            }
-        }
-        _ => unreachable!()
-    }
+    };
+    println!("Valid version detected.");
 
-    // send a test message
-    println!("Sending test message.");
+    // the terminal is put back before anything is printed
+    if let Err(e) = Terminal::enter().and_then(|mut terminal| session(&mut terminal, handshake, form)) {
+        println!("ERROR: {}", e);
+    }
 
-    connection.send(Packet::Message { message: format!("Test Message"), sender: format!(""), recipient: format!("test"), timestamp: format!("") }).expect("Failed to send test message");
+    println!("Disconnected");
+}
 
+/// Log in on the login screen, then show the chat screen until the user quits
+fn session<T: Transport + 'static>(terminal: &mut Terminal, mut handshake: Handshake<T>, mut form: LoginForm) -> Result<(), String> {
     loop {
-        let incoming = connection.check_expected(ExpectedPacket::Message).expect("Failed to get message");
-        if let Some(packet) = incoming {
-            match packet {
-                Packet::Message { message, sender, timestamp, .. } => {
-                    println!("MESSAGE from {} @ {} > {}", sender, timestamp, message);
+        terminal.draw_login(&form)?;
+        let key = match wait_input()? {
+            Input::Key(key) => key,
+            Input::Resize => {
+                terminal.clear()?;
+                continue;
+            }
+        };
+        match key.code {
+            KeyCode::Esc => {
+                handshake.disconnect();
+                return Ok(());
+            }
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                handshake.disconnect();
+                return Ok(());
+            }
+            KeyCode::Tab | KeyCode::Down => form.next_field(),
+            KeyCode::BackTab | KeyCode::Up => form.previous_field(),
+            KeyCode::Left | KeyCode::Right if form.field == LoginField::Mode => form.signup = !form.signup,
+            KeyCode::Backspace => form.backspace(),
+            KeyCode::Char(c) => form.type_char(c),
+            KeyCode::Enter => {
+                if form.username.is_empty() || form.password.is_empty() {
+                    form.error = Some(format!("Enter a username and a password."));
+                    continue;
                 }
-                Packet::Error { error, should_disconnect } => {
-                    println!("Error from server: {}", error);
-                    if should_disconnect {
-                        break;
+                match handshake.login(&form.username, &form.password, form.signup)? {
+                    None => break,
+                    Some(error) => {
+                        form.error = Some(error);
+                        form.password.clear();
+                        form.field = LoginField::Password;
                     }
                 }
-                Packet::Disconnect => {
-                    break;
-                }
-                _ => unreachable!()
             }
+            _ => {}
         }
     }
 
-    println!("Disconnected");
-
+    // keys are kept next to the client, in files named after the user
+    let client = handshake.start(".")?;
+    chat::run(terminal, client)
 }
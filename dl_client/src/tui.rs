@@ -0,0 +1,270 @@
+use std::io::{self, Stdout, Write};
+use std::time::Duration;
+use better_term::Color;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyEvent, KeyEventKind};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use crate::app::{App, Line, LoginField, LoginForm};
+
+const BORDER_COLOR: Color = Color::BrightBlack;
+const TITLE_COLOR: Color = Color::Cyan;
+const TEXT_COLOR: Color = Color::BrightWhite;
+const HINT_COLOR: Color = Color::White;
+const SELF_COLOR: Color = Color::BrightGreen;
+const CONTACT_COLOR: Color = Color::BrightCyan;
+const NOTICE_COLOR: Color = Color::BrightYellow;
+const UNREAD_COLOR: Color = Color::BrightYellow;
+const ERROR_COLOR: Color = Color::BrightRed;
+const ONLINE_COLOR: Color = Color::BrightGreen;
+const OFFLINE_COLOR: Color = Color::BrightBlack;
+
+// how many columns the conversation list takes up, not counting its border
+const SIDEBAR_WIDTH: usize = 22;
+
+/// Something the user did in the terminal
+pub enum Input {
+    Key(KeyEvent),
+    /// the terminal changed size and has to be drawn again from scratch
+    Resize,
+}
+
+/// Wait up to `timeout` for the user to do something
+pub fn next_input(timeout: Duration) -> Result<Option<Input>, String> {
+    match event::poll(timeout) {
+        Ok(true) => read_input(),
+        Ok(false) => Ok(None),
+        Err(e) => Err(format!("Failed to read from the terminal: {}", e)),
+    }
+}
+
+/// Wait for the user to do something
+pub fn wait_input() -> Result<Input, String> {
+    loop {
+        if let Some(input) = read_input()? {
+            return Ok(input);
+        }
+    }
+}
+
+fn read_input() -> Result<Option<Input>, String> {
+    match event::read() {
+        // some terminals report key releases too
+        Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => Ok(Some(Input::Key(key))),
+        Ok(Event::Resize(_, _)) => Ok(Some(Input::Resize)),
+        Ok(_) => Ok(None),
+        Err(e) => Err(format!("Failed to read from the terminal: {}", e)),
+    }
+}
+
+/// The terminal in full-screen mode, put back the way it was when dropped
+pub struct Terminal {
+    out: Stdout,
+}
+
+impl Terminal {
+    pub fn enter() -> Result<Self, String> {
+        if let Err(e) = terminal::enable_raw_mode() {
+            return Err(format!("Failed to set up the terminal: {}", e));
+        }
+        let mut out = io::stdout();
+        if let Err(e) = execute!(out, EnterAlternateScreen, Hide, Clear(ClearType::All)) {
+            let _ = terminal::disable_raw_mode();
+            return Err(format!("Failed to set up the terminal: {}", e));
+        }
+        Ok(Self { out })
+    }
+
+    /// Blank the whole screen, needed after it is resized
+    pub fn clear(&mut self) -> Result<(), String> {
+        execute!(self.out, Clear(ClearType::All)).map_err(|e| format!("Failed to draw: {}", e))
+    }
+
+    fn size(&self) -> (usize, usize) {
+        let (width, height) = terminal::size().unwrap_or((80, 24));
+        (width as usize, height as usize)
+    }
+
+    // write a row of text in one color, cut off or padded to `width` so it covers what was drawn there before
+    fn row(&mut self, x: usize, y: usize, width: usize, color: Color, text: &str) -> io::Result<()> {
+        queue!(self.out, MoveTo(x as u16, y as u16), Print(format!("{}{}", color, fit(text, width))))
+    }
+
+    pub fn draw_login(&mut self, form: &LoginForm) -> Result<(), String> {
+        self.login_rows(form).map_err(|e| format!("Failed to draw: {}", e))
+    }
+
+    fn login_rows(&mut self, form: &LoginForm) -> io::Result<()> {
+        let (width, height) = self.size();
+        let top = height.saturating_sub(9) / 2;
+        let cursor = |field: LoginField| if form.field == field { "> " } else { "  " };
+        let mode = if form.signup { format!(" log in  [sign up]") } else { format!("[log in]  sign up ") };
+
+        for y in 0..height {
+            self.row(0, y, width, TEXT_COLOR, "")?;
+        }
+        self.row(0, top, width, TITLE_COLOR, "  Delta Lima")?;
+        self.row(0, top + 2, width, TEXT_COLOR, format!("{}Username: {}", cursor(LoginField::Username), form.username).as_str())?;
+        self.row(0, top + 3, width, TEXT_COLOR, format!("{}Password: {}", cursor(LoginField::Password), "*".repeat(form.password.chars().count())).as_str())?;
+        self.row(0, top + 4, width, TEXT_COLOR, format!("{}Mode:     {}", cursor(LoginField::Mode), mode).as_str())?;
+        if let Some(error) = &form.error {
+            self.row(0, top + 6, width, ERROR_COLOR, format!("  {}", error).as_str())?;
+        }
+        self.row(0, top + 8, width, HINT_COLOR, "  Tab: next field   Space: change mode   Enter: continue   Esc: quit")?;
+        self.out.flush()
+    }
+
+    /// Draw the chat screen, keeping `app.scroll` within the messages of the open conversation
+    pub fn draw_chat(&mut self, app: &mut App) -> Result<(), String> {
+        self.chat_rows(app).map_err(|e| format!("Failed to draw: {}", e))
+    }
+
+    fn chat_rows(&mut self, app: &mut App) -> io::Result<()> {
+        let (width, height) = self.size();
+        // the title, the separator under the panes and the input line
+        let pane_height = height.saturating_sub(3);
+        let pane_x = SIDEBAR_WIDTH + 1;
+        let pane_width = width.saturating_sub(pane_x);
+
+        self.row(0, 0, width, TITLE_COLOR, format!(" Delta Lima - logged in as {}", app.username).as_str())?;
+
+        // the conversation list
+        for y in 0..pane_height {
+            let Some(conversation) = app.conversations.get(y) else {
+                self.row(0, y + 1, SIDEBAR_WIDTH, TEXT_COLOR, "")?;
+                continue;
+            };
+            // nobody is online in a group or channel, they get a mark of their own
+            let (dot_color, dot) = if !conversation.is_direct() {
+                (TEXT_COLOR, "#")
+            } else if conversation.online {
+                (ONLINE_COLOR, "●")
+            } else {
+                (OFFLINE_COLOR, "○")
+            };
+            let selected = if y == app.selected() { ">" } else { " " };
+            let unread = if conversation.unread > 0 { format!(" ({})", conversation.unread) } else { String::new() };
+            let name_color = if conversation.unread > 0 { UNREAD_COLOR } else if y == app.selected() { TEXT_COLOR } else { HINT_COLOR };
+            queue!(self.out, MoveTo(0, (y + 1) as u16), Print(format!("{}{}{}{} ", TEXT_COLOR, selected, dot_color, dot)))?;
+            self.row(3, y + 1, SIDEBAR_WIDTH - 3, name_color, format!("{}{}", conversation.name, unread).as_str())?;
+        }
+        for y in 0..pane_height {
+            self.row(SIDEBAR_WIDTH, y + 1, 1, BORDER_COLOR, "│")?;
+        }
+
+        // the newest messages of the open conversation that fit, scrolled up by `app.scroll`
+        let rows = match app.current() {
+            Some(conversation) => message_rows(&app.username, conversation.lines.as_slice(), conversation.marker(), pane_width),
+            None => vec![(HINT_COLOR, format!("No conversations yet, type /open <username> to start one"))],
+        };
+        // so scrolling back down starts from what is shown
+        app.scroll = app.scroll.min(rows.len().saturating_sub(pane_height));
+        let end = rows.len() - app.scroll;
+        let start = end.saturating_sub(pane_height);
+        // the messages sit at the bottom of the pane
+        let top = pane_height - (end - start);
+        for y in 0..top {
+            self.row(pane_x, y + 1, pane_width, TEXT_COLOR, "")?;
+        }
+        for (y, (color, text)) in rows.into_iter().skip(start).take(end - start).enumerate() {
+            self.row(pane_x, top + y + 1, pane_width, color, text.as_str())?;
+        }
+
+        let status = if app.status.is_empty() { String::new() } else { format!(" {} ", app.status) };
+        self.row(0, height.saturating_sub(2), width, BORDER_COLOR, format!("──{}{}", status, "─".repeat(width)).as_str())?;
+        // the end of the input is shown if it is too long for the line
+        let hidden = app.input.chars().count().saturating_sub(width.saturating_sub(4));
+        let input: String = app.input.chars().skip(hidden).collect();
+        self.row(0, height.saturating_sub(1), width, TEXT_COLOR, format!("> {}_", input).as_str())?;
+        self.out.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = execute!(self.out, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// the rows of the message pane for the lines of a conversation, with the unread marker before `marker`
+fn message_rows(username: &str, lines: &[Line], marker: Option<usize>, width: usize) -> Vec<(Color, String)> {
+    let mut rows = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        if marker == Some(index) {
+            rows.push((UNREAD_COLOR, format!("── new messages {}", "─".repeat(width))));
+        }
+        let (color, text) = match line {
+            Line::Message { sender, timestamp, text, .. } => {
+                let color = if sender == username { SELF_COLOR } else { CONTACT_COLOR };
+                match short_time(timestamp) {
+                    Some(time) => (color, format!("{} {} > {}", time, sender, text)),
+                    None => (color, format!("{} > {}", sender, text)),
+                }
+            }
+            Line::Notice(text) => (NOTICE_COLOR, format!("* {}", text)),
+        };
+        rows.extend(wrap(text.as_str(), width).into_iter().map(|row| (color, row)));
+    }
+    rows
+}
+
+// the hours and minutes of a timestamp sent by the server, like `2023-01-01 12:34:56.789 UTC`
+fn short_time(timestamp: &str) -> Option<&str> {
+    timestamp.split_whitespace().nth(1).and_then(|time| time.get(..5))
+}
+
+/// Split text into rows of at most `width` characters, breaking at spaces where possible
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    for word in text.split(' ') {
+        let mut word: Vec<char> = word.chars().collect();
+        let row_len = row.chars().count();
+        if row_len > 0 && row_len + 1 + word.len() > width {
+            rows.push(std::mem::take(&mut row));
+        } else if row_len > 0 {
+            row.push(' ');
+        }
+        // words longer than a row are broken up
+        while row.chars().count() + word.len() > width {
+            let fits = width - row.chars().count();
+            row.extend(word.drain(..fits));
+            rows.push(std::mem::take(&mut row));
+        }
+        row.extend(word);
+    }
+    rows.push(row);
+    rows
+}
+
+// cut text off at `width` characters, padding it with spaces if it is shorter
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.push_str(" ".repeat(width - len).as_str());
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_wrapped() {
+        assert_eq!(wrap("hello there world", 11), vec!["hello there", "world"]);
+        assert_eq!(wrap("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap("hi abcdefgh", 4), vec!["hi", "abcd", "efgh"]);
+        assert_eq!(wrap("", 5), vec![""]);
+    }
+
+    #[test]
+    fn rows_fit_their_width() {
+        assert_eq!(fit("hello", 3), "hel");
+        assert_eq!(fit("hi", 4), "hi  ");
+        assert_eq!(short_time("2023-01-01 12:34:56.789 UTC"), Some("12:34"));
+        assert_eq!(short_time(""), None);
+    }
+}
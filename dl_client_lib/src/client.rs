@@ -0,0 +1,829 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use dl_network_common::{Connection, Contact, ContactState, Group, Packet, Role, SealedCopy, SentMsg, Space, Timer, MAX_PREKEYS};
+use dl_network_common::transport::Transport;
+use dl_network_common::crypto::{open, safety_number, IdentityKey, SealedPayload};
+use crate::history::{HistoryPager, HistoryStore};
+use crate::inbox::Inbox;
+use crate::keys::{load_or_create_identity, KeyDirectory, PreKeyStore};
+use crate::link::Link;
+use crate::session::SessionStore;
+use crate::trust::{Trust, TrustStore};
+
+// the server does not announce when users come online, so watched users are asked about every so often
+const PRESENCE_REFRESH: Duration = Duration::from_secs(10);
+
+/// A message in a conversation, already decrypted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub id: String,
+    /// the other user in the conversation, or the id of the group or channel it was sent to
+    pub conversation: String,
+    pub sender: String,
+    /// when the server stored the message
+    pub timestamp: String,
+    /// the text of the message, or why it could not be decrypted
+    pub text: String,
+    /// seconds the message should be kept after it is read, 0 if it does not disappear
+    pub ttl: u64,
+}
+
+/// Something that happened without the client asking for it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// a message arrived, or one missed while offline was found
+    MessageReceived(Message),
+    /// a watched user came online or went offline
+    PresenceChanged { username: String, online: bool },
+    /// another member changed a group, the user is no longer in it if they are not one of its members
+    GroupChanged(Group),
+    /// another member changed a space, the user is no longer in it if they are not one of its members
+    SpaceChanged(Space),
+    /// another user sent a friend request, or answered or removed one, ContactState::None if they are no longer related
+    FriendChanged(Contact),
+    /// another user changed the disappearing message timer of a conversation
+    TimerChanged(Timer),
+    /// the server handed out a new identity key for a contact
+    /// sending to them is blocked until the safety numbers are compared and `trust_new_key` is called
+    IdentityChanged { username: String, old_safety_number: String, new_safety_number: String },
+    /// something went wrong that was not the answer to a request
+    Error(String),
+    /// the connection closed, no more events follow
+    Disconnected(String),
+}
+
+/// A connection to the server that passed the version check, waiting for a user to log in
+pub struct Handshake<T: Transport = TcpStream> {
+    connection: Connection<T>,
+    // the user that logged in, once one did
+    username: Option<String>,
+}
+
+impl<T: Transport + 'static> Handshake<T> {
+    /// Send a ping with the client's version, checking that the server accepts it
+    pub fn ping(mut connection: Connection<T>, version: &str) -> Result<Self, String> {
+        if connection.send(Packet::Ping { version: version.to_string(), disconnecting: false }).is_err() {
+            return Err(format!("Failed to send version data to server!"));
+        }
+
+        // expect a PingResponse from the server
+        match connection.recv() {
+            Ok(Packet::PingResponse { valid: true, .. }) => Ok(Self { connection, username: None }),
+            Ok(Packet::PingResponse { accepted_version, .. }) => {
+                Err(format!("Invalid version! The server only accepts version {}, and you are on {}.", accepted_version, version))
+            }
+            Ok(Packet::Error { error, .. }) => Err(format!("Error from server: {}", error)),
+            Ok(_) => Err(format!("The server did not reply to the version check.")),
+            Err(e) => Err(format!("Failed to read version data from server: {}", e)),
+        }
+    }
+
+    /// Log in, or create a new account if `signup` is set
+    /// @return: Ok(None) once logged in, Ok(Some(reason)) if the server refused and another attempt can be made
+    pub fn login(&mut self, username: &str, password: &str, signup: bool) -> Result<Option<String>, String> {
+        let request = Packet::LoginRequest { username: username.to_string(), password: password.to_string(), signup };
+        if self.connection.send(request).is_err() {
+            return Err(format!("Failed to send login info to server!"));
+        }
+
+        // expect a LoginResponse from the server
+        match self.connection.recv() {
+            Ok(Packet::LoginResponse { valid: true, .. }) => {
+                self.username = Some(username.to_string());
+                Ok(None)
+            }
+            Ok(Packet::LoginResponse { error, .. }) => Ok(Some(error.unwrap_or(format!("Invalid login.")))),
+            Ok(Packet::Error { error, should_disconnect: false }) => Ok(Some(error)),
+            Ok(Packet::Error { error, .. }) => Err(format!("Error from server: {}", error)),
+            Ok(_) => Err(format!("The server did not reply to the login request.")),
+            Err(e) => Err(format!("Failed to read login response from server: {}", e)),
+        }
+    }
+
+    /// Hang up without logging in
+    pub fn disconnect(mut self) {
+        // the server cleans up either way, so a failure here does not matter
+        let _ = self.connection.disconnect();
+    }
+
+    /// Publish the logged in user's keys and start reading from the server in the background
+    /// keys, prekeys, sessions, trusted contacts and the message history are kept in `dir`, in files named after the user
+    pub fn start<P: AsRef<Path>>(mut self, dir: P) -> Result<Client, String> {
+        let Some(username) = self.username.take() else {
+            return Err(format!("Log in before starting the client"));
+        };
+        let dir = dir.as_ref();
+
+        // publish the public half of our identity key so others can seal messages for us
+        let identity = load_or_create_identity(dir.join(format!("{}.key", username)))?;
+        if self.connection.send(Packet::IdentityKeyUpload { key: identity.public().to_vec(), signing_key: identity.signing_public().to_vec() }).is_err() {
+            return Err(format!("Failed to send identity key to server!"));
+        }
+
+        // publish the signed prekey, the server asks for one-time prekeys when it needs them
+        let mut prekeys = PreKeyStore::load(dir.join(format!("{}.prekeys", username)))?;
+        let signed_prekey = prekeys.signed_prekey()?;
+        if self.connection.send(Packet::SignedPreKeyUpload { signature: identity.sign_prekey(&signed_prekey), prekey: signed_prekey }).is_err() {
+            return Err(format!("Failed to send signed prekey to server!"));
+        }
+
+        let trust = TrustStore::load(dir.join(format!("{}.trusted", username)))?;
+        let history = HistoryStore::load(dir.join(format!("{}.history", username)))?;
+        let (link, pushes) = Link::new(self.connection)?;
+        let groups = match link.request(Packet::GroupListRequest)? {
+            Packet::GroupList { groups } => groups.into_iter().map(|group| (group.id.clone(), group)).collect(),
+            _ => return Err(format!("The server did not reply with the groups the user is in")),
+        };
+        let spaces = match link.request(Packet::SpaceListRequest)? {
+            Packet::SpaceList { spaces } => spaces.into_iter().map(|space| (space.id.clone(), space)).collect(),
+            _ => return Err(format!("The server did not reply with the spaces the user is in")),
+        };
+        let friends = match link.request(Packet::ContactListRequest)? {
+            Packet::ContactList { contacts } => contacts.into_iter().map(|contact| (contact.username, contact.state)).collect(),
+            _ => return Err(format!("The server did not reply with the friends of the user")),
+        };
+        let timers = match link.request(Packet::TimerListRequest)? {
+            Packet::TimerList { timers } => timers.into_iter().map(|timer| (timer.conversation, timer.ttl)).collect(),
+            _ => return Err(format!("The server did not reply with the disappearing message timers of the user")),
+        };
+        Ok(Client {
+            sessions: SessionStore::new(dir.join(format!("{}.sessions", username))),
+            username,
+            identity,
+            prekeys,
+            trust,
+            history,
+            inbox: Inbox::new(),
+            keys: KeyDirectory::new(),
+            link,
+            pushes,
+            events: VecDeque::new(),
+            watched: BTreeMap::new(),
+            groups,
+            spaces,
+            friends,
+            timers,
+            presence_checked: None,
+            disconnected: false,
+        })
+    }
+}
+
+/// A logged in user's connection to the server
+/// messages are encrypted and decrypted on the way, and what the server sends on its own is turned into events
+pub struct Client {
+    username: String,
+    identity: IdentityKey,
+    prekeys: PreKeyStore,
+    sessions: SessionStore,
+    trust: TrustStore,
+    history: HistoryStore,
+    inbox: Inbox,
+    keys: KeyDirectory,
+    link: Link,
+    // what the server sent without being asked, ending with why the connection closed
+    pushes: Receiver<Result<Packet, String>>,
+    // events found while handling something else, returned before waiting for more
+    events: VecDeque<Event>,
+    // the users whose presence is reported, with whether they were online when last asked
+    watched: BTreeMap<String, Option<bool>>,
+    // the groups the user is in by id, kept up to date with the changes the server announces
+    groups: BTreeMap<String, Group>,
+    // the spaces the user is in by id, kept up to date the same way
+    spaces: BTreeMap<String, Space>,
+    // the friends of the user and the unanswered friend requests they sent or were sent, by username
+    friends: BTreeMap<String, ContactState>,
+    // the disappearing message timer of each conversation that has one, kept up to date with the changes the server announces
+    timers: BTreeMap<String, u64>,
+    presence_checked: Option<Instant>,
+    disconnected: bool,
+}
+
+impl Client {
+    /// The user that is logged in
+    pub fn username(&self) -> &str {
+        self.username.as_str()
+    }
+
+    /// The users there is a trusted identity key for, everyone talked to before, in alphabetical order
+    pub fn contacts(&self) -> Vec<String> {
+        self.trust.contacts().map(|username| username.to_string()).collect()
+    }
+
+    /// The groups the user is in, in alphabetical order of their names
+    pub fn groups(&self) -> Vec<Group> {
+        let mut groups: Vec<Group> = self.groups.values().cloned().collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups
+    }
+
+    /// The group with an id, if the user is in it
+    pub fn group(&self, id: &str) -> Option<&Group> {
+        self.groups.get(id)
+    }
+
+    /// The spaces the user is in, in alphabetical order of their names
+    pub fn spaces(&self) -> Vec<Space> {
+        let mut spaces: Vec<Space> = self.spaces.values().cloned().collect();
+        spaces.sort_by(|a, b| a.name.cmp(&b.name));
+        spaces
+    }
+
+    /// The space with an id, if the user is in it
+    pub fn space(&self, id: &str) -> Option<&Space> {
+        self.spaces.get(id)
+    }
+
+    /// The space a channel is in, if the user is in that space
+    pub fn channel_space(&self, channel: &str) -> Option<&Space> {
+        self.spaces.values().find(|space| space.channels.iter().any(|other| other.id == channel))
+    }
+
+    /// The friends of the user and the unanswered friend requests they sent or were sent, in alphabetical order
+    pub fn friends(&self) -> Vec<Contact> {
+        self.friends.iter().map(|(username, state)| Contact { username: username.clone(), state: *state }).collect()
+    }
+
+    /// Wait up to `timeout` for something to happen
+    /// @return: None if nothing did, and always once the Disconnected event was returned
+    pub fn next_event(&mut self, timeout: Duration) -> Option<Event> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        if self.disconnected {
+            return None;
+        }
+
+        if self.presence_checked.is_none_or(|checked| checked.elapsed() >= PRESENCE_REFRESH) {
+            self.refresh_presence();
+            self.presence_checked = Some(Instant::now());
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+        }
+
+        match self.pushes.recv_timeout(timeout) {
+            Ok(Ok(packet)) => self.handle_packet(packet),
+            Ok(Err(e)) => self.closed(format!("Lost the connection to the server: {}", e)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => self.closed(format!("The connection was closed.")),
+        }
+        self.events.pop_front()
+    }
+
+    /// Report the presence of a user with PresenceChanged events, starting with whether they are online now
+    pub fn watch(&mut self, username: &str) {
+        if !self.watched.contains_key(username) {
+            self.watched.insert(username.to_string(), None);
+            // checked on the next call to next_event
+            self.presence_checked = None;
+        }
+    }
+
+    /// Seal a message for a user and send it
+    /// @return: the message as the server stored it
+    pub fn send_message(&mut self, username: &str, text: &str) -> Result<Message, String> {
+        let sealed = self.seal(username, text)?;
+        let message = Packet::Message { id: format!(""), seq: 0, message: sealed, sender: format!(""), recipient: username.to_string(), group: None, timestamp: format!(""), ttl: 0 };
+        let Packet::MessageReceipt { id, seq, timestamp, .. } = self.link.request(message)? else {
+            return Err(format!("The server did not confirm the message"));
+        };
+        // our own messages take up sequence numbers in the conversation too
+        self.request_missing(username, seq);
+        let message = Message { id, conversation: username.to_string(), sender: self.username.clone(), timestamp, text: text.to_string(), ttl: self.timer(username) };
+        self.remember(&message, seq);
+        Ok(message)
+    }
+
+    /// Seal a message for every other member of a group and send it
+    /// @return: the message as the server stored it
+    pub fn send_group_message(&mut self, group: &str, text: &str) -> Result<Message, String> {
+        let copies = self.seal_for_group(group, text)?;
+        let reply = match self.link.request(Packet::GroupMessage { group: group.to_string(), copies }) {
+            // the members changed without us hearing about it yet, so it is sealed again for the members the server has
+            Err(e) if e == "The message must be sealed for every other member of the group" => {
+                self.refresh_groups()?;
+                let copies = self.seal_for_group(group, text)?;
+                self.link.request(Packet::GroupMessage { group: group.to_string(), copies })?
+            }
+            reply => reply?,
+        };
+        let Packet::MessageReceipt { id, seq, timestamp, .. } = reply else {
+            return Err(format!("The server did not confirm the message"));
+        };
+        self.request_missing(group, seq);
+        let message = Message { id, conversation: group.to_string(), sender: self.username.clone(), timestamp, text: text.to_string(), ttl: self.timer(group) };
+        self.remember(&message, seq);
+        Ok(message)
+    }
+
+    /// Create a group with the user and other members in it
+    pub fn create_group(&mut self, name: &str, members: &[String]) -> Result<Group, String> {
+        self.change_group(Packet::GroupCreate { name: name.to_string(), members: members.to_vec() })
+    }
+
+    /// Add a user to a group
+    pub fn invite_to_group(&mut self, group: &str, username: &str) -> Result<Group, String> {
+        self.change_group(Packet::GroupInvite { group: group.to_string(), username: username.to_string() })
+    }
+
+    /// Remove another member from a group
+    pub fn remove_from_group(&mut self, group: &str, username: &str) -> Result<Group, String> {
+        self.change_group(Packet::GroupRemove { group: group.to_string(), username: username.to_string() })
+    }
+
+    /// Leave a group, no more of its messages are received
+    pub fn leave_group(&mut self, group: &str) -> Result<(), String> {
+        self.change_group(Packet::GroupLeave { group: group.to_string() })?;
+        Ok(())
+    }
+
+    /// Give a group a new name
+    pub fn rename_group(&mut self, group: &str, name: &str) -> Result<Group, String> {
+        self.change_group(Packet::GroupRename { group: group.to_string(), name: name.to_string() })
+    }
+
+    /// Seal a message for every other member of a channel's space and send it to the channel
+    /// @return: the message as the server stored it
+    pub fn send_channel_message(&mut self, channel: &str, text: &str) -> Result<Message, String> {
+        let copies = self.seal_for_channel(channel, text)?;
+        let reply = match self.link.request(Packet::ChannelMessage { channel: channel.to_string(), copies }) {
+            // the same as for groups, someone joined or left without us hearing about it yet
+            Err(e) if e == "The message must be sealed for every other member of the space" => {
+                self.refresh_spaces()?;
+                let copies = self.seal_for_channel(channel, text)?;
+                self.link.request(Packet::ChannelMessage { channel: channel.to_string(), copies })?
+            }
+            reply => reply?,
+        };
+        let Packet::MessageReceipt { id, seq, timestamp, .. } = reply else {
+            return Err(format!("The server did not confirm the message"));
+        };
+        self.request_missing(channel, seq);
+        let message = Message { id, conversation: channel.to_string(), sender: self.username.clone(), timestamp, text: text.to_string(), ttl: self.timer(channel) };
+        self.remember(&message, seq);
+        Ok(message)
+    }
+
+    /// Create a space owned by the user, with a general channel in it
+    pub fn create_space(&mut self, name: &str) -> Result<Space, String> {
+        self.change_space(Packet::SpaceCreate { name: name.to_string() })
+    }
+
+    /// Add a user to a space
+    pub fn invite_to_space(&mut self, space: &str, username: &str) -> Result<Space, String> {
+        self.change_space(Packet::SpaceInvite { space: space.to_string(), username: username.to_string() })
+    }
+
+    /// Leave a space, no more of its messages are received
+    pub fn leave_space(&mut self, space: &str) -> Result<(), String> {
+        self.change_space(Packet::SpaceLeave { space: space.to_string() })?;
+        Ok(())
+    }
+
+    /// Remove another member from a space, needs the KICK permission
+    pub fn kick_from_space(&mut self, space: &str, username: &str) -> Result<Space, String> {
+        self.change_space(Packet::SpaceKick { space: space.to_string(), username: username.to_string() })
+    }
+
+    /// Ban a user from a space or lift their ban, needs the BAN permission
+    pub fn ban_from_space(&mut self, space: &str, username: &str, banned: bool) -> Result<Space, String> {
+        self.change_space(Packet::SpaceBan { space: space.to_string(), username: username.to_string(), banned })
+    }
+
+    /// Add a channel to a space, needs the MANAGE_CHANNELS permission
+    pub fn create_channel(&mut self, space: &str, name: &str) -> Result<Space, String> {
+        self.change_space(Packet::ChannelCreate { space: space.to_string(), name: name.to_string() })
+    }
+
+    /// Remove a channel from a space, needs the MANAGE_CHANNELS permission
+    pub fn delete_channel(&mut self, space: &str, channel: &str) -> Result<Space, String> {
+        self.change_space(Packet::ChannelDelete { space: space.to_string(), channel: channel.to_string() })
+    }
+
+    /// Create a role, or change one if its id is set, needs the MANAGE_ROLES permission
+    pub fn set_role(&mut self, space: &str, role: Role) -> Result<Space, String> {
+        self.change_space(Packet::RoleSet { space: space.to_string(), role })
+    }
+
+    /// Delete a role of a space, needs the MANAGE_ROLES permission
+    pub fn delete_role(&mut self, space: &str, role: &str) -> Result<Space, String> {
+        self.change_space(Packet::RoleDelete { space: space.to_string(), role: role.to_string() })
+    }
+
+    /// Give a role to a member or take it away, needs the MANAGE_ROLES permission
+    pub fn assign_role(&mut self, space: &str, role: &str, username: &str, assigned: bool) -> Result<Space, String> {
+        self.change_space(Packet::RoleAssign { space: space.to_string(), role: role.to_string(), username: username.to_string(), assigned })
+    }
+
+    /// Ask a user to become a friend, or accept the request they already sent
+    pub fn send_friend_request(&mut self, username: &str) -> Result<ContactState, String> {
+        self.change_friends(username, Packet::ContactRequest { username: username.to_string() })
+    }
+
+    /// Accept or decline the friend request a user sent
+    pub fn answer_friend_request(&mut self, username: &str, accept: bool) -> Result<ContactState, String> {
+        self.change_friends(username, Packet::ContactRespond { username: username.to_string(), accept })
+    }
+
+    /// Stop being friends with a user, or take back a friend request
+    pub fn remove_friend(&mut self, username: &str) -> Result<(), String> {
+        self.change_friends(username, Packet::ContactRemove { username: username.to_string() })?;
+        Ok(())
+    }
+
+    /// Block a user, the server then drops their messages, hides whether the user is online and ignores their friend requests
+    /// blocking someone also ends any friendship or friend request with them
+    /// @return: the users that are blocked now, in alphabetical order
+    pub fn block(&mut self, username: &str) -> Result<Vec<String>, String> {
+        let blocked = self.change_blocks(Packet::Block { username: username.to_string() })?;
+        self.friends.remove(username);
+        Ok(blocked)
+    }
+
+    /// Stop blocking a user
+    /// @return: the users that are still blocked, in alphabetical order
+    pub fn unblock(&mut self, username: &str) -> Result<Vec<String>, String> {
+        self.change_blocks(Packet::Unblock { username: username.to_string() })
+    }
+
+    /// The users the user blocked, in alphabetical order
+    pub fn blocked(&mut self) -> Result<Vec<String>, String> {
+        self.change_blocks(Packet::BlockListRequest)
+    }
+
+    /// The disappearing message timer of a conversation in seconds, 0 if messages do not disappear
+    /// the conversation is the other user, or the id of a group or channel
+    pub fn timer(&self, conversation: &str) -> u64 {
+        self.timers.get(conversation).copied().unwrap_or(0)
+    }
+
+    /// Make the messages of a conversation disappear `ttl` seconds after they are read, 0 to keep them
+    /// the timer is shared with everyone in the conversation, changing it in a channel needs the MANAGE_CHANNELS permission
+    pub fn set_timer(&mut self, conversation: &str, ttl: u64) -> Result<(), String> {
+        let Packet::TimerInfo { timer } = self.link.request(Packet::TimerSet { conversation: conversation.to_string(), ttl })? else {
+            return Err(format!("The server sent an unexpected reply"));
+        };
+        self.update_timer(timer);
+        Ok(())
+    }
+
+    /// Get the page of messages before the ones `pager` already went through, oldest first
+    /// pages come from the messages kept on this device, the ones sent or received since the client started are left out
+    pub fn history(&mut self, pager: &mut HistoryPager) -> Result<Vec<Message>, String> {
+        let page = pager.previous_page(&self.history);
+        Ok(page.into_iter().filter(|message| !self.inbox.seen(message.id.as_str())).collect())
+    }
+
+    /// Check whether there is a user with a name
+    pub fn user_exists(&mut self, username: &str) -> Result<bool, String> {
+        self.ask(Packet::UserExistsRequest { username: username.to_string() })
+    }
+
+    /// Check whether a user is online right now
+    pub fn is_online(&mut self, username: &str) -> Result<bool, String> {
+        self.ask(Packet::UserOnlineRequest { username: username.to_string() })
+    }
+
+    /// The safety number of the conversation with a user, the same on both sides if nobody is intercepting it
+    pub fn safety_number(&mut self, username: &str) -> Result<String, String> {
+        let key = self.check_identity(username)?;
+        Ok(safety_number(&self.username, &self.identity.public(), username, &key))
+    }
+
+    /// Whether the identity key of a contact changed and was not trusted yet, blocking messages to them
+    pub fn identity_changed(&self, username: &str) -> bool {
+        self.trust.changed(username).is_some()
+    }
+
+    /// Trust the new identity key of a contact after the user compared safety numbers
+    /// @return: false if the contact's key had not changed
+    pub fn trust_new_key(&mut self, username: &str) -> Result<bool, String> {
+        if !self.trust.acknowledge(username)? {
+            return Ok(false);
+        }
+        // the old session was agreed with the old key
+        self.sessions.remove(username)?;
+        Ok(true)
+    }
+
+    /// Log out, waiting until everything queued was sent
+    pub fn disconnect(self) {
+        // the server cleans up either way, so a failure here does not matter
+        let _ = self.link.send(Packet::Disconnect);
+        self.link.close();
+    }
+
+    // send a request that changes a group, keeping what the server replies with
+    fn change_group(&mut self, request: Packet) -> Result<Group, String> {
+        let Packet::GroupInfo { group } = self.link.request(request)? else {
+            return Err(format!("The server sent an unexpected reply"));
+        };
+        self.update_group(group.clone());
+        Ok(group)
+    }
+
+    // remember how a group looks now, forgetting it once the user is no longer a member
+    fn update_group(&mut self, group: Group) {
+        if group.members.contains(&self.username) {
+            self.groups.insert(group.id.clone(), group);
+        } else {
+            self.groups.remove(&group.id);
+        }
+    }
+
+    // remember the timer of a conversation, forgetting it once it is turned off
+    fn update_timer(&mut self, timer: Timer) {
+        if timer.ttl > 0 {
+            self.timers.insert(timer.conversation, timer.ttl);
+        } else {
+            self.timers.remove(&timer.conversation);
+        }
+    }
+
+    // ask the server for the groups the user is in again
+    fn refresh_groups(&mut self) -> Result<(), String> {
+        let Packet::GroupList { groups } = self.link.request(Packet::GroupListRequest)? else {
+            return Err(format!("The server did not reply with the groups the user is in"));
+        };
+        self.groups = groups.into_iter().map(|group| (group.id.clone(), group)).collect();
+        Ok(())
+    }
+
+    // send a request that changes a space, keeping what the server replies with
+    fn change_space(&mut self, request: Packet) -> Result<Space, String> {
+        let Packet::SpaceInfo { space } = self.link.request(request)? else {
+            return Err(format!("The server sent an unexpected reply"));
+        };
+        self.update_space(space.clone());
+        Ok(space)
+    }
+
+    // remember how a space looks now, forgetting it once the user is no longer a member
+    fn update_space(&mut self, space: Space) {
+        if space.members.iter().any(|member| member.username == self.username) {
+            self.spaces.insert(space.id.clone(), space);
+        } else {
+            self.spaces.remove(&space.id);
+        }
+    }
+
+    // ask the server for the spaces the user is in again
+    fn refresh_spaces(&mut self) -> Result<(), String> {
+        let Packet::SpaceList { spaces } = self.link.request(Packet::SpaceListRequest)? else {
+            return Err(format!("The server did not reply with the spaces the user is in"));
+        };
+        self.spaces = spaces.into_iter().map(|space| (space.id.clone(), space)).collect();
+        Ok(())
+    }
+
+    // send a request that changes a friend, keeping the list the server replies with
+    // returns how the user is related to them now
+    fn change_friends(&mut self, username: &str, request: Packet) -> Result<ContactState, String> {
+        let Packet::ContactList { contacts } = self.link.request(request)? else {
+            return Err(format!("The server sent an unexpected reply"));
+        };
+        self.friends = contacts.into_iter().map(|contact| (contact.username, contact.state)).collect();
+        Ok(self.friends.get(username).copied().unwrap_or(ContactState::None))
+    }
+
+    // send a request that blocks or unblocks a user, or only asks which users are blocked
+    fn change_blocks(&mut self, request: Packet) -> Result<Vec<String>, String> {
+        let Packet::BlockList { usernames } = self.link.request(request)? else {
+            return Err(format!("The server sent an unexpected reply"));
+        };
+        Ok(usernames)
+    }
+
+    // seal a message for a contact, starting a session with their prekeys if there is none yet
+    fn seal(&mut self, username: &str, text: &str) -> Result<SealedPayload, String> {
+        // check who we are talking to before sending them anything
+        let recipient = self.check_identity(username)?;
+        if self.trust.changed(username).is_some() {
+            return Err(format!("Sending to {} is blocked until their new identity key is trusted", username));
+        }
+
+        let bundle = || {
+            let bundle = self.keys.bundle(&self.link, username)?;
+            if bundle.identity_key != recipient {
+                return Err(format!("The server handed out prekeys for a different identity key"));
+            }
+            Ok(bundle)
+        };
+        self.sessions.encrypt(&self.identity, username, text.as_bytes(), bundle)
+    }
+
+    // seal a copy of a message for every other member of a group
+    fn seal_for_group(&mut self, group: &str, text: &str) -> Result<Vec<SealedCopy>, String> {
+        let Some(group) = self.groups.get(group) else {
+            return Err(format!("You are not in this group"));
+        };
+        let others: Vec<String> = group.members.iter().filter(|member| **member != self.username).cloned().collect();
+        self.seal_for_each(others, text)
+    }
+
+    // seal a copy of a message for every other member of a channel's space
+    fn seal_for_channel(&mut self, channel: &str, text: &str) -> Result<Vec<SealedCopy>, String> {
+        let Some(space) = self.channel_space(channel) else {
+            return Err(format!("You are not in this space"));
+        };
+        let others: Vec<String> = space.members.iter().map(|member| member.username.clone()).filter(|member| *member != self.username).collect();
+        self.seal_for_each(others, text)
+    }
+
+    // seal a copy of a message for each of the given users
+    fn seal_for_each(&mut self, others: Vec<String>, text: &str) -> Result<Vec<SealedCopy>, String> {
+        let mut copies = Vec::with_capacity(others.len());
+        for recipient in others {
+            let message = self.seal(&recipient, text)?;
+            copies.push(SealedCopy { recipient, message });
+        }
+        Ok(copies)
+    }
+
+    // send a request the server answers with a UserResponse
+    fn ask(&mut self, request: Packet) -> Result<bool, String> {
+        match self.link.request(request)? {
+            Packet::UserResponse { response } => Ok(response),
+            _ => Err(format!("The server sent an unexpected reply")),
+        }
+    }
+
+    fn closed(&mut self, reason: String) {
+        self.disconnected = true;
+        self.events.push_back(Event::Disconnected(reason));
+    }
+
+    // handle a packet the server sent without being asked
+    fn handle_packet(&mut self, packet: Packet) {
+        match packet {
+            Packet::Message { id, seq, message, sender, group, timestamp, ttl, .. } => {
+                // duplicates are acked again in case the first ack was lost, also when it was read before the client started
+                if self.inbox.seen(id.as_str()) || self.history.contains(id.as_str()) {
+                    self.acknowledge(id.as_str());
+                    return;
+                }
+                // a message that can not be read is not acked, so the server sends it again when the user logs in next
+                let text = match self.decrypt(&sender, &message) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        self.events.push_back(Event::Error(format!("Failed to read a message from {}: {}", sender, e)));
+                        return;
+                    }
+                };
+                // messages to a group or channel are in its conversation, the others in the one with the sender
+                let conversation = group.unwrap_or(sender.clone());
+                let message = Message { id: id.clone(), conversation: conversation.clone(), sender, timestamp, text, ttl };
+                self.remember(&message, seq);
+                self.events.push_back(Event::MessageReceived(message));
+                self.acknowledge(id.as_str());
+                self.request_missing(&conversation, seq);
+            }
+            Packet::GroupUpdate { group } => {
+                self.update_group(group.clone());
+                self.events.push_back(Event::GroupChanged(group));
+            }
+            Packet::SpaceUpdate { space } => {
+                self.update_space(space.clone());
+                self.events.push_back(Event::SpaceChanged(space));
+            }
+            Packet::TimerUpdate { timer } => {
+                self.update_timer(timer.clone());
+                self.events.push_back(Event::TimerChanged(timer));
+            }
+            Packet::ContactUpdate { contact } => {
+                if contact.state == ContactState::None {
+                    self.friends.remove(&contact.username);
+                } else {
+                    self.friends.insert(contact.username.clone(), contact.state);
+                }
+                self.events.push_back(Event::FriendChanged(contact));
+            }
+            Packet::PreKeysLow { remaining } => {
+                let prekeys = match self.prekeys.generate_one_time(MAX_PREKEYS.saturating_sub(remaining)) {
+                    Ok(prekeys) => prekeys,
+                    Err(e) => {
+                        self.events.push_back(Event::Error(format!("Failed to create prekeys: {}", e)));
+                        return;
+                    }
+                };
+                if let Err(e) = self.link.send(Packet::PreKeysUpload { prekeys }) {
+                    self.events.push_back(Event::Error(format!("Failed to send prekeys to the server: {}", e)));
+                }
+            }
+            Packet::Error { error, should_disconnect: true } => self.closed(format!("Error from server: {}", error)),
+            Packet::Error { error, .. } => self.events.push_back(Event::Error(format!("Error from server: {}", error))),
+            Packet::Disconnect => self.closed(format!("The server closed the connection.")),
+            _ => self.events.push_back(Event::Error(format!("Ignoring an unexpected packet from the server."))),
+        }
+    }
+
+    // keep a message that was sent or read on this device, it is not returned by `history` again while the client runs
+    fn remember(&mut self, message: &Message, seq: u64) {
+        self.inbox.read(message.id.as_str());
+        if let Err(e) = self.history.record(message, seq) {
+            self.events.push_back(Event::Error(e));
+        }
+    }
+
+    // tell the server a message was read so it stops resending it
+    fn acknowledge(&mut self, id: &str) {
+        if let Err(e) = self.inbox.acknowledge(&self.link, id) {
+            self.events.push_back(Event::Error(e));
+        }
+    }
+
+    // ask the server whether every watched user is online, reporting the ones that changed
+    fn refresh_presence(&mut self) {
+        let usernames: Vec<String> = self.watched.keys().cloned().collect();
+        for username in usernames {
+            let online = match self.is_online(&username) {
+                Ok(online) => online,
+                Err(e) => {
+                    self.events.push_back(Event::Error(format!("Failed to check who is online: {}", e)));
+                    return;
+                }
+            };
+            if self.watched.insert(username.clone(), Some(online)) != Some(Some(online)) {
+                self.events.push_back(Event::PresenceChanged { username, online });
+            }
+        }
+    }
+
+    // ask the server for any messages skipped in a conversation before the given sequence number
+    // the conversation is named by the other user, or by the id of a group or channel
+    fn request_missing(&mut self, username: &str, seq: u64) {
+        let Some((from, to)) = self.inbox.sequence(username, seq) else {
+            return;
+        };
+        let history = match self.link.request(Packet::MsgRangeRequest { username: username.to_string(), from, to }) {
+            Ok(Packet::MsgHistory { history, .. }) => history,
+            Ok(_) => {
+                self.events.push_back(Event::Error(format!("The server did not reply with the messages missed from {}", username)));
+                return;
+            }
+            Err(e) => {
+                self.events.push_back(Event::Error(format!("Failed to get the messages missed from {}: {}", username, e)));
+                return;
+            }
+        };
+        for msg in self.inbox.unseen(history) {
+            if self.history.contains(msg.id.as_str()) {
+                continue;
+            }
+            let message = self.read_missed(username, msg);
+            self.events.push_back(Event::MessageReceived(message));
+        }
+    }
+
+    // get the text of a message missed in a conversation, keeping it if it could be read
+    // a message that could not be read is not remembered, so it is tried again when it is sent again
+    fn read_missed(&mut self, conversation: &str, msg: SentMsg) -> Message {
+        let mut message = Message { id: msg.id, conversation: conversation.to_string(), sender: msg.sender, timestamp: msg.timestamp, text: String::new(), ttl: msg.ttl };
+        // messages sent from another client are sealed for the recipient, so only they can read them
+        if message.sender == self.username && !msg.message.is_plaintext() {
+            message.text = format!("[sealed for the recipient]");
+            return message;
+        }
+        match self.decrypt(&message.sender, &msg.message) {
+            Ok(text) => {
+                message.text = text;
+                self.remember(&message, msg.seq);
+            }
+            Err(e) => message.text = format!("[could not be decrypted: {}]", e),
+        }
+        message
+    }
+
+    // open a message sealed for us
+    fn decrypt(&mut self, sender: &str, message: &SealedPayload) -> Result<String, String> {
+        if message.is_plaintext() {
+            return Ok(format!("{} [unencrypted]", String::from_utf8_lossy(&message.ciphertext)));
+        }
+        let sender_key = self.check_identity(sender)?;
+        let text = if message.header.is_empty() {
+            // sealed on its own, before messages were sent in sessions
+            open(message, &self.identity, &sender_key)?
+        } else {
+            self.sessions.decrypt(&self.identity, &mut self.prekeys, sender, &sender_key, message)?
+        };
+        Ok(String::from_utf8_lossy(&text).to_string())
+    }
+
+    // get the identity key the server has for a contact, reporting it if it is not the one trusted for them
+    fn check_identity(&mut self, username: &str) -> Result<Vec<u8>, String> {
+        let key = self.keys.get(&self.link, username)?;
+        if let Trust::Changed { trusted } = self.trust.observe(username, &key)? {
+            let own_key = self.identity.public();
+            self.events.push_back(Event::IdentityChanged {
+                username: username.to_string(),
+                old_safety_number: safety_number(&self.username, &own_key, username, &trusted),
+                new_safety_number: safety_number(&self.username, &own_key, username, &key),
+            });
+        }
+        Ok(key)
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::keys::{from_hex, to_hex};
+use crate::Message;
+
+/// The messages the user sent or read, kept on their own device
+/// the server only has messages sealed for their recipients, and a session opens each message once,
+/// so this is the only place a conversation can be read again
+pub struct HistoryStore {
+    path: PathBuf,
+    // the messages of each conversation, oldest first
+    conversations: BTreeMap<String, Vec<Stored>>,
+    ids: HashSet<String>,
+}
+
+struct Stored {
+    message: Message,
+    seq: u64,
+    // when the message disappears in seconds since the unix epoch, 0 if it never does
+    expires: u64,
+}
+
+impl HistoryStore {
+    /// Load the messages saved at `path`, leaving out the ones that disappeared since, starting empty if nothing was saved
+    /// the file has one line per message, `<conversation> <id> <sender> <timestamp> <seq> <ttl> <expires> <text>` with the text fields written as hex
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, String> {
+        let mut store = Self { path: path.into(), conversations: BTreeMap::new(), ids: HashSet::new() };
+        if !store.path.exists() {
+            return Ok(store);
+        }
+
+        let contents = fs::read_to_string(&store.path)
+            .map_err(|e| format!("Failed to read message history {}: {}", store.path.display(), e))?;
+        let now = now();
+        let mut expired = false;
+        for line in contents.lines() {
+            let invalid = || format!("Invalid line in {}: {}", store.path.display(), line);
+            // fields are never left out, the hex of an empty one is empty
+            let parts: Vec<&str> = line.split(' ').collect();
+            let [conversation, id, sender, timestamp, seq, ttl, expires, text] = parts.as_slice() else {
+                return Err(invalid());
+            };
+            let text_field = |hex: &str| from_hex(hex).and_then(|bytes| String::from_utf8(bytes).ok()).ok_or_else(invalid);
+            let number = |number: &str| number.parse::<u64>().map_err(|_| invalid());
+            let expires = number(expires)?;
+            if expires != 0 && expires <= now {
+                expired = true;
+                continue;
+            }
+            let message = Message {
+                id: text_field(id)?,
+                conversation: text_field(conversation)?,
+                sender: text_field(sender)?,
+                timestamp: text_field(timestamp)?,
+                text: text_field(text)?,
+                ttl: number(ttl)?,
+            };
+            store.insert(Stored { message, seq: number(seq)?, expires });
+        }
+        // messages that disappeared are removed from the file too
+        if expired {
+            store.write(store.lines().as_str(), false)?;
+        }
+        Ok(store)
+    }
+
+    /// Keep a message that was sent or read, a disappearing message is kept for its ttl from now
+    /// messages that are already kept are left alone
+    pub fn record(&mut self, message: &Message, seq: u64) -> Result<(), String> {
+        if self.contains(message.id.as_str()) {
+            return Ok(());
+        }
+        let expires = if message.ttl > 0 { now() + message.ttl } else { 0 };
+        let stored = Stored { message: message.clone(), seq, expires };
+        self.write(line(&stored).as_str(), true)?;
+        self.insert(stored);
+        Ok(())
+    }
+
+    /// Whether a message is kept, which means it was already read
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// The messages of a conversation that did not disappear yet, oldest first
+    /// the conversation is the other user, or the id of a group or channel
+    pub fn messages(&self, conversation: &str) -> Vec<&Message> {
+        let now = now();
+        self.conversations.get(conversation).map(|messages| {
+            messages.iter().filter(|stored| stored.expires == 0 || stored.expires > now).map(|stored| &stored.message).collect()
+        }).unwrap_or_default()
+    }
+
+    // add a message to its conversation, in sequence order when it has a sequence number
+    fn insert(&mut self, stored: Stored) {
+        self.ids.insert(stored.message.id.clone());
+        let messages = self.conversations.entry(stored.message.conversation.clone()).or_default();
+        let index = if stored.seq == 0 {
+            messages.len()
+        } else {
+            messages.iter().rposition(|other| other.seq <= stored.seq).map_or(0, |index| index + 1)
+        };
+        messages.insert(index, stored);
+    }
+
+    fn lines(&self) -> String {
+        self.conversations.values().flatten().map(line).collect()
+    }
+
+    // save lines to the file, or add them to its end, so only the user can read it
+    fn write(&self, contents: &str, append: bool) -> Result<(), String> {
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).append(append).truncate(!append);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&self.path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .map_err(|e| format!("Failed to save message history to {}: {}", self.path.display(), e))
+    }
+}
+
+fn line(stored: &Stored) -> String {
+    let message = &stored.message;
+    let hex = |text: &String| to_hex(text.as_bytes());
+    format!("{} {} {} {} {} {} {} {}\n", hex(&message.conversation), hex(&message.id), hex(&message.sender), hex(&message.timestamp),
+        stored.seq, message.ttl, stored.expires, hex(&message.text))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// Pages backwards through the conversation with another user, a group or a channel, starting at the newest message
+pub struct HistoryPager {
+    username: String,
+    page_size: u32,
+    // the id of the oldest message returned so far, where the next page ends
+    oldest: Option<String>,
+    at_start: bool,
+}
+
+impl HistoryPager {
+    pub fn new<S: Into<String>>(username: S, page_size: u32) -> Self {
+        Self {
+            username: username.into(),
+            page_size,
+            oldest: None,
+            at_start: false,
+        }
+    }
+
+    /// The user the conversation is with, or the id of the group or channel
+    pub fn username(&self) -> &str {
+        self.username.as_str()
+    }
+
+    /// true once the first message of the conversation has been returned
+    pub fn at_start(&self) -> bool {
+        self.at_start
+    }
+
+    /// The page of messages before the ones already returned, oldest first
+    pub fn previous_page(&mut self, store: &HistoryStore) -> Vec<Message> {
+        if self.at_start {
+            return Vec::new();
+        }
+
+        let messages = store.messages(self.username.as_str());
+        let end = match &self.oldest {
+            // a message that disappeared since has nothing older left before it either
+            Some(id) => messages.iter().position(|message| message.id == *id).unwrap_or(0),
+            None => messages.len(),
+        };
+        let start = end.saturating_sub(self.page_size as usize);
+        if let Some(oldest) = messages.get(start).filter(|_| start < end) {
+            self.oldest = Some(oldest.id.clone());
+        }
+        self.at_start = start == 0;
+        messages[start..end].iter().map(|message| (*message).clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, conversation: &str, ttl: u64) -> Message {
+        Message { id: id.to_string(), conversation: conversation.to_string(), sender: format!("test"), timestamp: String::new(), text: format!("text of {}", id), ttl }
+    }
+
+    #[test]
+    fn history_is_kept_and_paged() {
+        let path = std::env::temp_dir().join(format!("dl_client_history_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = HistoryStore::load(&path).unwrap();
+        store.record(&message("b", "test", 0), 2).unwrap();
+        store.record(&message("c", "test", 0), 3).unwrap();
+        // a message found in a gap goes before the newer ones
+        store.record(&message("a", "test", 0), 1).unwrap();
+        store.record(&message("a", "test", 0), 1).unwrap();
+        store.record(&message("x", "skepz", 0), 1).unwrap();
+        store.record(&message("gone", "test", 1), 4).unwrap();
+        assert!(store.contains("a") && !store.contains("d"));
+
+        // the store survives a restart, without the messages that disappeared
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let store = HistoryStore::load(&path).unwrap();
+        assert!(!store.contains("gone"));
+        let mut pager = HistoryPager::new("test", 2);
+        let ids = |page: Vec<Message>| page.into_iter().map(|message| message.id).collect::<Vec<String>>();
+        assert_eq!(ids(pager.previous_page(&store)), vec!["b", "c"]);
+        assert!(!pager.at_start());
+        assert_eq!(ids(pager.previous_page(&store)), vec!["a"]);
+        assert!(pager.at_start());
+        assert!(pager.previous_page(&store).is_empty());
+        assert_eq!(HistoryStore::load(&path).unwrap().messages("skepz").len(), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::{HashMap, HashSet};
+use dl_network_common::{Packet, SentMsg};
+use crate::link::Link;
+
+/// Keeps track of the messages received from the server
+/// the server resends messages until they are acknowledged, so the same message can arrive more than once
+#[derive(Default)]
+pub struct Inbox {
+    seen: HashSet<String>,
+    // the newest sequence number seen in the conversation with each user
+    last_seq: HashMap<String, u64>,
+}
+
+impl Inbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Acknowledge a message to the server, remembering it was read
+    /// a message is only acknowledged once it was read, duplicates are acknowledged again in case the first ack was lost
+    pub(crate) fn acknowledge(&mut self, link: &Link, id: &str) -> Result<(), String> {
+        self.read(id);
+        if link.send(Packet::MessageAck { id: id.to_string() }).is_err() {
+            return Err(format!("Failed to acknowledge message {}", id));
+        }
+        Ok(())
+    }
+
+    /// Whether a message was already read, the server resends messages until they are acknowledged
+    pub fn seen(&self, id: &str) -> bool {
+        self.seen.contains(id)
+    }
+
+    /// Remember that a message was read, without acknowledging it
+    pub fn read(&mut self, id: &str) {
+        self.seen.insert(id.to_string());
+    }
+
+    /// Record a sequence number seen in the conversation with a user, from either side of the conversation
+    /// @return: the range of sequence numbers that were skipped since the last one seen, if any
+    pub fn sequence(&mut self, username: &str, seq: u64) -> Option<(u64, u64)> {
+        // messages stored before sequence numbers were added have none
+        if seq == 0 {
+            return None;
+        }
+
+        // the first sequence number seen in a conversation is where tracking starts
+        let last = self.last_seq.entry(username.to_string()).or_insert(seq);
+        if seq <= *last {
+            return None;
+        }
+        let missing = if seq > *last + 1 { Some((*last + 1, seq - 1)) } else { None };
+        *last = seq;
+        missing
+    }
+
+    /// Keep only the messages of a history page that were not read yet
+    pub fn unseen(&self, history: Vec<SentMsg>) -> Vec<SentMsg> {
+        history.into_iter().filter(|msg| !self.seen(msg.id.as_str())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_are_ignored() {
+        let mut inbox = Inbox::new();
+        inbox.read("a");
+        inbox.read("b");
+        inbox.read("a");
+        assert!(inbox.seen("a") && inbox.seen("b"));
+        assert!(!inbox.seen("c"));
+    }
+
+    #[test]
+    fn gaps_are_found() {
+        let mut inbox = Inbox::new();
+        assert_eq!(inbox.sequence("test", 4), None);
+        assert_eq!(inbox.sequence("test", 5), None);
+        assert_eq!(inbox.sequence("test", 9), Some((6, 8)));
+        // older and repeated sequence numbers are not gaps
+        assert_eq!(inbox.sequence("test", 7), None);
+        assert_eq!(inbox.sequence("test", 9), None);
+        assert_eq!(inbox.sequence("test", 10), None);
+        // conversations are tracked separately
+        assert_eq!(inbox.sequence("skepz", 2), None);
+        assert_eq!(inbox.sequence("skepz", 0), None);
+    }
+}
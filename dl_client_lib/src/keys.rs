@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use dl_network_common::Packet;
+use dl_network_common::crypto::{IdentityKey, PreKey, PreKeyBundle, PublicPreKey};
+use crate::link::Link;
+
+/// Load the identity key saved at `path`, or create and save a new one if there is none
+pub fn load_or_create_identity<P: AsRef<Path>>(path: P) -> Result<IdentityKey, String> {
+    let path = path.as_ref();
+    if path.exists() {
+        let bytes = fs::read(path).map_err(|e| format!("Failed to read identity key {}: {}", path.display(), e))?;
+        return IdentityKey::from_bytes(&bytes);
+    }
+
+    let identity = IdentityKey::generate();
+    write_private(path, &identity.to_bytes())?;
+    Ok(identity)
+}
+
+// save private keys so only their owner can read them
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    if let Err(e) = fs::write(path, contents) {
+        return Err(format!("Failed to save keys to {}: {}", path.display(), e));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+            return Err(format!("Failed to restrict permissions of {}: {}", path.display(), e));
+        }
+    }
+    Ok(())
+}
+
+/// The private halves of the prekeys published to the server
+/// they are kept so conversations others start with them can still be read
+pub struct PreKeyStore {
+    path: PathBuf,
+    signed: Option<PreKey>,
+    one_time: BTreeMap<u32, PreKey>,
+    next_id: u32,
+}
+
+impl PreKeyStore {
+    /// Load the prekeys saved at `path`, starting with none if nothing was saved yet
+    /// the file has one key per line, written as `signed <id> <hex>` or `one_time <id> <hex>`, and the next free id as `next <id>`
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, String> {
+        let mut store = Self { path: path.into(), signed: None, one_time: BTreeMap::new(), next_id: 1 };
+        if !store.path.exists() {
+            return Ok(store);
+        }
+
+        let contents = fs::read_to_string(&store.path)
+            .map_err(|e| format!("Failed to read prekeys {}: {}", store.path.display(), e))?;
+        for line in contents.lines() {
+            let invalid = || format!("Invalid line in {}: {}", store.path.display(), line);
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let id = parts.get(1).and_then(|id| id.parse::<u32>().ok()).ok_or_else(invalid)?;
+            match (parts[0], parts.get(2)) {
+                ("next", None) => store.next_id = id,
+                ("signed", Some(key)) => store.signed = Some(PreKey::from_bytes(id, &from_hex(key).ok_or_else(invalid)?)?),
+                ("one_time", Some(key)) => {
+                    store.one_time.insert(id, PreKey::from_bytes(id, &from_hex(key).ok_or_else(invalid)?)?);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(store)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let mut contents = format!("next {}\n", self.next_id);
+        let signed = self.signed.iter().map(|prekey| ("signed", prekey));
+        let one_time = self.one_time.values().map(|prekey| ("one_time", prekey));
+        for (kind, prekey) in signed.chain(one_time) {
+            contents.push_str(format!("{} {} {}\n", kind, prekey.id, to_hex(&prekey.to_bytes())).as_str());
+        }
+        write_private(&self.path, contents.as_bytes())
+    }
+
+    /// The signed prekey, created the first time it is needed
+    pub fn signed_prekey(&mut self) -> Result<PublicPreKey, String> {
+        if let Some(prekey) = &self.signed {
+            return Ok(prekey.public());
+        }
+        let prekey = PreKey::generate(self.take_id());
+        let public = prekey.public();
+        self.signed = Some(prekey);
+        self.save()?;
+        Ok(public)
+    }
+
+    /// Create and save new one-time prekeys, returning their public halves for uploading
+    pub fn generate_one_time(&mut self, count: u32) -> Result<Vec<PublicPreKey>, String> {
+        let mut created = Vec::new();
+        for _ in 0..count {
+            let prekey = PreKey::generate(self.take_id());
+            created.push(prekey.public());
+            self.one_time.insert(prekey.id, prekey);
+        }
+        // the keys must be saved before the server can hand them out
+        self.save()?;
+        Ok(created)
+    }
+
+    /// The private half of the signed prekey with the given id
+    pub fn signed(&self, id: u32) -> Option<&PreKey> {
+        self.signed.as_ref().filter(|prekey| prekey.id == id)
+    }
+
+    /// The private half of an unused one-time prekey
+    pub fn one_time(&self, id: u32) -> Option<&PreKey> {
+        self.one_time.get(&id)
+    }
+
+    /// Forget a one-time prekey once a conversation was started with it, so it can never be used again
+    pub fn remove_one_time(&mut self, id: u32) -> Result<(), String> {
+        if self.one_time.remove(&id).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn take_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        id
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok()).collect()
+}
+
+/// The public identity keys of other users, fetched from the server the first time they are needed
+#[derive(Default)]
+pub struct KeyDirectory {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl KeyDirectory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the public identity key of a user
+    pub(crate) fn get(&mut self, link: &Link, username: &str) -> Result<Vec<u8>, String> {
+        if let Some(key) = self.keys.get(username) {
+            return Ok(key.clone());
+        }
+
+        match link.request(Packet::IdentityKeyRequest { username: username.to_string() })? {
+            Packet::IdentityKeyResponse { username: owner, key } if owner == username => {
+                let Some(key) = key else {
+                    return Err(format!("{} has not set up encryption yet", username));
+                };
+                self.keys.insert(owner, key.clone());
+                Ok(key)
+            }
+            _ => Err(format!("The server did not reply with the identity key of {}", username)),
+        }
+    }
+
+    /// Get a verified prekey bundle to start a conversation with a user
+    pub(crate) fn bundle(&mut self, link: &Link, username: &str) -> Result<PreKeyBundle, String> {
+        match link.request(Packet::PreKeyBundleRequest { username: username.to_string() })? {
+            Packet::PreKeyBundleResponse { username: owner, bundle } if owner == username => {
+                let Some(bundle) = bundle else {
+                    return Err(format!("{} has not set up encryption yet", username));
+                };
+                if !bundle.verify() {
+                    return Err(format!("The prekeys of {} are not signed by their identity", username));
+                }
+                self.keys.insert(owner, bundle.identity_key.clone());
+                Ok(bundle)
+            }
+            _ => Err(format!("The server did not reply with the prekeys of {}", username)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prekeys_are_saved() {
+        let path = std::env::temp_dir().join(format!("dl_client_prekeys_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = PreKeyStore::load(&path).unwrap();
+        let signed = store.signed_prekey().unwrap();
+        let one_time = store.generate_one_time(3).unwrap();
+        assert_eq!(one_time.iter().map(|prekey| prekey.id).collect::<Vec<_>>(), vec![2, 3, 4]);
+
+        let mut loaded = PreKeyStore::load(&path).unwrap();
+        assert_eq!(loaded.signed_prekey().unwrap(), signed);
+        assert_eq!(loaded.one_time.values().map(PreKey::public).collect::<Vec<_>>(), one_time);
+        assert_eq!(loaded.generate_one_time(1).unwrap()[0].id, 5);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("abc"), None);
+    }
+}
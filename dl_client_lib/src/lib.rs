@@ -0,0 +1,13 @@
+// Delta Lima client library, everything a client needs to talk to a server
+// `format!` is used for every owned string in this project, literal or not
+#![allow(clippy::useless_format)]
+
+pub mod history;
+pub mod inbox;
+pub mod keys;
+pub mod session;
+pub mod trust;
+mod client;
+mod link;
+
+pub use client::{Client, Event, Handshake, Message};
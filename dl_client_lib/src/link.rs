@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use dl_network_common::{Connection, Packet};
+use dl_network_common::transport::Transport;
+
+// the requests waiting for a reply, oldest first, None once the connection closed
+type Pending = Arc<Mutex<Option<VecDeque<Sender<Packet>>>>>;
+
+/// The connection to the server once logged in
+/// packets are written by a background thread from a queue, and read by another thread that hands
+/// replies to the requests waiting for them and everything else to `pushes`
+pub(crate) struct Link {
+    outgoing: Sender<Packet>,
+    pending: Pending,
+    writer: JoinHandle<()>,
+}
+
+impl Link {
+    /// Start the reader and writer threads
+    /// @return: the link, and the packets the server sent without being asked, ending with why the connection closed
+    pub(crate) fn new<T: Transport + 'static>(mut connection: Connection<T>) -> Result<(Self, Receiver<Result<Packet, String>>), String> {
+        let mut reader = connection.try_clone().map_err(|e| format!("Failed to create a second connection reference: {}", e))?;
+        let pending: Pending = Arc::new(Mutex::new(Some(VecDeque::new())));
+
+        let (outgoing, queue) = channel::<Packet>();
+        let writer = thread::spawn(move || {
+            // ends once the link is dropped or the connection fails
+            for packet in queue {
+                if connection.send(packet).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (pushes, receiver) = channel();
+        let waiting = Arc::clone(&pending);
+        thread::spawn(move || loop {
+            let packet = match reader.recv() {
+                Ok(packet) => packet,
+                Err(e) => {
+                    // the requests still waiting see their channels close, and new ones fail right away
+                    if let Ok(mut waiting) = waiting.lock() {
+                        *waiting = None;
+                    }
+                    let _ = pushes.send(Err(e));
+                    break;
+                }
+            };
+            let packet = match waiting.lock() {
+                Ok(mut waiting) if is_reply(&packet) => match waiting.as_mut().and_then(|waiting| waiting.pop_front()) {
+                    Some(request) => {
+                        let _ = request.send(packet);
+                        continue;
+                    }
+                    None => packet,
+                },
+                _ => packet,
+            };
+            if pushes.send(Ok(packet)).is_err() {
+                break;
+            }
+        });
+
+        Ok((Self { outgoing, pending, writer }, receiver))
+    }
+
+    /// Wait until every queued packet was written, then stop writing
+    pub(crate) fn close(self) {
+        drop(self.outgoing);
+        let _ = self.writer.join();
+    }
+
+    /// Queue a packet that the server does not reply to
+    pub(crate) fn send(&self, packet: Packet) -> Result<(), String> {
+        self.outgoing.send(packet).map_err(|_| format!("The connection was closed."))
+    }
+
+    /// Send a request and wait for the server's reply to it
+    /// an error the server sends back is returned as Err
+    pub(crate) fn request(&self, packet: Packet) -> Result<Packet, String> {
+        let (tx, reply) = channel();
+        {
+            // replies arrive in the order requests were sent, so the request is queued while holding the lock
+            let mut pending = self.pending.lock().map_err(|_| format!("The connection was closed."))?;
+            let Some(pending) = pending.as_mut() else {
+                return Err(format!("The connection was closed."));
+            };
+            pending.push_back(tx);
+            self.send(packet)?;
+        }
+        match reply.recv() {
+            Ok(Packet::Error { error, .. }) => Err(error),
+            Ok(packet) => Ok(packet),
+            Err(_) => Err(format!("The connection was closed.")),
+        }
+    }
+}
+
+// whether a packet answers a request, everything else is sent by the server on its own
+// the server does not say which request an error is about, so it is taken to be about the oldest one waiting
+// that only works because the server never sends a non-fatal error about a packet it does not reply to
+fn is_reply(packet: &Packet) -> bool {
+    matches!(packet,
+        Packet::UserResponse { .. }
+        | Packet::IdentityKeyResponse { .. }
+        | Packet::PreKeyBundleResponse { .. }
+        | Packet::MsgHistory { .. }
+        | Packet::MessageReceipt { .. }
+        | Packet::GroupInfo { .. }
+        | Packet::GroupList { .. }
+        | Packet::SpaceInfo { .. }
+        | Packet::SpaceList { .. }
+        | Packet::ContactList { .. }
+        | Packet::BlockList { .. }
+        | Packet::TimerInfo { .. }
+        | Packet::TimerList { .. }
+        | Packet::Error { should_disconnect: false, .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dl_network_common::transport::pipe;
+
+    #[test]
+    fn replies_go_to_requests() {
+        let (client, server) = pipe();
+        let (link, pushes) = Link::new(Connection::new(client)).unwrap();
+        let mut server = Connection::new(server);
+        let handle = thread::spawn(move || {
+            assert_eq!(server.recv().unwrap(), Packet::UserExistsRequest { username: format!("test") });
+            // something arrives for the client before the reply
+            server.send(Packet::PreKeysLow { remaining: 3 }).unwrap();
+            server.send(Packet::UserResponse { response: true }).unwrap();
+            assert_eq!(server.recv().unwrap(), Packet::UserOnlineRequest { username: format!("test") });
+            server.send(Packet::Error { should_disconnect: false, error: format!("nope") }).unwrap();
+        });
+
+        assert_eq!(link.request(Packet::UserExistsRequest { username: format!("test") }), Ok(Packet::UserResponse { response: true }));
+        assert_eq!(link.request(Packet::UserOnlineRequest { username: format!("test") }), Err(format!("nope")));
+        assert_eq!(pushes.recv().unwrap(), Ok(Packet::PreKeysLow { remaining: 3 }));
+        handle.join().unwrap();
+
+        // the server hung up
+        assert!(pushes.recv().unwrap().is_err());
+        assert!(link.request(Packet::UserExistsRequest { username: format!("test") }).is_err());
+    }
+}
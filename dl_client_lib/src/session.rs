@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use dl_network_common::crypto::{IdentityKey, PreKeyBundle, SealedPayload, KEY_LEN};
+use crate::keys::{from_hex, to_hex, write_private, PreKeyStore};
+
+// the most message keys that will be skipped in one chain waiting for messages that arrive late
+const MAX_SKIP: u32 = 1000;
+const NONCE_LEN: usize = 12;
+
+// binds derived keys to this use, so they can never match keys derived for anything else
+const AGREEMENT_INFO: &[u8] = b"delta_lima key agreement v1";
+const RATCHET_INFO: &[u8] = b"delta_lima ratchet v1";
+
+// a header is a kind byte, the length of the previous sending chain and the message number,
+// followed by the key agreement for the first messages of a session
+const HEADER_LEN: usize = 9;
+const AGREEMENT_LEN: usize = 2 * KEY_LEN + 8;
+
+type Secret = [u8; KEY_LEN];
+
+/// How the side that started a session agreed on its first key, sent with every message until the other side replies
+#[derive(Debug, Clone, PartialEq)]
+struct Agreement {
+    identity: Secret,
+    /// the public half of the one-time key used for the agreement
+    base: Secret,
+    signed_prekey: u32,
+    one_time_prekey: Option<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    /// the number of messages sent in the previous sending chain
+    previous: u32,
+    number: u32,
+    agreement: Option<Agreement>,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.agreement.is_some() as u8];
+        bytes.extend_from_slice(&self.previous.to_le_bytes());
+        bytes.extend_from_slice(&self.number.to_le_bytes());
+        if let Some(agreement) = &self.agreement {
+            bytes.extend_from_slice(&agreement.identity);
+            bytes.extend_from_slice(&agreement.base);
+            bytes.extend_from_slice(&agreement.signed_prekey.to_le_bytes());
+            bytes.extend_from_slice(&agreement.one_time_prekey.unwrap_or(0).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let invalid = || format!("The message has an invalid session header");
+        let u32_at = |index: usize| u32::from_le_bytes(bytes[index..index + 4].try_into().unwrap());
+        let key_at = |index: usize| -> Secret { bytes[index..index + KEY_LEN].try_into().unwrap() };
+
+        let agreement = match (bytes.first(), bytes.len()) {
+            (Some(0), HEADER_LEN) => None,
+            (Some(1), len) if len == HEADER_LEN + AGREEMENT_LEN => {
+                let one_time_prekey = u32_at(HEADER_LEN + 2 * KEY_LEN + 4);
+                Some(Agreement {
+                    identity: key_at(HEADER_LEN),
+                    base: key_at(HEADER_LEN + KEY_LEN),
+                    signed_prekey: u32_at(HEADER_LEN + 2 * KEY_LEN),
+                    // prekey ids start at 1
+                    one_time_prekey: if one_time_prekey == 0 { None } else { Some(one_time_prekey) },
+                })
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(Self { previous: u32_at(1), number: u32_at(5), agreement })
+    }
+}
+
+/// A Double Ratchet session with one contact
+/// every message is sealed with a new key, and keys are forgotten once used so past messages stay safe if the session leaks
+#[derive(Clone)]
+struct Session {
+    remote_identity: Secret,
+    root: Secret,
+    /// our current ratchet key, replaced every time the other side replies
+    ratchet: StaticSecret,
+    /// the other side's current ratchet key
+    remote_ratchet: Option<Secret>,
+    send_chain: Option<Secret>,
+    receive_chain: Option<Secret>,
+    sent: u32,
+    received: u32,
+    previous: u32,
+    /// keys of messages that were skipped over, by ratchet key and message number
+    skipped: HashMap<(Secret, u32), Secret>,
+    /// set on the side that started the session until the other side replies
+    agreement: Option<Agreement>,
+    /// the base key the other side started the session with, to tell its repeated first messages from a new session
+    remote_base: Option<Secret>,
+}
+
+impl Session {
+    /// Start a session with the owner of a prekey bundle
+    fn initiate(identity: &IdentityKey, bundle: &PreKeyBundle) -> Result<Self, String> {
+        if !bundle.verify() {
+            return Err(format!("The prekey bundle is not signed by its identity"));
+        }
+        let remote_identity = key(&bundle.identity_key)?;
+        let signed_prekey = key(&bundle.signed_prekey.key)?;
+
+        let base = StaticSecret::random_from_rng(OsRng);
+        let mut shared = [
+            identity.diffie_hellman(&signed_prekey)?,
+            diffie_hellman(&base, &remote_identity)?,
+            diffie_hellman(&base, &signed_prekey)?,
+        ].concat();
+        if let Some(one_time) = &bundle.one_time_prekey {
+            shared.extend_from_slice(&diffie_hellman(&base, &key(&one_time.key)?)?);
+        }
+
+        // the signed prekey is the other side's first ratchet key
+        let ratchet = StaticSecret::random_from_rng(OsRng);
+        let (root, send_chain) = kdf_root(&agree(&shared)?, &diffie_hellman(&ratchet, &signed_prekey)?)?;
+        Ok(Self {
+            remote_identity,
+            root,
+            ratchet,
+            remote_ratchet: Some(signed_prekey),
+            send_chain: Some(send_chain),
+            receive_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: HashMap::new(),
+            agreement: Some(Agreement {
+                identity: identity.public(),
+                base: PublicKey::from(&base).to_bytes(),
+                signed_prekey: bundle.signed_prekey.id,
+                one_time_prekey: bundle.one_time_prekey.as_ref().map(|prekey| prekey.id),
+            }),
+            remote_base: None,
+        })
+    }
+
+    /// Accept a session another user started with our prekeys
+    fn respond(identity: &IdentityKey, prekeys: &PreKeyStore, agreement: &Agreement) -> Result<Self, String> {
+        let Some(signed_prekey) = prekeys.signed(agreement.signed_prekey) else {
+            return Err(format!("The message was sealed for a signed prekey that no longer exists"));
+        };
+        let mut shared = [
+            signed_prekey.diffie_hellman(&agreement.identity)?,
+            identity.diffie_hellman(&agreement.base)?,
+            signed_prekey.diffie_hellman(&agreement.base)?,
+        ].concat();
+        if let Some(id) = agreement.one_time_prekey {
+            let Some(one_time) = prekeys.one_time(id) else {
+                return Err(format!("The message was sealed for a one-time prekey that was already used"));
+            };
+            shared.extend_from_slice(&one_time.diffie_hellman(&agreement.base)?);
+        }
+
+        Ok(Self {
+            remote_identity: agreement.identity,
+            root: agree(&shared)?,
+            ratchet: StaticSecret::from(signed_prekey.to_bytes()),
+            remote_ratchet: None,
+            send_chain: None,
+            receive_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: HashMap::new(),
+            agreement: None,
+            remote_base: Some(agreement.base),
+        })
+    }
+
+    fn encrypt(&mut self, identity: &IdentityKey, plaintext: &[u8]) -> Result<SealedPayload, String> {
+        let Some(chain) = self.send_chain else {
+            return Err(format!("The session can not send until the other side replies"));
+        };
+        let (chain, message_key) = kdf_chain(&chain);
+        self.send_chain = Some(chain);
+
+        let header = Header { previous: self.previous, number: self.sent, agreement: self.agreement.clone() }.to_bytes();
+        self.sent += 1;
+
+        let ratchet = PublicKey::from(&self.ratchet).to_bytes();
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = [header.as_slice(), &ratchet, &identity.public(), &self.remote_identity].concat();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&message_key));
+        let Ok(ciphertext) = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad }) else {
+            return Err(format!("Failed to encrypt message"));
+        };
+        Ok(SealedPayload { ephemeral: ratchet.to_vec(), nonce: nonce.to_vec(), ciphertext, header })
+    }
+
+    /// Decrypt a message, only changing the session if it could be read
+    fn decrypt(&mut self, identity: &IdentityKey, sealed: &SealedPayload, header: &Header) -> Result<Vec<u8>, String> {
+        let remote_ratchet = key(&sealed.ephemeral)?;
+        let mut next = self.clone();
+
+        let message_key = match next.skipped.remove(&(remote_ratchet, header.number)) {
+            Some(message_key) => message_key,
+            None => {
+                if next.remote_ratchet != Some(remote_ratchet) {
+                    next.skip_until(header.previous)?;
+                    next.step(remote_ratchet)?;
+                }
+                next.skip_until(header.number)?;
+                let Some(chain) = next.receive_chain else {
+                    return Err(format!("The message was already read"));
+                };
+                let (chain, message_key) = kdf_chain(&chain);
+                next.receive_chain = Some(chain);
+                next.received += 1;
+                message_key
+            }
+        };
+
+        let aad = [sealed.header.as_slice(), &remote_ratchet, &self.remote_identity, &identity.public()].concat();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&message_key));
+        let Ok(nonce) = <[u8; NONCE_LEN]>::try_from(sealed.nonce.as_slice()) else {
+            return Err(format!("The message has an invalid nonce"));
+        };
+        let Ok(plaintext) = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &sealed.ciphertext, aad: &aad }) else {
+            return Err(format!("The message could not be decrypted, it was altered, already read, or sent in another session"));
+        };
+
+        // anything the other side sends means it has the session, so the agreement is no longer needed
+        next.agreement = None;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    // keep the keys of messages in the receiving chain up to `until`, so they can be read when they arrive
+    fn skip_until(&mut self, until: u32) -> Result<(), String> {
+        let (Some(mut chain), Some(remote_ratchet)) = (self.receive_chain, self.remote_ratchet) else {
+            return Ok(());
+        };
+        if until > self.received.saturating_add(MAX_SKIP) {
+            return Err(format!("The message skips too many messages"));
+        }
+        while self.received < until {
+            let (next, message_key) = kdf_chain(&chain);
+            self.skipped.insert((remote_ratchet, self.received), message_key);
+            chain = next;
+            self.received += 1;
+        }
+        self.receive_chain = Some(chain);
+        Ok(())
+    }
+
+    // the other side replied with a new ratchet key, start new chains for both directions
+    fn step(&mut self, remote_ratchet: Secret) -> Result<(), String> {
+        self.previous = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.remote_ratchet = Some(remote_ratchet);
+        let (root, receive_chain) = kdf_root(&self.root, &diffie_hellman(&self.ratchet, &remote_ratchet)?)?;
+        self.ratchet = StaticSecret::random_from_rng(OsRng);
+        let (root, send_chain) = kdf_root(&root, &diffie_hellman(&self.ratchet, &remote_ratchet)?)?;
+        self.root = root;
+        self.receive_chain = Some(receive_chain);
+        self.send_chain = Some(send_chain);
+        Ok(())
+    }
+
+    // one `name value...` line per field, skipped message keys as `skipped <ratchet> <number> <key>`
+    fn to_text(&self) -> String {
+        let mut text = format!("remote_identity {}\nroot {}\nratchet {}\ncounters {} {} {}\n",
+            to_hex(&self.remote_identity), to_hex(&self.root), to_hex(&self.ratchet.to_bytes()), self.sent, self.received, self.previous);
+        let keys = [("remote_ratchet", &self.remote_ratchet), ("send_chain", &self.send_chain),
+            ("receive_chain", &self.receive_chain), ("remote_base", &self.remote_base)];
+        for (name, value) in keys {
+            if let Some(value) = value {
+                text.push_str(format!("{} {}\n", name, to_hex(value)).as_str());
+            }
+        }
+        if let Some(agreement) = &self.agreement {
+            text.push_str(format!("agreement {} {} {} {}\n", to_hex(&agreement.identity), to_hex(&agreement.base),
+                agreement.signed_prekey, agreement.one_time_prekey.unwrap_or(0)).as_str());
+        }
+        for ((ratchet, number), message_key) in &self.skipped {
+            text.push_str(format!("skipped {} {} {}\n", to_hex(ratchet), number, to_hex(message_key)).as_str());
+        }
+        text
+    }
+
+    fn from_text(text: &str) -> Option<Self> {
+        let hex_key = |hex: &str| -> Option<Secret> { from_hex(hex)?.try_into().ok() };
+        let mut fields: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut skipped = HashMap::new();
+        for line in text.lines() {
+            let mut parts = line.split_whitespace();
+            let name = parts.next()?;
+            let values: Vec<&str> = parts.collect();
+            if name == "skipped" {
+                let [ratchet, number, message_key] = values.as_slice() else {
+                    return None;
+                };
+                skipped.insert((hex_key(ratchet)?, number.parse().ok()?), hex_key(message_key)?);
+            } else {
+                fields.insert(name, values);
+            }
+        }
+
+        let single = |name: &str| -> Option<Option<Secret>> {
+            match fields.get(name).map(Vec::as_slice) {
+                None => Some(None),
+                Some([value]) => Some(Some(hex_key(value)?)),
+                Some(_) => None,
+            }
+        };
+        let [sent, received, previous] = fields.get("counters")?.as_slice() else {
+            return None;
+        };
+        let agreement = match fields.get("agreement").map(Vec::as_slice) {
+            None => None,
+            Some([identity, base, signed_prekey, one_time_prekey]) => {
+                let one_time_prekey: u32 = one_time_prekey.parse().ok()?;
+                Some(Agreement {
+                    identity: hex_key(identity)?,
+                    base: hex_key(base)?,
+                    signed_prekey: signed_prekey.parse().ok()?,
+                    one_time_prekey: if one_time_prekey == 0 { None } else { Some(one_time_prekey) },
+                })
+            }
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            remote_identity: single("remote_identity")??,
+            root: single("root")??,
+            ratchet: StaticSecret::from(single("ratchet")??),
+            remote_ratchet: single("remote_ratchet")?,
+            send_chain: single("send_chain")?,
+            receive_chain: single("receive_chain")?,
+            sent: sent.parse().ok()?,
+            received: received.parse().ok()?,
+            previous: previous.parse().ok()?,
+            skipped,
+            agreement,
+            remote_base: single("remote_base")?,
+        })
+    }
+}
+
+/// The ratchet sessions with every contact, saved to disk so conversations survive restarts
+pub struct SessionStore {
+    dir: PathBuf,
+    sessions: HashMap<String, Session>,
+}
+
+impl SessionStore {
+    /// Keep sessions in `dir`, one file per contact, loading them the first time they are needed
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into(), sessions: HashMap::new() }
+    }
+
+    /// Encrypt a message for a contact, starting a session with the bundle from `bundle` if there is none yet
+    pub fn encrypt<F>(&mut self, identity: &IdentityKey, username: &str, plaintext: &[u8], bundle: F) -> Result<SealedPayload, String>
+        where F: FnOnce() -> Result<PreKeyBundle, String> {
+        let mut session = match self.load(username)? {
+            Some(session) => session,
+            None => Session::initiate(identity, &bundle()?)?,
+        };
+        let sealed = session.encrypt(identity, plaintext)?;
+        // the message key must be used up on disk before the message is sent
+        self.store(username, session)?;
+        Ok(sealed)
+    }
+
+    /// Decrypt a message from a contact whose identity key is `remote_identity`
+    /// a message that starts a new session replaces the old one, and uses up the one-time prekey it was sealed for
+    pub fn decrypt(&mut self, identity: &IdentityKey, prekeys: &mut PreKeyStore, username: &str, remote_identity: &[u8], sealed: &SealedPayload) -> Result<Vec<u8>, String> {
+        let header = Header::from_bytes(&sealed.header)?;
+        let existing = self.load(username)?;
+
+        let (mut session, agreement) = match (&header.agreement, existing) {
+            // the other side sends the agreement until we reply, these are messages in the session we already have
+            (Some(agreement), Some(session)) if session.remote_base == Some(agreement.base) => (session, None),
+            (Some(agreement), _) => {
+                if agreement.identity.as_slice() != remote_identity {
+                    return Err(format!("The message was not sealed by the identity key of {}", username));
+                }
+                (Session::respond(identity, prekeys, agreement)?, Some(agreement))
+            }
+            (None, Some(session)) => (session, None),
+            (None, None) => return Err(format!("There is no session with {}", username)),
+        };
+
+        let plaintext = session.decrypt(identity, sealed, &header)?;
+        self.store(username, session)?;
+        if let Some(id) = agreement.and_then(|agreement| agreement.one_time_prekey) {
+            prekeys.remove_one_time(id)?;
+        }
+        Ok(plaintext)
+    }
+
+    /// Forget the session with a contact, the next message sent to them starts a new one
+    pub fn remove(&mut self, username: &str) -> Result<(), String> {
+        self.sessions.remove(username);
+        let path = self.path(username);
+        if path.exists() {
+            if let Err(e) = fs::remove_file(&path) {
+                return Err(format!("Failed to remove session {}: {}", path.display(), e));
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, username: &str) -> Result<Option<Session>, String> {
+        if let Some(session) = self.sessions.get(username) {
+            return Ok(Some(session.clone()));
+        }
+        let path = self.path(username);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&path).map_err(|e| format!("Failed to read session {}: {}", path.display(), e))?;
+        let Some(session) = Session::from_text(&text) else {
+            return Err(format!("Invalid session in {}", path.display()));
+        };
+        self.sessions.insert(username.to_string(), session.clone());
+        Ok(Some(session))
+    }
+
+    fn store(&mut self, username: &str, session: Session) -> Result<(), String> {
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            return Err(format!("Failed to create {}: {}", self.dir.display(), e));
+        }
+        write_private(&self.path(username), session.to_text().as_bytes())?;
+        self.sessions.insert(username.to_string(), session);
+        Ok(())
+    }
+
+    // usernames are hex encoded so they are always valid file names
+    fn path(&self, username: &str) -> PathBuf {
+        self.dir.join(to_hex(username.as_bytes()))
+    }
+}
+
+fn key(bytes: &[u8]) -> Result<Secret, String> {
+    match Secret::try_from(bytes) {
+        Ok(key) => Ok(key),
+        Err(_) => Err(format!("A public key must be {} bytes", KEY_LEN)),
+    }
+}
+
+fn diffie_hellman(secret: &StaticSecret, public: &Secret) -> Result<Secret, String> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+        return Err(format!("Refusing to use an insecure public key"));
+    }
+    Ok(shared.to_bytes())
+}
+
+// the first root key of a session, from the secrets shared by the key agreement
+fn agree(shared: &[u8]) -> Result<Secret, String> {
+    let mut root = [0u8; KEY_LEN];
+    if Hkdf::<Sha256>::new(Some(&[0u8; KEY_LEN]), shared).expand(AGREEMENT_INFO, &mut root).is_err() {
+        return Err(format!("Failed to derive session key"));
+    }
+    Ok(root)
+}
+
+// mix a new shared secret into the root key, giving the next root key and a new chain key
+fn kdf_root(root: &Secret, shared: &Secret) -> Result<(Secret, Secret), String> {
+    let mut output = [0u8; 2 * KEY_LEN];
+    if Hkdf::<Sha256>::new(Some(root), shared).expand(RATCHET_INFO, &mut output).is_err() {
+        return Err(format!("Failed to derive ratchet keys"));
+    }
+    let (root, chain) = output.split_at(KEY_LEN);
+    Ok((root.try_into().unwrap(), chain.try_into().unwrap()))
+}
+
+// advance a chain, giving the next chain key and the key for one message
+fn kdf_chain(chain: &Secret) -> (Secret, Secret) {
+    let derive = |byte: u8| -> Secret {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain).expect("HMAC takes keys of any length");
+        mac.update(&[byte]);
+        mac.finalize().into_bytes().into()
+    };
+    (derive(2), derive(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct User {
+        identity: IdentityKey,
+        prekeys: PreKeyStore,
+        sessions: SessionStore,
+        path: PathBuf,
+    }
+
+    impl User {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("dl_client_session_test_{}_{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self {
+                identity: IdentityKey::generate(),
+                prekeys: PreKeyStore::load(path.join("prekeys")).unwrap(),
+                sessions: SessionStore::new(path.join("sessions")),
+                path,
+            }
+        }
+
+        fn bundle(&mut self) -> PreKeyBundle {
+            let signed_prekey = self.prekeys.signed_prekey().unwrap();
+            PreKeyBundle {
+                identity_key: self.identity.public().to_vec(),
+                signing_key: self.identity.signing_public().to_vec(),
+                signature: self.identity.sign_prekey(&signed_prekey),
+                signed_prekey,
+                one_time_prekey: self.prekeys.generate_one_time(1).unwrap().pop(),
+            }
+        }
+
+        fn send(&mut self, to: &mut User, text: &str) -> SealedPayload {
+            let bundle = to.bundle();
+            self.sessions.encrypt(&self.identity, "other", text.as_bytes(), || Ok(bundle)).unwrap()
+        }
+
+        fn read(&mut self, from: &User, sealed: &SealedPayload) -> Result<String, String> {
+            let text = self.sessions.decrypt(&self.identity, &mut self.prekeys, "other", &from.identity.public(), sealed)?;
+            Ok(String::from_utf8(text).unwrap())
+        }
+    }
+
+    impl Drop for User {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn conversation() {
+        let (mut alice, mut bob) = (User::new("alice_conversation"), User::new("bob_conversation"));
+        let first = alice.send(&mut bob, "hi");
+        let second = alice.send(&mut bob, "are you there?");
+        assert_eq!(bob.read(&alice, &first).unwrap(), "hi");
+        assert_eq!(bob.read(&alice, &second).unwrap(), "are you there?");
+        // the one-time prekey is gone once the session is started
+        assert!(bob.prekeys.one_time(2).is_none());
+
+        let reply = bob.send(&mut alice, "yes");
+        assert_eq!(alice.read(&bob, &reply).unwrap(), "yes");
+        let next = alice.send(&mut bob, "good");
+        // only the first messages of a session carry the key agreement
+        assert_eq!(next.header.len(), HEADER_LEN);
+        assert_ne!(next.ephemeral, first.ephemeral);
+        assert_eq!(bob.read(&alice, &next).unwrap(), "good");
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (mut alice, mut bob) = (User::new("alice_order"), User::new("bob_order"));
+        let sent: Vec<SealedPayload> = (0..4).map(|i| alice.send(&mut bob, format!("{}", i).as_str())).collect();
+        assert_eq!(bob.read(&alice, &sent[2]).unwrap(), "2");
+        assert_eq!(bob.read(&alice, &sent[0]).unwrap(), "0");
+
+        // messages from an old chain still decrypt after the ratchet has moved on
+        let reply = bob.send(&mut alice, "reply");
+        assert_eq!(alice.read(&bob, &reply).unwrap(), "reply");
+        let after = alice.send(&mut bob, "after");
+        assert_eq!(bob.read(&alice, &after).unwrap(), "after");
+        assert_eq!(bob.read(&alice, &sent[3]).unwrap(), "3");
+        assert_eq!(bob.read(&alice, &sent[1]).unwrap(), "1");
+    }
+
+    #[test]
+    fn keys_are_used_once() {
+        let (mut alice, mut bob) = (User::new("alice_once"), User::new("bob_once"));
+        let sealed = alice.send(&mut bob, "once");
+        assert!(bob.read(&alice, &sealed).is_ok());
+        assert!(bob.read(&alice, &sealed).is_err());
+
+        let mut tampered = alice.send(&mut bob, "twice");
+        tampered.ciphertext[0] ^= 1;
+        assert!(bob.read(&alice, &tampered).is_err());
+        // a failed message does not change the session
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(bob.read(&alice, &tampered).unwrap(), "twice");
+    }
+
+    #[test]
+    fn sessions_are_saved() {
+        let (mut alice, mut bob) = (User::new("alice_saved"), User::new("bob_saved"));
+        let first = alice.send(&mut bob, "first");
+        let skipped = alice.send(&mut bob, "skipped");
+        let third = alice.send(&mut bob, "third");
+        assert_eq!(bob.read(&alice, &first).unwrap(), "first");
+        assert_eq!(bob.read(&alice, &third).unwrap(), "third");
+
+        // start over from what is on disk
+        alice.sessions = SessionStore::new(alice.path.join("sessions"));
+        bob.sessions = SessionStore::new(bob.path.join("sessions"));
+        assert_eq!(bob.read(&alice, &skipped).unwrap(), "skipped");
+        let reply = bob.send(&mut alice, "reply");
+        assert_eq!(alice.read(&bob, &reply).unwrap(), "reply");
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let agreement = Agreement { identity: [1; KEY_LEN], base: [2; KEY_LEN], signed_prekey: 3, one_time_prekey: None };
+        let header = Header { previous: 4, number: 5, agreement: Some(agreement) };
+        assert_eq!(Header::from_bytes(&header.to_bytes()).unwrap(), header);
+        assert!(Header::from_bytes(&[0; HEADER_LEN + 1]).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use crate::keys::{from_hex, to_hex, write_private};
+
+/// What is known about an identity key the server handed out for a contact
+#[derive(Debug, PartialEq)]
+pub enum Trust {
+    /// the first key seen for the contact, it is trusted from now on
+    New,
+    /// the key that was trusted before
+    Known,
+    /// a different key than the trusted one, sending to the contact is blocked until it is acknowledged
+    Changed { trusted: Vec<u8> },
+}
+
+struct Contact {
+    key: Vec<u8>,
+    // a new key the server handed out that was not acknowledged yet
+    changed: Option<Vec<u8>>,
+}
+
+/// The identity keys trusted for each contact, recorded the first time they are seen
+pub struct TrustStore {
+    path: PathBuf,
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl TrustStore {
+    /// Load the keys saved at `path`, trusting nobody yet if nothing was saved
+    /// the file has one line per contact, `trusted <username> <key>` and `changed <username> <key>` with both written as hex
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, String> {
+        let mut store = Self { path: path.into(), contacts: BTreeMap::new() };
+        if !store.path.exists() {
+            return Ok(store);
+        }
+
+        let contents = fs::read_to_string(&store.path)
+            .map_err(|e| format!("Failed to read trusted keys {}: {}", store.path.display(), e))?;
+        for line in contents.lines() {
+            let invalid = || format!("Invalid line in {}: {}", store.path.display(), line);
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let [kind, username, key] = parts.as_slice() else {
+                return Err(invalid());
+            };
+            let username = from_hex(username).and_then(|username| String::from_utf8(username).ok()).ok_or_else(invalid)?;
+            let key = from_hex(key).ok_or_else(invalid)?;
+            match *kind {
+                "trusted" => {
+                    store.contacts.insert(username, Contact { key, changed: None });
+                }
+                "changed" => {
+                    let Some(contact) = store.contacts.get_mut(&username) else {
+                        return Err(invalid());
+                    };
+                    contact.changed = Some(key);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(store)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let mut contents = String::new();
+        for (username, contact) in &self.contacts {
+            let username = to_hex(username.as_bytes());
+            contents.push_str(format!("trusted {} {}\n", username, to_hex(&contact.key)).as_str());
+            if let Some(changed) = &contact.changed {
+                contents.push_str(format!("changed {} {}\n", username, to_hex(changed)).as_str());
+            }
+        }
+        write_private(&self.path, contents.as_bytes())
+    }
+
+    /// Record an identity key the server handed out for a contact
+    pub fn observe(&mut self, username: &str, key: &[u8]) -> Result<Trust, String> {
+        let Some(contact) = self.contacts.get_mut(username) else {
+            self.contacts.insert(username.to_string(), Contact { key: key.to_vec(), changed: None });
+            self.save()?;
+            return Ok(Trust::New);
+        };
+        if contact.key == key {
+            // the server went back to the trusted key, nothing needs acknowledging
+            if contact.changed.take().is_some() {
+                self.save()?;
+            }
+            return Ok(Trust::Known);
+        }
+        if contact.changed.as_deref() != Some(key) {
+            contact.changed = Some(key.to_vec());
+            let trusted = contact.key.clone();
+            self.save()?;
+            return Ok(Trust::Changed { trusted });
+        }
+        Ok(Trust::Changed { trusted: contact.key.clone() })
+    }
+
+    /// The usernames of every contact with a trusted key, in alphabetical order
+    pub fn contacts(&self) -> impl Iterator<Item = &str> {
+        self.contacts.keys().map(|username| username.as_str())
+    }
+
+    /// The new key of a contact whose key changed, if it was not acknowledged yet
+    /// sending to the contact is blocked while there is one
+    pub fn changed(&self, username: &str) -> Option<&[u8]> {
+        self.contacts.get(username)?.changed.as_deref()
+    }
+
+    /// Trust the new key of a contact after the user checked it
+    /// @return: false if the contact's key had not changed
+    pub fn acknowledge(&mut self, username: &str) -> Result<bool, String> {
+        let Some(contact) = self.contacts.get_mut(username) else {
+            return Ok(false);
+        };
+        let Some(changed) = contact.changed.take() else {
+            return Ok(false);
+        };
+        contact.key = changed;
+        self.save()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_changes_need_acknowledging() {
+        let path = std::env::temp_dir().join(format!("dl_client_trust_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = TrustStore::load(&path).unwrap();
+        assert_eq!(store.observe("test", &[1; 32]).unwrap(), Trust::New);
+        assert_eq!(store.observe("test", &[1; 32]).unwrap(), Trust::Known);
+        assert_eq!(store.observe("test", &[2; 32]).unwrap(), Trust::Changed { trusted: vec![1; 32] });
+        assert_eq!(store.changed("test"), Some([2; 32].as_slice()));
+
+        // the warning survives a restart
+        let mut store = TrustStore::load(&path).unwrap();
+        assert_eq!(store.changed("test"), Some([2; 32].as_slice()));
+        assert!(store.acknowledge("test").unwrap());
+        assert!(!store.acknowledge("test").unwrap());
+        assert_eq!(store.changed("test"), None);
+        assert_eq!(store.observe("test", &[2; 32]).unwrap(), Trust::Known);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+// Drives the client library against a real server with an in-memory store
+// `format!` is used for every owned string in this project, literal or not
+#![allow(clippy::useless_format)]
+
+use std::fs;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use dl_client_lib::history::HistoryPager;
+use dl_client_lib::{Client, Event, Handshake};
+use dl_network_common::{Connection, Contact, ContactState, Timer, MAX_MESSAGE_TTL};
+use dl_server::ACCEPTED_CLIENT_VERSION;
+use dl_server::database::memory::MemoryStore;
+use dl_server::password::HashConfig;
+use dl_server::router::Router;
+use dl_server::server::{serve, ServerState};
+
+// small parameters so signing up and logging in are quick
+const CHEAP: HashConfig = HashConfig { memory_kib: 256, iterations: 1, parallelism: 1 };
+
+// how long to wait for an event before the test fails
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server with an empty in-memory store, and a directory for the keys of its clients
+struct TestServer {
+    addr: SocketAddr,
+    dir: PathBuf,
+    terminate: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    fn start(name: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState {
+            store: Arc::new(MemoryStore::new()),
+            router: Arc::new(Router::new()),
+            hash_config: CHEAP,
+            tls: None,
+        };
+        let terminate = Arc::new(AtomicBool::new(false));
+        let tarc = Arc::clone(&terminate);
+        let handle = thread::spawn(move || serve(listener, state, tarc));
+
+        let dir = std::env::temp_dir().join(format!("dl_client_lib_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self { addr, dir, terminate, handle: Some(handle) }
+    }
+
+    fn handshake(&self) -> Handshake {
+        Handshake::ping(Connection::new(TcpStream::connect(self.addr).unwrap()), ACCEPTED_CLIENT_VERSION).unwrap()
+    }
+
+    fn signup(&self, username: &str) -> Client {
+        let mut handshake = self.handshake();
+        assert_eq!(handshake.login(username, "hunter2", true), Ok(None));
+        handshake.start(&self.dir).unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.terminate.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            // don't hide the reason a test failed behind a second panic
+            if handle.join().is_err() && !thread::panicking() {
+                panic!("The server panicked");
+            }
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Wait for an event matching `expected`, skipping any others
+fn expect_event<F: Fn(&Event) -> bool>(client: &mut Client, expected: F) -> Event {
+    let start = Instant::now();
+    while start.elapsed() < EVENT_TIMEOUT {
+        if let Some(event) = client.next_event(Duration::from_millis(50)) {
+            if expected(&event) {
+                return event;
+            }
+        }
+    }
+    panic!("Timed out waiting for an event");
+}
+
+#[test]
+fn login_can_be_retried() {
+    let server = TestServer::start("login");
+    server.signup("skepz").disconnect();
+
+    let mut handshake = server.handshake();
+    assert!(matches!(handshake.login("skepz", "wrong", false), Ok(Some(_))));
+    assert_eq!(handshake.login("skepz", "hunter2", false), Ok(None));
+    handshake.start(&server.dir).unwrap().disconnect();
+
+    assert!(Handshake::ping(Connection::new(TcpStream::connect(server.addr).unwrap()), "0.0.0").is_err());
+}
+
+#[test]
+fn messages_are_sent_and_received() {
+    let server = TestServer::start("messages");
+    let mut skepz = server.signup("skepz");
+    let mut test = server.signup("test");
+    // a round trip makes sure the server has the keys test uploaded
+    assert!(test.user_exists("skepz").unwrap());
+    assert!(!skepz.user_exists("nobody").unwrap());
+
+    skepz.watch("test");
+    let online = expect_event(&mut skepz, |event| matches!(event, Event::PresenceChanged { .. }));
+    assert_eq!(online, Event::PresenceChanged { username: format!("test"), online: true });
+
+    let sent = skepz.send_message("test", "hello").unwrap();
+    assert_eq!((sent.sender.as_str(), sent.text.as_str()), ("skepz", "hello"));
+    assert!(!sent.timestamp.is_empty());
+
+    let Event::MessageReceived(received) = expect_event(&mut test, |event| matches!(event, Event::MessageReceived(_))) else {
+        unreachable!();
+    };
+    assert_eq!((received.conversation.as_str(), received.sender.as_str(), received.text.as_str()), ("skepz", "skepz", "hello"));
+    assert_eq!(received.id, sent.id);
+
+    // the reply continues the session test started from
+    test.send_message("skepz", "hi!").unwrap();
+    let Event::MessageReceived(reply) = expect_event(&mut skepz, |event| matches!(event, Event::MessageReceived(_))) else {
+        unreachable!();
+    };
+    assert_eq!(reply.text, "hi!");
+    assert_eq!(skepz.safety_number("test").unwrap(), test.safety_number("skepz").unwrap());
+    assert_eq!(skepz.contacts(), vec![format!("test")]);
+
+    // a new client for skepz reads the conversation from the history kept on its device, its own message included
+    skepz.disconnect();
+    let mut handshake = server.handshake();
+    assert_eq!(handshake.login("skepz", "hunter2", false), Ok(None));
+    let mut skepz = handshake.start(&server.dir).unwrap();
+    let mut pager = HistoryPager::new("test", 10);
+    let history = skepz.history(&mut pager).unwrap();
+    let lines: Vec<(&str, &str)> = history.iter().map(|message| (message.sender.as_str(), message.text.as_str())).collect();
+    assert_eq!(lines, vec![("skepz", "hello"), ("test", "hi!")]);
+    assert!(pager.at_start());
+
+    skepz.disconnect();
+    test.disconnect();
+}
+
+#[test]
+fn groups_are_managed_and_messaged() {
+    let server = TestServer::start("groups");
+    let mut skepz = server.signup("skepz");
+    let mut test = server.signup("test");
+    let mut third = server.signup("third");
+
+    let group = skepz.create_group("friends", &[format!("test")]).unwrap();
+    assert_eq!(group.members, vec![format!("skepz"), format!("test")]);
+    let Event::GroupChanged(changed) = expect_event(&mut test, |event| matches!(event, Event::GroupChanged(_))) else {
+        unreachable!();
+    };
+    assert_eq!(changed, group);
+    assert_eq!(test.groups(), vec![group.clone()]);
+
+    // third is invited behind test's back, so test has to find out before its message reaches them
+    skepz.invite_to_group(&group.id, "third").unwrap();
+    expect_event(&mut third, |event| matches!(event, Event::GroupChanged(_)));
+    let sent = test.send_group_message(&group.id, "hello all").unwrap();
+    assert_eq!(sent.conversation, group.id);
+    for client in [&mut skepz, &mut third] {
+        let Event::MessageReceived(received) = expect_event(client, |event| matches!(event, Event::MessageReceived(_))) else {
+            unreachable!();
+        };
+        assert_eq!((received.conversation.as_str(), received.sender.as_str(), received.text.as_str()), (group.id.as_str(), "test", "hello all"));
+    }
+
+    third.leave_group(&group.id).unwrap();
+    assert!(third.groups().is_empty());
+    assert!(third.send_group_message(&group.id, "still here?").is_err());
+    let renamed = skepz.rename_group(&group.id, "pals").unwrap();
+    assert_eq!(renamed.members, vec![format!("skepz"), format!("test")]);
+
+    skepz.disconnect();
+    test.disconnect();
+    third.disconnect();
+}
+
+#[test]
+fn spaces_are_managed_and_messaged() {
+    let server = TestServer::start("spaces");
+    let mut skepz = server.signup("skepz");
+    let mut test = server.signup("test");
+
+    let space = skepz.create_space("club").unwrap();
+    let general = space.channels[0].id.clone();
+    let space = skepz.invite_to_space(&space.id, "test").unwrap();
+    let Event::SpaceChanged(changed) = expect_event(&mut test, |event| matches!(event, Event::SpaceChanged(_))) else {
+        unreachable!();
+    };
+    assert_eq!(changed, space);
+    assert_eq!(test.channel_space(&general), Some(&space));
+
+    // a channel is a conversation named by its id, like a group
+    let sent = test.send_channel_message(&general, "hello club").unwrap();
+    assert_eq!(sent.conversation, general);
+    let Event::MessageReceived(received) = expect_event(&mut skepz, |event| matches!(event, Event::MessageReceived(_))) else {
+        unreachable!();
+    };
+    assert_eq!((received.conversation.as_str(), received.sender.as_str(), received.text.as_str()), (general.as_str(), "test", "hello club"));
+
+    // only the owner can manage the space so far
+    assert!(test.create_channel(&space.id, "off-topic").is_err());
+    skepz.kick_from_space(&space.id, "test").unwrap();
+    expect_event(&mut test, |event| matches!(event, Event::SpaceChanged(_)));
+    assert!(test.spaces().is_empty());
+
+    skepz.disconnect();
+    test.disconnect();
+}
+
+#[test]
+fn friend_requests() {
+    let server = TestServer::start("friends");
+    let mut skepz = server.signup("skepz");
+    let mut test = server.signup("test");
+
+    assert_eq!(skepz.send_friend_request("test"), Ok(ContactState::Outgoing));
+    let Event::FriendChanged(request) = expect_event(&mut test, |event| matches!(event, Event::FriendChanged(_))) else {
+        unreachable!();
+    };
+    assert_eq!(request, Contact { username: format!("skepz"), state: ContactState::Incoming });
+    assert_eq!(test.answer_friend_request("skepz", true), Ok(ContactState::Accepted));
+    expect_event(&mut skepz, |event| matches!(event, Event::FriendChanged(_)));
+    assert_eq!(skepz.friends(), vec![Contact { username: format!("test"), state: ContactState::Accepted }]);
+
+    test.remove_friend("skepz").unwrap();
+    expect_event(&mut skepz, |event| matches!(event, Event::FriendChanged(_)));
+    assert!(skepz.friends().is_empty());
+
+    skepz.disconnect();
+    test.disconnect();
+}
+
+#[test]
+fn blocked_users_are_refused() {
+    let server = TestServer::start("blocks");
+    let mut skepz = server.signup("skepz");
+    let mut test = server.signup("test");
+
+    test.send_friend_request("skepz").unwrap();
+    expect_event(&mut skepz, |event| matches!(event, Event::FriendChanged(_)));
+    assert_eq!(skepz.block("test"), Ok(vec![format!("test")]));
+    assert!(skepz.friends().is_empty());
+    let Event::FriendChanged(removed) = expect_event(&mut test, |event| matches!(event, Event::FriendChanged(_))) else {
+        unreachable!();
+    };
+    assert_eq!(removed, Contact { username: format!("skepz"), state: ContactState::None });
+
+    assert_eq!(test.send_message("skepz", "hello?").map(|message| message.text), Err(format!("skepz is not accepting your messages")));
+    assert_eq!(skepz.blocked(), Ok(vec![format!("test")]));
+    assert_eq!(skepz.unblock("test"), Ok(Vec::new()));
+    assert_eq!(test.send_message("skepz", "hello").map(|message| message.text), Ok(format!("hello")));
+
+    skepz.disconnect();
+    test.disconnect();
+}
+
+#[test]
+fn disappearing_message_timers() {
+    let server = TestServer::start("timers");
+    let mut skepz = server.signup("skepz");
+    let mut test = server.signup("test");
+
+    assert_eq!(skepz.timer("test"), 0);
+    assert!(skepz.set_timer("test", MAX_MESSAGE_TTL + 1).is_err());
+    skepz.set_timer("test", 30).unwrap();
+    assert_eq!(skepz.timer("test"), 30);
+
+    // the other user is told right away, and their messages disappear too
+    let changed = expect_event(&mut test, |event| matches!(event, Event::TimerChanged(_)));
+    assert_eq!(changed, Event::TimerChanged(Timer { conversation: format!("skepz"), ttl: 30, set_by: format!("skepz") }));
+    assert_eq!(test.timer("skepz"), 30);
+    assert_eq!(skepz.send_message("test", "gone soon").unwrap().ttl, 30);
+    let Event::MessageReceived(received) = expect_event(&mut test, |event| matches!(event, Event::MessageReceived(_))) else {
+        unreachable!();
+    };
+    assert_eq!((received.text.as_str(), received.ttl), ("gone soon", 30));
+    assert_eq!(test.send_message("skepz", "ok").unwrap().ttl, 30);
+
+    // a group has one timer for every member
+    let group = skepz.create_group("pals", &[format!("test")]).unwrap();
+    skepz.set_timer(&group.id, 60).unwrap();
+    expect_event(&mut test, |event| matches!(event, Event::TimerChanged(timer) if timer.conversation == group.id));
+    assert_eq!(test.send_group_message(&group.id, "hi").unwrap().ttl, 60);
+
+    // a new client gets every timer when it starts
+    skepz.disconnect();
+    let mut handshake = server.handshake();
+    assert_eq!(handshake.login("skepz", "hunter2", false), Ok(None));
+    let skepz = handshake.start(&server.dir).unwrap();
+    assert_eq!((skepz.timer("test"), skepz.timer(&group.id)), (30, 60));
+
+    skepz.disconnect();
+    test.disconnect();
+}
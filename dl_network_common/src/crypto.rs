@@ -0,0 +1,427 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+
+/// The length of X25519 public and private keys, and of Ed25519 public keys
+pub const KEY_LEN: usize = 32;
+/// The length of an Ed25519 signature
+pub const SIGNATURE_LEN: usize = 64;
+const NONCE_LEN: usize = 12;
+// the Poly1305 tag appended to every ciphertext
+const TAG_LEN: usize = 16;
+
+// binds derived keys to this use, so they can never match keys derived for anything else
+const SEAL_INFO: &[u8] = b"delta_lima sealed message v1";
+const SIGNING_INFO: &[u8] = b"delta_lima signing key v1";
+
+const SAFETY_NUMBER_VERSION: u16 = 0;
+// hashing many times makes it slow to search for a key with a matching safety number
+const SAFETY_NUMBER_ITERATIONS: usize = 5200;
+
+/// A user's long term X25519 key, only the public half ever leaves the client
+pub struct IdentityKey {
+    secret: StaticSecret,
+}
+
+impl IdentityKey {
+    pub fn generate() -> Self {
+        Self { secret: StaticSecret::random_from_rng(OsRng) }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let Ok(bytes) = <[u8; KEY_LEN]>::try_from(bytes) else {
+            return Err(format!("An identity key must be {} bytes", KEY_LEN));
+        };
+        Ok(Self { secret: StaticSecret::from(bytes) })
+    }
+
+    /// The private key, for saving to disk
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.secret.to_bytes()
+    }
+
+    /// The public key to share with the server and other users
+    pub fn public(&self) -> [u8; KEY_LEN] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// Agree on a shared secret with another user's public key
+    pub fn diffie_hellman(&self, public: &[u8]) -> Result<[u8; KEY_LEN], String> {
+        contributory(self.secret.diffie_hellman(&public_key(public)?))
+    }
+
+    /// The public half of the key prekeys are signed with, shared alongside the identity key
+    pub fn signing_public(&self) -> [u8; KEY_LEN] {
+        self.signing_key().verifying_key().to_bytes()
+    }
+
+    /// Sign a prekey so others can tell it was made by the owner of this identity
+    pub fn sign_prekey(&self, prekey: &PublicPreKey) -> Vec<u8> {
+        self.signing_key().sign(&prekey.key).to_bytes().to_vec()
+    }
+
+    // the signing key is derived from the identity key so only one secret needs to be kept
+    fn signing_key(&self) -> SigningKey {
+        let mut seed = [0u8; KEY_LEN];
+        Hkdf::<Sha256>::new(None, self.secret.as_bytes()).expand(SIGNING_INFO, &mut seed)
+            .expect("32 bytes is a valid HKDF output length");
+        SigningKey::from_bytes(&seed)
+    }
+}
+
+/// A short term X25519 key published to the server so others can start conversations while we are offline
+/// one-time prekeys are handed out once each, the signed prekey is shared until it is replaced
+pub struct PreKey {
+    pub id: u32,
+    secret: StaticSecret,
+}
+
+impl PreKey {
+    pub fn generate(id: u32) -> Self {
+        Self { id, secret: StaticSecret::random_from_rng(OsRng) }
+    }
+
+    pub fn from_bytes(id: u32, bytes: &[u8]) -> Result<Self, String> {
+        let Ok(bytes) = <[u8; KEY_LEN]>::try_from(bytes) else {
+            return Err(format!("A prekey must be {} bytes", KEY_LEN));
+        };
+        Ok(Self { id, secret: StaticSecret::from(bytes) })
+    }
+
+    /// The private key, for saving to disk
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public(&self) -> PublicPreKey {
+        PublicPreKey { id: self.id, key: PublicKey::from(&self.secret).to_bytes().to_vec() }
+    }
+
+    /// Agree on a shared secret with another user's public key
+    pub fn diffie_hellman(&self, public: &[u8]) -> Result<[u8; KEY_LEN], String> {
+        contributory(self.secret.diffie_hellman(&public_key(public)?))
+    }
+}
+
+/// The public half of a prekey, with the id its owner knows it by
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicPreKey {
+    pub id: u32,
+    pub key: Vec<u8>,
+}
+
+/// Everything needed to start a conversation with a user, fetched from the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreKeyBundle {
+    pub identity_key: Vec<u8>,
+    pub signing_key: Vec<u8>,
+    pub signed_prekey: PublicPreKey,
+    /// the signature of the signed prekey by the signing key
+    pub signature: Vec<u8>,
+    /// None if the user has run out of one-time prekeys
+    pub one_time_prekey: Option<PublicPreKey>,
+}
+
+impl PreKeyBundle {
+    /// true if the signed prekey was signed by the bundle's signing key
+    pub fn verify(&self) -> bool {
+        verify_prekey(&self.signing_key, &self.signed_prekey, &self.signature)
+    }
+}
+
+/// Check a prekey was signed by the owner of a signing key
+pub fn verify_prekey(signing_key: &[u8], prekey: &PublicPreKey, signature: &[u8]) -> bool {
+    let Ok(signing_key) = <[u8; KEY_LEN]>::try_from(signing_key) else {
+        return false;
+    };
+    let Ok(signing_key) = VerifyingKey::from_bytes(&signing_key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    signing_key.verify(&prekey.key, &signature).is_ok()
+}
+
+/// The number two users compare, in person or over another channel, to check they have each other's real identity keys
+/// both users get the same 60 digits in groups of 5, whichever order they are given in
+pub fn safety_number(user: &str, key: &[u8], other: &str, other_key: &[u8]) -> String {
+    let mut halves = [fingerprint(user, key), fingerprint(other, other_key)];
+    halves.sort();
+    let digits = halves.concat();
+    digits.as_bytes().chunks(5).map(|group| String::from_utf8_lossy(group).to_string()).collect::<Vec<_>>().join(" ")
+}
+
+// 30 digits that only depend on one user's name and identity key
+fn fingerprint(user: &str, key: &[u8]) -> String {
+    let mut hash = Sha512::new()
+        .chain_update(SAFETY_NUMBER_VERSION.to_le_bytes())
+        .chain_update(key)
+        .chain_update(user.as_bytes())
+        .finalize();
+    for _ in 0..SAFETY_NUMBER_ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(key).finalize();
+    }
+    // each 5 bytes of the hash become 5 digits
+    hash[..30].chunks(5).fold(String::new(), |mut digits, chunk| {
+        let value = chunk.iter().fold(0u64, |value, byte| value << 8 | *byte as u64);
+        digits.push_str(format!("{:05}", value % 100000).as_str());
+        digits
+    })
+}
+
+/// A message body encrypted for a single recipient
+/// the server stores and forwards these without being able to read them
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealedPayload {
+    /// the public half of the one-time key the message was sealed with
+    pub ephemeral: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    /// extra data for the client's session layer, empty for payloads made by `seal`
+    pub header: Vec<u8>,
+}
+
+impl SealedPayload {
+    /// A message stored before end-to-end encryption was added, the ciphertext is the plaintext message
+    /// these have no ephemeral key or nonce
+    pub fn plaintext<S: Into<String>>(message: S) -> Self {
+        Self { ephemeral: Vec::new(), nonce: Vec::new(), ciphertext: message.into().into_bytes(), header: Vec::new() }
+    }
+
+    /// true if this is a message from before end-to-end encryption, see `SealedPayload::plaintext`
+    pub fn is_plaintext(&self) -> bool {
+        self.ephemeral.is_empty() && self.nonce.is_empty()
+    }
+
+    /// true if the payload has the shape of one made by `seal`, it may still fail to open
+    pub fn is_well_formed(&self) -> bool {
+        self.ephemeral.len() == KEY_LEN && self.nonce.len() == NONCE_LEN && self.ciphertext.len() >= TAG_LEN
+    }
+
+    /// Pack the ephemeral key, nonce and ciphertext into bytes for storage, the header is not included
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.ephemeral.as_slice(), self.nonce.as_slice(), self.ciphertext.as_slice()].concat()
+    }
+
+    /// Unpack bytes made by `SealedPayload::to_bytes`, with an empty header
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < KEY_LEN + NONCE_LEN + TAG_LEN {
+            return Err(format!("A sealed payload must be at least {} bytes", KEY_LEN + NONCE_LEN + TAG_LEN));
+        }
+        let (ephemeral, rest) = bytes.split_at(KEY_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        Ok(Self { ephemeral: ephemeral.to_vec(), nonce: nonce.to_vec(), ciphertext: ciphertext.to_vec(), header: Vec::new() })
+    }
+}
+
+/// Encrypt a message so only the owner of the recipient's identity key can read it
+/// the key is agreed from a new ephemeral key and the sender's identity key, so the recipient can tell who sent it
+pub fn seal(message: &[u8], sender: &IdentityKey, recipient: &[u8]) -> Result<SealedPayload, String> {
+    let recipient = public_key(recipient)?;
+
+    let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral = PublicKey::from(&ephemeral_secret);
+    let key = derive_key(
+        ephemeral_secret.diffie_hellman(&recipient),
+        sender.secret.diffie_hellman(&recipient),
+        &ephemeral, &PublicKey::from(&sender.secret), &recipient,
+    )?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let Ok(ciphertext) = ChaCha20Poly1305::new(&key).encrypt(Nonce::from_slice(&nonce), message) else {
+        return Err(format!("Failed to encrypt message"));
+    };
+
+    Ok(SealedPayload { ephemeral: ephemeral.to_bytes().to_vec(), nonce: nonce.to_vec(), ciphertext, header: Vec::new() })
+}
+
+/// Decrypt a message sealed for our identity key by the owner of the sender's identity key
+pub fn open(sealed: &SealedPayload, recipient: &IdentityKey, sender: &[u8]) -> Result<Vec<u8>, String> {
+    if !sealed.is_well_formed() {
+        return Err(format!("The message is not a sealed payload"));
+    }
+    let sender = public_key(sender)?;
+    let ephemeral = public_key(&sealed.ephemeral)?;
+
+    let key = derive_key(
+        recipient.secret.diffie_hellman(&ephemeral),
+        recipient.secret.diffie_hellman(&sender),
+        &ephemeral, &sender, &PublicKey::from(&recipient.secret),
+    )?;
+
+    match ChaCha20Poly1305::new(&key).decrypt(Nonce::from_slice(&sealed.nonce), sealed.ciphertext.as_slice()) {
+        Ok(message) => Ok(message),
+        Err(_) => Err(format!("The message could not be decrypted, it was altered or not sent to this key")),
+    }
+}
+
+fn public_key(bytes: &[u8]) -> Result<PublicKey, String> {
+    match <[u8; KEY_LEN]>::try_from(bytes) {
+        Ok(bytes) => Ok(PublicKey::from(bytes)),
+        Err(_) => Err(format!("A public key must be {} bytes", KEY_LEN)),
+    }
+}
+
+fn contributory(shared: SharedSecret) -> Result<[u8; KEY_LEN], String> {
+    if !shared.was_contributory() {
+        return Err(format!("Refusing to use an insecure public key"));
+    }
+    Ok(shared.to_bytes())
+}
+
+fn derive_key(ephemeral_shared: SharedSecret, static_shared: SharedSecret, ephemeral: &PublicKey, sender: &PublicKey, recipient: &PublicKey) -> Result<Key, String> {
+    // keys of low order give a shared secret anyone could guess
+    if !ephemeral_shared.was_contributory() || !static_shared.was_contributory() {
+        return Err(format!("Refusing to use an insecure public key"));
+    }
+
+    let input = [ephemeral_shared.as_bytes().as_slice(), static_shared.as_bytes()].concat();
+    let info = [SEAL_INFO, ephemeral.as_bytes(), sender.as_bytes(), recipient.as_bytes()].concat();
+    let mut key = Key::default();
+    if Hkdf::<Sha256>::new(None, &input).expand(&info, &mut key).is_err() {
+        return Err(format!("Failed to derive message key"));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+
+        let sealed = seal(b"hello bob", &alice, &bob.public()).unwrap();
+        assert!(sealed.is_well_formed());
+        assert!(!sealed.is_plaintext());
+        assert_eq!(open(&sealed, &bob, &alice.public()).unwrap(), b"hello bob");
+    }
+
+    #[test]
+    fn every_message_has_new_keys() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+
+        let first = seal(b"same", &alice, &bob.public()).unwrap();
+        let second = seal(b"same", &alice, &bob.public()).unwrap();
+        assert_ne!(first.ephemeral, second.ephemeral);
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    #[test]
+    fn only_the_recipient_can_open() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+        let eve = IdentityKey::generate();
+
+        let sealed = seal(b"hello bob", &alice, &bob.public()).unwrap();
+        assert!(open(&sealed, &eve, &alice.public()).is_err());
+    }
+
+    #[test]
+    fn sender_is_checked() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+        let eve = IdentityKey::generate();
+
+        // eve can not pass off her message as one from alice
+        let sealed = seal(b"from alice, honest", &eve, &bob.public()).unwrap();
+        assert!(open(&sealed, &bob, &alice.public()).is_err());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+
+        let mut sealed = seal(b"hello bob", &alice, &bob.public()).unwrap();
+        sealed.ciphertext[0] ^= 1;
+        assert!(open(&sealed, &bob, &alice.public()).is_err());
+    }
+
+    #[test]
+    fn low_order_keys_are_refused() {
+        let alice = IdentityKey::generate();
+        assert!(seal(b"hello", &alice, &[0u8; KEY_LEN]).is_err());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+
+        let sealed = seal(b"hello bob", &alice, &bob.public()).unwrap();
+        assert_eq!(SealedPayload::from_bytes(&sealed.to_bytes()).unwrap(), sealed);
+        assert!(SealedPayload::from_bytes(&[0u8; 10]).is_err());
+
+        let restored = IdentityKey::from_bytes(&alice.to_bytes()).unwrap();
+        assert_eq!(restored.public(), alice.public());
+    }
+
+    #[test]
+    fn signed_prekeys() {
+        let alice = IdentityKey::generate();
+        let eve = IdentityKey::generate();
+        let prekey = PreKey::generate(1).public();
+
+        let signature = alice.sign_prekey(&prekey);
+        assert_eq!(signature.len(), SIGNATURE_LEN);
+        assert!(verify_prekey(&alice.signing_public(), &prekey, &signature));
+        assert!(!verify_prekey(&eve.signing_public(), &prekey, &signature));
+        assert!(!verify_prekey(&alice.signing_public(), &PreKey::generate(2).public(), &signature));
+        assert!(!verify_prekey(&alice.signing_public(), &prekey, &[0u8; 3]));
+
+        // the signing key survives saving the identity key
+        let restored = IdentityKey::from_bytes(&alice.to_bytes()).unwrap();
+        assert_eq!(restored.signing_public(), alice.signing_public());
+    }
+
+    #[test]
+    fn bundles_are_verified() {
+        let alice = IdentityKey::generate();
+        let signed_prekey = PreKey::generate(1).public();
+        let mut bundle = PreKeyBundle {
+            identity_key: alice.public().to_vec(),
+            signing_key: alice.signing_public().to_vec(),
+            signature: alice.sign_prekey(&signed_prekey),
+            signed_prekey,
+            one_time_prekey: Some(PreKey::generate(2).public()),
+        };
+        assert!(bundle.verify());
+
+        bundle.signed_prekey = PreKey::generate(1).public();
+        assert!(!bundle.verify());
+    }
+
+    #[test]
+    fn plaintext_is_not_opened() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+
+        let legacy = SealedPayload::plaintext("from before encryption");
+        assert!(legacy.is_plaintext());
+        assert!(open(&legacy, &bob, &alice.public()).is_err());
+    }
+
+    #[test]
+    fn safety_numbers() {
+        let alice = IdentityKey::generate();
+        let bob = IdentityKey::generate();
+
+        let number = safety_number("alice", &alice.public(), "bob", &bob.public());
+        assert_eq!(number.len(), 60 + 11);
+        assert!(number.split(' ').all(|group| group.len() == 5 && group.bytes().all(|c| c.is_ascii_digit())));
+        // both sides see the same number
+        assert_eq!(number, safety_number("bob", &bob.public(), "alice", &alice.public()));
+        // a different key gives a different number
+        assert_ne!(number, safety_number("alice", &alice.public(), "bob", &IdentityKey::generate().public()));
+    }
+}
@@ -1,12 +1,22 @@
+// `format!` is used for every owned string in this project, literal or not
+#![allow(clippy::useless_format)]
+
 use std::io;
-use std::net::TcpStream;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use capnp::message::Builder;
+use capnp::message::{Builder, HeapAllocator};
 use capnp::{message, serialize};
 use capnp::serialize::OwnedSegments;
-use regex::Regex;
+use crate::crypto::{PreKeyBundle, PublicPreKey, SealedPayload};
+use crate::transport::Transport;
 
+#[allow(dead_code, clippy::all)]
 pub(crate) mod packet_capnp;
+pub mod crypto;
+pub mod tls;
+pub mod transport;
 
 pub fn systime() -> Duration {
     SystemTime::now()
@@ -19,26 +29,223 @@ pub fn to_epoch(time: SystemTime) -> Duration {
         .expect("Fatal error occurred: System time moved backwards! Are you a time traveler?")
 }
 
-pub fn validate_ip<S: Into<String>>(ip: S) -> bool {
-    let ip_pattern =
-        Regex::new(r"^(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)|localhost$")
-            .expect("Failed to init regex");
-    ip_pattern.is_match(ip.into().as_str())
+/// Check that a host is an IPv4 address, an IPv6 address surrounded with '[' and ']', or a hostname
+pub fn validate_ip<S: Into<String>>(ip: S) -> Result<(), String> {
+    let ip = ip.into();
+    if ip.is_empty() {
+        return Err(format!("No address was given"));
+    }
+    if let Some(inner) = ip.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+        return match inner.parse::<Ipv6Addr>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("`{}` is not a valid IPv6 address", inner)),
+        };
+    }
+    if ip.parse::<Ipv6Addr>().is_ok() {
+        return Err(format!("IPv6 addresses must be surrounded with '[' and ']', like `[{}]`", ip));
+    }
+    // names made of only numbers and dots are meant to be IPv4 addresses
+    if ip.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return match ip.parse::<Ipv4Addr>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("`{}` is not a valid IPv4 address, expected four numbers from 0 to 255 like `127.0.0.1`", ip)),
+        };
+    }
+    if !is_hostname(&ip) {
+        return Err(format!("`{}` is not a valid address, expected an IPv4 address like `127.0.0.1`, an IPv6 address like `[::1]` or a hostname like `example.com`", ip));
+    }
+    Ok(())
 }
 
-pub fn validate_port<S: Into<String>>(port: S) -> bool {
-    let port_pattern =
-        Regex::new(r"^((6553[0-5])|(655[0-2][0-9])|(65[0-4][0-9]{2})|(6[0-4][0-9]{3})|([1-5][0-9]{4})|([0-5]{0,5})|([0-9]{1,4}))$")
-            .expect("Failed to init regex");
-    port_pattern.is_match(port.into().as_str())
+// hostnames are labels of letters, digits and '-' separated by dots, and labels can not start or end with '-'
+fn is_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    host.len() <= 253 && host.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
 }
 
+/// Check that a port is a number from 1 to 65535
+pub fn validate_port<S: Into<String>>(port: S) -> Result<(), String> {
+    let port = port.into();
+    match port.parse::<u16>() {
+        Ok(0) => Err(format!("Port 0 can not be connected to, expected a number from 1 to 65535")),
+        Ok(_) => Ok(()),
+        Err(_) if port.is_empty() => Err(format!("No port was given")),
+        Err(_) => Err(format!("`{}` is not a valid port, expected a number from 1 to 65535", port)),
+    }
+}
+
+/// Find the socket addresses of a host and port, looking up hostnames
+/// the host can be anything `validate_ip` accepts, a hostname can have an address for both IPv4 and IPv6
+pub fn resolve_address(host: &str, port: &str) -> Result<Vec<SocketAddr>, String> {
+    validate_ip(host)?;
+    validate_port(port)?;
+    let port = port.parse::<u16>().map_err(|e| format!("`{}` is not a valid port: {}", port, e))?;
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()
+        .map_err(|e| format!("Failed to look up the address of `{}`: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("`{}` does not have any addresses", host));
+    }
+    Ok(addrs)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct SentMsg {
-    message: String,
-    sender: String,
-    timestamp: String
+    pub id: String,
+    pub message: SealedPayload,
+    pub sender: String,
+    pub timestamp: String,
+    pub seq: u64,
+    /// seconds the message disappears after, 0 if it never does
+    pub ttl: u64,
 }
 
+/// A group conversation, members are usernames
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+/// One member's copy of a group message, sealed for them by the sender
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealedCopy {
+    pub recipient: String,
+    pub message: SealedPayload,
+}
+
+/// A community of members with text channels, and roles giving them permissions
+/// the owner and members are usernames, and every member has the role with the id of the space, its everyone role
+#[derive(Debug, Clone, PartialEq)]
+pub struct Space {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    pub channels: Vec<Channel>,
+    pub roles: Vec<Role>,
+    pub members: Vec<SpaceMember>,
+    /// the users that can not be invited back
+    pub banned: Vec<String>,
+}
+
+impl Space {
+    /// The permission bits a user has in the space, every bit for the owner and none for users that are not members
+    pub fn permissions(&self, username: &str) -> u32 {
+        if username == self.owner {
+            return permissions::ALL;
+        }
+        let Some(member) = self.members.iter().find(|member| member.username == username) else {
+            return 0;
+        };
+        self.roles.iter()
+            .filter(|role| role.id == self.id || member.roles.contains(&role.id))
+            .fold(0, |permissions, role| permissions | role.permissions)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Role {
+    pub id: String,
+    pub name: String,
+    /// the bits in `permissions`
+    pub permissions: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpaceMember {
+    pub username: String,
+    /// the ids of the roles the member was given, besides the everyone role
+    pub roles: Vec<String>,
+}
+
+/// How self is related to another user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContactState {
+    /// not a contact, or no longer one
+    None,
+    /// they sent self a request that was not answered yet
+    Incoming,
+    /// self sent them a request that was not answered yet
+    Outgoing,
+    Accepted,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contact {
+    pub username: String,
+    pub state: ContactState,
+}
+
+/// The disappearing message timer of a conversation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timer {
+    /// the other user of a direct conversation, or the id of a group or channel
+    pub conversation: String,
+    /// seconds messages are kept for, 0 if they do not disappear
+    pub ttl: u64,
+    /// the user who set it last
+    pub set_by: String,
+}
+
+/// What the roles of a space allow its members to do, a member can do what any of their roles allows
+pub mod permissions {
+    /// send messages in the channels of the space
+    pub const SEND: u32 = 1;
+    /// create and delete channels
+    pub const MANAGE_CHANNELS: u32 = 1 << 1;
+    /// remove members from the space
+    pub const KICK: u32 = 1 << 2;
+    /// remove members and keep them from being invited back
+    pub const BAN: u32 = 1 << 3;
+    /// create, change, delete and hand out roles, with at most the permissions the member has
+    pub const MANAGE_ROLES: u32 = 1 << 4;
+    pub const ALL: u32 = SEND | MANAGE_CHANNELS | KICK | BAN | MANAGE_ROLES;
+}
+
+/// Where a page of message history starts
+/// ids and timestamps are the ones sent by the server with each SentMsg
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryCursor {
+    /// The newest messages in the conversation
+    Latest,
+    /// Messages older than the message with this id
+    BeforeId(String),
+    /// Messages newer than the message with this id
+    AfterId(String),
+    /// Messages sent before this time
+    BeforeTime(String),
+    /// Messages sent after this time
+    AfterTime(String),
+}
+
+/// The version of the packet layout, sent in every envelope
+pub const PROTOCOL_VERSION: u16 = 12;
+
+/// The most one-time prekeys the server keeps for a user
+pub const MAX_PREKEYS: u32 = 100;
+
+/// The most members a group can have, including its creator
+pub const MAX_GROUP_MEMBERS: usize = 50;
+
+/// The most members a space can have, including its owner
+/// every message to a channel is sealed once for each of them
+pub const MAX_SPACE_MEMBERS: usize = 200;
+
+/// The longest a disappearing message timer can be, in seconds
+pub const MAX_MESSAGE_TTL: u64 = 4 * 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Packet {
     /// Client --> Server | Check if client's version is valid
     /// disconnecting determines if the client is just checking compatibility or attempting a full connection
@@ -50,138 +257,994 @@ pub enum Packet {
     /// Client <-- Server | Send if the login attempt was valid or not, and if not send an error
     LoginResponse { valid: bool, error: Option<String> },
     /// Client <-> Server | A message sent from a client intended for another user
-    Message { message: String, sender: String, recipient: String, timestamp: String },
+    /// the message is sealed for the recipient by the sending client, the server can not read it
+    /// id and seq are set by the server and are left empty by the sending client
+    /// seq is the position of the message in the conversation, a skipped seq means a message was missed
+    /// group is the id of the group or channel a message was sent in, the server sets it when delivering one
+    /// ttl is set by the server from the disappearing message timer of the conversation, 0 if messages do not disappear
+    /// the server purges the message once it is older than that, and the recipient deletes it that long after reading it
+    Message { id: String, seq: u64, message: SealedPayload, sender: String, recipient: String, group: Option<String>, timestamp: String, ttl: u64 },
+    /// Client --> Server | Confirm a message was received so the server stops redelivering it
+    MessageAck { id: String },
+    /// Client <-- Server | Tells the sender of a message the id and seq it was stored with
+    /// the recipient is the group or channel id for a message sent to one
+    MessageReceipt { id: String, recipient: String, seq: u64, timestamp: String },
     /// Client --> Server | A request to see if a user with a specific name exists
     UserExistsRequest { username: String },
-    /// Client --> Server | A request to see if a user with a specific name exists
+    /// Client --> Server | A request to see if a user with a specific name is online
     UserOnlineRequest { username: String },
     /// Server --> Client | A response responding to a UserExists or UserOnline with a true or false value
     UserResponse { response: bool },
-    /// Client --> Server | A request to get the message history between self and a user
-    MsgHistoryRequest { username: String },
-    /// Server --> Client | The message history
-    MsgHistory { history: Vec<SentMsg> },
+    /// Client --> Server | Publish the public half of the client's identity key and the key its prekeys are signed with
+    /// replacing the identity key also removes the prekeys signed by the old one
+    IdentityKeyUpload { key: Vec<u8>, signing_key: Vec<u8> },
+    /// Client --> Server | A request for the public identity key of a user
+    IdentityKeyRequest { username: String },
+    /// Server --> Client | The public identity key of a user, None if they have not uploaded one
+    IdentityKeyResponse { username: String, key: Option<Vec<u8>> },
+    /// Client --> Server | Publish a signed prekey, replacing the previous one
+    SignedPreKeyUpload { prekey: PublicPreKey, signature: Vec<u8> },
+    /// Client --> Server | Publish a batch of one-time prekeys, the server keeps at most MAX_PREKEYS and drops the rest
+    PreKeysUpload { prekeys: Vec<PublicPreKey> },
+    /// Client --> Server | A request for the prekey bundle of a user, using up one of their one-time prekeys
+    PreKeyBundleRequest { username: String },
+    /// Server --> Client | The prekey bundle of a user, None if they have not published an identity key and signed prekey
+    PreKeyBundleResponse { username: String, bundle: Option<PreKeyBundle> },
+    /// Server --> Client | The server is running low on the client's one-time prekeys and it should upload more
+    PreKeysLow { remaining: u32 },
+    /// Client --> Server | Create a group with self and other users as its members, answered with a GroupInfo
+    GroupCreate { name: String, members: Vec<String> },
+    /// Client --> Server | Add a user to a group self is a member of, answered with a GroupInfo
+    GroupInvite { group: String, username: String },
+    /// Client --> Server | Remove another member from a group self is a member of, answered with a GroupInfo
+    GroupRemove { group: String, username: String },
+    /// Client --> Server | Leave a group, answered with a GroupInfo of the group without self
+    GroupLeave { group: String },
+    /// Client --> Server | Give a group self is a member of a new name, answered with a GroupInfo
+    GroupRename { group: String, name: String },
+    /// Server --> Client | A group after the change that was asked for
+    GroupInfo { group: Group },
+    /// Server --> Client | A group self is or was a member of was changed by another member
+    /// sent to removed members too, which can tell from the member list
+    GroupUpdate { group: Group },
+    /// Client --> Server | A request for every group self is a member of, answered with a GroupList
+    GroupListRequest,
+    /// Server --> Client | The groups self is a member of
+    GroupList { groups: Vec<Group> },
+    /// Client --> Server | A message to a group, sealed separately for every other member
+    /// there must be exactly one copy for each other member, answered with a MessageReceipt
+    GroupMessage { group: String, copies: Vec<SealedCopy> },
+    /// Client --> Server | Create a space owned by self with a general channel, answered with a SpaceInfo
+    SpaceCreate { name: String },
+    /// Client --> Server | Add a user to a space self is a member of, answered with a SpaceInfo
+    SpaceInvite { space: String, username: String },
+    /// Client --> Server | Leave a space, answered with a SpaceInfo of the space without self
+    /// the owner can not leave their space
+    SpaceLeave { space: String },
+    /// Client --> Server | Remove a member from a space, needs the KICK permission, answered with a SpaceInfo
+    SpaceKick { space: String, username: String },
+    /// Client --> Server | Ban a user from a space, removing them if they are a member, or lift a ban
+    /// needs the BAN permission, answered with a SpaceInfo
+    SpaceBan { space: String, username: String, banned: bool },
+    /// Client --> Server | Add a channel to a space, needs the MANAGE_CHANNELS permission, answered with a SpaceInfo
+    ChannelCreate { space: String, name: String },
+    /// Client --> Server | Remove a channel from a space, needs the MANAGE_CHANNELS permission, answered with a SpaceInfo
+    ChannelDelete { space: String, channel: String },
+    /// Client --> Server | Create a role if its id is empty, otherwise change the name and permissions of one
+    /// needs the MANAGE_ROLES permission and every permission given, answered with a SpaceInfo
+    RoleSet { space: String, role: Role },
+    /// Client --> Server | Delete a role of a space, needs the MANAGE_ROLES permission, answered with a SpaceInfo
+    RoleDelete { space: String, role: String },
+    /// Client --> Server | Give a role to a member or take it away
+    /// needs the MANAGE_ROLES permission and every permission of the role, answered with a SpaceInfo
+    RoleAssign { space: String, role: String, username: String, assigned: bool },
+    /// Server --> Client | A space after the change that was asked for
+    SpaceInfo { space: Space },
+    /// Server --> Client | A space self is or was a member of was changed by another member
+    /// sent to removed members too, which can tell from the member list
+    SpaceUpdate { space: Space },
+    /// Client --> Server | A request for every space self is a member of, answered with a SpaceList
+    SpaceListRequest,
+    /// Server --> Client | The spaces self is a member of
+    SpaceList { spaces: Vec<Space> },
+    /// Client --> Server | A message to a channel, sealed separately for every other member of its space
+    /// needs the SEND permission and exactly one copy for each other member, answered with a MessageReceipt
+    ChannelMessage { channel: String, copies: Vec<SealedCopy> },
+    /// Client --> Server | Ask a user to become a contact, accepting their request if they already sent one
+    /// answered with a ContactList
+    ContactRequest { username: String },
+    /// Client --> Server | Accept or decline the request a user sent, answered with a ContactList
+    ContactRespond { username: String, accept: bool },
+    /// Client --> Server | Remove a contact, or take back a request, answered with a ContactList
+    ContactRemove { username: String },
+    /// Client --> Server | A request for every contact and unanswered request of self, answered with a ContactList
+    ContactListRequest,
+    /// Server --> Client | The contacts and unanswered requests of self, ordered by username
+    ContactList { contacts: Vec<Contact> },
+    /// Server --> Client | Another user changed how they are related to self
+    /// requests sent while self was offline are sent after logging in
+    ContactUpdate { contact: Contact },
+    /// Client --> Server | Block a user, answered with a BlockList
+    /// the server drops their messages to self, hides self's presence from them and ignores their contact requests
+    Block { username: String },
+    /// Client --> Server | Stop blocking a user, answered with a BlockList
+    Unblock { username: String },
+    /// Client --> Server | A request for every user self blocked, answered with a BlockList
+    BlockListRequest,
+    /// Server --> Client | The users self blocked, ordered by username
+    BlockList { usernames: Vec<String> },
+    /// Client --> Server | Set the disappearing message timer of a conversation self is part of, 0 turns it off
+    /// conversation names a user, or a group or channel by its id, answered with a TimerInfo
+    /// changing the timer of a channel needs MANAGE_CHANNELS
+    TimerSet { conversation: String, ttl: u64 },
+    /// Server --> Client | A timer after the change that was asked for
+    TimerInfo { timer: Timer },
+    /// Server --> Client | Another user changed the timer of a conversation self is part of
+    /// the conversation of a direct timer is the user who changed it
+    TimerUpdate { timer: Timer },
+    /// Client --> Server | A request for every timer set in a conversation self is part of, answered with a TimerList
+    TimerListRequest,
+    /// Server --> Client | The timers set in the conversations self is part of
+    TimerList { timers: Vec<Timer> },
+    /// Client --> Server | A request to get a page of the message history between self and a user
+    /// the username can also be the id of a group self is a member of
+    /// limit is the most messages the server should send back
+    MsgHistoryRequest { username: String, cursor: HistoryCursor, limit: u32 },
+    /// Client --> Server | A request for the messages between self and a user with a seq from `from` to `to`, inclusive
+    /// the username can also be the id of a group self is a member of
+    /// answered with a MsgHistory, more is true if the range was too long to send at once
+    MsgRangeRequest { username: String, from: u64, to: u64 },
+    /// Server --> Client | A page of message history, oldest message first
+    /// more is true if there are more messages past the page in the direction of the cursor
+    MsgHistory { history: Vec<SentMsg>, more: bool },
     /// Client <-> Server | A way to announce a disconnection is required or imminent
     Disconnect,
     /// Client <-> Server | A way to announce an error has occurred, what the error is and if it requires a disconnection
     Error { should_disconnect: bool, error: String },
 }
 
-/// was here
-pub enum ExpectedPacket {
-    // No reason to ever expect an Error or Disconnect packet, and they will only be received under a Message packet
-    Ping, PingResponse, LoginRequest, LoginResponse, Message
-}
-
-pub struct Connection {
-    stream: TcpStream,
-}
-
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
-        Self {
-            stream,
-        }
-    }
-
-    pub fn try_clone(&mut self) -> io::Result<Connection> {
-        Ok(Self {
-            stream: self.stream.try_clone()?
-        })
-    }
-
-    pub fn disconnect(&mut self) -> Result<(), String> {
-        let err = self.send(Packet::Disconnect);
-        if let Err(_) = err {
-            return Err(format!("Failed to send disconnect packet: already disconnected!"));
-        }
-        Ok(())
-    }
-
-    /// Send a packet across the stream
-    pub fn send(&mut self, packet: Packet) -> ::capnp::Result<()> {
+impl Packet {
+    /// Wrap the packet in an envelope, ready to be written to a stream
+    fn encode(self) -> Builder<HeapAllocator> {
         let mut message = Builder::new_default();
-        match packet {
+        let mut envelope = message.init_root::<packet_capnp::envelope::Builder>();
+        envelope.set_version(PROTOCOL_VERSION);
+        match self {
             Packet::Ping { version, disconnecting } => {
-                let mut ep = message.init_root::<packet_capnp::ping::Builder>();
+                let mut ep = envelope.init_ping();
                 ep.set_version(version.as_str());
-                ep.set_disconnecting(disconnecting)
+                ep.set_disconnecting(disconnecting);
             }
             Packet::PingResponse { valid, accepted_version: version } => {
-                let mut ep = message.init_root::<packet_capnp::ping_response::Builder>();
+                let mut ep = envelope.init_ping_response();
                 ep.set_valid(valid);
                 ep.set_version(version.as_str());
             }
             Packet::LoginRequest { username, password, signup } => {
-                let mut ep = message.init_root::<packet_capnp::login_request::Builder>();
+                let mut ep = envelope.init_login_request();
                 ep.set_username(username.as_str());
                 ep.set_password(password.as_str());
                 ep.set_signup(signup);
             }
             Packet::LoginResponse { valid, error } => {
-                let mut ep = message.init_root::<packet_capnp::login_response::Builder>();
-                ep.set_valid(valid);
-                if let Some(err) = error {
-                    ep.set_error(err.as_str());
+                let mut ep = envelope.init_login_response();
+                match error {
+                    Some(err) => ep.set_error(err.as_str()),
+                    None => ep.set_valid(valid),
                 }
             }
-            Packet::Message { message: msg, sender, recipient, timestamp } => {
-                let ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                let mut minit = ep.init_message();
-                minit.set_message(msg.as_str());
-                minit.set_sender(sender.as_str());
-                minit.set_recipient(recipient.as_str());
-                minit.set_timestamp(timestamp.as_str());
+            Packet::Message { id, seq, message: msg, sender, recipient, group, timestamp, ttl } => {
+                let mut ep = envelope.init_message();
+                ep.set_id(id.as_str());
+                ep.set_seq(seq);
+                ep.set_ttl(ttl);
+                set_sealed(ep.reborrow().init_message(), &msg);
+                ep.set_sender(sender.as_str());
+                ep.set_recipient(recipient.as_str());
+                ep.set_group(group.unwrap_or_default().as_str());
+                ep.set_timestamp(timestamp.as_str());
+            }
+            Packet::MessageAck { id } => {
+                envelope.set_message_ack(id.as_str());
+            }
+            Packet::MessageReceipt { id, recipient, seq, timestamp } => {
+                let mut ep = envelope.init_message_receipt();
+                ep.set_id(id.as_str());
+                ep.set_recipient(recipient.as_str());
+                ep.set_seq(seq);
+                ep.set_timestamp(timestamp.as_str());
             }
             Packet::UserExistsRequest { username } => {
-                let ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                let mut init = ep.init_info_request();
-                init.set_username_exists(username.as_str());
+                envelope.set_user_exists_request(username.as_str());
             }
             Packet::UserOnlineRequest { username } => {
-                let ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                let mut init = ep.init_info_request();
-                init.set_username_online(username.as_str());
+                envelope.set_user_online_request(username.as_str());
             }
             Packet::UserResponse { response } => {
-                let ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                let mut init = ep.init_info_response();
-                init.set_user_response(response);
-            }
-            Packet::MsgHistoryRequest { username } => {
-                let ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                let mut init = ep.init_info_request();
-                init.set_msg_history(username.as_str());
-            }
-            Packet::MsgHistory { history } => {
-                let ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                let mut init = ep.init_info_response();
-                // initialize the message history list
-                let mut list = init.init_msg_history(history.len() as u32);
-
-                // build the list from the history vector
-                // there is probably a better way to do this, but I cant find it.
-                for x in 0..history.len() {
-                    let msg = history.get(x).unwrap();
-                    let index = x as u32;
-                    list.reborrow().get(index).set_message(msg.message.as_str());
-                    list.reborrow().get(index).set_timestamp(msg.timestamp.as_str());
-                    list.reborrow().get(index).set_sender(msg.sender.as_str());
-                    list.reborrow().get(index).set_recipient("");
+                envelope.set_user_response(response);
+            }
+            Packet::IdentityKeyUpload { key, signing_key } => {
+                let mut ep = envelope.init_identity_key_upload();
+                ep.set_key(key.as_slice());
+                ep.set_signing_key(signing_key.as_slice());
+            }
+            Packet::IdentityKeyRequest { username } => {
+                envelope.set_identity_key_request(username.as_str());
+            }
+            Packet::IdentityKeyResponse { username, key } => {
+                let mut ep = envelope.init_identity_key_response();
+                ep.set_username(username.as_str());
+                ep.set_key(key.unwrap_or_default().as_slice());
+            }
+            Packet::SignedPreKeyUpload { prekey, signature } => {
+                let mut ep = envelope.init_signed_pre_key_upload();
+                set_prekey(ep.reborrow().init_pre_key(), &prekey);
+                ep.set_signature(signature.as_slice());
+            }
+            Packet::PreKeysUpload { prekeys } => {
+                let mut list = envelope.init_pre_keys_upload(prekeys.len() as u32);
+                for (index, prekey) in prekeys.iter().enumerate() {
+                    set_prekey(list.reborrow().get(index as u32), prekey);
+                }
+            }
+            Packet::PreKeyBundleRequest { username } => {
+                envelope.set_pre_key_bundle_request(username.as_str());
+            }
+            Packet::PreKeyBundleResponse { username, bundle } => {
+                let mut ep = envelope.init_pre_key_bundle();
+                ep.set_username(username.as_str());
+                // a missing bundle is sent with an empty identity key
+                if let Some(bundle) = bundle {
+                    ep.set_identity_key(bundle.identity_key.as_slice());
+                    ep.set_signing_key(bundle.signing_key.as_slice());
+                    let mut signed = ep.reborrow().init_signed_pre_key();
+                    set_prekey(signed.reborrow().init_pre_key(), &bundle.signed_prekey);
+                    signed.set_signature(bundle.signature.as_slice());
+                    if let Some(one_time) = bundle.one_time_prekey {
+                        set_prekey(ep.init_one_time_pre_key(), &one_time);
+                    }
+                }
+            }
+            Packet::PreKeysLow { remaining } => {
+                envelope.init_pre_keys_low().set_remaining(remaining);
+            }
+            Packet::GroupCreate { name, members } => {
+                set_group(envelope.init_group_create(), &Group { id: String::new(), name, members });
+            }
+            Packet::GroupInvite { group, username } => {
+                let mut ep = envelope.init_group_invite();
+                ep.set_group(group.as_str());
+                ep.set_username(username.as_str());
+            }
+            Packet::GroupRemove { group, username } => {
+                let mut ep = envelope.init_group_remove();
+                ep.set_group(group.as_str());
+                ep.set_username(username.as_str());
+            }
+            Packet::GroupLeave { group } => {
+                envelope.set_group_leave(group.as_str());
+            }
+            Packet::GroupRename { group, name } => {
+                let mut ep = envelope.init_group_rename();
+                ep.set_group(group.as_str());
+                ep.set_name(name.as_str());
+            }
+            Packet::GroupInfo { group } => {
+                set_group(envelope.init_group_info(), &group);
+            }
+            Packet::GroupUpdate { group } => {
+                set_group(envelope.init_group_update(), &group);
+            }
+            Packet::GroupListRequest => {
+                envelope.set_group_list_request(());
+            }
+            Packet::GroupList { groups } => {
+                let mut list = envelope.init_group_list(groups.len() as u32);
+                for (index, group) in groups.iter().enumerate() {
+                    set_group(list.reborrow().get(index as u32), group);
+                }
+            }
+            Packet::GroupMessage { group, copies } => {
+                let mut ep = envelope.init_group_message();
+                ep.set_group(group.as_str());
+                let mut list = ep.init_copies(copies.len() as u32);
+                for (index, copy) in copies.iter().enumerate() {
+                    let mut entry = list.reborrow().get(index as u32);
+                    entry.set_recipient(copy.recipient.as_str());
+                    set_sealed(entry.init_message(), &copy.message);
+                }
+            }
+            Packet::SpaceCreate { name } => {
+                envelope.set_space_create(name.as_str());
+            }
+            Packet::SpaceInvite { space, username } => {
+                set_space_target(envelope.init_space_invite(), &space, &username);
+            }
+            Packet::SpaceLeave { space } => {
+                envelope.set_space_leave(space.as_str());
+            }
+            Packet::SpaceKick { space, username } => {
+                set_space_target(envelope.init_space_kick(), &space, &username);
+            }
+            Packet::SpaceBan { space, username, banned } => {
+                let mut ep = envelope.init_space_ban();
+                ep.set_space(space.as_str());
+                ep.set_username(username.as_str());
+                ep.set_banned(banned);
+            }
+            Packet::ChannelCreate { space, name } => {
+                set_space_target(envelope.init_channel_create(), &space, &name);
+            }
+            Packet::ChannelDelete { space, channel } => {
+                set_space_target(envelope.init_channel_delete(), &space, &channel);
+            }
+            Packet::RoleSet { space, role } => {
+                let mut ep = envelope.init_role_set();
+                ep.set_space(space.as_str());
+                set_role(ep.init_role(), &role);
+            }
+            Packet::RoleDelete { space, role } => {
+                set_space_target(envelope.init_role_delete(), &space, &role);
+            }
+            Packet::RoleAssign { space, role, username, assigned } => {
+                let mut ep = envelope.init_role_assign();
+                ep.set_space(space.as_str());
+                ep.set_role(role.as_str());
+                ep.set_username(username.as_str());
+                ep.set_assigned(assigned);
+            }
+            Packet::SpaceInfo { space } => {
+                set_space(envelope.init_space_info(), &space);
+            }
+            Packet::SpaceUpdate { space } => {
+                set_space(envelope.init_space_update(), &space);
+            }
+            Packet::SpaceListRequest => {
+                envelope.set_space_list_request(());
+            }
+            Packet::SpaceList { spaces } => {
+                let mut list = envelope.init_space_list(spaces.len() as u32);
+                for (index, space) in spaces.iter().enumerate() {
+                    set_space(list.reborrow().get(index as u32), space);
+                }
+            }
+            Packet::ChannelMessage { channel, copies } => {
+                let mut ep = envelope.init_channel_message();
+                ep.set_channel(channel.as_str());
+                let mut list = ep.init_copies(copies.len() as u32);
+                for (index, copy) in copies.iter().enumerate() {
+                    let mut entry = list.reborrow().get(index as u32);
+                    entry.set_recipient(copy.recipient.as_str());
+                    set_sealed(entry.init_message(), &copy.message);
+                }
+            }
+            Packet::ContactRequest { username } => {
+                envelope.set_contact_request(username.as_str());
+            }
+            Packet::ContactRespond { username, accept } => {
+                let mut ep = envelope.init_contact_respond();
+                ep.set_username(username.as_str());
+                ep.set_accept(accept);
+            }
+            Packet::ContactRemove { username } => {
+                envelope.set_contact_remove(username.as_str());
+            }
+            Packet::ContactListRequest => {
+                envelope.set_contact_list_request(());
+            }
+            Packet::ContactList { contacts } => {
+                let mut list = envelope.init_contact_list(contacts.len() as u32);
+                for (index, contact) in contacts.iter().enumerate() {
+                    set_contact(list.reborrow().get(index as u32), contact);
+                }
+            }
+            Packet::ContactUpdate { contact } => {
+                set_contact(envelope.init_contact_update(), &contact);
+            }
+            Packet::Block { username } => {
+                envelope.set_block(username.as_str());
+            }
+            Packet::Unblock { username } => {
+                envelope.set_unblock(username.as_str());
+            }
+            Packet::BlockListRequest => {
+                envelope.set_block_list_request(());
+            }
+            Packet::BlockList { usernames } => {
+                let mut list = envelope.init_block_list(usernames.len() as u32);
+                for (index, username) in usernames.iter().enumerate() {
+                    list.set(index as u32, username.as_str());
+                }
+            }
+            Packet::TimerSet { conversation, ttl } => {
+                let mut ep = envelope.init_timer_set();
+                ep.set_conversation(conversation.as_str());
+                ep.set_ttl(ttl);
+            }
+            Packet::TimerInfo { timer } => {
+                set_timer(envelope.init_timer_info(), &timer);
+            }
+            Packet::TimerUpdate { timer } => {
+                set_timer(envelope.init_timer_update(), &timer);
+            }
+            Packet::TimerListRequest => {
+                envelope.set_timer_list_request(());
+            }
+            Packet::TimerList { timers } => {
+                let mut list = envelope.init_timer_list(timers.len() as u32);
+                for (index, timer) in timers.iter().enumerate() {
+                    set_timer(list.reborrow().get(index as u32), timer);
+                }
+            }
+            Packet::MsgHistoryRequest { username, cursor, limit } => {
+                let mut ep = envelope.init_msg_history_request();
+                ep.set_username(username.as_str());
+                ep.set_limit(limit);
+                match cursor {
+                    HistoryCursor::Latest => ep.set_latest(()),
+                    HistoryCursor::BeforeId(id) => ep.set_before_id(id.as_str()),
+                    HistoryCursor::AfterId(id) => ep.set_after_id(id.as_str()),
+                    HistoryCursor::BeforeTime(time) => ep.set_before_time(time.as_str()),
+                    HistoryCursor::AfterTime(time) => ep.set_after_time(time.as_str()),
+                }
+            }
+            Packet::MsgRangeRequest { username, from, to } => {
+                let mut ep = envelope.init_msg_range_request();
+                ep.set_username(username.as_str());
+                ep.set_from(from);
+                ep.set_to(to);
+            }
+            Packet::MsgHistory { history, more } => {
+                let mut ep = envelope.init_msg_history();
+                ep.set_more(more);
+                // initialize the message history list and build it from the history vector
+                let mut list = ep.init_messages(history.len() as u32);
+                for (index, msg) in history.iter().enumerate() {
+                    let mut entry = list.reborrow().get(index as u32);
+                    entry.set_id(msg.id.as_str());
+                    set_sealed(entry.reborrow().init_message(), &msg.message);
+                    entry.set_timestamp(msg.timestamp.as_str());
+                    entry.set_sender(msg.sender.as_str());
+                    entry.set_seq(msg.seq);
+                    entry.set_ttl(msg.ttl);
                 }
             }
             Packet::Disconnect => {
-                let mut ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                ep.set_disconnect(true);
+                envelope.set_disconnect(());
             }
             Packet::Error { should_disconnect, error } => {
-                let ep = message.init_root::<packet_capnp::big_boi_chonk::Builder>();
-                let mut err_init = ep.init_error();
-                err_init.set_error(error.as_str());
-                err_init.set_disconnect(should_disconnect);
+                let mut ep = envelope.init_error();
+                ep.set_error(error.as_str());
+                ep.set_disconnect(should_disconnect);
+            }
+        }
+        message
+    }
+
+    /// Read a packet out of a received envelope
+    fn decode(reader: message::Reader<OwnedSegments>) -> ::capnp::Result<Packet> {
+        use packet_capnp::envelope::Which;
+
+        let envelope = reader.get_root::<packet_capnp::envelope::Reader>()?;
+        if envelope.get_version() != PROTOCOL_VERSION {
+            return Err(::capnp::Error::failed(format!("Unsupported protocol version {} (expected {})",
+                envelope.get_version(), PROTOCOL_VERSION)));
+        }
+
+        let packet = match envelope.which()? {
+            Which::Ping(ep) => {
+                let ep = ep?;
+                Packet::Ping {
+                    version: ep.get_version()?.to_string(),
+                    disconnecting: ep.get_disconnecting()
+                }
+            }
+            Which::PingResponse(ep) => {
+                let ep = ep?;
+                Packet::PingResponse {
+                    valid: ep.get_valid(),
+                    accepted_version: ep.get_version()?.to_string()
+                }
+            }
+            Which::LoginRequest(ep) => {
+                let ep = ep?;
+                Packet::LoginRequest {
+                    username: ep.get_username()?.to_string(),
+                    password: ep.get_password()?.to_string(),
+                    signup: ep.get_signup(),
+                }
+            }
+            Which::LoginResponse(ep) => {
+                match ep?.which()? {
+                    packet_capnp::login_response::Valid(valid) => {
+                        Packet::LoginResponse { valid, error: None }
+                    }
+                    packet_capnp::login_response::Error(e) => {
+                        Packet::LoginResponse { valid: false, error: Some(e?.to_string()) }
+                    }
+                }
+            }
+            Which::Message(ep) => {
+                let ep = ep?;
+                let group = ep.get_group()?;
+                Packet::Message {
+                    id: ep.get_id()?.to_string(),
+                    seq: ep.get_seq(),
+                    message: get_sealed(ep.get_message()?)?,
+                    sender: ep.get_sender()?.to_string(),
+                    recipient: ep.get_recipient()?.to_string(),
+                    group: if group.is_empty() { None } else { Some(group.to_string()) },
+                    timestamp: ep.get_timestamp()?.to_string(),
+                    ttl: ep.get_ttl(),
+                }
+            }
+            Which::MessageAck(id) => {
+                Packet::MessageAck { id: id?.to_string() }
+            }
+            Which::MessageReceipt(ep) => {
+                let ep = ep?;
+                Packet::MessageReceipt {
+                    id: ep.get_id()?.to_string(),
+                    recipient: ep.get_recipient()?.to_string(),
+                    seq: ep.get_seq(),
+                    timestamp: ep.get_timestamp()?.to_string(),
+                }
+            }
+            Which::UserExistsRequest(username) => {
+                Packet::UserExistsRequest { username: username?.to_string() }
+            }
+            Which::UserOnlineRequest(username) => {
+                Packet::UserOnlineRequest { username: username?.to_string() }
+            }
+            Which::UserResponse(response) => {
+                Packet::UserResponse { response }
+            }
+            Which::IdentityKeyUpload(ep) => {
+                let ep = ep?;
+                Packet::IdentityKeyUpload { key: ep.get_key()?.to_vec(), signing_key: ep.get_signing_key()?.to_vec() }
+            }
+            Which::IdentityKeyRequest(username) => {
+                Packet::IdentityKeyRequest { username: username?.to_string() }
+            }
+            Which::IdentityKeyResponse(ep) => {
+                let ep = ep?;
+                let key = ep.get_key()?;
+                Packet::IdentityKeyResponse {
+                    username: ep.get_username()?.to_string(),
+                    key: if key.is_empty() { None } else { Some(key.to_vec()) },
+                }
+            }
+            Which::SignedPreKeyUpload(ep) => {
+                let ep = ep?;
+                Packet::SignedPreKeyUpload { prekey: get_prekey(ep.get_pre_key()?)?, signature: ep.get_signature()?.to_vec() }
+            }
+            Which::PreKeysUpload(list) => {
+                let mut prekeys = Vec::new();
+                for prekey in list?.iter() {
+                    prekeys.push(get_prekey(prekey)?);
+                }
+                Packet::PreKeysUpload { prekeys }
+            }
+            Which::PreKeyBundleRequest(username) => {
+                Packet::PreKeyBundleRequest { username: username?.to_string() }
+            }
+            Which::PreKeyBundle(ep) => {
+                let ep = ep?;
+                let identity_key = ep.get_identity_key()?;
+                let bundle = if identity_key.is_empty() {
+                    None
+                } else {
+                    let signed = ep.get_signed_pre_key()?;
+                    let one_time = get_prekey(ep.get_one_time_pre_key()?)?;
+                    Some(PreKeyBundle {
+                        identity_key: identity_key.to_vec(),
+                        signing_key: ep.get_signing_key()?.to_vec(),
+                        signed_prekey: get_prekey(signed.get_pre_key()?)?,
+                        signature: signed.get_signature()?.to_vec(),
+                        one_time_prekey: if one_time.key.is_empty() { None } else { Some(one_time) },
+                    })
+                };
+                Packet::PreKeyBundleResponse { username: ep.get_username()?.to_string(), bundle }
+            }
+            Which::PreKeysLow(ep) => {
+                Packet::PreKeysLow { remaining: ep?.get_remaining() }
+            }
+            Which::GroupCreate(ep) => {
+                let group = get_group(ep?)?;
+                Packet::GroupCreate { name: group.name, members: group.members }
+            }
+            Which::GroupInvite(ep) => {
+                let ep = ep?;
+                Packet::GroupInvite { group: ep.get_group()?.to_string(), username: ep.get_username()?.to_string() }
+            }
+            Which::GroupRemove(ep) => {
+                let ep = ep?;
+                Packet::GroupRemove { group: ep.get_group()?.to_string(), username: ep.get_username()?.to_string() }
+            }
+            Which::GroupLeave(group) => {
+                Packet::GroupLeave { group: group?.to_string() }
+            }
+            Which::GroupRename(ep) => {
+                let ep = ep?;
+                Packet::GroupRename { group: ep.get_group()?.to_string(), name: ep.get_name()?.to_string() }
+            }
+            Which::GroupInfo(ep) => {
+                Packet::GroupInfo { group: get_group(ep?)? }
+            }
+            Which::GroupUpdate(ep) => {
+                Packet::GroupUpdate { group: get_group(ep?)? }
+            }
+            Which::GroupListRequest(()) => Packet::GroupListRequest,
+            Which::GroupList(list) => {
+                let mut groups = Vec::new();
+                for group in list?.iter() {
+                    groups.push(get_group(group)?);
+                }
+                Packet::GroupList { groups }
+            }
+            Which::GroupMessage(ep) => {
+                let ep = ep?;
+                let mut copies = Vec::new();
+                for copy in ep.get_copies()?.iter() {
+                    copies.push(SealedCopy { recipient: copy.get_recipient()?.to_string(), message: get_sealed(copy.get_message()?)? });
+                }
+                Packet::GroupMessage { group: ep.get_group()?.to_string(), copies }
+            }
+            Which::SpaceCreate(name) => {
+                Packet::SpaceCreate { name: name?.to_string() }
+            }
+            Which::SpaceInvite(ep) => {
+                let (space, username) = get_space_target(ep?)?;
+                Packet::SpaceInvite { space, username }
+            }
+            Which::SpaceLeave(space) => {
+                Packet::SpaceLeave { space: space?.to_string() }
+            }
+            Which::SpaceKick(ep) => {
+                let (space, username) = get_space_target(ep?)?;
+                Packet::SpaceKick { space, username }
+            }
+            Which::SpaceBan(ep) => {
+                let ep = ep?;
+                Packet::SpaceBan { space: ep.get_space()?.to_string(), username: ep.get_username()?.to_string(), banned: ep.get_banned() }
+            }
+            Which::ChannelCreate(ep) => {
+                let (space, name) = get_space_target(ep?)?;
+                Packet::ChannelCreate { space, name }
+            }
+            Which::ChannelDelete(ep) => {
+                let (space, channel) = get_space_target(ep?)?;
+                Packet::ChannelDelete { space, channel }
+            }
+            Which::RoleSet(ep) => {
+                let ep = ep?;
+                Packet::RoleSet { space: ep.get_space()?.to_string(), role: get_role(ep.get_role()?)? }
+            }
+            Which::RoleDelete(ep) => {
+                let (space, role) = get_space_target(ep?)?;
+                Packet::RoleDelete { space, role }
             }
+            Which::RoleAssign(ep) => {
+                let ep = ep?;
+                Packet::RoleAssign {
+                    space: ep.get_space()?.to_string(),
+                    role: ep.get_role()?.to_string(),
+                    username: ep.get_username()?.to_string(),
+                    assigned: ep.get_assigned(),
+                }
+            }
+            Which::SpaceInfo(ep) => {
+                Packet::SpaceInfo { space: get_space(ep?)? }
+            }
+            Which::SpaceUpdate(ep) => {
+                Packet::SpaceUpdate { space: get_space(ep?)? }
+            }
+            Which::SpaceListRequest(()) => Packet::SpaceListRequest,
+            Which::SpaceList(list) => {
+                let mut spaces = Vec::new();
+                for space in list?.iter() {
+                    spaces.push(get_space(space)?);
+                }
+                Packet::SpaceList { spaces }
+            }
+            Which::ChannelMessage(ep) => {
+                let ep = ep?;
+                let mut copies = Vec::new();
+                for copy in ep.get_copies()?.iter() {
+                    copies.push(SealedCopy { recipient: copy.get_recipient()?.to_string(), message: get_sealed(copy.get_message()?)? });
+                }
+                Packet::ChannelMessage { channel: ep.get_channel()?.to_string(), copies }
+            }
+            Which::ContactRequest(username) => {
+                Packet::ContactRequest { username: username?.to_string() }
+            }
+            Which::ContactRespond(ep) => {
+                let ep = ep?;
+                Packet::ContactRespond { username: ep.get_username()?.to_string(), accept: ep.get_accept() }
+            }
+            Which::ContactRemove(username) => {
+                Packet::ContactRemove { username: username?.to_string() }
+            }
+            Which::ContactListRequest(()) => Packet::ContactListRequest,
+            Which::ContactList(list) => {
+                let mut contacts = Vec::new();
+                for contact in list?.iter() {
+                    contacts.push(get_contact(contact)?);
+                }
+                Packet::ContactList { contacts }
+            }
+            Which::ContactUpdate(ep) => {
+                Packet::ContactUpdate { contact: get_contact(ep?)? }
+            }
+            Which::Block(username) => {
+                Packet::Block { username: username?.to_string() }
+            }
+            Which::Unblock(username) => {
+                Packet::Unblock { username: username?.to_string() }
+            }
+            Which::BlockListRequest(()) => Packet::BlockListRequest,
+            Which::BlockList(list) => {
+                let mut usernames = Vec::new();
+                for username in list?.iter() {
+                    usernames.push(username?.to_string());
+                }
+                Packet::BlockList { usernames }
+            }
+            Which::TimerSet(ep) => {
+                let ep = ep?;
+                Packet::TimerSet { conversation: ep.get_conversation()?.to_string(), ttl: ep.get_ttl() }
+            }
+            Which::TimerInfo(ep) => {
+                Packet::TimerInfo { timer: get_timer(ep?)? }
+            }
+            Which::TimerUpdate(ep) => {
+                Packet::TimerUpdate { timer: get_timer(ep?)? }
+            }
+            Which::TimerListRequest(()) => Packet::TimerListRequest,
+            Which::TimerList(list) => {
+                let mut timers = Vec::new();
+                for timer in list?.iter() {
+                    timers.push(get_timer(timer)?);
+                }
+                Packet::TimerList { timers }
+            }
+            Which::MsgHistoryRequest(ep) => {
+                use packet_capnp::history_request::Which as Cursor;
+
+                let ep = ep?;
+                let cursor = match ep.which()? {
+                    Cursor::Latest(()) => HistoryCursor::Latest,
+                    Cursor::BeforeId(id) => HistoryCursor::BeforeId(id?.to_string()),
+                    Cursor::AfterId(id) => HistoryCursor::AfterId(id?.to_string()),
+                    Cursor::BeforeTime(time) => HistoryCursor::BeforeTime(time?.to_string()),
+                    Cursor::AfterTime(time) => HistoryCursor::AfterTime(time?.to_string()),
+                };
+                Packet::MsgHistoryRequest { username: ep.get_username()?.to_string(), cursor, limit: ep.get_limit() }
+            }
+            Which::MsgRangeRequest(ep) => {
+                let ep = ep?;
+                Packet::MsgRangeRequest { username: ep.get_username()?.to_string(), from: ep.get_from(), to: ep.get_to() }
+            }
+            Which::MsgHistory(ep) => {
+                let ep = ep?;
+                let mut history = Vec::new();
+                for msg in ep.get_messages()?.iter() {
+                    history.push(SentMsg {
+                        id: msg.get_id()?.to_string(),
+                        message: get_sealed(msg.get_message()?)?,
+                        sender: msg.get_sender()?.to_string(),
+                        timestamp: msg.get_timestamp()?.to_string(),
+                        seq: msg.get_seq(),
+                        ttl: msg.get_ttl(),
+                    });
+                }
+                Packet::MsgHistory { history, more: ep.get_more() }
+            }
+            Which::Disconnect(()) => Packet::Disconnect,
+            Which::Error(ep) => {
+                let ep = ep?;
+                Packet::Error { should_disconnect: ep.get_disconnect(), error: ep.get_error()?.to_string() }
+            }
+        };
+        Ok(packet)
+    }
+}
+
+fn set_sealed(mut builder: packet_capnp::sealed::Builder, sealed: &SealedPayload) {
+    builder.set_ephemeral(sealed.ephemeral.as_slice());
+    builder.set_nonce(sealed.nonce.as_slice());
+    builder.set_ciphertext(sealed.ciphertext.as_slice());
+    builder.set_header(sealed.header.as_slice());
+}
+
+fn get_sealed(reader: packet_capnp::sealed::Reader) -> ::capnp::Result<SealedPayload> {
+    Ok(SealedPayload {
+        ephemeral: reader.get_ephemeral()?.to_vec(),
+        nonce: reader.get_nonce()?.to_vec(),
+        ciphertext: reader.get_ciphertext()?.to_vec(),
+        header: reader.get_header()?.to_vec(),
+    })
+}
+
+fn set_prekey(mut builder: packet_capnp::pre_key::Builder, prekey: &PublicPreKey) {
+    builder.set_id(prekey.id);
+    builder.set_key(prekey.key.as_slice());
+}
+
+fn get_prekey(reader: packet_capnp::pre_key::Reader) -> ::capnp::Result<PublicPreKey> {
+    Ok(PublicPreKey { id: reader.get_id(), key: reader.get_key()?.to_vec() })
+}
+
+fn set_group(mut builder: packet_capnp::group::Builder, group: &Group) {
+    builder.set_id(group.id.as_str());
+    builder.set_name(group.name.as_str());
+    let mut members = builder.init_members(group.members.len() as u32);
+    for (index, member) in group.members.iter().enumerate() {
+        members.set(index as u32, member.as_str());
+    }
+}
+
+fn get_group(reader: packet_capnp::group::Reader) -> ::capnp::Result<Group> {
+    let mut members = Vec::new();
+    for member in reader.get_members()?.iter() {
+        members.push(member?.to_string());
+    }
+    Ok(Group { id: reader.get_id()?.to_string(), name: reader.get_name()?.to_string(), members })
+}
+
+fn set_space_target(mut builder: packet_capnp::space_target::Builder, space: &str, target: &str) {
+    builder.set_space(space);
+    builder.set_target(target);
+}
+
+fn get_space_target(reader: packet_capnp::space_target::Reader) -> ::capnp::Result<(String, String)> {
+    Ok((reader.get_space()?.to_string(), reader.get_target()?.to_string()))
+}
+
+fn set_role(mut builder: packet_capnp::role::Builder, role: &Role) {
+    builder.set_id(role.id.as_str());
+    builder.set_name(role.name.as_str());
+    builder.set_permissions(role.permissions);
+}
+
+fn get_role(reader: packet_capnp::role::Reader) -> ::capnp::Result<Role> {
+    Ok(Role { id: reader.get_id()?.to_string(), name: reader.get_name()?.to_string(), permissions: reader.get_permissions() })
+}
+
+fn set_space(mut builder: packet_capnp::space::Builder, space: &Space) {
+    builder.set_id(space.id.as_str());
+    builder.set_name(space.name.as_str());
+    builder.set_owner(space.owner.as_str());
+    let mut channels = builder.reborrow().init_channels(space.channels.len() as u32);
+    for (index, channel) in space.channels.iter().enumerate() {
+        let mut entry = channels.reborrow().get(index as u32);
+        entry.set_id(channel.id.as_str());
+        entry.set_name(channel.name.as_str());
+    }
+    let mut roles = builder.reborrow().init_roles(space.roles.len() as u32);
+    for (index, role) in space.roles.iter().enumerate() {
+        set_role(roles.reborrow().get(index as u32), role);
+    }
+    let mut members = builder.reborrow().init_members(space.members.len() as u32);
+    for (index, member) in space.members.iter().enumerate() {
+        let mut entry = members.reborrow().get(index as u32);
+        entry.set_username(member.username.as_str());
+        let mut member_roles = entry.init_roles(member.roles.len() as u32);
+        for (index, role) in member.roles.iter().enumerate() {
+            member_roles.set(index as u32, role.as_str());
         }
-        serialize::write_message(&mut self.stream, &message)
+    }
+    let mut banned = builder.init_banned(space.banned.len() as u32);
+    for (index, username) in space.banned.iter().enumerate() {
+        banned.set(index as u32, username.as_str());
+    }
+}
+
+fn get_space(reader: packet_capnp::space::Reader) -> ::capnp::Result<Space> {
+    let mut channels = Vec::new();
+    for channel in reader.get_channels()?.iter() {
+        channels.push(Channel { id: channel.get_id()?.to_string(), name: channel.get_name()?.to_string() });
+    }
+    let mut roles = Vec::new();
+    for role in reader.get_roles()?.iter() {
+        roles.push(get_role(role)?);
+    }
+    let mut members = Vec::new();
+    for member in reader.get_members()?.iter() {
+        let mut member_roles = Vec::new();
+        for role in member.get_roles()?.iter() {
+            member_roles.push(role?.to_string());
+        }
+        members.push(SpaceMember { username: member.get_username()?.to_string(), roles: member_roles });
+    }
+    let mut banned = Vec::new();
+    for username in reader.get_banned()?.iter() {
+        banned.push(username?.to_string());
+    }
+    Ok(Space {
+        id: reader.get_id()?.to_string(),
+        name: reader.get_name()?.to_string(),
+        owner: reader.get_owner()?.to_string(),
+        channels,
+        roles,
+        members,
+        banned,
+    })
+}
+
+fn set_contact(mut builder: packet_capnp::contact::Builder, contact: &Contact) {
+    builder.set_username(contact.username.as_str());
+    builder.set_state(match contact.state {
+        ContactState::None => packet_capnp::ContactState::None,
+        ContactState::Incoming => packet_capnp::ContactState::Incoming,
+        ContactState::Outgoing => packet_capnp::ContactState::Outgoing,
+        ContactState::Accepted => packet_capnp::ContactState::Accepted,
+    });
+}
+
+fn get_contact(reader: packet_capnp::contact::Reader) -> ::capnp::Result<Contact> {
+    let state = match reader.get_state()? {
+        packet_capnp::ContactState::None => ContactState::None,
+        packet_capnp::ContactState::Incoming => ContactState::Incoming,
+        packet_capnp::ContactState::Outgoing => ContactState::Outgoing,
+        packet_capnp::ContactState::Accepted => ContactState::Accepted,
+    };
+    Ok(Contact { username: reader.get_username()?.to_string(), state })
+}
+
+fn set_timer(mut builder: packet_capnp::timer::Builder, timer: &Timer) {
+    builder.set_conversation(timer.conversation.as_str());
+    builder.set_ttl(timer.ttl);
+    builder.set_set_by(timer.set_by.as_str());
+}
+
+fn get_timer(reader: packet_capnp::timer::Reader) -> ::capnp::Result<Timer> {
+    Ok(Timer {
+        conversation: reader.get_conversation()?.to_string(),
+        ttl: reader.get_ttl(),
+        set_by: reader.get_set_by()?.to_string(),
+    })
+}
+
+/// Sends and receives packets over any transport, TCP unless told otherwise
+pub struct Connection<T: Transport = TcpStream> {
+    stream: T,
+    // shared by every clone, so packets sent from different threads never interleave on the stream
+    writer: Arc<Mutex<()>>,
+}
+
+impl<T: Transport> Connection<T> {
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn try_clone(&mut self) -> io::Result<Connection<T>> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+            writer: Arc::clone(&self.writer),
+        })
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), String> {
+        if self.send(Packet::Disconnect).is_err() {
+            return Err(format!("Failed to send disconnect packet: already disconnected!"));
+        }
+        Ok(())
+    }
+
+    /// Send a packet across the stream
+    /// the packet is encoded first and written all at once, capnp writes a message in several parts
+    pub fn send(&mut self, packet: Packet) -> ::capnp::Result<()> {
+        let mut frame = Vec::new();
+        write_packet(&mut frame, packet)?;
+        let Ok(_writer) = self.writer.lock() else {
+            return Err(::capnp::Error::failed(format!("Another thread panicked while sending on the connection")));
+        };
+        self.stream.write_all(&frame)?;
+        Ok(())
     }
 
     // sends an error if invalid data was received and handles if it was because of a disconnection
@@ -193,176 +1256,444 @@ impl Connection {
         Ok(())
     }
 
-    /// Expect a specific packet and read its data
-    /// @param expected: the packet type to expect
+    /// Wait for the next packet, whatever it is, and read its data
     /// @return: Ok(..): the packet that was read, Err(..): An error message
-    pub fn expect(&mut self, expected: ExpectedPacket) -> Result<Packet, String> {
-        let msg_reader_raw = serialize::read_message(&mut self.stream, ::capnp::message::ReaderOptions::default());
-        if msg_reader_raw.is_err() {
-            self.send_invalid_data_error()?;
-            return Err(format!("Invalid or corrupt data was received!"));
+    pub fn recv(&mut self) -> Result<Packet, String> {
+        match read_packet(&mut self.stream) {
+            Ok(Some(packet)) => Ok(packet),
+            Ok(None) => Err(format!("The connection was closed.")),
+            Err(e) => {
+                self.send_invalid_data_error()?;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Write a packet to any byte stream
+pub(crate) fn write_packet<W: Write>(stream: W, packet: Packet) -> ::capnp::Result<()> {
+    serialize::write_message(stream, &packet.encode())
+}
+
+/// Read the next packet from any byte stream
+/// @return: Ok(Some(..)): the packet that was read, Ok(None): the stream was closed before a packet started,
+///          Err(..): the data was invalid or corrupt
+pub(crate) fn read_packet<R: Read>(stream: R) -> Result<Option<Packet>, String> {
+    let msg_reader = match serialize::try_read_message(stream, ::capnp::message::ReaderOptions::default()) {
+        Ok(Some(reader)) => reader,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(format!("Invalid or corrupt data was received: {}", e)),
+    };
+
+    match Packet::decode(msg_reader) {
+        Ok(packet) => Ok(Some(packet)),
+        Err(e) => Err(format!("Invalid data received: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // send a packet into an in-memory buffer and read it back out
+    fn round_trip(packet: Packet) -> Packet {
+        let mut stream = Vec::new();
+        write_packet(&mut stream, packet).expect("failed to write packet");
+        let mut reader = stream.as_slice();
+        let received = read_packet(&mut reader).expect("failed to read packet").expect("stream was empty");
+        assert!(reader.is_empty(), "packet was not fully read");
+        received
+    }
+
+    fn assert_round_trip(packet: Packet) {
+        assert_eq!(round_trip(packet.clone()), packet);
+    }
+
+    fn encoded(packet: Packet) -> Vec<u8> {
+        let mut stream = Vec::new();
+        write_packet(&mut stream, packet).unwrap();
+        stream
+    }
+
+    #[test]
+    fn addresses_are_validated() {
+        assert!(validate_ip("127.0.0.1").is_ok());
+        assert!(validate_ip("localhost").is_ok());
+        assert!(validate_ip("chat.example.com").is_ok());
+        assert!(validate_ip("[::1]").is_ok());
+        assert!(validate_ip("[2001:db8::1]").is_ok());
+        assert!(validate_ip("::1").is_err());
+        assert!(validate_ip("[::g]").is_err());
+        assert!(validate_ip("256.0.0.1").is_err());
+        assert!(validate_ip("1.2.3").is_err());
+        assert!(validate_ip("-chat.example.com").is_err());
+        assert!(validate_ip("chat..example.com").is_err());
+        assert!(validate_ip("").is_err());
+
+        assert!(validate_port("2277").is_ok());
+        assert!(validate_port("65535").is_ok());
+        assert!(validate_port("65536").is_err());
+        assert!(validate_port("0").is_err());
+        assert!(validate_port("port").is_err());
+        assert!(validate_port("").is_err());
+    }
+
+    #[test]
+    fn addresses_are_resolved() {
+        assert_eq!(resolve_address("127.0.0.1", "2277"), Ok(vec![SocketAddr::from(([127, 0, 0, 1], 2277))]));
+        assert_eq!(resolve_address("[::1]", "2277"), Ok(vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 2277))]));
+        assert!(resolve_address("::1", "2277").is_err());
+        assert!(resolve_address("127.0.0.1", "0").is_err());
+    }
+
+    #[test]
+    fn ping() {
+        assert_round_trip(Packet::Ping { version: format!("0.1.1"), disconnecting: false });
+        assert_round_trip(Packet::Ping { version: format!("0.1.1"), disconnecting: true });
+    }
+
+    #[test]
+    fn ping_response() {
+        assert_round_trip(Packet::PingResponse { valid: true, accepted_version: format!("0.1.1") });
+        assert_round_trip(Packet::PingResponse { valid: false, accepted_version: format!("0.2.0") });
+    }
+
+    #[test]
+    fn login_request() {
+        assert_round_trip(Packet::LoginRequest { username: format!("skepz"), password: format!("hunter2"), signup: false });
+        assert_round_trip(Packet::LoginRequest { username: format!("test"), password: format!("p4ss"), signup: true });
+    }
+
+    #[test]
+    fn login_response() {
+        assert_round_trip(Packet::LoginResponse { valid: true, error: None });
+        assert_round_trip(Packet::LoginResponse { valid: false, error: None });
+        assert_round_trip(Packet::LoginResponse { valid: false, error: Some(format!("Invalid login credentials")) });
+    }
+
+    #[test]
+    fn message() {
+        assert_round_trip(Packet::Message {
+            id: format!("6f1c1f4e-8d1a-4c1e-9a51-0c2b6f0d9a11"),
+            seq: 42,
+            message: SealedPayload { ephemeral: vec![1; 32], nonce: vec![2; 12], ciphertext: vec![3; 40], header: vec![4; 9] },
+            sender: format!("skepz"),
+            recipient: format!("test"),
+            group: None,
+            timestamp: format!("2023-01-01 00:00:00 UTC"),
+            ttl: 0,
+        });
+        assert_round_trip(Packet::Message {
+            id: format!(""),
+            seq: 0,
+            message: SealedPayload::plaintext(""),
+            sender: format!(""),
+            recipient: format!(""),
+            group: Some(format!("0b7d4c9e-2f1a-4e8b-9c3d-5a6f7e8d9c0b")),
+            timestamp: format!(""),
+            ttl: 30,
+        });
+    }
+
+    #[test]
+    fn message_ack() {
+        assert_round_trip(Packet::MessageAck { id: format!("6f1c1f4e-8d1a-4c1e-9a51-0c2b6f0d9a11") });
+    }
+
+    #[test]
+    fn message_receipt() {
+        assert_round_trip(Packet::MessageReceipt {
+            id: format!("6f1c1f4e-8d1a-4c1e-9a51-0c2b6f0d9a11"),
+            recipient: format!("test"),
+            seq: u64::MAX,
+            timestamp: format!("2023-01-01 00:00:00 UTC"),
+        });
+    }
+
+    #[test]
+    fn info_requests() {
+        assert_round_trip(Packet::UserExistsRequest { username: format!("skepz") });
+        assert_round_trip(Packet::UserOnlineRequest { username: format!("skepz") });
+    }
+
+    #[test]
+    fn msg_history_request() {
+        let cursors = vec![
+            HistoryCursor::Latest,
+            HistoryCursor::BeforeId(format!("6f1c1f4e-8d1a-4c1e-9a51-0c2b6f0d9a11")),
+            HistoryCursor::AfterId(format!("6f1c1f4e-8d1a-4c1e-9a51-0c2b6f0d9a11")),
+            HistoryCursor::BeforeTime(format!("2023-01-01 00:00:00 UTC")),
+            HistoryCursor::AfterTime(format!("2023-01-01 00:00:00 UTC")),
+        ];
+        for cursor in cursors {
+            assert_round_trip(Packet::MsgHistoryRequest { username: format!("skepz"), cursor, limit: 25 });
         }
-        let msg_reader = msg_reader_raw.unwrap();
+    }
 
-        self.parse_received(expected, msg_reader)
+    #[test]
+    fn groups() {
+        let group = Group {
+            id: format!("0b7d4c9e-2f1a-4e8b-9c3d-5a6f7e8d9c0b"),
+            name: format!("the group"),
+            members: vec![format!("skepz"), format!("test")],
+        };
+        let id = group.id.clone();
+        assert_round_trip(Packet::GroupCreate { name: format!("the group"), members: vec![format!("test")] });
+        assert_round_trip(Packet::GroupCreate { name: format!(""), members: Vec::new() });
+        assert_round_trip(Packet::GroupInvite { group: id.clone(), username: format!("bob") });
+        assert_round_trip(Packet::GroupRemove { group: id.clone(), username: format!("bob") });
+        assert_round_trip(Packet::GroupLeave { group: id.clone() });
+        assert_round_trip(Packet::GroupRename { group: id.clone(), name: format!("renamed") });
+        assert_round_trip(Packet::GroupInfo { group: group.clone() });
+        assert_round_trip(Packet::GroupUpdate { group: Group { members: Vec::new(), ..group.clone() } });
+        assert_round_trip(Packet::GroupListRequest);
+        assert_round_trip(Packet::GroupList { groups: Vec::new() });
+        assert_round_trip(Packet::GroupList { groups: vec![group.clone(), group] });
     }
 
-    /// Check if there is a packet to read of an expected type
-    /// returns None if there is nothing to read, allowing the program to do other things
-    pub fn check_expected(&mut self, expected: ExpectedPacket) -> Result<Option<Packet>, String> {
-        let msg_reader_raw = serialize::try_read_message(&mut self.stream, ::capnp::message::ReaderOptions::default());
-        if msg_reader_raw.is_err() {
-            self.send_invalid_data_error()?;
-            return Err(format!("Invalid or corrupt data was received!"));
+    #[test]
+    fn group_message() {
+        let copy = |recipient: &str| SealedCopy {
+            recipient: recipient.to_string(),
+            message: SealedPayload { ephemeral: vec![1; 32], nonce: vec![2; 12], ciphertext: vec![3; 40], header: vec![4; 9] },
+        };
+        let group = format!("0b7d4c9e-2f1a-4e8b-9c3d-5a6f7e8d9c0b");
+        assert_round_trip(Packet::GroupMessage { group: group.clone(), copies: vec![copy("test"), copy("bob")] });
+        assert_round_trip(Packet::GroupMessage { group, copies: Vec::new() });
+    }
+
+    fn space() -> Space {
+        let id = format!("5e1f0c2a-7b3d-4c9e-8a6f-1d2e3f4a5b6c");
+        Space {
+            id: id.clone(),
+            name: format!("the space"),
+            owner: format!("skepz"),
+            channels: vec![Channel { id: format!("9c8b7a6f-5e4d-4c3b-8a29-18f7e6d5c4b3"), name: format!("general") }],
+            roles: vec![
+                Role { id: id.clone(), name: format!("everyone"), permissions: permissions::SEND },
+                Role { id: format!("2a3b4c5d-6e7f-4a8b-9c0d-1e2f3a4b5c6d"), name: format!("mods"), permissions: permissions::KICK | permissions::BAN },
+            ],
+            members: vec![
+                SpaceMember { username: format!("skepz"), roles: Vec::new() },
+                SpaceMember { username: format!("test"), roles: vec![format!("2a3b4c5d-6e7f-4a8b-9c0d-1e2f3a4b5c6d")] },
+                SpaceMember { username: format!("bob"), roles: Vec::new() },
+            ],
+            banned: vec![format!("eve")],
         }
-        let msg_reader = msg_reader_raw.unwrap();
+    }
+
+    #[test]
+    fn spaces() {
+        let space = space();
+        let id = space.id.clone();
+        let role = space.roles[1].clone();
+        assert_round_trip(Packet::SpaceCreate { name: format!("the space") });
+        assert_round_trip(Packet::SpaceInvite { space: id.clone(), username: format!("bob") });
+        assert_round_trip(Packet::SpaceLeave { space: id.clone() });
+        assert_round_trip(Packet::SpaceKick { space: id.clone(), username: format!("bob") });
+        assert_round_trip(Packet::SpaceBan { space: id.clone(), username: format!("eve"), banned: true });
+        assert_round_trip(Packet::SpaceBan { space: id.clone(), username: format!("eve"), banned: false });
+        assert_round_trip(Packet::ChannelCreate { space: id.clone(), name: format!("memes") });
+        assert_round_trip(Packet::ChannelDelete { space: id.clone(), channel: space.channels[0].id.clone() });
+        assert_round_trip(Packet::RoleSet { space: id.clone(), role: role.clone() });
+        assert_round_trip(Packet::RoleSet { space: id.clone(), role: Role { id: String::new(), ..role.clone() } });
+        assert_round_trip(Packet::RoleDelete { space: id.clone(), role: role.id.clone() });
+        assert_round_trip(Packet::RoleAssign { space: id.clone(), role: role.id.clone(), username: format!("bob"), assigned: true });
+        assert_round_trip(Packet::RoleAssign { space: id, role: role.id, username: format!("bob"), assigned: false });
+        assert_round_trip(Packet::SpaceInfo { space: space.clone() });
+        assert_round_trip(Packet::SpaceUpdate { space: Space { members: Vec::new(), banned: Vec::new(), ..space.clone() } });
+        assert_round_trip(Packet::SpaceListRequest);
+        assert_round_trip(Packet::SpaceList { spaces: Vec::new() });
+        assert_round_trip(Packet::SpaceList { spaces: vec![space.clone(), space] });
+    }
+
+    #[test]
+    fn channel_message() {
+        let copy = SealedCopy {
+            recipient: format!("test"),
+            message: SealedPayload { ephemeral: vec![1; 32], nonce: vec![2; 12], ciphertext: vec![3; 40], header: vec![4; 9] },
+        };
+        assert_round_trip(Packet::ChannelMessage { channel: format!("9c8b7a6f-5e4d-4c3b-8a29-18f7e6d5c4b3"), copies: vec![copy] });
+    }
+
+    #[test]
+    fn contacts() {
+        let contact = |username: &str, state| Contact { username: username.to_string(), state };
+        assert_round_trip(Packet::ContactRequest { username: format!("test") });
+        assert_round_trip(Packet::ContactRespond { username: format!("test"), accept: true });
+        assert_round_trip(Packet::ContactRespond { username: format!("test"), accept: false });
+        assert_round_trip(Packet::ContactRemove { username: format!("test") });
+        assert_round_trip(Packet::ContactListRequest);
+        assert_round_trip(Packet::ContactList { contacts: Vec::new() });
+        assert_round_trip(Packet::ContactList { contacts: vec![
+            contact("alice", ContactState::Accepted),
+            contact("bob", ContactState::Incoming),
+            contact("carol", ContactState::Outgoing),
+        ] });
+        assert_round_trip(Packet::ContactUpdate { contact: contact("test", ContactState::None) });
+    }
+
+    #[test]
+    fn blocks() {
+        assert_round_trip(Packet::Block { username: format!("test") });
+        assert_round_trip(Packet::Unblock { username: format!("test") });
+        assert_round_trip(Packet::BlockListRequest);
+        assert_round_trip(Packet::BlockList { usernames: Vec::new() });
+        assert_round_trip(Packet::BlockList { usernames: vec![format!("alice"), format!("bob")] });
+    }
+
+    #[test]
+    fn timers() {
+        let timer = |conversation: &str, ttl| Timer { conversation: conversation.to_string(), ttl, set_by: format!("alice") };
+        assert_round_trip(Packet::TimerSet { conversation: format!("bob"), ttl: 3600 });
+        assert_round_trip(Packet::TimerSet { conversation: format!("bob"), ttl: 0 });
+        assert_round_trip(Packet::TimerInfo { timer: timer("bob", 3600) });
+        assert_round_trip(Packet::TimerUpdate { timer: timer("9c8b7a6f-5e4d-4c3b-8a29-18f7e6d5c4b3", MAX_MESSAGE_TTL) });
+        assert_round_trip(Packet::TimerListRequest);
+        assert_round_trip(Packet::TimerList { timers: Vec::new() });
+        assert_round_trip(Packet::TimerList { timers: vec![timer("bob", 60), timer("carol", 0)] });
+    }
+
+    #[test]
+    fn space_permissions() {
+        let space = space();
+        assert_eq!(space.permissions("skepz"), permissions::ALL);
+        assert_eq!(space.permissions("test"), permissions::SEND | permissions::KICK | permissions::BAN);
+        assert_eq!(space.permissions("bob"), permissions::SEND);
+        // banned users and strangers are not members
+        assert_eq!(space.permissions("eve"), 0);
+        assert_eq!(space.permissions("nobody"), 0);
+    }
+
+    #[test]
+    fn msg_range_request() {
+        assert_round_trip(Packet::MsgRangeRequest { username: format!("skepz"), from: 3, to: 7 });
+    }
+
+    #[test]
+    fn identity_keys() {
+        assert_round_trip(Packet::IdentityKeyUpload { key: vec![7; 32], signing_key: vec![8; 32] });
+        assert_round_trip(Packet::IdentityKeyRequest { username: format!("skepz") });
+        assert_round_trip(Packet::IdentityKeyResponse { username: format!("skepz"), key: Some(vec![7; 32]) });
+        assert_round_trip(Packet::IdentityKeyResponse { username: format!("skepz"), key: None });
+    }
+
+    #[test]
+    fn prekeys() {
+        let prekey = |id| PublicPreKey { id, key: vec![id as u8; 32] };
+        assert_round_trip(Packet::SignedPreKeyUpload { prekey: prekey(1), signature: vec![9; 64] });
+        assert_round_trip(Packet::PreKeysUpload { prekeys: Vec::new() });
+        assert_round_trip(Packet::PreKeysUpload { prekeys: vec![prekey(2), prekey(3), prekey(4)] });
+        assert_round_trip(Packet::PreKeyBundleRequest { username: format!("skepz") });
+        assert_round_trip(Packet::PreKeysLow { remaining: 3 });
+    }
+
+    #[test]
+    fn prekey_bundle() {
+        let bundle = PreKeyBundle {
+            identity_key: vec![7; 32],
+            signing_key: vec![8; 32],
+            signed_prekey: PublicPreKey { id: 1, key: vec![1; 32] },
+            signature: vec![9; 64],
+            one_time_prekey: Some(PublicPreKey { id: 2, key: vec![2; 32] }),
+        };
+        assert_round_trip(Packet::PreKeyBundleResponse { username: format!("skepz"), bundle: Some(bundle.clone()) });
+        assert_round_trip(Packet::PreKeyBundleResponse { username: format!("skepz"), bundle: Some(PreKeyBundle { one_time_prekey: None, ..bundle }) });
+        assert_round_trip(Packet::PreKeyBundleResponse { username: format!("skepz"), bundle: None });
+    }
+
+    #[test]
+    fn user_response() {
+        assert_round_trip(Packet::UserResponse { response: true });
+        assert_round_trip(Packet::UserResponse { response: false });
+    }
 
-        if msg_reader.is_none() {
-            return Ok(None);
+    #[test]
+    fn msg_history() {
+        assert_round_trip(Packet::MsgHistory { history: Vec::new(), more: false });
+        assert_round_trip(Packet::MsgHistory { history: vec![
+            SentMsg { id: format!("a"), message: SealedPayload::plaintext("first"), sender: format!("skepz"), timestamp: format!("1"), seq: 1, ttl: 0 },
+            SentMsg { id: format!("b"), message: SealedPayload { ephemeral: vec![1; 32], nonce: vec![2; 12], ciphertext: vec![3; 20], header: vec![4; 9] }, sender: format!("test"), timestamp: format!("2"), seq: 2, ttl: 3600 },
+        ], more: true });
+    }
+
+    #[test]
+    fn disconnect() {
+        assert_round_trip(Packet::Disconnect);
+    }
+
+    #[test]
+    fn error() {
+        assert_round_trip(Packet::Error { should_disconnect: true, error: format!("Invalid data received!") });
+        assert_round_trip(Packet::Error { should_disconnect: false, error: format!("Invalid recipient") });
+    }
+
+    #[test]
+    fn several_packets_on_one_stream() {
+        let packets = vec![
+            Packet::Ping { version: format!("0.1.1"), disconnecting: false },
+            Packet::UserExistsRequest { username: format!("a") },
+            Packet::MsgHistoryRequest { username: format!("b"), cursor: HistoryCursor::Latest, limit: 10 },
+            Packet::Disconnect,
+        ];
+
+        let mut stream = Vec::new();
+        for packet in packets.clone() {
+            write_packet(&mut stream, packet).unwrap();
         }
 
-        Ok(Some(self.parse_received(expected, msg_reader.unwrap())?))
+        let mut reader = stream.as_slice();
+        for packet in packets {
+            assert_eq!(read_packet(&mut reader), Ok(Some(packet)));
+        }
+        assert_eq!(read_packet(&mut reader), Ok(None));
     }
 
-    fn parse_received(&mut self, expected: ExpectedPacket, reader: message::Reader<OwnedSegments>) -> Result<Packet, String> {
-        match expected {
-            ExpectedPacket::Ping => {
-                let ep_raw = reader.get_root::<packet_capnp::ping::Reader>();
-                if let Err(_) = ep_raw {
-                    self.send_invalid_data_error()?;
-                    return Err(format!("Invalid data received! Disconnecting."));
-                }
-                let ep = ep_raw.unwrap();
+    #[test]
+    fn closed_stream() {
+        let mut reader: &[u8] = &[];
+        assert_eq!(read_packet(&mut reader), Ok(None));
+    }
 
-                Ok(Packet::Ping {
-                    version: ep.get_version().unwrap().to_string(),
-                    disconnecting: ep.get_disconnecting()
-                })
-            }
-            ExpectedPacket::PingResponse => {
-                let ep_raw = reader.get_root::<packet_capnp::ping_response::Reader>();
-                if let Err(_) = ep_raw {
-                    self.send_invalid_data_error()?;
-                    return Err(format!("Invalid data received! Disconnecting."));
-                }
-                let ep = ep_raw.unwrap();
+    #[test]
+    fn garbage_data() {
+        let mut reader: &[u8] = &[0xff; 64];
+        assert!(read_packet(&mut reader).is_err());
+    }
 
-                Ok(Packet::PingResponse {
-                    valid: ep.get_valid(),
-                    accepted_version: ep.get_version().unwrap().to_string()
-                })
-            }
-            ExpectedPacket::LoginRequest => {
-                let ep_raw = reader.get_root::<packet_capnp::login_request::Reader>();
-                if let Err(_) = ep_raw {
-                    self.send_invalid_data_error()?;
-                    return Err(format!("Invalid data received! Disconnecting."));
-                }
-                let ep = ep_raw.unwrap();
+    #[test]
+    fn truncated_packet() {
+        let stream = encoded(Packet::Message {
+            id: format!(""),
+            seq: 0,
+            message: SealedPayload::plaintext("this will be cut off"),
+            sender: format!("skepz"),
+            recipient: format!("test"),
+            group: None,
+            timestamp: format!("now"),
+            ttl: 0,
+        });
+        let mut reader = &stream[..stream.len() / 2];
+        assert!(read_packet(&mut reader).is_err());
+    }
 
-                Ok(Packet::LoginRequest {
-                    username: ep.get_username().unwrap().to_string(),
-                    password: ep.get_password().unwrap().to_string(),
-                    signup: ep.get_signup(),
-                })
-            }
-            ExpectedPacket::LoginResponse => {
-                let ep_raw = reader.get_root::<packet_capnp::login_response::Reader>();
-                if let Err(_) = ep_raw {
-                    self.send_invalid_data_error()?;
-                    return Err(format!("Invalid data received! Disconnecting."));
-                }
-                let ep = ep_raw.unwrap();
+    #[test]
+    fn wrong_protocol_version() {
+        let mut stream = encoded(Packet::Disconnect);
+        // segment table (8 bytes) + root pointer (8 bytes), then the version is the first field of the data section
+        stream[16..18].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        let mut reader = stream.as_slice();
+        let err = read_packet(&mut reader).unwrap_err();
+        assert!(err.contains("protocol version"), "unexpected error: {}", err);
+    }
 
-                return match ep.which() {
-                    Ok(packet_capnp::login_response::Valid(_)) => {
-                        Ok(Packet::LoginResponse { valid: true, error: None })
-                    }
-                    Ok(packet_capnp::login_response::Error(e)) => {
-                        Ok(Packet::LoginResponse { valid: false, error: Some(e.unwrap().to_string()) })
-                    }
-                    Err(::capnp::NotInSchema(_)) => {
-                        self.send_invalid_data_error()?;
-                        Err(format!("Invalid data received when expecting a login response! Disconnecting."))
-                    }
-                }
-            }
-            ExpectedPacket::Message => {
-                let ep_raw = reader.get_root::<packet_capnp::big_boi_chonk::Reader>();
-                if let Err(_) = ep_raw {
-                    self.send_invalid_data_error()?;
-                    return Err(format!("Invalid data received! Disconnecting."));
-                }
-                let ep = ep_raw.unwrap();
-
-                // This handles 3 "packets" in 1
-                match ep.which() {
-                    Ok(packet_capnp::big_boi_chonk::Message(mreader)) => {
-                        let mr = mreader.unwrap();
-                        Ok(Packet::Message {
-                            message: mr.get_message().unwrap().to_string(),
-                            sender: mr.get_sender().unwrap().to_string(),
-                            recipient: mr.get_recipient().unwrap().to_string(),
-                            timestamp: mr.get_timestamp().unwrap().to_string(),
-                        })
-                    }
-                    Ok(packet_capnp::big_boi_chonk::Disconnect(_)) => {
-                        Ok(Packet::Disconnect)
-                    }
-                    Ok(packet_capnp::big_boi_chonk::InfoRequest(ireader)) => {
-                        match ireader.unwrap().which() {
-                            Ok(packet_capnp::info_request::UsernameOnline(ureader)) => {
-                                let ur = ureader.unwrap();
-                                Ok(Packet::UserOnlineRequest { username: ur.to_string() })
-                            }
-                            Ok(packet_capnp::info_request::UsernameExists(ureader)) => {
-                                let ur = ureader.unwrap();
-                                Ok(Packet::UserOnlineRequest { username: ur.to_string() })
-                            }
-                            Ok(packet_capnp::info_request::MsgHistory(ureader)) => {
-                                let ur = ureader.unwrap();
-                                Ok(Packet::UserOnlineRequest { username: ur.to_string() })
-                            }
-                            Err(::capnp::NotInSchema(_)) => {
-                                self.send_invalid_data_error()?;
-                                Err(format!("Invalid data received when expecting an info request! Disconnecting."))
-                            }
-                        }
-                    }
-                    Ok(packet_capnp::big_boi_chonk::InfoResponse(ireader)) => {
-                        match ireader.unwrap().which() {
-                            Ok(packet_capnp::info_response::UserResponse(response)) => {
-                                Ok(Packet::UserResponse { response })
-                            }
-                            Ok(packet_capnp::info_response::MsgHistory(hreader)) => {
-                                let reader = hreader.unwrap();
-                                let mut history = Vec::new();
-                                for msg in reader.into_iter() {
-                                    history.push(SentMsg {
-                                        message: msg.get_message().unwrap().to_string(),
-                                        sender: msg.get_sender().unwrap().to_string(),
-                                        timestamp: msg.get_timestamp().unwrap().to_string()
-                                    });
-                                }
-
-                                Ok(Packet::MsgHistory { history })
-                            }
-                            Err(::capnp::NotInSchema(_)) => {
-                                self.send_invalid_data_error()?;
-                                Err(format!("Invalid data received when expecting an info response! Disconnecting."))
-                            }
-                        }
-                    }
-                    Ok(packet_capnp::big_boi_chonk::Error(ereader)) => {
-                        let er = ereader.unwrap();
-                        Ok(Packet::Error { should_disconnect: er.get_disconnect(), error: er.get_error().unwrap().to_string() })
-                    }
-                    Err(::capnp::NotInSchema(_)) => {
-                        self.send_invalid_data_error()?;
-                        Err(format!("Invalid data received when expecting a message! Disconnecting."))
-                    }
-                }
-            } // end of ExpectedPacket::Message
-        } // end of match expected
-    } // end of parse_received
-} // end of impl
\ No newline at end of file
+    #[test]
+    fn unknown_packet_type() {
+        let mut stream = encoded(Packet::Disconnect);
+        // the union discriminant follows the version in the data section
+        stream[18..20].copy_from_slice(&u16::MAX.to_le_bytes());
+        let mut reader = stream.as_slice();
+        let err = read_packet(&mut reader).unwrap_err();
+        assert!(err.contains("schema"), "unexpected error: {}", err);
+    }
+}
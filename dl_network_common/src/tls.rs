@@ -0,0 +1,268 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConnection, DigitallySignedStruct, RootCertStore, ServerConnection, SignatureScheme};
+pub use rustls::{ClientConfig, ServerConfig};
+use sha2::{Digest, Sha256};
+use crate::transport::Transport;
+
+// TLS records are at most 16 KiB, reading less at a time keeps decrypted data under rustls' buffer limit
+const READ_CHUNK: usize = 8 * 1024;
+
+/// A TLS session over another transport, TCP unless told otherwise
+/// clones share the session, so one thread can read while others write
+pub struct TlsStream<S: Transport = TcpStream> {
+    session: Arc<Mutex<rustls::Connection>>,
+    socket: S,
+}
+
+impl<S: Transport> TlsStream<S> {
+    /// Start a session with a server, finishing the handshake before returning
+    /// `server_name` is the name or IP the server's certificate must be issued for, unless it is pinned
+    pub fn connect(config: Arc<ClientConfig>, server_name: &str, socket: S) -> Result<Self, String> {
+        let Ok(name) = ServerName::try_from(server_name.to_string()) else {
+            return Err(format!("Invalid server name: {}", server_name));
+        };
+        let session = ClientConnection::new(config, name).map_err(|e| format!("Failed to start TLS session: {}", e))?;
+        Self::handshake(session.into(), socket)
+    }
+
+    /// Accept a session from a client, finishing the handshake before returning
+    pub fn accept(config: Arc<ServerConfig>, socket: S) -> Result<Self, String> {
+        let session = ServerConnection::new(config).map_err(|e| format!("Failed to start TLS session: {}", e))?;
+        Self::handshake(session.into(), socket)
+    }
+
+    fn handshake(mut session: rustls::Connection, mut socket: S) -> Result<Self, String> {
+        while session.is_handshaking() {
+            if let Err(e) = session.complete_io(&mut socket) {
+                return Err(format!("TLS handshake failed: {}", e));
+            }
+        }
+        Ok(Self { session: Arc::new(Mutex::new(session)), socket })
+    }
+}
+
+fn lock(session: &Mutex<rustls::Connection>) -> io::Result<MutexGuard<'_, rustls::Connection>> {
+    session.lock().map_err(|_| io::Error::other(format!("The TLS session was poisoned")))
+}
+
+impl<S: Transport> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0u8; READ_CHUNK];
+        loop {
+            match lock(&self.session)?.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            // wait for more data without holding the session, so other clones can still write
+            let read = self.socket.read(&mut incoming)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            let mut session = lock(&self.session)?;
+            let mut data = &incoming[..read];
+            while !data.is_empty() {
+                session.read_tls(&mut data)?;
+                if let Err(e) = session.process_new_packets() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+            // the session may need to answer, to key updates for example
+            while session.wants_write() {
+                session.write_tls(&mut self.socket)?;
+            }
+        }
+    }
+}
+
+impl<S: Transport> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = lock(&self.session)?;
+        let written = session.writer().write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut self.socket)?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = lock(&self.session)?;
+        session.writer().flush()?;
+        while session.wants_write() {
+            session.write_tls(&mut self.socket)?;
+        }
+        self.socket.flush()
+    }
+}
+
+impl<S: Transport> Transport for TlsStream<S> {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self { session: Arc::clone(&self.session), socket: self.socket.try_clone()? })
+    }
+}
+
+/// The SHA-256 fingerprint of a DER encoded certificate, as colon separated hex
+pub fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate).iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+// read a fingerprint written with or without colons, in either case
+fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], String> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|index| hex.get(index..index + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<Vec<u8>>>();
+    match bytes.and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()) {
+        Some(fingerprint) => Ok(fingerprint),
+        None => Err(format!("Invalid certificate fingerprint: {}", fingerprint)),
+    }
+}
+
+/// Settings for connecting to servers
+/// with a fingerprint only the certificate with that fingerprint is accepted, self-signed or not,
+/// otherwise certificates must be issued by one of the web's certificate authorities
+pub fn client_config(fingerprint: Option<&str>) -> Result<Arc<ClientConfig>, String> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Failed to set up TLS: {}", e))?;
+    let config = match fingerprint {
+        Some(fingerprint) => {
+            let verifier = PinnedCertificate { fingerprint: parse_fingerprint(fingerprint)?, provider };
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+        }
+        None => {
+            let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+/// Settings for accepting clients, with the certificate chain and private key read from PEM files
+pub fn server_config(certificate: &Path, key: &Path) -> Result<Arc<ServerConfig>, String> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read certificate {}: {}", certificate.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("Failed to read private key {}: {}", key.display(), e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(|e| format!("Failed to set up TLS: {}", e))?;
+    Ok(Arc::new(config))
+}
+
+/// The fingerprint of the first certificate in a PEM file, which clients can pin
+pub fn certificate_fingerprint(certificate: &Path) -> Result<String, String> {
+    match CertificateDer::pem_file_iter(certificate).map(|mut certificates| certificates.next()) {
+        Ok(Some(Ok(der))) => Ok(fingerprint(&der)),
+        Ok(None) => Err(format!("There is no certificate in {}", certificate.display())),
+        Ok(Some(Err(e))) | Err(e) => Err(format!("Failed to read certificate {}: {}", certificate.display(), e)),
+    }
+}
+
+// accepts only the server certificate with a known fingerprint, the handshake is still checked as usual
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>,
+                          _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() != self.fingerprint {
+            return Err(rustls::Error::General(format!("The server certificate does not match the pinned fingerprint, its fingerprint is {}", fingerprint(end_entity))));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+    use crate::{Connection, Packet};
+
+    // a server with a new self-signed certificate, and the certificate's fingerprint
+    fn server(name: &str) -> (Arc<ServerConfig>, String) {
+        let certified = rcgen::generate_simple_self_signed(vec![format!("localhost")]).unwrap();
+        let dir = std::env::temp_dir();
+        let certificate = dir.join(format!("dl_tls_test_{}_{}.pem", name, std::process::id()));
+        let key = dir.join(format!("dl_tls_test_{}_{}.key", name, std::process::id()));
+        std::fs::write(&certificate, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        let config = server_config(&certificate, &key).unwrap();
+        std::fs::remove_file(certificate).unwrap();
+        std::fs::remove_file(key).unwrap();
+        (config, fingerprint(certified.cert.der()))
+    }
+
+    // accept one client and answer its ping, returning the result of the handshake
+    fn connect(server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> Result<Packet, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let socket = listener.accept().unwrap().0;
+            let mut connection = Connection::new(TlsStream::accept(server, socket)?);
+            let ping = connection.recv()?;
+            connection.send(Packet::PingResponse { valid: true, accepted_version: format!("1") }).unwrap();
+            Ok::<Packet, String>(ping)
+        });
+
+        let result = TlsStream::connect(client, "localhost", TcpStream::connect(address).unwrap()).map(|stream| {
+            let mut connection = Connection::new(stream);
+            connection.send(Packet::Ping { version: format!("1"), disconnecting: false }).unwrap();
+            connection.recv().unwrap()
+        });
+        let _ = handle.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn pinned_certificates() {
+        let (server_config, pin) = server("pinned");
+        let reply = connect(server_config, client_config(Some(pin.as_str())).unwrap()).unwrap();
+        assert_eq!(reply, Packet::PingResponse { valid: true, accepted_version: format!("1") });
+
+        // the same names with a different certificate is refused
+        let (other, _) = server("other");
+        assert!(connect(other, client_config(Some(pin.as_str())).unwrap()).is_err());
+    }
+
+    #[test]
+    fn self_signed_needs_a_pin() {
+        let (server_config, _) = server("unpinned");
+        assert!(connect(server_config, client_config(None).unwrap()).is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        let hex = fingerprint(b"certificate");
+        assert_eq!(hex.len(), 32 * 3 - 1);
+        assert_eq!(parse_fingerprint(&hex).unwrap().as_slice(), Sha256::digest(b"certificate").as_slice());
+        assert_eq!(parse_fingerprint(&hex.replace(':', "").to_lowercase()), parse_fingerprint(&hex));
+        assert!(parse_fingerprint("AB:CD").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// A byte stream packets can be sent over
+/// clones must refer to the same stream, so one thread can read while another writes
+pub trait Transport: Read + Write + Send + Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+/// Accepts incoming transports, see `TcpListener`
+/// a non-blocking listener returns `io::ErrorKind::WouldBlock` from `accept` when nobody is waiting to connect
+pub trait Listener {
+    type Transport: Transport;
+
+    fn accept(&self) -> io::Result<Self::Transport>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl Listener for TcpListener {
+    type Transport = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        let (stream, _) = TcpListener::accept(self)?;
+        // streams inherit non-blocking mode on some platforms, but connections are always read with blocking reads
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for std::os::unix::net::UnixListener {
+    type Transport = std::os::unix::net::UnixStream;
+
+    fn accept(&self) -> io::Result<Self::Transport> {
+        let (stream, _) = std::os::unix::net::UnixListener::accept(self)?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Accepts from every listener in the list, for example one for IPv4 and one for IPv6
+/// it only works in non-blocking mode, a blocking accept would wait on the first listener and never see the others
+impl<L: Listener> Listener for Vec<L> {
+    type Transport = L::Transport;
+
+    fn accept(&self) -> io::Result<L::Transport> {
+        for listener in self {
+            match listener.accept() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.iter().try_for_each(|listener| listener.set_nonblocking(nonblocking))
+    }
+}
+
+// bytes written to one end of a pipe, waiting to be read from the other
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+struct PipeState {
+    data: VecDeque<u8>,
+    // the number of open handles to each end, reads end once there are no writers left and writes fail once there are no readers
+    readers: usize,
+    writers: usize,
+}
+
+impl Pipe {
+    fn new() -> Arc<Self> {
+        Arc::new(Self { state: Mutex::new(PipeState { data: VecDeque::new(), readers: 1, writers: 1 }), ready: Condvar::new() })
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, PipeState>> {
+        self.state.lock().map_err(|_| io::Error::other(format!("The pipe was poisoned")))
+    }
+}
+
+/// One end of an in-memory duplex pipe, made with `pipe`
+/// the other end reads end of stream once every clone of this end is dropped
+pub struct MemoryStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+/// Create two connected in-memory streams, what is written to one can be read from the other
+pub fn pipe() -> (MemoryStream, MemoryStream) {
+    let (a_to_b, b_to_a) = (Pipe::new(), Pipe::new());
+    (
+        MemoryStream { incoming: Arc::clone(&b_to_a), outgoing: Arc::clone(&a_to_b) },
+        MemoryStream { incoming: a_to_b, outgoing: b_to_a },
+    )
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.incoming.lock()?;
+        loop {
+            if !state.data.is_empty() || buf.is_empty() {
+                let read = buf.len().min(state.data.len());
+                for (byte, data) in buf.iter_mut().zip(state.data.drain(..read)) {
+                    *byte = data;
+                }
+                return Ok(read);
+            }
+            if state.writers == 0 {
+                return Ok(0);
+            }
+            state = self.incoming.ready.wait(state).map_err(|_| io::Error::other(format!("The pipe was poisoned")))?;
+        }
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.lock()?;
+        if state.readers == 0 {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("The other end of the pipe was closed")));
+        }
+        state.data.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        self.incoming.lock()?.readers += 1;
+        self.outgoing.lock()?.writers += 1;
+        Ok(Self { incoming: Arc::clone(&self.incoming), outgoing: Arc::clone(&self.outgoing) })
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.incoming.lock() {
+            state.readers -= 1;
+        }
+        if let Ok(mut state) = self.outgoing.lock() {
+            state.writers -= 1;
+            self.outgoing.ready.notify_all();
+        }
+    }
+}
+
+/// Accepts in-memory streams opened with the `MemoryConnector` made alongside it
+pub struct MemoryListener {
+    incoming: Mutex<Receiver<MemoryStream>>,
+    nonblocking: AtomicBool,
+}
+
+/// Opens in-memory streams to a `MemoryListener`, it can be cloned to connect from many threads
+#[derive(Clone)]
+pub struct MemoryConnector {
+    outgoing: Sender<MemoryStream>,
+}
+
+impl MemoryListener {
+    pub fn new() -> (MemoryListener, MemoryConnector) {
+        let (outgoing, incoming) = mpsc::channel();
+        (MemoryListener { incoming: Mutex::new(incoming), nonblocking: AtomicBool::new(false) }, MemoryConnector { outgoing })
+    }
+}
+
+impl Listener for MemoryListener {
+    type Transport = MemoryStream;
+
+    fn accept(&self) -> io::Result<MemoryStream> {
+        let incoming = self.incoming.lock().map_err(|_| io::Error::other(format!("The listener was poisoned")))?;
+        let closed = || io::Error::new(io::ErrorKind::NotConnected, format!("Every connector to the listener was dropped"));
+        if self.nonblocking.load(Ordering::SeqCst) {
+            return match incoming.try_recv() {
+                Ok(stream) => Ok(stream),
+                Err(TryRecvError::Empty) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                Err(TryRecvError::Disconnected) => Err(closed()),
+            };
+        }
+        incoming.recv().map_err(|_| closed())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl MemoryConnector {
+    /// Open a stream to the listener, it can be written to before the listener accepts it
+    pub fn connect(&self) -> io::Result<MemoryStream> {
+        let (local, remote) = pipe();
+        if self.outgoing.send(remote).is_err() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("The listener was dropped")));
+        }
+        Ok(local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, Packet};
+
+    fn ping() -> Packet {
+        Packet::Ping { version: format!("1"), disconnecting: false }
+    }
+
+    #[test]
+    fn pipes_are_duplex() {
+        let (a, b) = pipe();
+        let (mut a, mut b) = (Connection::new(a), Connection::new(b));
+        a.send(ping()).unwrap();
+        assert_eq!(b.recv().unwrap(), ping());
+        b.send(Packet::Disconnect).unwrap();
+        assert_eq!(a.recv().unwrap(), Packet::Disconnect);
+    }
+
+    #[test]
+    fn clones_send_whole_packets() {
+        let (a, b) = pipe();
+        let mut a = Connection::new(a);
+        let mut b = Connection::new(b);
+        let mut clone = a.try_clone().unwrap();
+
+        // packets sent from two threads at once arrive intact, one after the other
+        let sender = std::thread::spawn(move || {
+            for _ in 0..100 {
+                clone.send(ping()).unwrap();
+            }
+        });
+        for _ in 0..100 {
+            a.send(Packet::Disconnect).unwrap();
+        }
+        sender.join().unwrap();
+        let received: Vec<Packet> = (0..200).map(|_| b.recv().unwrap()).collect();
+        assert_eq!(received.iter().filter(|packet| **packet == ping()).count(), 100);
+    }
+
+    #[test]
+    fn pipes_close_when_every_clone_is_dropped() {
+        let (a, mut b) = pipe();
+        let mut clone = a.try_clone().unwrap();
+        drop(a);
+
+        // the clone keeps the stream open
+        let mut buf = [0u8; 8];
+        clone.write_all(&[1, 2]).unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 2);
+        drop(clone);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        // nobody is left to read what b writes
+        assert_eq!(b.write(&[1]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn memory_listener() {
+        let (listener, connector) = MemoryListener::new();
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+
+        let mut client = Connection::new(connector.connect().unwrap());
+        client.send(ping()).unwrap();
+        let mut server = Connection::new(listener.accept().unwrap());
+        assert_eq!(server.recv().unwrap(), ping());
+
+        drop(listener);
+        assert!(connector.connect().is_err());
+    }
+
+    #[test]
+    fn listener_lists() {
+        let (first, _first_connector) = MemoryListener::new();
+        let (second, second_connector) = MemoryListener::new();
+        let listeners = vec![first, second];
+        listeners.set_nonblocking(true).unwrap();
+        assert_eq!(listeners.accept().err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+
+        // connections to any of the listeners are accepted
+        let mut client = Connection::new(second_connector.connect().unwrap());
+        client.send(ping()).unwrap();
+        let mut server = Connection::new(listeners.accept().unwrap());
+        assert_eq!(server.recv().unwrap(), ping());
+        assert_eq!(listeners.accept().err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_sockets() {
+        use std::os::unix::net::{UnixListener, UnixStream};
+        let path = std::env::temp_dir().join(format!("dl_transport_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut client = Connection::new(UnixStream::connect(&path).unwrap());
+        let mut server = Connection::new(Listener::accept(&listener).unwrap());
+        client.send(ping()).unwrap();
+        assert_eq!(server.recv().unwrap(), ping());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
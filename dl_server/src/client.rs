@@ -1,114 +1,171 @@
-use std::net::TcpStream;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use r2d2_postgres::postgres::NoTls;
-use r2d2_postgres::{PostgresConnectionManager, r2d2};
+use std::time::Duration;
+use chrono::Utc;
 use dl_network_common::{Connection, Packet};
+use dl_network_common::transport::Transport;
+use crate::client::contacts::pending_requests;
 use crate::client::login::login_handler;
 use crate::{debug, info, warn};
 use crate::client::msg_receiver::msg_receive_handler;
 use crate::client::ping::expect_ping;
-use crate::database::{delete_msg, get_next_msg, set_id_online};
+use crate::database::{Store, PREKEYS_LOW};
+use crate::password::HashConfig;
+use crate::router::Router;
 
 mod ping;
 mod login;
 mod msg_receiver;
+mod groups;
+mod spaces;
+mod contacts;
+mod timers;
 
-/// Spawns a second thread
-pub fn chandler(stream: TcpStream, db_pool: r2d2::Pool<PostgresConnectionManager<NoTls>>, tarc: Arc<AtomicBool>) {
-    // ensure the stream is non-blocking
-    if let Err(_) = stream.set_nonblocking(false) {
-        warn!("Failed to set stream to blocking, failed to properly handle connection!");
-        return;
-    }
-
-    // convert the stream into a Connection wrapper for sending capnp packets
-    let mut connection = Connection::new(stream);
+// How long the client handler waits for routed packets before checking if it should shut down
+const SHUTDOWN_CHECK_DELAY_MS: u64 = 250;
 
+/// Spawns a second thread
+pub fn chandler<T: Transport + 'static>(mut connection: Connection<T>, store: Arc<dyn Store>, router: Arc<Router>, hash_config: HashConfig, tarc: Arc<AtomicBool>) {
     // handle ping commands
     if expect_ping(&mut connection) {
         return;
     }
 
-    // create a connection to the database
-    let mut db = db_pool.get().unwrap();
-
     // handle before login to not waste time
     let Ok(mut cloned_connection) = connection.try_clone() else {
         warn!("Failed to create second connection reference for client handler!");
         return;
     };
 
-    let Some(id) = login_handler(&mut connection, &mut db) else {
+    let Some(id) = login_handler(&mut connection, store.as_ref(), &hash_config) else {
         return;
     };
     debug!("Client logged in with ID: {}", id);
 
     // set the user to online in the database
-    if let Err(e) = set_id_online(&mut db, &id, true) {
+    if let Err(e) = store.set_id_online(&id, true) {
         warn!("Database write error: could not set user {} to online. Error: {}", id, e);
         return;
     }
 
-    // todo(skepz): add function that updates the client on how many messages are unread and send them before the client can send messages so
-    //  messages dont get out of order
+    // register the session before reading queued messages so nothing sent in between is missed
+    let (session, routed) = Router::register(&router, id);
+
+    // ask for more one-time prekeys if others are close to using them all up
+    match store.count_one_time_prekeys(&id) {
+        Ok(remaining) if remaining < PREKEYS_LOW => {
+            if connection.send(Packet::PreKeysLow { remaining: remaining as u32 }).is_err() {
+                warn!("Failed to send PreKeysLow to client!");
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to count prekeys: {}", e),
+    }
+
+    // resend every message the user has not acknowledged yet, they stay queued until the client acks them
+    // messages that disappeared while the user was offline are purged first, so they are never sent
+    if let Err(e) = store.purge_expired_msgs(Utc::now()) {
+        warn!("Failed to purge expired messages: {}", e);
+    }
+    match store.get_queued_msgs(&id) {
+        Ok(queued) => {
+            for msg in queued {
+                if connection.send(Packet::Message {
+                    id: msg.id.to_string(),
+                    seq: msg.seq as u64,
+                    message: msg.message,
+                    sender: msg.sender,
+                    recipient: format!("SELF"),
+                    group: msg.group.map(|group| group.to_string()),
+                    timestamp: msg.timestamp.to_string(),
+                    ttl: msg.ttl,
+                }).is_err() {
+                    warn!("Failed to send message to client!");
+                    break;
+                }
+            }
+        }
+        Err(e) => warn!("Failed to get queued messages: {}", e),
+    }
+
+    // contact requests sent while the user was offline
+    match pending_requests(store.as_ref(), &id) {
+        Ok(updates) => {
+            for update in updates {
+                if connection.send(update).is_err() {
+                    warn!("Failed to send contact request to client!");
+                    break;
+                }
+            }
+        }
+        Err(e) => warn!("Failed to get pending contact requests: {}", e),
+    }
 
     let local_tarc = Arc::new(AtomicBool::new(false));
 
     let ltarc_clone = Arc::clone(&local_tarc);
+    let receiver_router = Arc::clone(&router);
+    let receiver_store = Arc::clone(&store);
 
     // spawn the message receiver thread to handle incoming messages to the client
     let msg_receiver = thread::spawn(move || {
-        msg_receive_handler(&mut cloned_connection, db_pool.clone(), id.clone(), ltarc_clone);
+        msg_receive_handler(&mut cloned_connection, receiver_store, receiver_router, id, ltarc_clone);
     });
 
+    // routed packets waiting to be written, a packet that fails to write is kept and tried again
+    let mut unsent: VecDeque<Packet> = VecDeque::new();
     loop {
         // check if the server is shutting down
         if tarc.load(Ordering::SeqCst) {
             info!("Client received shutdown signal. Terminating connection");
             // store for the msg_receiver
             local_tarc.store(true, Ordering::SeqCst);
-            if let Err(_) = connection.send(Packet::Disconnect) {
+            if connection.send(Packet::Disconnect).is_err() {
                 warn!("Failed to send disconnect to client, maybe they already disconnected?");
             }
             break;
         }
         if local_tarc.load(Ordering::SeqCst) {
-            if let Err(_) = connection.send(Packet::Disconnect) {
+            if connection.send(Packet::Disconnect).is_err() {
                 warn!("Failed to send disconnect to client, maybe they already disconnected?");
             }
             break;
         }
 
-        // get the next message addressed to this user
-        let msg_query = get_next_msg(&mut db, &id);
-        if let Err(e) = msg_query {
-            warn!("Failed to get next message: {}", e);
-            continue;
-        }
-
-        // if there is a message, send it and remove it from the database
-        if let Some(msg) = msg_query.unwrap() {
-            // send the message
-            if connection.send(Packet::Message { message: msg.message, sender: msg.sender, recipient: format!("SELF"), timestamp: msg.timestamp.to_string() }).is_err() {
-                warn!("Failed to send message to client!");
-                continue;
+        // wait for packets routed to this user by other sessions
+        if unsent.is_empty() {
+            match routed.recv_timeout(Duration::from_millis(SHUTDOWN_CHECK_DELAY_MS)) {
+                Ok(packet) => unsent.push_back(packet),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
             }
-
-            if let Err(e) = delete_msg(&mut db, &msg.id) {
-                warn!("Failed to delete message from database after sending: {}", e);
+        }
+        unsent.extend(routed.try_iter());
+
+        while let Some(packet) = unsent.pop_front() {
+            if connection.send(packet.clone()).is_err() {
+                // the receiver thread stops the session if the client is gone, until then the packet is retried in order
+                warn!("Failed to send routed packet to client, retrying");
+                unsent.push_front(packet);
+                thread::sleep(Duration::from_millis(SHUTDOWN_CHECK_DELAY_MS));
+                break;
             }
         }
     }
 
+    // stop receiving routed packets
+    drop(session);
+
     local_tarc.store(true, Ordering::SeqCst);
-    if let Err(_) = msg_receiver.join() {
+    if msg_receiver.join().is_err() {
         warn!("Failed to join msg_receiver thread when shutting down client!");
     }
 
     // set the user to offline in the database
-    if let Err(e) = set_id_online(&mut db, &id, false) {
+    if let Err(e) = store.set_id_online(&id, false) {
         warn!("Database write error: could not set user {} to online. Error: {}", id, e);
         return;
     }
@@ -0,0 +1,142 @@
+use uuid::Uuid;
+use dl_network_common::{Connection, Contact, ContactState, Packet};
+use dl_network_common::transport::Transport;
+use crate::database::{DBContact, Store};
+use crate::router::Router;
+use crate::warn;
+
+/// Answer a packet that sends, answers or removes a contact request, blocks or unblocks a user, or asks for either list
+/// the other user is told about each change to their contacts with a ContactUpdate if they are online
+/// @return: false if the client could not be answered and should be disconnected
+pub fn contact_handler<T: Transport>(connection: &mut Connection<T>, store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> bool {
+    let reply = match handle(store, router, id, username, packet) {
+        Ok(reply) => reply,
+        Err(error) => Packet::Error { error, should_disconnect: false },
+    };
+    if connection.send(reply).is_err() {
+        warn!("failed to send reply to contact packet to client.");
+        return false;
+    }
+    true
+}
+
+/// The requests sent to a user that were not answered yet, as the updates that would have told them about each
+/// sent after logging in, as the user could not be told while they were offline
+pub fn pending_requests(store: &dyn Store, id: &Uuid) -> Result<Vec<Packet>, String> {
+    let contacts = store.get_contacts(id)?;
+    Ok(contacts.into_iter()
+        .filter(|contact| contact.state == ContactState::Incoming)
+        .map(|contact| Packet::ContactUpdate { contact: to_contact(contact) })
+        .collect())
+}
+
+// the reply to a contact packet, or the error to send back instead
+fn handle(store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> Result<Packet, String> {
+    match packet {
+        Packet::ContactRequest { username: other } => {
+            let other_id = user(store, id, &other)?;
+            if store.is_blocked(&other_id, id).map_err(database_error)? {
+                return Err(format!("Unblock {} first", other));
+            }
+            // requests from a blocked user are dropped without telling them, so they can not tell they were blocked
+            if store.is_blocked(id, &other_id).map_err(database_error)? {
+                return contact_list(store, id);
+            }
+            match store.get_contact_state(id, &other_id).map_err(database_error)? {
+                ContactState::Accepted => return Err(format!("{} is already a contact", other)),
+                ContactState::Outgoing => return Err(format!("You already sent {} a request", other)),
+                // they asked first, so asking back accepts their request
+                ContactState::Incoming => {
+                    store.accept_contact_request(id, &other_id).map_err(database_error)?;
+                    tell(router, &other_id, username, ContactState::Accepted);
+                }
+                ContactState::None => {
+                    store.add_contact_request(id, &other_id).map_err(database_error)?;
+                    tell(router, &other_id, username, ContactState::Incoming);
+                }
+            }
+            contact_list(store, id)
+        }
+        Packet::ContactRespond { username: other, accept } => {
+            let other_id = user(store, id, &other)?;
+            if store.get_contact_state(id, &other_id).map_err(database_error)? != ContactState::Incoming {
+                return Err(format!("{} did not send you a request", other));
+            }
+            if accept {
+                store.accept_contact_request(id, &other_id).map_err(database_error)?;
+                tell(router, &other_id, username, ContactState::Accepted);
+            } else {
+                store.remove_contact(id, &other_id).map_err(database_error)?;
+                tell(router, &other_id, username, ContactState::None);
+            }
+            contact_list(store, id)
+        }
+        Packet::ContactRemove { username: other } => {
+            let other_id = user(store, id, &other)?;
+            if !store.remove_contact(id, &other_id).map_err(database_error)? {
+                return Err(format!("{} is not a contact", other));
+            }
+            tell(router, &other_id, username, ContactState::None);
+            contact_list(store, id)
+        }
+        Packet::ContactListRequest => contact_list(store, id),
+        Packet::Block { username: other } => {
+            if other == username {
+                return Err(format!("You can not block yourself"));
+            }
+            let other_id = user(store, id, &other)?;
+            if !store.set_blocked(id, &other_id, true).map_err(database_error)? {
+                return Err(format!("{} is already blocked", other));
+            }
+            // blocking someone ends any contact or request between them
+            if store.remove_contact(id, &other_id).map_err(database_error)? {
+                tell(router, &other_id, username, ContactState::None);
+            }
+            block_list(store, id)
+        }
+        Packet::Unblock { username: other } => {
+            let other_id = user(store, id, &other)?;
+            if !store.set_blocked(id, &other_id, false).map_err(database_error)? {
+                return Err(format!("{} is not blocked", other));
+            }
+            block_list(store, id)
+        }
+        Packet::BlockListRequest => block_list(store, id),
+        _ => Err(format!("Unexpected packet")),
+    }
+}
+
+// the id of another user, users can not be their own contact
+fn user(store: &dyn Store, id: &Uuid, username: &str) -> Result<Uuid, String> {
+    let Ok(other) = store.get_id_from_username(username) else {
+        return Err(format!("There is no user named {}", username));
+    };
+    if other == *id {
+        return Err(format!("You can not be your own contact"));
+    }
+    Ok(other)
+}
+
+// tell another user how the user that changed something is related to them now
+// nothing is queued, a user that is offline gets their contact list when they log in
+fn tell(router: &Router, other: &Uuid, username: &str, state: ContactState) {
+    router.deliver(other, Packet::ContactUpdate { contact: Contact { username: username.to_string(), state } });
+}
+
+fn contact_list(store: &dyn Store, id: &Uuid) -> Result<Packet, String> {
+    let contacts = store.get_contacts(id).map_err(database_error)?;
+    Ok(Packet::ContactList { contacts: contacts.into_iter().map(to_contact).collect() })
+}
+
+fn block_list(store: &dyn Store, id: &Uuid) -> Result<Packet, String> {
+    Ok(Packet::BlockList { usernames: store.get_blocked(id).map_err(database_error)? })
+}
+
+fn to_contact(contact: DBContact) -> Contact {
+    Contact { username: contact.username, state: contact.state }
+}
+
+fn database_error(e: String) -> String {
+    warn!("Database error while handling a contact packet: {}", e);
+    format!("Database error")
+}
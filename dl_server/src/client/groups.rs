@@ -0,0 +1,208 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use dl_network_common::{Connection, Group, Packet, SealedCopy, MAX_GROUP_MEMBERS};
+use dl_network_common::transport::Transport;
+use crate::database::{Conversation, DBGroup, DBGroupCopy, Store};
+use crate::router::Router;
+use crate::warn;
+
+// the longest name a group can have, in characters
+const MAX_GROUP_NAME: usize = 64;
+
+/// Answer a packet that manages a group or sends a message to one
+/// every member can invite, remove and rename, the other members are told about each change with a GroupUpdate
+/// @return: false if the client could not be answered and should be disconnected
+pub fn group_handler<T: Transport>(connection: &mut Connection<T>, store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> bool {
+    let reply = match handle(store, router, id, username, packet) {
+        Ok(reply) => reply,
+        Err(error) => Packet::Error { error, should_disconnect: false },
+    };
+    if connection.send(reply).is_err() {
+        warn!("failed to send reply to group packet to client.");
+        return false;
+    }
+    true
+}
+
+/// The conversation a history request is for, it names either a user, or a group or channel by its id
+/// usernames can never be a uuid, so they can not be mixed up with the others
+pub fn conversation_with(store: &dyn Store, name: &str) -> Result<Conversation, String> {
+    if let Ok(group) = Uuid::parse_str(name) {
+        if store.get_group(&group).map_err(database_error)?.is_some() {
+            return Ok(Conversation::Group(group));
+        }
+        return match store.get_channel_space(&group) {
+            Ok(Some(_)) => Ok(Conversation::Channel(group)),
+            Ok(None) => Err(format!("Invalid group")),
+            Err(e) => Err(database_error(e)),
+        };
+    }
+    match store.get_id_from_username(name) {
+        Ok(other) => Ok(Conversation::Direct(other)),
+        Err(_) => Err(format!("Invalid username")),
+    }
+}
+
+// the reply to a group packet, or the error to send back instead
+fn handle(store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> Result<Packet, String> {
+    match packet {
+        Packet::GroupCreate { name, members } => {
+            let name = valid_name(&name)?;
+            let mut ids = vec![*id];
+            for member in members {
+                let Ok(member_id) = store.get_id_from_username(&member) else {
+                    return Err(format!("There is no user named {}", member));
+                };
+                if !ids.contains(&member_id) {
+                    ids.push(member_id);
+                }
+            }
+            if ids.len() > MAX_GROUP_MEMBERS {
+                return Err(format!("Groups can have at most {} members", MAX_GROUP_MEMBERS));
+            }
+
+            let group = store.create_group(name, &ids).map_err(database_error)?;
+            Ok(Packet::GroupInfo { group: changed(store, router, id, &group, None)? })
+        }
+        Packet::GroupInvite { group, username: invited } => {
+            let group = member_of(store, id, &group)?;
+            if group.members.len() >= MAX_GROUP_MEMBERS {
+                return Err(format!("Groups can have at most {} members", MAX_GROUP_MEMBERS));
+            }
+            let Ok(invited_id) = store.get_id_from_username(&invited) else {
+                return Err(format!("There is no user named {}", invited));
+            };
+            if !store.add_group_member(&group.id, &invited_id).map_err(database_error)? {
+                return Err(format!("{} is already in the group", invited));
+            }
+            Ok(Packet::GroupInfo { group: changed(store, router, id, &group.id, None)? })
+        }
+        Packet::GroupRemove { group, username: removed } => {
+            let group = member_of(store, id, &group)?;
+            let Some((removed_id, _)) = group.members.iter().find(|(_, member)| *member == removed) else {
+                return Err(format!("{} is not in the group", removed));
+            };
+            if removed_id == id {
+                return Err(format!("Leave the group instead of removing yourself"));
+            }
+            store.remove_group_member(&group.id, removed_id).map_err(database_error)?;
+            // the removed member is told too, so their client can stop showing the group
+            Ok(Packet::GroupInfo { group: changed(store, router, id, &group.id, Some(removed_id))? })
+        }
+        Packet::GroupLeave { group } => {
+            let group = member_of(store, id, &group)?;
+            store.remove_group_member(&group.id, id).map_err(database_error)?;
+            Ok(Packet::GroupInfo { group: changed(store, router, id, &group.id, None)? })
+        }
+        Packet::GroupRename { group, name } => {
+            let group = member_of(store, id, &group)?;
+            store.rename_group(&group.id, valid_name(&name)?).map_err(database_error)?;
+            Ok(Packet::GroupInfo { group: changed(store, router, id, &group.id, None)? })
+        }
+        Packet::GroupListRequest => {
+            let groups = store.get_user_groups(id).map_err(database_error)?;
+            Ok(Packet::GroupList { groups: groups.into_iter().map(to_group).collect() })
+        }
+        Packet::GroupMessage { group, copies } => {
+            let group = member_of(store, id, &group)?;
+            let ttl = store.get_timer(id, &Conversation::Group(group.id)).map_err(database_error)?;
+            fan_out(store, router, id, username, &group.id, &group.members, copies, ttl, "group",
+                    |stored, timestamp, ttl| store.store_group_msg(&group.id, id, stored, timestamp, ttl))
+        }
+        _ => Err(format!("Unexpected packet")),
+    }
+}
+
+/// Store and route a message sent to every other member of a group or a channel's space, each in their own sealed copy
+/// `ttl` is the disappearing message timer of the conversation, the copies are stored and delivered with it
+/// `what` names the conversation in errors, `save` stores the copies and returns the sequence number of the message
+/// the copies for members that blocked the sender are dropped, the sender keeps their own copy of the message on their device
+/// @return: the receipt for the sender
+#[allow(clippy::too_many_arguments)]
+pub fn fan_out<F>(store: &dyn Store, router: &Router, id: &Uuid, username: &str, conversation: &Uuid, members: &[(Uuid, String)], copies: Vec<SealedCopy>, ttl: u64, what: &str, save: F) -> Result<Packet, String>
+    where F: FnOnce(&[DBGroupCopy], DateTime<Utc>, u64) -> Result<i64, String> {
+    // every other member needs exactly one copy, so nobody misses the message because the sender's member list was out of date
+    let others: Vec<&(Uuid, String)> = members.iter().filter(|(member, _)| member != id).collect();
+    if others.is_empty() {
+        return Err(format!("There is nobody else in the {}", what));
+    }
+    if copies.len() != others.len() || !others.iter().all(|(_, member)| copies.iter().any(|copy| copy.recipient == *member)) {
+        return Err(format!("The message must be sealed for every other member of the {}", what));
+    }
+    // the server only ever stores sealed messages
+    if copies.iter().any(|copy| !copy.message.is_well_formed()) {
+        return Err(format!("Messages must be sealed for their recipient"));
+    }
+
+    let mut stored: Vec<DBGroupCopy> = copies.into_iter().filter_map(|copy| {
+        let (member, _) = others.iter().find(|(_, member)| *member == copy.recipient)?;
+        Some(DBGroupCopy { id: Uuid::new_v4(), recipient: *member, message: copy.message })
+    }).collect();
+    let blockers = store.get_blockers(id).map_err(database_error)?;
+    stored.retain(|copy| !blockers.contains(&copy.recipient));
+
+    // the copies stay queued until each member acknowledges theirs, so they are written before they are routed
+    let timestamp = Utc::now();
+    let seq = save(&stored, timestamp, ttl).map_err(database_error)? as u64;
+    for copy in stored {
+        router.deliver(&copy.recipient, Packet::Message {
+            id: copy.id.to_string(),
+            seq,
+            message: copy.message,
+            sender: username.to_string(),
+            recipient: format!("SELF"),
+            group: Some(conversation.to_string()),
+            timestamp: timestamp.to_string(),
+            ttl,
+        });
+    }
+
+    // none of the copies can be read by the sender, so nothing is kept for them and the receipt only names the message
+    Ok(Packet::MessageReceipt { id: Uuid::new_v4().to_string(), recipient: conversation.to_string(), seq, timestamp: timestamp.to_string() })
+}
+
+/// The group with this id, if the user is one of its members
+pub fn member_of(store: &dyn Store, id: &Uuid, group: &str) -> Result<DBGroup, String> {
+    let Ok(group) = Uuid::parse_str(group) else {
+        return Err(format!("Invalid group"));
+    };
+    match store.get_group(&group).map_err(database_error)? {
+        Some(group) if group.members.iter().any(|(member, _)| member == id) => Ok(group),
+        Some(_) => Err(format!("You are not in this group")),
+        None => Err(format!("Invalid group")),
+    }
+}
+
+// tell the members of a group other than the one who changed it, and a removed member, what it looks like now
+// returns the group for the reply to the member who changed it
+fn changed(store: &dyn Store, router: &Router, by: &Uuid, group: &Uuid, removed: Option<&Uuid>) -> Result<Group, String> {
+    let Some(group) = store.get_group(group).map_err(database_error)? else {
+        return Err(format!("Invalid group"));
+    };
+    let told = group.members.iter().map(|(member, _)| member).chain(removed).filter(|member| *member != by);
+    for member in told {
+        router.deliver(member, Packet::GroupUpdate { group: to_group(group.clone()) });
+    }
+    Ok(to_group(group))
+}
+
+fn to_group(group: DBGroup) -> Group {
+    Group {
+        id: group.id.to_string(),
+        name: group.name,
+        members: group.members.into_iter().map(|(_, username)| username).collect(),
+    }
+}
+
+fn valid_name(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_GROUP_NAME {
+        return Err(format!("Group names must be 1 to {} characters long", MAX_GROUP_NAME));
+    }
+    Ok(name)
+}
+
+fn database_error(e: String) -> String {
+    warn!("Database error while handling a group packet: {}", e);
+    format!("Database error")
+}
@@ -1,11 +1,10 @@
-use r2d2_postgres::PostgresConnectionManager;
-use r2d2_postgres::postgres::NoTls;
-use r2d2_postgres::r2d2::PooledConnection;
 use regex::Regex;
 use uuid::Uuid;
-use dl_network_common::{Connection, ExpectedPacket, Packet};
+use dl_network_common::{Connection, Packet};
+use dl_network_common::transport::Transport;
 use crate::{debug, warn};
-use crate::database::{get_user_from_username, insert_user};
+use crate::database::Store;
+use crate::password::{hash_password, verify_password, HashConfig, Verified};
 
 pub fn validate_username(name: String) -> bool {
 
@@ -31,15 +30,15 @@ pub fn validate_password(pass: String) -> bool {
 
 /// Handles login and signup attempts from the client
 /// returns true if disconnecting
-pub fn login_handler(connection: &mut Connection, db: &mut PooledConnection<PostgresConnectionManager<NoTls>>) -> Option<Uuid> {
+pub fn login_handler<T: Transport>(connection: &mut Connection<T>, store: &dyn Store, hash_config: &HashConfig) -> Option<Uuid> {
 
     // for storing the username for debugging
     let mut uname = format!("");
     // store the id when received
-    let mut id: Uuid;
+    let id: Uuid;
     loop {
         // expect Login packet from client
-        let expected = connection.expect(ExpectedPacket::LoginRequest);
+        let expected = connection.recv();
         if let Err(e) = expected {
             warn!("Failed to get LoginRequest from a client: {}", e);
             return None;
@@ -48,7 +47,24 @@ pub fn login_handler(connection: &mut Connection, db: &mut PooledConnection<Post
             Packet::LoginRequest { username, password, signup } => {
                 (username, password, signup)
             }
-            _ => unreachable!()
+            Packet::Disconnect => return None,
+            Packet::Error { should_disconnect, error } => {
+                warn!("Client sent an error before logging in: {}", error);
+                if should_disconnect {
+                    return None;
+                }
+                continue;
+            }
+            _ => {
+                if connection.send(Packet::Error {
+                    should_disconnect: false,
+                    error: format!("You must log in first")
+                }).is_err() {
+                    warn!("Failed to send error to a client that has not logged in.");
+                    return None;
+                }
+                continue;
+            }
         };
 
         // Handle if the user is signing up
@@ -76,7 +92,21 @@ pub fn login_handler(connection: &mut Connection, db: &mut PooledConnection<Post
                 continue;
             }
 
-            let id_result = insert_user(db, username.clone(), password);
+            let hash = match hash_password(password.as_str(), hash_config) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    warn!("Failed to hash password for new user {}: {}", username, e);
+                    if connection.send(Packet::LoginResponse {
+                        valid: false,
+                        error: Some(format!("Server error"))
+                    }).is_err() {
+                        warn!("Failed to send Login Accept to {}", username);
+                    }
+                    continue;
+                }
+            };
+
+            let id_result = store.insert_user(&username, &hash);
 
             if let Err(e) = id_result {
                 if connection.send(Packet::LoginResponse {
@@ -95,7 +125,7 @@ pub fn login_handler(connection: &mut Connection, db: &mut PooledConnection<Post
 
         // get the password of the user from db to verify if the received password is correct
         // this also gives the user's id to reduce the amount of database queries
-        let pass_query = get_user_from_username(db, username.clone());
+        let pass_query = store.get_user_from_username(&username);
         if let Err(e) = pass_query {
             // query result sent an error
             warn!("Client login attempt with username {} sent a database error: {}", username, e);
@@ -108,8 +138,10 @@ pub fn login_handler(connection: &mut Connection, db: &mut PooledConnection<Post
 
         debug!("client attempting login under username {}", username);
 
+        let verified = verify_password(password.as_str(), pass.as_str(), hash_config);
+
         // password is invalid
-        if password != pass {
+        if verified == Verified::Invalid {
             if connection.send(Packet::LoginResponse {
                 valid: false,
                 error: Some(format!("Invalid login credentials"))
@@ -120,6 +152,20 @@ pub fn login_handler(connection: &mut Connection, db: &mut PooledConnection<Post
             continue;
         }
 
+        // upgrade plaintext passwords and hashes made with old parameters now that the password is known
+        if verified == Verified::ValidNeedsRehash {
+            match hash_password(password.as_str(), hash_config) {
+                Ok(hash) => {
+                    if let Err(e) = store.set_user_password(&qid, &hash) {
+                        warn!("Failed to store rehashed password for {}: {}", username, e);
+                    } else {
+                        debug!("Rehashed the stored password of {}", username);
+                    }
+                }
+                Err(e) => warn!("Failed to rehash password for {}: {}", username, e),
+            }
+        }
+
         debug!("{} logged in.", username);
         uname = username;
         id = qid;
@@ -135,4 +181,73 @@ pub fn login_handler(connection: &mut Connection, db: &mut PooledConnection<Post
     }
 
     Some(id)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use dl_network_common::transport::pipe;
+    use crate::database::memory::MemoryStore;
+    use super::*;
+
+    // small parameters so the tests run quickly
+    const CHEAP: HashConfig = HashConfig { memory_kib: 256, iterations: 1, parallelism: 1 };
+
+    fn login(username: &str, password: &str, signup: bool) -> Packet {
+        Packet::LoginRequest { username: username.to_string(), password: password.to_string(), signup }
+    }
+
+    // send packets from an in-memory client, then hang up, returning the id logged in as and every reply
+    fn attempt(store: &MemoryStore, packets: Vec<Packet>) -> (Option<Uuid>, Vec<Packet>) {
+        let (client, server) = pipe();
+        let mut client = Connection::new(client);
+        for packet in packets {
+            client.send(packet).unwrap();
+        }
+        client.send(Packet::Disconnect).unwrap();
+        let id = login_handler(&mut Connection::new(server), store, &CHEAP);
+        let mut replies = Vec::new();
+        while let Ok(reply) = client.recv() {
+            replies.push(reply);
+        }
+        (id, replies)
+    }
+
+    fn rejected(error: &str) -> Packet {
+        Packet::LoginResponse { valid: false, error: Some(error.to_string()) }
+    }
+
+    const ACCEPTED: Packet = Packet::LoginResponse { valid: true, error: None };
+
+    #[test]
+    fn signup_then_login() {
+        let store = MemoryStore::new();
+        let (id, replies) = attempt(&store, vec![login("test", "hunter2", true)]);
+        assert_eq!(replies, vec![ACCEPTED]);
+        let id = id.unwrap();
+        assert!(store.get_user_from_username("test").unwrap().1.starts_with("$argon2id$"));
+
+        let (again, replies) = attempt(&store, vec![login("test", "other", true), login("test", "hunter3", false), login("test", "hunter2", false)]);
+        assert_eq!(replies, vec![rejected("Username is taken"), rejected("Invalid login credentials"), ACCEPTED]);
+        assert_eq!(again, Some(id));
+    }
+
+    #[test]
+    fn legacy_passwords_are_rehashed() {
+        let store = MemoryStore::new();
+        let id = store.insert_user("legacy", "hunter2").unwrap();
+        let (logged_in, _) = attempt(&store, vec![login("legacy", "hunter2", false)]);
+        assert_eq!(logged_in, Some(id));
+        assert!(store.get_user_from_username("legacy").unwrap().1.starts_with("$argon2id$"));
+    }
+
+    #[test]
+    fn must_log_in_first() {
+        let store = MemoryStore::new();
+        let (id, replies) = attempt(&store, vec![Packet::UserExistsRequest { username: format!("test") }, login("nobody", "hunter2", false)]);
+        assert_eq!(id, None);
+        assert_eq!(replies, vec![
+            Packet::Error { should_disconnect: false, error: format!("You must log in first") },
+            rejected("Invalid login credentials"),
+        ]);
+    }
+}
@@ -1,108 +1,447 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use chrono::{DateTime, Utc};
-use postgres::NoTls;
-use r2d2_postgres::{PostgresConnectionManager, r2d2};
+use chrono::Utc;
 use uuid::Uuid;
-use dl_network_common::{Connection, ExpectedPacket, Packet};
-use crate::database::{get_id_from_username, insert_msg, is_username_online, user_exists};
+use dl_network_common::{Connection, Packet, SentMsg, MAX_PREKEYS};
+use dl_network_common::transport::Transport;
+use dl_network_common::crypto::{verify_prekey, KEY_LEN};
+use crate::client::groups::{conversation_with, group_handler};
+use crate::client::spaces::space_handler;
+use crate::client::contacts::contact_handler;
+use crate::client::timers::timer_handler;
+use crate::database::{Conversation, Store, PREKEYS_LOW};
+use crate::router::Router;
 use crate::warn;
 
-pub fn msg_receive_handler(connection: &mut Connection, db_pool: r2d2::Pool<PostgresConnectionManager<NoTls>>, id: Uuid, tarc: Arc<AtomicBool>) {
+pub fn msg_receive_handler<T: Transport>(connection: &mut Connection<T>, store: Arc<dyn Store>, router: Arc<Router>, id: Uuid, tarc: Arc<AtomicBool>) {
 
-    let Ok(mut db) = db_pool.get() else {
-        warn!("Failed to get database instance for msg_receive_handler!");
-        tarc.store(true, Ordering::SeqCst);
-        return;
+    // the username is sent along with every message this client sends
+    let username = match store.get_username_from_id(&id) {
+        Ok(username) => username,
+        Err(e) => {
+            warn!("Failed to get username of client with id {}: {}", id, e);
+            tarc.store(true, Ordering::SeqCst);
+            return;
+        }
     };
 
     loop {
         if tarc.load(Ordering::SeqCst) {
-            if let Err(_) = connection.send(Packet::Disconnect) {
+            if connection.send(Packet::Disconnect).is_err() {
                 warn!("Failed to send disconnect to client msg_receive_handler, maybe they already disconnected?");
             }
             return;
         }
 
-        // check for incoming messages from client
-        if let Ok(pkt_opt) = connection.check_expected(ExpectedPacket::Message) {
-            if let Some(packet) = pkt_opt {
-                // handle incoming messages from client
-                match packet {
-                    Packet::Message { message, recipient, timestamp, .. } => {
-                        // get the recipient's ID from username
-                        let rec_query = get_id_from_username(&mut db, recipient);
-                        if let Err(e) = rec_query {
-                            if connection.send(Packet::Error {
-                                error: format!("Invalid recipient"),
-                                should_disconnect: false
-                            }).is_err() {
-                                warn!("failed to send error message to client: {}", e);
-                                break;
-                            }
-                            warn!("Failed to get recipient ID from username: {}", e);
-                            continue;
+        // wait for the next packet from the client
+        let packet = match connection.recv() {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Failed to read from client; disconnecting: {}", e);
+                break;
+            }
+        };
+
+        // handle incoming packets from client
+        match packet {
+            Packet::Message { message, recipient, .. } => {
+                // the server only ever stores sealed messages
+                if !message.is_well_formed() {
+                    if connection.send(Packet::Error {
+                        error: format!("Messages must be sealed for their recipient"),
+                        should_disconnect: false
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
+                        break;
+                    }
+                    continue;
+                }
+
+                // get the recipient's ID from username
+                let rec_query = store.get_id_from_username(&recipient);
+                if let Err(e) = rec_query {
+                    if connection.send(Packet::Error {
+                        error: format!("Invalid recipient"),
+                        should_disconnect: false
+                    }).is_err() {
+                        warn!("failed to send error message to client: {}", e);
+                        break;
+                    }
+                    warn!("Failed to get recipient ID from username: {}", e);
+                    continue;
+                }
+                let recipient_id = rec_query.unwrap();
+
+                // a user that blocked the sender never gets their messages, not even queued
+                match store.is_blocked(&id, &recipient_id) {
+                    Ok(false) => {}
+                    blocked => {
+                        if let Err(e) = blocked {
+                            warn!("Failed to check if the recipient blocked the sender: {}", e);
                         }
-                        let recipient_id = rec_query.unwrap();
-
-                        if let Err(e) = insert_msg(&mut db, &id, &recipient_id, message, DateTime::from(Utc::now())) {
-                            warn!("Failed to write message to database: {}", e);
-                            if connection.send(Packet::Error {
-                                error: format!("Database error"),
-                                should_disconnect: false
-                            }).is_err() {
-                                warn!("failed to send error message to client.");
-                                break;
-                            }
-                            continue;
+                        if connection.send(Packet::Error {
+                            error: format!("{} is not accepting your messages", recipient),
+                            should_disconnect: false
+                        }).is_err() {
+                            warn!("failed to send error message to client.");
+                            break;
                         }
+                        continue;
                     }
-                    Packet::UserOnlineRequest { username } => {
-                        let online = is_username_online(&mut db, username);
-                        if let Err(e) = online {
-                            warn!("Client online check failed to read database! Safely closing connection. Database Error: {}", e);
+                }
+
+                // the message disappears after the timer of the conversation, whatever the sender asked for
+                let ttl = match store.get_timer(&id, &Conversation::Direct(recipient_id)) {
+                    Ok(ttl) => ttl,
+                    Err(e) => {
+                        warn!("Failed to get the timer of a conversation: {}", e);
+                        if connection.send(Packet::Error {
+                            error: format!("Database error"),
+                            should_disconnect: false
+                        }).is_err() {
+                            warn!("failed to send error message to client.");
                             break;
                         }
-                        if connection.send(Packet::UserResponse { response: online.unwrap() }).is_err() {
-                            warn!("failed to send UserResponse to client.");
+                        continue;
+                    }
+                };
+
+                // the message is kept in the history of the conversation and queued for the recipient under the same id
+                // it stays queued until the recipient acknowledges it, so it is written before it is routed
+                let msg_id = Uuid::new_v4();
+                let timestamp = Utc::now();
+                let seq = match store.store_msg(&msg_id, &id, &recipient_id, &message, timestamp, ttl) {
+                    Ok(seq) => seq as u64,
+                    Err(e) => {
+                        warn!("Failed to write message to database: {}", e);
+                        if connection.send(Packet::Error {
+                            error: format!("Database error"),
+                            should_disconnect: false
+                        }).is_err() {
+                            warn!("failed to send error message to client.");
                             break;
                         }
+                        continue;
                     }
-                    Packet::UserExistsRequest { username } => {
-                        let exists = user_exists(&mut db, username);
-                        if let Err(e) = exists {
-                            warn!("Client exists check failed to read database! Safely closing connection. Database Error: {}", e);
-                            break;
+                };
+
+                if connection.send(Packet::MessageReceipt {
+                    id: msg_id.to_string(),
+                    recipient,
+                    seq,
+                    timestamp: timestamp.to_string(),
+                }).is_err() {
+                    warn!("failed to send MessageReceipt to client.");
+                    break;
+                }
+
+                // send the message straight to the recipient if they are online, otherwise it is sent when they log in
+                router.deliver(&recipient_id, Packet::Message {
+                    id: msg_id.to_string(),
+                    seq,
+                    message,
+                    sender: username.clone(),
+                    recipient: format!("SELF"),
+                    group: None,
+                    timestamp: timestamp.to_string(),
+                    ttl,
+                });
+            }
+            packet @ (Packet::GroupCreate { .. } | Packet::GroupInvite { .. } | Packet::GroupRemove { .. } | Packet::GroupLeave { .. }
+                | Packet::GroupRename { .. } | Packet::GroupListRequest | Packet::GroupMessage { .. }) => {
+                if !group_handler(connection, store.as_ref(), &router, &id, &username, packet) {
+                    break;
+                }
+            }
+            packet @ (Packet::SpaceCreate { .. } | Packet::SpaceInvite { .. } | Packet::SpaceLeave { .. } | Packet::SpaceKick { .. }
+                | Packet::SpaceBan { .. } | Packet::ChannelCreate { .. } | Packet::ChannelDelete { .. } | Packet::RoleSet { .. }
+                | Packet::RoleDelete { .. } | Packet::RoleAssign { .. } | Packet::SpaceListRequest | Packet::ChannelMessage { .. }) => {
+                if !space_handler(connection, store.as_ref(), &router, &id, &username, packet) {
+                    break;
+                }
+            }
+            packet @ (Packet::ContactRequest { .. } | Packet::ContactRespond { .. } | Packet::ContactRemove { .. } | Packet::ContactListRequest
+                | Packet::Block { .. } | Packet::Unblock { .. } | Packet::BlockListRequest) => {
+                if !contact_handler(connection, store.as_ref(), &router, &id, &username, packet) {
+                    break;
+                }
+            }
+            packet @ (Packet::TimerSet { .. } | Packet::TimerListRequest) => {
+                if !timer_handler(connection, store.as_ref(), &router, &id, &username, packet) {
+                    break;
+                }
+            }
+            // acks, like key uploads, are not replied to, so the client would take an error as the reply to its next request
+            Packet::MessageAck { id: msg_id } => {
+                let Ok(msg_id) = Uuid::parse_str(msg_id.as_str()) else {
+                    warn!("Client with id {} acknowledged an invalid message id: {}", id, msg_id);
+                    continue;
+                };
+
+                // acks for messages that were already removed are ignored, the client may have received it twice
+                if let Err(e) = store.delete_msg(&msg_id, &id) {
+                    warn!("Failed to remove acknowledged message from the database: {}", e);
+                }
+            }
+            Packet::UserOnlineRequest { username } => {
+                // users that do not exist are never online, and users that blocked self never seem to be
+                let online = match store.get_id_from_username(&username) {
+                    Ok(user) => router.is_online(&user) && store.is_blocked(&id, &user) == Ok(false),
+                    Err(_) => false,
+                };
+                if connection.send(Packet::UserResponse { response: online }).is_err() {
+                    warn!("failed to send UserResponse to client.");
+                    break;
+                }
+            }
+            // without its keys nobody can message the user, so a failed upload ends the session instead of sending an error
+            // that the client would take as the reply to its next request
+            Packet::IdentityKeyUpload { key, signing_key } => {
+                if key.len() != KEY_LEN || signing_key.len() != KEY_LEN {
+                    if connection.send(Packet::Error {
+                        error: format!("Invalid identity key"),
+                        should_disconnect: true
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
+                    }
+                    break;
+                }
+                match store.set_identity_key(&id, &key, &signing_key) {
+                    // prekeys signed by the old identity can not be verified anymore
+                    Ok(true) => if let Err(e) = store.delete_prekeys(&id) {
+                        warn!("Failed to remove old prekeys of client with id {}: {}", id, e);
+                    },
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("Failed to store identity key of client with id {}: {}", id, e);
+                        if connection.send(Packet::Error {
+                            error: format!("Database error"),
+                            should_disconnect: true
+                        }).is_err() {
+                            warn!("failed to send error message to client.");
                         }
-                        if connection.send(Packet::UserResponse { response: exists.unwrap() }).is_err() {
-                            warn!("failed to send UserResponse to client.");
+                        break;
+                    }
+                }
+            }
+            Packet::IdentityKeyRequest { username } => {
+                let Ok(key) = store.get_identity_key(&username) else {
+                    if connection.send(Packet::Error {
+                        error: format!("Invalid username"),
+                        should_disconnect: false
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
+                        break;
+                    }
+                    continue;
+                };
+                if connection.send(Packet::IdentityKeyResponse { username, key }).is_err() {
+                    warn!("failed to send IdentityKeyResponse to client.");
+                    break;
+                }
+            }
+            Packet::SignedPreKeyUpload { prekey, signature } => {
+                // the server only hands out prekeys that were signed by the identity they are bundled with
+                let verified = match store.get_signing_key(&id) {
+                    Ok(Some(signing_key)) => verify_prekey(&signing_key, &prekey, &signature),
+                    Ok(None) => false,
+                    Err(e) => {
+                        warn!("Failed to read signing key of client with id {}: {}", id, e);
+                        false
+                    }
+                };
+                if prekey.key.len() != KEY_LEN || !verified {
+                    if connection.send(Packet::Error {
+                        error: format!("Invalid signed prekey, upload an identity key first"),
+                        should_disconnect: true
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
+                    }
+                    break;
+                }
+                if let Err(e) = store.set_signed_prekey(&id, &prekey, &signature) {
+                    warn!("Failed to store signed prekey of client with id {}: {}", id, e);
+                    if connection.send(Packet::Error {
+                        error: format!("Database error"),
+                        should_disconnect: true
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
+                    }
+                    break;
+                }
+            }
+            Packet::PreKeysUpload { prekeys } => {
+                if prekeys.iter().any(|prekey| prekey.key.len() != KEY_LEN) {
+                    if connection.send(Packet::Error {
+                        error: format!("Invalid prekey"),
+                        should_disconnect: true
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
+                    }
+                    break;
+                }
+                // one-time prekeys are optional, bundles work without them, so failing to store them is only logged
+                let stored = match store.count_one_time_prekeys(&id) {
+                    Ok(stored) => stored,
+                    Err(e) => {
+                        warn!("Failed to count prekeys of client with id {}: {}", id, e);
+                        continue;
+                    }
+                };
+                // the client may have answered several PreKeysLow before its first upload arrived, the extra prekeys are dropped
+                let room = (MAX_PREKEYS as i64 - stored).clamp(0, prekeys.len() as i64) as usize;
+                if room == 0 {
+                    continue;
+                }
+                if let Err(e) = store.add_one_time_prekeys(&id, &prekeys[..room]) {
+                    warn!("Failed to store prekeys of client with id {}: {}", id, e);
+                }
+            }
+            Packet::PreKeyBundleRequest { username } => {
+                let (owner, bundle) = match store.take_prekey_bundle(&username) {
+                    Ok(bundle) => bundle,
+                    Err(e) => {
+                        warn!("Failed to get prekey bundle: {}", e);
+                        if connection.send(Packet::Error {
+                            error: format!("Invalid username"),
+                            should_disconnect: false
+                        }).is_err() {
+                            warn!("failed to send error message to client.");
                             break;
                         }
+                        continue;
                     }
-                    Packet::MsgHistoryRequest { username } => {
-                        todo!()
+                };
+
+                // a bundle without a one-time prekey still works, but the owner should know to upload more
+                if bundle.is_some() {
+                    match store.count_one_time_prekeys(&owner) {
+                        Ok(remaining) if remaining < PREKEYS_LOW => {
+                            router.deliver(&owner, Packet::PreKeysLow { remaining: remaining as u32 });
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Failed to count prekeys of user with id {}: {}", owner, e),
                     }
-                    Packet::Disconnect => {
+                }
+
+                if connection.send(Packet::PreKeyBundleResponse { username, bundle }).is_err() {
+                    warn!("failed to send PreKeyBundleResponse to client.");
+                    break;
+                }
+            }
+            Packet::UserExistsRequest { username } => {
+                let exists = store.user_exists(&username);
+                if let Err(e) = exists {
+                    warn!("Client exists check failed to read database! Safely closing connection. Database Error: {}", e);
+                    break;
+                }
+                if connection.send(Packet::UserResponse { response: exists.unwrap() }).is_err() {
+                    warn!("failed to send UserResponse to client.");
+                    break;
+                }
+            }
+            Packet::MsgHistoryRequest { username, cursor, limit } => {
+                // the username can also be the id of a group
+                let conversation = match conversation_with(store.as_ref(), &username) {
+                    Ok(conversation) => conversation,
+                    Err(error) => {
+                        if connection.send(Packet::Error { error, should_disconnect: false }).is_err() {
+                            warn!("failed to send error message to client.");
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                let history = store.get_history(&id, &conversation, &cursor, limit);
+                if let Err(e) = history {
+                    warn!("Failed to read message history: {}", e);
+                    if connection.send(Packet::Error {
+                        error: format!("Failed to get message history"),
+                        should_disconnect: false
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
                         break;
                     }
-                    Packet::Error { error, should_disconnect } => {
-                        warn!("Client with id {} sent an error: {}.{}", id, error, if should_disconnect { " Disconnecting." } else { "" });
-                        if should_disconnect {
+                    continue;
+                }
+                let (page, more) = history.unwrap();
+
+                let history = page.into_iter().map(|msg| SentMsg {
+                    id: msg.id.to_string(),
+                    message: msg.message,
+                    sender: msg.sender,
+                    timestamp: msg.timestamp.to_string(),
+                    seq: msg.seq as u64,
+                    ttl: msg.ttl,
+                }).collect();
+                if connection.send(Packet::MsgHistory { history, more }).is_err() {
+                    warn!("failed to send MsgHistory to client.");
+                    break;
+                }
+            }
+            Packet::MsgRangeRequest { username, from, to } => {
+                // the username can also be the id of a group
+                let conversation = match conversation_with(store.as_ref(), &username) {
+                    Ok(conversation) => conversation,
+                    Err(error) => {
+                        if connection.send(Packet::Error { error, should_disconnect: false }).is_err() {
+                            warn!("failed to send error message to client.");
                             break;
                         }
+                        continue;
                     }
-                    _ => unreachable!()
+                };
+
+                // sequence numbers past what the database can hold are clamped, there are no messages there anyway
+                let range = store.get_history_range(&id, &conversation, from.min(i64::MAX as u64) as i64, to.min(i64::MAX as u64) as i64);
+                if let Err(e) = range {
+                    warn!("Failed to read message range: {}", e);
+                    if connection.send(Packet::Error {
+                        error: format!("Failed to get message history"),
+                        should_disconnect: false
+                    }).is_err() {
+                        warn!("failed to send error message to client.");
+                        break;
+                    }
+                    continue;
+                }
+                let (page, more) = range.unwrap();
+
+                let history = page.into_iter().map(|msg| SentMsg {
+                    id: msg.id.to_string(),
+                    message: msg.message,
+                    sender: msg.sender,
+                    timestamp: msg.timestamp.to_string(),
+                    seq: msg.seq as u64,
+                    ttl: msg.ttl,
+                }).collect();
+                if connection.send(Packet::MsgHistory { history, more }).is_err() {
+                    warn!("failed to send MsgHistory to client.");
+                    break;
                 }
             }
-        } else {
-            warn!("Failed to read from client; disconnecting.");
-            if connection.send(Packet::Error { error: format!("Invalid data received."),
-                should_disconnect: true }).is_err() {
-                warn!("Failed to send disconnect message to client. Most likely they already disconnected.");
+            Packet::Disconnect => {
+                break;
+            }
+            Packet::Error { error, should_disconnect } => {
+                warn!("Client with id {} sent an error: {}.{}", id, error, if should_disconnect { " Disconnecting." } else { "" });
+                if should_disconnect {
+                    break;
+                }
+            }
+            _ => {
+                // the client sent a packet that only the server should send, or one that is only valid before login
+                if connection.send(Packet::Error {
+                    error: format!("Unexpected packet"),
+                    should_disconnect: false
+                }).is_err() {
+                    warn!("failed to send error message to client.");
+                    break;
+                }
             }
-            break;
         }
     }
 
     tarc.store(true, Ordering::SeqCst);
-}
\ No newline at end of file
+}
@@ -1,11 +1,12 @@
-use dl_network_common::{Connection, ExpectedPacket, Packet};
+use dl_network_common::{Connection, Packet};
+use dl_network_common::transport::Transport;
 use crate::{ACCEPTED_CLIENT_VERSION, error, warn};
 
 /// Expect, read, and reply to a Ping from the client at the start of a connection
 /// returns true if disconnecting
-pub fn expect_ping(connection: &mut Connection) -> bool {
+pub fn expect_ping<T: Transport>(connection: &mut Connection<T>) -> bool {
     // read ping
-    let response = connection.expect(ExpectedPacket::Ping);
+    let response = connection.recv();
     if let Err(e) = response {
         error!("Failed to read ping request: {}", e);
         return true;
@@ -15,12 +16,60 @@ pub fn expect_ping(connection: &mut Connection) -> bool {
         Packet::Ping { version, disconnecting } => {
             let valid = version.as_str() == ACCEPTED_CLIENT_VERSION;
 
-            if let Err(_) = connection.send(Packet::PingResponse { valid, accepted_version: ACCEPTED_CLIENT_VERSION.to_string() }) {
+            if connection.send(Packet::PingResponse { valid, accepted_version: ACCEPTED_CLIENT_VERSION.to_string() }).is_err() {
                 warn!("Failed to send ping response to client. They may have disconnected");
                 return true;
             }
-            return disconnecting;
+            disconnecting
         }
-        _ => unreachable!()
+        Packet::Disconnect => true,
+        _ => {
+            warn!("Client sent a packet other than a ping at the start of a connection; disconnecting.");
+            if connection.send(Packet::Error { should_disconnect: true, error: format!("Expected a ping") }).is_err() {
+                warn!("Failed to send error to client. They may have disconnected");
+            }
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dl_network_common::transport::pipe;
+    use super::*;
+
+    // send packets from an in-memory client, returning if the server disconnects and what it replied
+    fn ping(packets: Vec<Packet>) -> (bool, Option<Packet>) {
+        let (client, server) = pipe();
+        let mut client = Connection::new(client);
+        for packet in packets {
+            client.send(packet).unwrap();
+        }
+        let disconnecting = expect_ping(&mut Connection::new(server));
+        (disconnecting, client.recv().ok())
+    }
+
+    #[test]
+    fn versions_are_checked() {
+        let accepted = ACCEPTED_CLIENT_VERSION.to_string();
+        let (disconnecting, reply) = ping(vec![Packet::Ping { version: accepted.clone(), disconnecting: false }]);
+        assert!(!disconnecting);
+        assert_eq!(reply, Some(Packet::PingResponse { valid: true, accepted_version: accepted.clone() }));
+
+        let (disconnecting, reply) = ping(vec![Packet::Ping { version: format!("0.0.0"), disconnecting: true }]);
+        assert!(disconnecting);
+        assert_eq!(reply, Some(Packet::PingResponse { valid: false, accepted_version: accepted }));
+    }
+
+    #[test]
+    fn anything_else_disconnects() {
+        let (disconnecting, reply) = ping(vec![Packet::UserOnlineRequest { username: format!("test") }]);
+        assert!(disconnecting);
+        assert!(matches!(reply, Some(Packet::Error { should_disconnect: true, .. })));
+
+        // the client hung up before pinging
+        let (client, server) = pipe();
+        drop(client);
+        assert!(expect_ping(&mut Connection::new(server)));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,286 @@
+use uuid::Uuid;
+use dl_network_common::{permissions, Channel, Connection, Packet, Role, Space, SpaceMember, MAX_SPACE_MEMBERS};
+use dl_network_common::transport::Transport;
+use crate::client::groups::fan_out;
+use crate::database::{Conversation, DBRole, DBSpace, Store};
+use crate::router::Router;
+use crate::warn;
+
+// the longest name a space, channel or role can have, in characters
+const MAX_SPACE_NAME: usize = 64;
+// the most channels and roles a space can have, each
+const MAX_SPACE_CHANNELS: usize = 100;
+const MAX_SPACE_ROLES: usize = 50;
+
+/// Answer a packet that manages a space or sends a message to one of its channels
+/// what a member may do is decided by the permissions of their roles, the owner may do everything
+/// the other members are told about each change with a SpaceUpdate
+/// @return: false if the client could not be answered and should be disconnected
+pub fn space_handler<T: Transport>(connection: &mut Connection<T>, store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> bool {
+    let reply = match handle(store, router, id, username, packet) {
+        Ok(reply) => reply,
+        Err(error) => Packet::Error { error, should_disconnect: false },
+    };
+    if connection.send(reply).is_err() {
+        warn!("failed to send reply to space packet to client.");
+        return false;
+    }
+    true
+}
+
+// the reply to a space packet, or the error to send back instead
+fn handle(store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> Result<Packet, String> {
+    match packet {
+        Packet::SpaceCreate { name } => {
+            let name = valid_name(&name, "Space")?;
+            // members can talk in the general channel until the owner decides otherwise
+            let space = store.create_space(name, id, permissions::SEND).map_err(database_error)?;
+            store.create_channel(&space, "general").map_err(database_error)?;
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space, None)? })
+        }
+        Packet::SpaceInvite { space, username: invited } => {
+            let space = member_of(store, id, &space)?;
+            if space.members.len() >= MAX_SPACE_MEMBERS {
+                return Err(format!("Spaces can have at most {} members", MAX_SPACE_MEMBERS));
+            }
+            let Ok(invited_id) = store.get_id_from_username(&invited) else {
+                return Err(format!("There is no user named {}", invited));
+            };
+            if space.banned.iter().any(|(banned, _)| *banned == invited_id) {
+                return Err(format!("{} is banned from the space", invited));
+            }
+            if !store.add_space_member(&space.id, &invited_id).map_err(database_error)? {
+                return Err(format!("{} is already in the space", invited));
+            }
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, None)? })
+        }
+        Packet::SpaceLeave { space } => {
+            let space = member_of(store, id, &space)?;
+            if space.owner == *id {
+                return Err(format!("The owner can not leave their space"));
+            }
+            store.remove_space_member(&space.id, id).map_err(database_error)?;
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, None)? })
+        }
+        Packet::SpaceKick { space, username: kicked } => {
+            let space = member_of(store, id, &space)?;
+            require(&space, username, permissions::KICK)?;
+            let Some(member) = space.members.iter().find(|member| member.username == kicked) else {
+                return Err(format!("{} is not in the space", kicked));
+            };
+            others_only(&space, id, &member.id)?;
+            store.remove_space_member(&space.id, &member.id).map_err(database_error)?;
+            // the kicked member is told too, so their client can stop showing the space
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, Some(&member.id))? })
+        }
+        Packet::SpaceBan { space, username: banned_name, banned } => {
+            let space = member_of(store, id, &space)?;
+            require(&space, username, permissions::BAN)?;
+            let Ok(banned_id) = store.get_id_from_username(&banned_name) else {
+                return Err(format!("There is no user named {}", banned_name));
+            };
+            others_only(&space, id, &banned_id)?;
+            if !store.set_space_ban(&space.id, &banned_id, banned).map_err(database_error)? {
+                return Err(if banned { format!("{} is already banned", banned_name) } else { format!("{} is not banned", banned_name) });
+            }
+
+            // a banned member is removed straight away
+            let mut removed = None;
+            if banned && space.members.iter().any(|member| member.id == banned_id) {
+                store.remove_space_member(&space.id, &banned_id).map_err(database_error)?;
+                removed = Some(&banned_id);
+            }
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, removed)? })
+        }
+        Packet::ChannelCreate { space, name } => {
+            let space = member_of(store, id, &space)?;
+            require(&space, username, permissions::MANAGE_CHANNELS)?;
+            let name = valid_name(&name, "Channel")?;
+            if space.channels.len() >= MAX_SPACE_CHANNELS {
+                return Err(format!("Spaces can have at most {} channels", MAX_SPACE_CHANNELS));
+            }
+            if space.channels.iter().any(|(_, channel)| channel == name) {
+                return Err(format!("There already is a channel named {}", name));
+            }
+            store.create_channel(&space.id, name).map_err(database_error)?;
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, None)? })
+        }
+        Packet::ChannelDelete { space, channel } => {
+            let space = member_of(store, id, &space)?;
+            require(&space, username, permissions::MANAGE_CHANNELS)?;
+            let Ok(channel) = Uuid::parse_str(&channel) else {
+                return Err(format!("Invalid channel"));
+            };
+            if !store.delete_channel(&space.id, &channel).map_err(database_error)? {
+                return Err(format!("Invalid channel"));
+            }
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, None)? })
+        }
+        Packet::RoleSet { space, role } => {
+            let space = member_of(store, id, &space)?;
+            let allowed = require(&space, username, permissions::MANAGE_ROLES)?;
+            if role.permissions & !permissions::ALL != 0 {
+                return Err(format!("Unknown permission"));
+            }
+            if role.permissions & !allowed != 0 {
+                return Err(format!("You can only give out permissions you have"));
+            }
+
+            let role = if role.id.is_empty() {
+                if space.roles.len() >= MAX_SPACE_ROLES {
+                    return Err(format!("Spaces can have at most {} roles", MAX_SPACE_ROLES));
+                }
+                DBRole { id: Uuid::new_v4(), name: valid_name(&role.name, "Role")?.to_string(), permissions: role.permissions }
+            } else {
+                let old = role_of(&space, &role.id, allowed)?;
+                // the everyone role keeps its name, only what it allows can change
+                let name = if old.id == space.id { old.name.clone() } else { valid_name(&role.name, "Role")?.to_string() };
+                DBRole { id: old.id, name, permissions: role.permissions }
+            };
+            store.set_role(&space.id, &role).map_err(database_error)?;
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, None)? })
+        }
+        Packet::RoleDelete { space, role } => {
+            let space = member_of(store, id, &space)?;
+            let allowed = require(&space, username, permissions::MANAGE_ROLES)?;
+            let role = role_of(&space, &role, allowed)?;
+            if role.id == space.id {
+                return Err(format!("The everyone role can not be deleted"));
+            }
+            store.delete_role(&space.id, &role.id).map_err(database_error)?;
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, None)? })
+        }
+        Packet::RoleAssign { space, role, username: member_name, assigned } => {
+            let space = member_of(store, id, &space)?;
+            let allowed = require(&space, username, permissions::MANAGE_ROLES)?;
+            let role = role_of(&space, &role, allowed)?;
+            if role.id == space.id {
+                return Err(format!("Every member has the everyone role"));
+            }
+            let Some(member) = space.members.iter().find(|member| member.username == member_name) else {
+                return Err(format!("{} is not in the space", member_name));
+            };
+            if !store.assign_role(&space.id, &role.id, &member.id, assigned).map_err(database_error)? {
+                return Err(if assigned {
+                    format!("{} already has the role {}", member_name, role.name)
+                } else {
+                    format!("{} does not have the role {}", member_name, role.name)
+                });
+            }
+            Ok(Packet::SpaceInfo { space: changed(store, router, id, &space.id, None)? })
+        }
+        Packet::SpaceListRequest => {
+            let spaces = store.get_user_spaces(id).map_err(database_error)?;
+            Ok(Packet::SpaceList { spaces: spaces.into_iter().map(to_space).collect() })
+        }
+        Packet::ChannelMessage { channel, copies } => {
+            let Ok(channel) = Uuid::parse_str(&channel) else {
+                return Err(format!("Invalid channel"));
+            };
+            let space = channel_space(store, id, username, &channel, permissions::SEND)?;
+
+            let members: Vec<(Uuid, String)> = space.members.into_iter().map(|member| (member.id, member.username)).collect();
+            let ttl = store.get_timer(id, &Conversation::Channel(channel)).map_err(database_error)?;
+            fan_out(store, router, id, username, &channel, &members, copies, ttl, "space",
+                    |stored, timestamp, ttl| store.store_channel_msg(&channel, id, stored, timestamp, ttl))
+        }
+        _ => Err(format!("Unexpected packet")),
+    }
+}
+
+/// The space a channel is in, if the user is one of its members and is allowed everything that is needed
+pub fn channel_space(store: &dyn Store, id: &Uuid, username: &str, channel: &Uuid, needed: u32) -> Result<DBSpace, String> {
+    let Some(space) = store.get_channel_space(channel).map_err(database_error)? else {
+        return Err(format!("Invalid channel"));
+    };
+    let space = member_of(store, id, &space.to_string())?;
+    require(&space, username, needed)?;
+    Ok(space)
+}
+
+// the space with this id, if the user is one of its members
+fn member_of(store: &dyn Store, id: &Uuid, space: &str) -> Result<DBSpace, String> {
+    let Ok(space) = Uuid::parse_str(space) else {
+        return Err(format!("Invalid space"));
+    };
+    match store.get_space(&space).map_err(database_error)? {
+        Some(space) if space.members.iter().any(|member| member.id == *id) => Ok(space),
+        Some(_) => Err(format!("You are not in this space")),
+        None => Err(format!("Invalid space")),
+    }
+}
+
+// every permission the member has, if it includes the one needed
+fn require(space: &DBSpace, username: &str, needed: u32) -> Result<u32, String> {
+    let allowed = to_space(space.clone()).permissions(username);
+    if allowed & needed != needed {
+        return Err(format!("You do not have permission to do that in this space"));
+    }
+    Ok(allowed)
+}
+
+// the role of a space with this id, if it allows nothing the member is not allowed
+// so nobody can change or hand out a role that can do more than they can
+fn role_of<'a>(space: &'a DBSpace, role: &str, allowed: u32) -> Result<&'a DBRole, String> {
+    let Some(role) = Uuid::parse_str(role).ok().and_then(|role| space.roles.iter().find(|other| other.id == role)) else {
+        return Err(format!("Invalid role"));
+    };
+    if role.permissions & !allowed != 0 {
+        return Err(format!("You can only manage roles with permissions you have"));
+    }
+    Ok(role)
+}
+
+// kicks and bans can not be used on oneself or the owner
+fn others_only(space: &DBSpace, id: &Uuid, target: &Uuid) -> Result<(), String> {
+    if target == id {
+        return Err(format!("Leave the space instead of removing yourself"));
+    }
+    if *target == space.owner {
+        return Err(format!("The owner can not be removed from their space"));
+    }
+    Ok(())
+}
+
+// tell the members of a space other than the one who changed it, and a removed member, what it looks like now
+// returns the space for the reply to the member who changed it
+fn changed(store: &dyn Store, router: &Router, by: &Uuid, space: &Uuid, removed: Option<&Uuid>) -> Result<Space, String> {
+    let Some(space) = store.get_space(space).map_err(database_error)? else {
+        return Err(format!("Invalid space"));
+    };
+    let told = space.members.iter().map(|member| &member.id).chain(removed).filter(|member| *member != by);
+    for member in told {
+        router.deliver(member, Packet::SpaceUpdate { space: to_space(space.clone()) });
+    }
+    Ok(to_space(space))
+}
+
+fn to_space(space: DBSpace) -> Space {
+    // the owner is always a member, they are only missing if the space was left in a broken state
+    let owner = space.members.iter().find(|member| member.id == space.owner).map(|member| member.username.clone()).unwrap_or_default();
+    Space {
+        id: space.id.to_string(),
+        name: space.name,
+        owner,
+        channels: space.channels.into_iter().map(|(id, name)| Channel { id: id.to_string(), name }).collect(),
+        roles: space.roles.into_iter().map(|role| Role { id: role.id.to_string(), name: role.name, permissions: role.permissions }).collect(),
+        members: space.members.into_iter().map(|member| SpaceMember {
+            username: member.username,
+            roles: member.roles.iter().map(Uuid::to_string).collect(),
+        }).collect(),
+        banned: space.banned.into_iter().map(|(_, username)| username).collect(),
+    }
+}
+
+fn valid_name<'a>(name: &'a str, what: &str) -> Result<&'a str, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_SPACE_NAME {
+        return Err(format!("{} names must be 1 to {} characters long", what, MAX_SPACE_NAME));
+    }
+    Ok(name)
+}
+
+fn database_error(e: String) -> String {
+    warn!("Database error while handling a space packet: {}", e);
+    format!("Database error")
+}
@@ -0,0 +1,84 @@
+use uuid::Uuid;
+use dl_network_common::{permissions, Connection, Packet, Timer, MAX_MESSAGE_TTL};
+use dl_network_common::transport::Transport;
+use crate::client::groups::{self, conversation_with};
+use crate::client::spaces::channel_space;
+use crate::database::{Conversation, DBTimer, Store};
+use crate::router::Router;
+use crate::warn;
+
+/// Answer a packet that sets the disappearing message timer of a conversation, or asks for every timer
+/// the timer is shared by the whole conversation, everyone else in it is told about each change with a TimerUpdate
+/// @return: false if the client could not be answered and should be disconnected
+pub fn timer_handler<T: Transport>(connection: &mut Connection<T>, store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> bool {
+    let reply = match handle(store, router, id, username, packet) {
+        Ok(reply) => reply,
+        Err(error) => Packet::Error { error, should_disconnect: false },
+    };
+    if connection.send(reply).is_err() {
+        warn!("failed to send reply to timer packet to client.");
+        return false;
+    }
+    true
+}
+
+// the reply to a timer packet, or the error to send back instead
+fn handle(store: &dyn Store, router: &Router, id: &Uuid, username: &str, packet: Packet) -> Result<Packet, String> {
+    match packet {
+        Packet::TimerSet { conversation: name, ttl } => {
+            if ttl > MAX_MESSAGE_TTL {
+                return Err(format!("Messages can disappear after at most {} seconds", MAX_MESSAGE_TTL));
+            }
+            let conversation = conversation_with(store, &name)?;
+            // everyone else in the conversation, and what the conversation is called for them
+            let (others, shared) = match conversation {
+                Conversation::Direct(other) => {
+                    if other == *id {
+                        return Err(format!("You can not set a timer with yourself"));
+                    }
+                    // a user that blocked the sender does not get their messages, so they do not get to change the timer either
+                    if store.is_blocked(id, &other).map_err(database_error)? {
+                        return Err(format!("{} is not accepting your messages", name));
+                    }
+                    (vec![other], username.to_string())
+                }
+                Conversation::Group(group) => {
+                    let group = groups::member_of(store, id, &group.to_string())?;
+                    (group.members.into_iter().map(|(member, _)| member).filter(|member| member != id).collect(), group.id.to_string())
+                }
+                Conversation::Channel(channel) => {
+                    let space = channel_space(store, id, username, &channel, permissions::MANAGE_CHANNELS)?;
+                    (space.members.into_iter().map(|member| member.id).filter(|member| member != id).collect(), channel.to_string())
+                }
+            };
+
+            store.set_timer(id, &conversation, ttl).map_err(database_error)?;
+            // members that are offline get every timer when they log in again
+            for other in others {
+                router.deliver(&other, Packet::TimerUpdate { timer: Timer { conversation: shared.clone(), ttl, set_by: username.to_string() } });
+            }
+            let conversation = if matches!(conversation, Conversation::Direct(_)) { name } else { shared };
+            Ok(Packet::TimerInfo { timer: Timer { conversation, ttl, set_by: username.to_string() } })
+        }
+        Packet::TimerListRequest => {
+            let timers = store.get_user_timers(id).map_err(database_error)?;
+            let timers = timers.into_iter().map(|timer| to_timer(store, timer)).collect::<Result<Vec<Timer>, String>>()?;
+            Ok(Packet::TimerList { timers })
+        }
+        _ => Err(format!("Unexpected packet")),
+    }
+}
+
+// a stored timer as it is sent to a client, which names the other user of a direct conversation
+fn to_timer(store: &dyn Store, timer: DBTimer) -> Result<Timer, String> {
+    let conversation = match timer.conversation {
+        Conversation::Direct(other) => store.get_username_from_id(&other).map_err(database_error)?,
+        Conversation::Group(target) | Conversation::Channel(target) => target.to_string(),
+    };
+    Ok(Timer { conversation, ttl: timer.ttl, set_by: timer.set_by })
+}
+
+fn database_error(e: String) -> String {
+    warn!("Database error while handling a timer packet: {}", e);
+    format!("Database error")
+}
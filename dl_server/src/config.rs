@@ -5,26 +5,62 @@ use std::io::{Read, Write};
 use serde::Deserialize;
 use crate::error;
 
+/// The addresses to listen on, either one or a list of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Addresses {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Addresses {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Addresses::One(ip) => vec![ip],
+            Addresses::Many(ips) => ips,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Server {
-    pub ip: Option<String>,
+    pub ip: Option<Addresses>,
     pub port: Option<String>
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DBCfg {
+    pub backend: Option<String>,
+    pub path: Option<String>,
     pub ip: Option<String>,
     pub port: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Passwords {
+    pub memory_kib: Option<u32>,
+    pub iterations: Option<u32>,
+    pub parallelism: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    pub enabled: Option<bool>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub server: Option<Server>,
     pub database: Option<DBCfg>,
+    pub passwords: Option<Passwords>,
+    pub tls: Option<Tls>,
 }
 
+#[allow(clippy::result_unit_err)]
 pub fn config_path<S: Into<String>>(file_name: S) -> Result<String, ()> {
     let cdir_r = std::env::current_dir();
     if let Err(e) = cdir_r {
@@ -62,7 +98,7 @@ pub fn read_config(path: &Path, default: String) -> Config {
         .create(true)
         .append(true)
         .read(true)
-        .open(path.clone())
+        .open(path)
         .expect("An error occurred in opening the config file.");
 
     let mut data = read_config_raw(&mut file);
@@ -77,5 +113,5 @@ pub fn read_config(path: &Path, default: String) -> Config {
         data = default;
     }
 
-    toml::from_str(data.as_str()).expect(format!("Could not read config file `{}`!", path.to_str().unwrap()).as_str())
+    toml::from_str(data.as_str()).unwrap_or_else(|_| panic!("Could not read config file `{}`!", path.to_str().unwrap()))
 }
\ No newline at end of file
@@ -1,13 +1,31 @@
 use std::path::Path;
+use std::sync::Arc;
 use chrono::{DateTime, Utc};
-use r2d2_postgres::postgres::NoTls;
-use r2d2_postgres::PostgresConnectionManager;
-use r2d2_postgres::r2d2::PooledConnection;
 use uuid::Uuid;
+use dl_network_common::crypto::{PreKeyBundle, PublicPreKey, SealedPayload};
+use dl_network_common::{ContactState, HistoryCursor};
 use crate::config::{config_path, read_config};
+use crate::database::migrations::Migration;
 use crate::warn;
 
+pub mod postgres;
+pub mod sqlite;
+pub mod memory;
+pub mod migrations;
+
+/// Where users and messages are stored
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Postgres,
+    /// an embedded database in a single file, for small servers
+    Sqlite,
+    /// nothing is kept once the server stops, for testing
+    Memory,
+}
+
 pub struct DBInfo {
+    pub backend: Backend,
+    pub path: String,
     pub ip: String,
     pub port: String,
     pub uname: String,
@@ -22,6 +40,13 @@ pub fn get_db_address() -> Result<DBInfo, String> {
     let raw_path = Path::new(&cfg_path);
     let config = read_config(raw_path, format!("\
         [database]\
+        \n# backend: where users and messages are stored, \"postgres\", \"sqlite\" or \"memory\"\
+        \n# memory keeps nothing once the server stops and is only meant for testing\
+        \n# defaults to postgres\
+        \nbackend = \"postgres\"\
+        \n# path: the database file when using sqlite\
+        \n# defaults to config/delta_lima.db\
+        \npath = \"config/delta_lima.db\"\
         \n# ip: the ip to connect to\
         \n# surround with '[' and ']' for Ipv6 addresses\
         \n# Can be set to localhost\
@@ -36,218 +61,713 @@ pub fn get_db_address() -> Result<DBInfo, String> {
         \npassword = \"admin\""));
 
     // set default values for the config
+    let mut backend = Backend::Postgres;
+    let mut path = format!("config/delta_lima.db");
     let mut ip = format!("localhost");
     let mut port = format!("5432");
     let mut username = format!("postgres");
     let mut password = format!("admin");
 
     if let Some(dbcfg) = config.database {
+        // configs written before there was a choice of backend are for postgres
+        if let Some(backendv) = dbcfg.backend {
+            backend = match backendv.to_lowercase().as_str() {
+                "postgres" | "postgresql" => Backend::Postgres,
+                "sqlite" => Backend::Sqlite,
+                "memory" => Backend::Memory,
+                _ => return Err(format!("Unknown backend `{}` in `~/config/database.toml`, expected postgres, sqlite or memory", backendv)),
+            };
+        }
+        if let Some(pathv) = dbcfg.path {
+            path = pathv;
+        } else if backend == Backend::Sqlite {
+            warn!("Failed to read path value from `~/config/database.toml`");
+        }
+
+        // the connection settings are only needed for postgres
+        let postgres = backend == Backend::Postgres;
         if let Some(ipv) = dbcfg.ip {
             ip = ipv;
-        } else {
+        } else if postgres {
             warn!("Failed to read ip value from `~/config/database.toml`");
         }
         if let Some(portv) = dbcfg.port {
             port = portv;
-        } else {
+        } else if postgres {
             warn!("Failed to read port value from `~/config/database.toml`");
         }
         if let Some(usernamev) = dbcfg.username {
             username = usernamev;
-        } else {
+        } else if postgres {
             warn!("Failed to read username value from `~/config/database.toml`");
         }
         if let Some(passv) = dbcfg.password {
             password = passv;
-        } else {
+        } else if postgres {
             warn!("Failed to read password value from `~/config/database.toml`");
         }
     }
 
     Ok(DBInfo {
+        backend, path,
         ip, port,
         uname: username,
         pass: password,
     })
 }
 
-// == UNSENT_MSGS
-
-pub fn insert_msg(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, sender: &Uuid, recipient: &Uuid, message: String, timestamp: DateTime<Utc>) -> Result<(), String> {
-    if let Err(e) = db.execute(
-        "INSERT INTO messages(sender, recipient, message, timestamp, id) VALUES ($1, $2, $3, $4, $5)",
-        &[&sender, &recipient, &(message.as_str()), &timestamp, &(Uuid::new_v4())]) {
-        return Err(format!("insert_msg.{}", e));
-    }
-
-    Ok(())
+/// Connect to the configured backend, its schema is brought up to date with `Store::migrate`
+pub fn open_store(info: &DBInfo) -> Result<Arc<dyn Store>, String> {
+    Ok(match info.backend {
+        Backend::Postgres => Arc::new(postgres::PostgresStore::connect(info)?),
+        Backend::Sqlite => Arc::new(sqlite::SqliteStore::open(Path::new(&info.path))?),
+        Backend::Memory => Arc::new(memory::MemoryStore::new()),
+    })
 }
 
 pub struct DBMessageQuery {
     pub id: Uuid,
     pub sender: String,
-    pub message: String,
+    pub message: SealedPayload,
     pub timestamp: DateTime<Utc>,
+    pub seq: i64,
+    /// the group or channel the message was sent in, None for a message between two users
+    pub group: Option<Uuid>,
+    /// seconds after its timestamp the message is purged, 0 if it is kept
+    pub ttl: u64,
 }
 
-pub fn get_next_msg(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, receiver: &Uuid) -> Result<Option<DBMessageQuery>, String> {
-    // todo(skepz): timestamps need to be handled here
-    let msg_query_result = db.query(
-        "SELECT sender, message, id, timestamp FROM messages WHERE recipient=$1",
-        &[&receiver]);
-    if let Err(e) = msg_query_result {
-        warn!("{}", e);
-        return Err(format!("get_next_msg.{}", e));
-    }
-    let msg_query = msg_query_result.unwrap();
-
-    if msg_query.len() == 0 {
-        return Ok(None);
-    }
-    let msg = msg_query.get(0).unwrap();
+/// One member's copy of a group or channel message
+pub struct DBGroupCopy {
+    pub id: Uuid,
+    pub recipient: Uuid,
+    pub message: SealedPayload,
+}
 
-    let sender: Uuid = msg.get(0);
-    let message: String = msg.get(1);
-    let id: Uuid = msg.get(2);
-    let timestamp: DateTime<Utc> = msg.get(3);
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBGroup {
+    pub id: Uuid,
+    pub name: String,
+    /// the id and username of every member, ordered by username
+    pub members: Vec<(Uuid, String)>,
+}
 
-    // get the username of the sender
-    let sender_name = get_username_from_id(db, &sender);
-    if let Err(e) = sender_name {
-        return Err(format!("Failed to get sender of an unset message: {}", e));
-    }
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBSpace {
+    pub id: Uuid,
+    pub name: String,
+    pub owner: Uuid,
+    /// the id and name of every channel, ordered by name
+    pub channels: Vec<(Uuid, String)>,
+    /// every role including the everyone role, ordered by name
+    pub roles: Vec<DBRole>,
+    /// ordered by username
+    pub members: Vec<DBSpaceMember>,
+    /// the id and username of every banned user, ordered by username
+    pub banned: Vec<(Uuid, String)>,
+}
 
-    // return the message
-    Ok(Some(DBMessageQuery {
-        id, message, sender: sender_name.unwrap(), timestamp
-    }))
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBRole {
+    pub id: Uuid,
+    pub name: String,
+    pub permissions: u32,
 }
 
-pub fn delete_msg(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, id: &Uuid) -> Result<(), String> {
-    if let Err(e) = db.execute("DELETE FROM messages WHERE id=$1",
-                                     &[&id]) {
-        return Err(format!("A message could not be removed from the database! {}", e));
-    }
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBSpaceMember {
+    pub id: Uuid,
+    pub username: String,
+    /// the ids of the roles the member was given, ordered by id
+    pub roles: Vec<Uuid>,
+}
 
-    Ok(())
+/// Another user and how they are related to the user that asked, never ContactState::None
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBContact {
+    pub id: Uuid,
+    pub username: String,
+    pub state: ContactState,
 }
 
-// == USER_DATA
+/// The disappearing message timer of a conversation, only kept while it is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBTimer {
+    pub conversation: Conversation,
+    pub ttl: u64,
+    /// the username of who set it last
+    pub set_by: String,
+}
 
-pub fn insert_user(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, username: String, password: String) -> Result<Uuid, String> {
-    let id = Uuid::new_v4();
-    if let Err(e) = db.execute("INSERT INTO user_data(id, username, password, online) VALUES ($1, $2, $3, false)",
-                               &[&id, &(username.as_str()), &(password.as_str())]) {
-        return Err(format!("insert_user.{}", e));
-    }
-    Ok(id)
+/// Who the messages of a conversation are between, besides the user asking for them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conversation {
+    /// another user
+    Direct(Uuid),
+    /// the members of a group, every member only sees the copies sealed for them
+    Group(Uuid),
+    /// the members of a space in one of its channels, stored like the messages of a group
+    Channel(Uuid),
 }
 
-pub fn user_exists(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, username: String) -> Result<bool, String> {
-    let query_result = db.query(
-        "SELECT id FROM user_data WHERE username=$1", &[&username]);
-    if let Err(e) = query_result {
-        return Err(format!("get_username_from_id.{}", e));
-    }
-    let user_rows = query_result.unwrap();
+/// The most messages that will be sent in one page of history
+pub const MAX_HISTORY_PAGE: u32 = 100;
 
-    Ok(user_rows.len() != 0)
-}
+/// Clients are asked for more one-time prekeys when fewer than this are left
+pub const PREKEYS_LOW: i64 = 10;
 
-pub fn get_username_from_id(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, id: &Uuid) -> Result<String, String> {
-    let query_result = db.query(
-        "SELECT username FROM user_data WHERE id=$1", &[&id]);
-    if let Err(e) = query_result {
-        return Err(format!("get_username_from_id.{}", e));
-    }
-    let user_rows = query_result.unwrap();
-    if user_rows.len() > 1 {
-        return Err(format!("Multiple users with the same username found!"));
-    }
-    if user_rows.len() == 0 {
-        return Err(format!("Invalid id!"));
-    }
-    let user = user_rows.get(0).unwrap();
+/// Everything the server stores about users, their messages and their keys
+/// every backend must behave the same, the handlers never know which one they are using
+pub trait Store: Send + Sync {
+    /// Apply every migration the schema is missing, see `migrations`
+    /// returns the migrations that were applied, and fails if the schema is newer than this server
+    fn migrate(&self) -> Result<Vec<&'static Migration>, String>;
 
-    Ok(user.get(0))
-}
+    // == MESSAGES
 
-pub fn get_id_from_username(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, username: String) -> Result<Uuid, String> {
-    let id_query = db.query(
-        format!("SELECT id FROM user_data WHERE username=$1").as_str(), &[&username]);
-    if let Err(e) = id_query {
-        return Err(format!("get_id_from_username.{}", e));
-    }
-    let user_rows = id_query.unwrap();
-    if user_rows.len() > 1 {
-        return Err(format!("Multiple users with the same username found!"));
-    }
-    if user_rows.len() == 0 {
-        return Err(format!("Invalid username"));
-    }
-    let row = user_rows.get(0).unwrap();
+    /// Store a new message in the history of its conversation and queue it for the recipient
+    /// a message with a ttl is purged that many seconds after its timestamp, 0 keeps it
+    /// returns the sequence number the message was given in the conversation
+    fn store_msg(&self, id: &Uuid, sender: &Uuid, recipient: &Uuid, message: &SealedPayload, timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String>;
+
+    /// Store a message sent to a group, its copies all get the next sequence number of the group, which is returned
+    /// every copy is kept in the history and queued for its recipient
+    /// the copies are purged like a message stored with `store_msg` once their ttl has passed
+    fn store_group_msg(&self, group: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String>;
+
+    /// Store a message sent to a channel like `store_group_msg`, numbered by the channel
+    fn store_channel_msg(&self, channel: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String>;
+
+    /// Get every message waiting to be acknowledged by a user, in sequence order for each conversation
+    fn get_queued_msgs(&self, receiver: &Uuid) -> Result<Vec<DBMessageQuery>, String>;
+
+    /// Remove a message from the queue once its recipient has acknowledged it
+    /// returns false if there was no such message waiting for the recipient
+    fn delete_msg(&self, id: &Uuid, recipient: &Uuid) -> Result<bool, String>;
+
+    /// Remove every message whose ttl has passed at `now` from the history and the queue
+    /// returns how many messages were removed
+    fn purge_expired_msgs(&self, now: DateTime<Utc>) -> Result<usize, String>;
+
+    // == HISTORY
+
+    /// Get a page of a conversation of a user, oldest message first
+    /// returns the page and if there are more messages past it in the direction of the cursor
+    fn get_history(&self, user: &Uuid, conversation: &Conversation, cursor: &HistoryCursor, limit: u32) -> Result<(Vec<DBMessageQuery>, bool), String>;
+
+    /// Get the messages of a conversation of a user with a sequence number from `from` to `to`, inclusive
+    /// returns at most MAX_HISTORY_PAGE messages, and if there were more in the range
+    fn get_history_range(&self, user: &Uuid, conversation: &Conversation, from: i64, to: i64) -> Result<(Vec<DBMessageQuery>, bool), String>;
+
+    // == GROUPS
+
+    /// Create a group with its first members, returning its id
+    fn create_group(&self, name: &str, members: &[Uuid]) -> Result<Uuid, String>;
+
+    /// Get a group and its members, None if there is no such group
+    fn get_group(&self, group: &Uuid) -> Result<Option<DBGroup>, String>;
+
+    /// Get every group a user is a member of, ordered by name
+    fn get_user_groups(&self, user: &Uuid) -> Result<Vec<DBGroup>, String>;
+
+    /// returns false if the user already was a member
+    fn add_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String>;
+
+    /// returns false if the user was not a member
+    fn remove_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String>;
+
+    fn rename_group(&self, group: &Uuid, name: &str) -> Result<(), String>;
+
+    // == SPACES
+
+    /// Create a space with its owner as the only member, returning its id
+    /// the everyone role is created with it, with the id of the space and the given permissions
+    fn create_space(&self, name: &str, owner: &Uuid, everyone: u32) -> Result<Uuid, String>;
+
+    /// Get a space with its channels, roles, members and bans, None if there is no such space
+    fn get_space(&self, space: &Uuid) -> Result<Option<DBSpace>, String>;
+
+    /// Get every space a user is a member of, ordered by name
+    fn get_user_spaces(&self, user: &Uuid) -> Result<Vec<DBSpace>, String>;
+
+    /// returns false if the user already was a member
+    fn add_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String>;
+
+    /// Remove a member and every role they were given
+    /// returns false if the user was not a member
+    fn remove_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String>;
+
+    /// Ban a user from a space or lift their ban, banning a member does not remove them
+    /// returns false if the user already was banned, or was not banned
+    fn set_space_ban(&self, space: &Uuid, user: &Uuid, banned: bool) -> Result<bool, String>;
+
+    /// Add a channel to a space, returning its id
+    fn create_channel(&self, space: &Uuid, name: &str) -> Result<Uuid, String>;
+
+    /// returns false if the space has no such channel
+    fn delete_channel(&self, space: &Uuid, channel: &Uuid) -> Result<bool, String>;
+
+    /// Get the id of the space a channel is in, None if there is no such channel
+    fn get_channel_space(&self, channel: &Uuid) -> Result<Option<Uuid>, String>;
+
+    /// Create a role, or change the name and permissions of the role of the space with its id
+    fn set_role(&self, space: &Uuid, role: &DBRole) -> Result<(), String>;
+
+    /// Delete a role and take it away from every member
+    /// returns false if the space has no such role
+    fn delete_role(&self, space: &Uuid, role: &Uuid) -> Result<bool, String>;
+
+    /// Give a role to a member or take it away
+    /// returns false if the member already had the role, or did not have it
+    fn assign_role(&self, space: &Uuid, role: &Uuid, user: &Uuid, assigned: bool) -> Result<bool, String>;
+
+    // == CONTACTS
+
+    /// Get every contact of a user and every unanswered request they sent or were sent, ordered by username
+    fn get_contacts(&self, user: &Uuid) -> Result<Vec<DBContact>, String>;
+
+    /// How another user is related to a user
+    fn get_contact_state(&self, user: &Uuid, other: &Uuid) -> Result<ContactState, String>;
 
-    Ok(row.get(0))
+    /// Store a request from one user to another
+    /// returns false if there already is a request or contact between them
+    fn add_contact_request(&self, from: &Uuid, to: &Uuid) -> Result<bool, String>;
+
+    /// Accept the request `from` sent to `user`, making them contacts
+    /// returns false if there is no such request
+    fn accept_contact_request(&self, user: &Uuid, from: &Uuid) -> Result<bool, String>;
+
+    /// Remove a contact or a request between two users, whichever of them sent it
+    /// returns false if there was none
+    fn remove_contact(&self, user: &Uuid, other: &Uuid) -> Result<bool, String>;
+
+    // == BLOCKS
+
+    /// Get the usernames of every user a user blocked, ordered by username
+    fn get_blocked(&self, user: &Uuid) -> Result<Vec<String>, String>;
+
+    /// Get every user that blocked a user
+    fn get_blockers(&self, user: &Uuid) -> Result<Vec<Uuid>, String>;
+
+    /// Whether `by` blocked `user`
+    fn is_blocked(&self, user: &Uuid, by: &Uuid) -> Result<bool, String>;
+
+    /// Block another user or stop blocking them
+    /// returns false if the user was already blocked, or was not blocked
+    fn set_blocked(&self, user: &Uuid, other: &Uuid, blocked: bool) -> Result<bool, String>;
+
+    // == TIMERS
+
+    /// Set the disappearing message timer of a conversation of a user, 0 turns it off
+    /// the timer is shared by everyone in the conversation
+    fn set_timer(&self, user: &Uuid, conversation: &Conversation, ttl: u64) -> Result<(), String>;
+
+    /// Get the disappearing message timer of a conversation of a user, 0 if it is not set
+    fn get_timer(&self, user: &Uuid, conversation: &Conversation) -> Result<u64, String>;
+
+    /// Get every timer that is set in a conversation of a user, with another user, or in a group or space they are a member of
+    fn get_user_timers(&self, user: &Uuid) -> Result<Vec<DBTimer>, String>;
+
+    // == KEYS
+
+    /// Set the public identity and signing keys of a user
+    /// returns true if they are different from the keys that were stored before
+    fn set_identity_key(&self, id: &Uuid, key: &[u8], signing_key: &[u8]) -> Result<bool, String>;
+
+    /// Get the public identity key of a user, None if they have not uploaded one
+    fn get_identity_key(&self, username: &str) -> Result<Option<Vec<u8>>, String>;
+
+    /// Get the key a user's prekeys are signed with, None if they have not uploaded one
+    fn get_signing_key(&self, id: &Uuid) -> Result<Option<Vec<u8>>, String>;
+
+    /// Remove every prekey of a user, used when they are no longer signed by the user's identity
+    fn delete_prekeys(&self, id: &Uuid) -> Result<(), String>;
+
+    fn set_signed_prekey(&self, id: &Uuid, prekey: &PublicPreKey, signature: &[u8]) -> Result<(), String>;
+
+    /// Store a batch of one-time prekeys, keys with an id that is already stored are skipped
+    fn add_one_time_prekeys(&self, id: &Uuid, prekeys: &[PublicPreKey]) -> Result<(), String>;
+
+    fn count_one_time_prekeys(&self, id: &Uuid) -> Result<i64, String>;
+
+    /// Get the prekey bundle of a user, removing the one-time prekey it contains so it is never handed out again
+    /// returns the id of the user with the bundle, or None for the bundle if the user has not published one
+    fn take_prekey_bundle(&self, username: &str) -> Result<(Uuid, Option<PreKeyBundle>), String>;
+
+    // == USER_DATA
+
+    /// Add a new user with an already hashed password, failing if the username is taken
+    fn insert_user(&self, username: &str, password: &str) -> Result<Uuid, String>;
+
+    fn set_user_password(&self, id: &Uuid, password: &str) -> Result<(), String>;
+
+    fn user_exists(&self, username: &str) -> Result<bool, String>;
+
+    fn get_username_from_id(&self, id: &Uuid) -> Result<String, String>;
+
+    fn get_id_from_username(&self, username: &str) -> Result<Uuid, String>;
+
+    /// Get the id and stored password of a user
+    fn get_user_from_username(&self, username: &str) -> Result<(Uuid, String), String>;
+
+    // == PRESENCE
+
+    fn set_id_online(&self, id: &Uuid, online: bool) -> Result<(), String>;
+
+    fn is_username_online(&self, username: &str) -> Result<bool, String>;
+
+    fn is_id_online(&self, user: &Uuid) -> Result<bool, String>;
 }
 
-pub fn get_user_from_username(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, username: String) -> Result<(Uuid, String), String> {
-    let password_query = db.query(
-        format!("SELECT id, password FROM user_data WHERE username=$1").as_str(), &[&username]);
-    if let Err(e) = password_query {
-        return Err(format!("get_user_from_username.{}", e));
-    }
-    let user_rows = password_query.unwrap();
-    if user_rows.len() > 1 {
-        return Err(format!("Multiple users with the same username found"));
-    }
-    if user_rows.len() == 0 {
-        return Err(format!("Invalid username"));
-    }
-    let row = user_rows.get(0).unwrap();
+// where a page of history starts, read from the cursor a client sent
+enum PageStart {
+    Latest,
+    Id(Uuid),
+    Time(DateTime<Utc>),
+}
 
-    Ok((row.get(0), row.get(1)))
+// returns where the page starts, and if it goes back in time from there
+fn page_start(cursor: &HistoryCursor) -> Result<(PageStart, bool), String> {
+    match cursor {
+        HistoryCursor::Latest => Ok((PageStart::Latest, true)),
+        HistoryCursor::BeforeId(id) | HistoryCursor::AfterId(id) => {
+            let Ok(id) = Uuid::parse_str(id) else {
+                return Err(format!("Invalid message id"));
+            };
+            Ok((PageStart::Id(id), matches!(cursor, HistoryCursor::BeforeId(_))))
+        }
+        HistoryCursor::BeforeTime(time) | HistoryCursor::AfterTime(time) => {
+            let Ok(time) = time.parse::<DateTime<Utc>>() else {
+                return Err(format!("Invalid timestamp"));
+            };
+            Ok((PageStart::Time(time), matches!(cursor, HistoryCursor::BeforeTime(_))))
+        }
+    }
 }
 
-pub fn set_id_online(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, id: &Uuid, online: bool) -> Result<(), String> {
-    if let Err(e) = db.execute("UPDATE user_data SET online=$1 WHERE id=$2;",
-                               &[&online, &id]) {
-        return Err(format!("set_id_online.{}", e));
+// how the other user of a stored request is related to the user that asked, `sent` if that user is the requester
+fn contact_state(accepted: bool, sent: bool) -> ContactState {
+    match (accepted, sent) {
+        (true, _) => ContactState::Accepted,
+        (false, true) => ContactState::Outgoing,
+        (false, false) => ContactState::Incoming,
     }
-    Ok(())
 }
 
-pub fn is_username_online(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, username: String) -> Result<bool, String> {
-    let online_query = db.query(
-        format!("SELECT online FROM user_data WHERE username=$1").as_str(), &[&username]);
-    if let Err(e) = online_query {
-        return Err(format!("is_username_online.{}", e));
+// limit a page read with one extra message, to know if there is another page
+// pages going backwards are read newest first and put back in order here
+fn finish_page(mut rows: Vec<DBMessageQuery>, limit: usize, backwards: bool) -> (Vec<DBMessageQuery>, bool) {
+    let more = rows.len() > limit;
+    rows.truncate(limit);
+    if backwards {
+        rows.reverse();
     }
-    let user_rows = online_query.unwrap();
-    if user_rows.len() > 1 {
-        return Err(format!("Multiple users with the same username found!"));
+    (rows, more)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed(byte: u8) -> SealedPayload {
+        SealedPayload { ephemeral: vec![byte; 32], nonce: vec![byte; 12], ciphertext: vec![byte; 20], header: vec![byte; 3] }
     }
-    if user_rows.len() == 0 {
-        return Err(format!("Invalid username"));
+
+    fn seqs(page: &[DBMessageQuery]) -> Vec<i64> {
+        page.iter().map(|msg| msg.seq).collect()
     }
-    let row = user_rows.get(0).unwrap();
 
-    Ok(row.get(0))
-}
+    // every backend is run through the same checks
+    fn exercise(store: &dyn Store) {
+        // users
+        let alice = store.insert_user("alice", "hash").unwrap();
+        let bob = store.insert_user("bob", "hash").unwrap();
+        assert!(store.insert_user("alice", "other").is_err());
+        assert!(store.user_exists("alice").unwrap());
+        assert!(!store.user_exists("carol").unwrap());
+        assert_eq!(store.get_id_from_username("bob").unwrap(), bob);
+        assert!(store.get_id_from_username("carol").is_err());
+        assert_eq!(store.get_username_from_id(&alice).unwrap(), "alice");
+        store.set_user_password(&alice, "rehashed").unwrap();
+        assert_eq!(store.get_user_from_username("alice").unwrap(), (alice, format!("rehashed")));
 
-pub fn is_id_online(db: &mut PooledConnection<PostgresConnectionManager<NoTls>>, user: &Uuid) -> Result<bool, String> {
-    let online_query = db.query(
-        format!("SELECT online FROM user_data WHERE id=$1").as_str(), &[user]);
-    if let Err(e) = online_query {
-        return Err(format!("is_id_online.{}", e));
-    }
-    let user_rows = online_query.unwrap();
-    if user_rows.len() > 1 {
-        return Err(format!("Multiple users with the same username found!"));
+        // presence
+        assert!(!store.is_id_online(&alice).unwrap());
+        store.set_id_online(&alice, true).unwrap();
+        assert!(store.is_username_online("alice").unwrap());
+        assert!(store.is_username_online("carol").is_err());
+
+        // messages are numbered per conversation, in both directions
+        let start = Utc::now();
+        let mut ids = Vec::new();
+        for n in 0..5 {
+            let (sender, recipient) = if n % 2 == 0 { (&alice, &bob) } else { (&bob, &alice) };
+            let id = Uuid::new_v4();
+            let timestamp = start + chrono::Duration::seconds(n);
+            assert_eq!(store.store_msg(&id, sender, recipient, &sealed(n as u8), timestamp, 0).unwrap(), n + 1);
+            ids.push(id);
+        }
+        let queued = store.get_queued_msgs(&bob).unwrap();
+        assert_eq!(seqs(&queued), vec![1, 3, 5]);
+        assert_eq!(queued[0].sender, "alice");
+        assert_eq!(queued[0].id, ids[0]);
+        assert_eq!(queued[0].message, sealed(0));
+        assert_eq!(queued[0].timestamp.timestamp_micros(), start.timestamp_micros());
+        assert_eq!(queued[0].ttl, 0);
+        assert!(store.delete_msg(&ids[0], &bob).unwrap());
+        assert!(!store.delete_msg(&ids[0], &bob).unwrap());
+        // only the recipient can remove a message
+        assert!(!store.delete_msg(&ids[2], &alice).unwrap());
+        assert_eq!(seqs(&store.get_queued_msgs(&bob).unwrap()), vec![3, 5]);
+
+        // history pages
+        let (with_alice, with_bob) = (Conversation::Direct(alice), Conversation::Direct(bob));
+        let (page, more) = store.get_history(&bob, &with_alice, &HistoryCursor::Latest, 2).unwrap();
+        assert_eq!((seqs(&page), more), (vec![4, 5], true));
+        let (page, more) = store.get_history(&alice, &with_bob, &HistoryCursor::BeforeId(ids[3].to_string()), 10).unwrap();
+        assert_eq!((seqs(&page), more), (vec![1, 2, 3], false));
+        let (page, more) = store.get_history(&alice, &with_bob, &HistoryCursor::AfterId(ids[0].to_string()), 2).unwrap();
+        assert_eq!((seqs(&page), more), (vec![2, 3], true));
+        let after = (start + chrono::Duration::milliseconds(1500)).to_string();
+        let (page, more) = store.get_history(&alice, &with_bob, &HistoryCursor::AfterTime(after.clone()), 10).unwrap();
+        assert_eq!((seqs(&page), more), (vec![3, 4, 5], false));
+        let (page, _) = store.get_history(&alice, &with_bob, &HistoryCursor::BeforeTime(after), 10).unwrap();
+        assert_eq!(seqs(&page), vec![1, 2]);
+        assert!(store.get_history(&alice, &with_bob, &HistoryCursor::BeforeId(format!("nope")), 10).is_err());
+        let (page, more) = store.get_history_range(&alice, &with_bob, 2, 4).unwrap();
+        assert_eq!((seqs(&page), more), (vec![2, 3, 4], false));
+        let carol = store.insert_user("carol", "hash").unwrap();
+        assert!(store.get_history(&alice, &Conversation::Direct(carol), &HistoryCursor::Latest, 10).unwrap().0.is_empty());
+
+        // disappearing messages are purged from the history and the queue once their ttl passes
+        let erin = store.insert_user("erin", "hash").unwrap();
+        let (expired, kept) = (Uuid::new_v4(), Uuid::new_v4());
+        store.store_msg(&expired, &alice, &erin, &sealed(1), start - chrono::Duration::seconds(120), 60).unwrap();
+        store.store_msg(&kept, &alice, &erin, &sealed(2), start, 3600).unwrap();
+        assert_eq!(store.purge_expired_msgs(start).unwrap(), 1);
+        assert_eq!(store.purge_expired_msgs(start).unwrap(), 0);
+        let queued = store.get_queued_msgs(&erin).unwrap();
+        assert_eq!((queued.len(), queued[0].id, queued[0].ttl), (1, kept, 3600));
+        let (page, _) = store.get_history(&erin, &with_alice, &HistoryCursor::Latest, 10).unwrap();
+        assert_eq!(seqs(&page), vec![2]);
+        // the rest of the history is untouched
+        assert_eq!(store.get_history(&alice, &with_bob, &HistoryCursor::Latest, 10).unwrap().0.len(), 5);
+        // a page cannot start at a message of another conversation, or one that does not exist
+        assert!(store.get_history(&alice, &with_bob, &HistoryCursor::BeforeId(kept.to_string()), 10).is_err());
+        assert!(store.get_history(&alice, &with_bob, &HistoryCursor::AfterId(Uuid::new_v4().to_string()), 10).is_err());
+
+        // groups
+        let group = store.create_group("friends", &[bob, alice]).unwrap();
+        assert_eq!(store.get_group(&group).unwrap(), Some(DBGroup {
+            id: group,
+            name: format!("friends"),
+            members: vec![(alice, format!("alice")), (bob, format!("bob"))],
+        }));
+        assert_eq!(store.get_group(&Uuid::new_v4()).unwrap(), None);
+        assert!(store.add_group_member(&group, &carol).unwrap());
+        assert!(!store.add_group_member(&group, &carol).unwrap());
+        store.rename_group(&group, "best friends").unwrap();
+        let other = store.create_group("other", &[alice]).unwrap();
+        let groups = store.get_user_groups(&alice).unwrap();
+        assert_eq!(groups.iter().map(|group| group.name.as_str()).collect::<Vec<_>>(), vec!["best friends", "other"]);
+        assert_eq!(groups[0].members.len(), 3);
+        assert!(store.get_user_groups(&carol).unwrap().iter().all(|group| group.id != other));
+
+        // group messages are numbered per group, and every copy is only seen by its recipient
+        for n in 0..2 {
+            let copies: Vec<DBGroupCopy> = [bob, carol].iter()
+                .map(|recipient| DBGroupCopy { id: Uuid::new_v4(), recipient: *recipient, message: sealed(10 + n) })
+                .collect();
+            assert_eq!(store.store_group_msg(&group, &alice, &copies, Utc::now(), 0).unwrap(), n as i64 + 1);
+        }
+        let copy = DBGroupCopy { id: Uuid::new_v4(), recipient: bob, message: sealed(0) };
+        assert!(store.store_group_msg(&Uuid::new_v4(), &alice, &[copy], Utc::now(), 0).is_err());
+        // the sender has no copy of their own
+        assert!(store.get_queued_msgs(&alice).unwrap().iter().all(|msg| msg.group.is_none()));
+        let queued = store.get_queued_msgs(&carol).unwrap();
+        assert_eq!(seqs(&queued), vec![1, 2]);
+        assert_eq!((queued[0].sender.as_str(), queued[0].group), ("alice", Some(group)));
+        assert!(store.delete_msg(&queued[0].id, &carol).unwrap());
+
+        let (page, more) = store.get_history(&bob, &Conversation::Group(group), &HistoryCursor::Latest, 10).unwrap();
+        assert_eq!((seqs(&page), more), (vec![1, 2], false));
+        assert_eq!(page[1].message, sealed(11));
+        assert!(store.get_history(&alice, &Conversation::Group(group), &HistoryCursor::Latest, 10).unwrap().0.is_empty());
+        let (page, _) = store.get_history(&bob, &Conversation::Group(group), &HistoryCursor::AfterId(page[0].id.to_string()), 10).unwrap();
+        assert_eq!(seqs(&page), vec![2]);
+        assert_eq!(seqs(&store.get_history_range(&carol, &Conversation::Group(group), 2, 9).unwrap().0), vec![2]);
+        // group messages are not part of the conversation between two members
+        assert_eq!(seqs(&store.get_history(&alice, &with_bob, &HistoryCursor::Latest, 10).unwrap().0), vec![1, 2, 3, 4, 5]);
+
+        assert!(store.remove_group_member(&group, &carol).unwrap());
+        assert!(!store.remove_group_member(&group, &carol).unwrap());
+        assert!(store.get_user_groups(&carol).unwrap().is_empty());
+
+        // spaces
+        let space = store.create_space("club", &alice, 1).unwrap();
+        let general = store.create_channel(&space, "general").unwrap();
+        assert!(store.add_space_member(&space, &bob).unwrap());
+        assert!(!store.add_space_member(&space, &bob).unwrap());
+        assert!(store.add_space_member(&space, &carol).unwrap());
+        let mods = DBRole { id: Uuid::new_v4(), name: format!("mods"), permissions: 6 };
+        store.set_role(&space, &mods).unwrap();
+        assert!(store.assign_role(&space, &mods.id, &bob, true).unwrap());
+        assert!(!store.assign_role(&space, &mods.id, &bob, true).unwrap());
+        assert_eq!(store.get_space(&space).unwrap(), Some(DBSpace {
+            id: space,
+            name: format!("club"),
+            owner: alice,
+            channels: vec![(general, format!("general"))],
+            roles: vec![DBRole { id: space, name: format!("everyone"), permissions: 1 }, mods.clone()],
+            members: vec![
+                DBSpaceMember { id: alice, username: format!("alice"), roles: Vec::new() },
+                DBSpaceMember { id: bob, username: format!("bob"), roles: vec![mods.id] },
+                DBSpaceMember { id: carol, username: format!("carol"), roles: Vec::new() },
+            ],
+            banned: Vec::new(),
+        }));
+        assert_eq!(store.get_space(&Uuid::new_v4()).unwrap(), None);
+        assert_eq!(store.get_channel_space(&general).unwrap(), Some(space));
+        assert_eq!(store.get_channel_space(&space).unwrap(), None);
+        store.set_role(&space, &DBRole { permissions: 14, ..mods.clone() }).unwrap();
+        assert_eq!(store.get_space(&space).unwrap().unwrap().roles[1].permissions, 14);
+
+        // removing a member takes their roles away, and banning keeps them out
+        assert!(store.set_space_ban(&space, &bob, true).unwrap());
+        assert!(!store.set_space_ban(&space, &bob, true).unwrap());
+        assert!(store.remove_space_member(&space, &bob).unwrap());
+        assert!(!store.remove_space_member(&space, &bob).unwrap());
+        let club = store.get_space(&space).unwrap().unwrap();
+        assert_eq!(club.banned, vec![(bob, format!("bob"))]);
+        assert!(store.get_user_spaces(&bob).unwrap().is_empty());
+        assert!(store.set_space_ban(&space, &bob, false).unwrap());
+        store.add_space_member(&space, &bob).unwrap();
+        assert!(store.get_space(&space).unwrap().unwrap().members[1].roles.is_empty());
+        assert_eq!(store.get_user_spaces(&bob).unwrap().len(), 1);
+
+        // channel messages are numbered per channel, and keep the ttl they were sent with
+        let copies = vec![DBGroupCopy { id: Uuid::new_v4(), recipient: bob, message: sealed(20) }];
+        assert_eq!(store.store_channel_msg(&general, &alice, &copies, Utc::now(), 60).unwrap(), 1);
+        assert_eq!(seqs(&store.get_history(&bob, &Conversation::Channel(general), &HistoryCursor::Latest, 10).unwrap().0), vec![1]);
+        assert!(store.get_queued_msgs(&bob).unwrap().iter().any(|msg| msg.group == Some(general) && msg.ttl == 60));
+        let copy = DBGroupCopy { id: Uuid::new_v4(), recipient: bob, message: sealed(0) };
+        assert!(store.store_channel_msg(&space, &alice, &[copy], Utc::now(), 0).is_err());
+
+        assert!(store.delete_role(&space, &mods.id).unwrap());
+        assert!(!store.delete_role(&space, &mods.id).unwrap());
+        assert!(store.delete_channel(&space, &general).unwrap());
+        assert!(!store.delete_channel(&space, &general).unwrap());
+        let club = store.get_space(&space).unwrap().unwrap();
+        assert_eq!((club.channels.len(), club.roles.len()), (0, 1));
+
+        // timers are shared by everyone in a conversation, and can be set before its first message
+        let with_erin = Conversation::Direct(erin);
+        assert_eq!(store.get_timer(&bob, &with_erin).unwrap(), 0);
+        store.set_timer(&bob, &with_erin, 60).unwrap();
+        assert_eq!(store.get_timer(&erin, &Conversation::Direct(bob)).unwrap(), 60);
+        store.set_timer(&alice, &Conversation::Group(group), 3600).unwrap();
+        assert_eq!(store.get_timer(&bob, &Conversation::Group(group)).unwrap(), 3600);
+        let news = store.create_channel(&space, "news").unwrap();
+        store.set_timer(&alice, &Conversation::Channel(news), 120).unwrap();
+        let news_timer = DBTimer { conversation: Conversation::Channel(news), ttl: 120, set_by: format!("alice") };
+        let timers = store.get_user_timers(&bob).unwrap();
+        assert_eq!(timers.len(), 3);
+        assert!(timers.contains(&DBTimer { conversation: with_erin, ttl: 60, set_by: format!("bob") }));
+        assert!(timers.contains(&DBTimer { conversation: Conversation::Group(group), ttl: 3600, set_by: format!("alice") }));
+        assert!(timers.contains(&news_timer));
+        // carol left the group but is still in the space
+        assert_eq!(store.get_user_timers(&carol).unwrap(), vec![news_timer]);
+        // a timer that was turned off is not listed
+        store.set_timer(&erin, &Conversation::Direct(bob), 0).unwrap();
+        assert_eq!(store.get_timer(&bob, &with_erin).unwrap(), 0);
+        assert!(store.get_user_timers(&erin).unwrap().is_empty());
+        // setting a timer does not use up a sequence number
+        assert_eq!(store.store_msg(&Uuid::new_v4(), &bob, &erin, &sealed(3), Utc::now(), 0).unwrap(), 1);
+
+        // contacts
+        assert!(store.add_contact_request(&alice, &bob).unwrap());
+        assert!(!store.add_contact_request(&alice, &bob).unwrap());
+        // there is only ever one request between two users
+        assert!(!store.add_contact_request(&bob, &alice).unwrap());
+        assert!(store.add_contact_request(&carol, &alice).unwrap());
+        assert_eq!(store.get_contact_state(&alice, &bob).unwrap(), ContactState::Outgoing);
+        assert_eq!(store.get_contact_state(&bob, &alice).unwrap(), ContactState::Incoming);
+        assert_eq!(store.get_contact_state(&bob, &carol).unwrap(), ContactState::None);
+        // only the user a request was sent to can accept it
+        assert!(!store.accept_contact_request(&alice, &bob).unwrap());
+        assert!(store.accept_contact_request(&bob, &alice).unwrap());
+        assert!(!store.accept_contact_request(&bob, &alice).unwrap());
+        assert_eq!(store.get_contacts(&alice).unwrap(), vec![
+            DBContact { id: bob, username: format!("bob"), state: ContactState::Accepted },
+            DBContact { id: carol, username: format!("carol"), state: ContactState::Incoming },
+        ]);
+        assert_eq!(store.get_contacts(&bob).unwrap(), vec![DBContact { id: alice, username: format!("alice"), state: ContactState::Accepted }]);
+        // either side can remove a contact
+        assert!(store.remove_contact(&bob, &alice).unwrap());
+        assert!(!store.remove_contact(&alice, &bob).unwrap());
+        assert!(store.remove_contact(&alice, &carol).unwrap());
+        assert!(store.get_contacts(&alice).unwrap().is_empty());
+
+        // blocks
+        assert!(store.set_blocked(&alice, &carol, true).unwrap());
+        assert!(!store.set_blocked(&alice, &carol, true).unwrap());
+        assert!(store.set_blocked(&alice, &bob, true).unwrap());
+        assert!(store.set_blocked(&bob, &carol, true).unwrap());
+        assert_eq!(store.get_blocked(&alice).unwrap(), vec![format!("bob"), format!("carol")]);
+        assert!(store.is_blocked(&carol, &alice).unwrap());
+        // blocking only goes one way
+        assert!(!store.is_blocked(&alice, &carol).unwrap());
+        let mut blockers = store.get_blockers(&carol).unwrap();
+        blockers.sort();
+        let mut expected = vec![alice, bob];
+        expected.sort();
+        assert_eq!(blockers, expected);
+        assert!(store.set_blocked(&alice, &carol, false).unwrap());
+        assert!(!store.set_blocked(&alice, &carol, false).unwrap());
+        assert_eq!(store.get_blocked(&alice).unwrap(), vec![format!("bob")]);
+        assert_eq!(store.get_blockers(&carol).unwrap(), vec![bob]);
+
+        // keys
+        assert_eq!(store.get_identity_key("alice").unwrap(), None);
+        assert!(store.get_identity_key("dave").is_err());
+        assert!(store.set_identity_key(&alice, &[1; 32], &[2; 32]).unwrap());
+        assert!(!store.set_identity_key(&alice, &[1; 32], &[2; 32]).unwrap());
+        assert_eq!(store.get_identity_key("alice").unwrap(), Some(vec![1; 32]));
+        assert_eq!(store.get_signing_key(&alice).unwrap(), Some(vec![2; 32]));
+
+        // no bundle until a signed prekey is published
+        assert_eq!(store.take_prekey_bundle("alice").unwrap(), (alice, None));
+        assert!(store.take_prekey_bundle("dave").is_err());
+        store.set_signed_prekey(&alice, &PublicPreKey { id: 1, key: vec![3; 32] }, &[4; 64]).unwrap();
+        store.set_signed_prekey(&alice, &PublicPreKey { id: 2, key: vec![5; 32] }, &[6; 64]).unwrap();
+        let one_time: Vec<PublicPreKey> = (10..13).map(|id| PublicPreKey { id, key: vec![id as u8; 32] }).collect();
+        store.add_one_time_prekeys(&alice, &one_time).unwrap();
+        store.add_one_time_prekeys(&alice, &one_time[..1]).unwrap();
+        assert_eq!(store.count_one_time_prekeys(&alice).unwrap(), 3);
+
+        let (owner, bundle) = store.take_prekey_bundle("alice").unwrap();
+        assert_eq!(owner, alice);
+        let bundle = bundle.unwrap();
+        assert_eq!(bundle.signed_prekey, PublicPreKey { id: 2, key: vec![5; 32] });
+        assert_eq!(bundle.signature, vec![6; 64]);
+        assert_eq!(bundle.one_time_prekey, Some(one_time[0].clone()));
+        assert_eq!(store.count_one_time_prekeys(&alice).unwrap(), 2);
+
+        store.delete_prekeys(&alice).unwrap();
+        assert_eq!(store.count_one_time_prekeys(&alice).unwrap(), 0);
+        assert_eq!(store.take_prekey_bundle("alice").unwrap(), (alice, None));
     }
-    if user_rows.len() == 0 {
-        return Err(format!("Invalid username"));
+
+    #[test]
+    fn memory_store() {
+        exercise(&memory::MemoryStore::new());
     }
-    let row = user_rows.get(0).unwrap();
 
-    Ok(row.get(0))
-}
\ No newline at end of file
+    #[test]
+    fn sqlite_store() {
+        let store = sqlite::SqliteStore::open(Path::new(":memory:")).unwrap();
+        assert_eq!(store.migrate().unwrap().len(), migrations::SQLITE.len());
+        assert!(store.migrate().unwrap().is_empty());
+        exercise(&store);
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use dl_network_common::crypto::{PreKeyBundle, PublicPreKey, SealedPayload};
+use dl_network_common::{ContactState, HistoryCursor};
+use crate::database::migrations::Migration;
+use crate::database::{finish_page, page_start, Conversation, DBContact, DBGroup, DBGroupCopy, DBMessageQuery, DBRole, DBSpace, DBSpaceMember, DBTimer, PageStart, Store, MAX_HISTORY_PAGE};
+
+/// Keeps everything in memory, it is all lost when the store is dropped
+/// meant for tests, so the server can run without a database
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    users: HashMap<Uuid, User>,
+    // the last sequence number of every conversation, keyed by its users in order
+    conversations: HashMap<(Uuid, Uuid), i64>,
+    // the disappearing message timer of every conversation between two users that has one, and who set it, keyed like conversations
+    timers: HashMap<(Uuid, Uuid), (u64, Uuid)>,
+    groups: HashMap<Uuid, Group>,
+    spaces: HashMap<Uuid, Space>,
+    channels: HashMap<Uuid, Channel>,
+    // every request and contact keyed by who sent the request and who it was sent to, true once it was accepted
+    contacts: HashMap<(Uuid, Uuid), bool>,
+    // every block keyed by who blocked and who they blocked
+    blocks: BTreeSet<(Uuid, Uuid)>,
+    // every message ever sent, in the order they were stored
+    history: Vec<StoredMsg>,
+    // the ids of messages waiting to be acknowledged by their recipient
+    queued: Vec<Uuid>,
+    signed_prekeys: HashMap<Uuid, (PublicPreKey, Vec<u8>)>,
+    one_time_prekeys: HashMap<Uuid, BTreeMap<u32, Vec<u8>>>,
+}
+
+struct User {
+    username: String,
+    password: String,
+    online: bool,
+    identity_key: Option<Vec<u8>>,
+    signing_key: Option<Vec<u8>>,
+}
+
+struct Group {
+    name: String,
+    members: BTreeSet<Uuid>,
+    last_seq: i64,
+    // the disappearing message timer and who set it
+    timer: Option<(u64, Uuid)>,
+}
+
+struct Space {
+    name: String,
+    owner: Uuid,
+    // every member with the roles they were given
+    members: BTreeMap<Uuid, BTreeSet<Uuid>>,
+    banned: BTreeSet<Uuid>,
+    // the name and permissions of every role, including the everyone role
+    roles: HashMap<Uuid, (String, u32)>,
+}
+
+struct Channel {
+    space: Uuid,
+    name: String,
+    last_seq: i64,
+    // the disappearing message timer and who set it
+    timer: Option<(u64, Uuid)>,
+}
+
+struct StoredMsg {
+    id: Uuid,
+    sender: Uuid,
+    recipient: Uuid,
+    message: SealedPayload,
+    timestamp: DateTime<Utc>,
+    seq: i64,
+    group: Option<Uuid>,
+    ttl: u64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>, String> {
+        self.state.lock().map_err(|_| format!("The memory store was poisoned"))
+    }
+}
+
+impl State {
+    fn id_of(&self, username: &str) -> Option<Uuid> {
+        self.users.iter().find(|(_, user)| user.username == username).map(|(id, _)| *id)
+    }
+
+    fn query(&self, msg: &StoredMsg) -> Result<DBMessageQuery, String> {
+        let Some(sender) = self.users.get(&msg.sender) else {
+            return Err(format!("A stored message has no sender"));
+        };
+        Ok(DBMessageQuery {
+            id: msg.id,
+            sender: sender.username.clone(),
+            message: msg.message.clone(),
+            timestamp: msg.timestamp,
+            seq: msg.seq,
+            group: msg.group,
+            ttl: msg.ttl,
+        })
+    }
+
+    fn group(&self, id: &Uuid) -> Option<DBGroup> {
+        let group = self.groups.get(id)?;
+        let mut members: Vec<(Uuid, String)> = group.members.iter()
+            .filter_map(|member| self.users.get(member).map(|user| (*member, user.username.clone())))
+            .collect();
+        members.sort_by(|a, b| a.1.cmp(&b.1));
+        Some(DBGroup { id: *id, name: group.name.clone(), members })
+    }
+
+    fn space(&self, id: &Uuid) -> Option<DBSpace> {
+        let space = self.spaces.get(id)?;
+        let username = |user: &Uuid| self.users.get(user).map(|stored| stored.username.clone());
+
+        let mut channels: Vec<(Uuid, String)> = self.channels.iter()
+            .filter(|(_, channel)| channel.space == *id)
+            .map(|(channel_id, channel)| (*channel_id, channel.name.clone()))
+            .collect();
+        channels.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut roles: Vec<DBRole> = space.roles.iter()
+            .map(|(role, (name, permissions))| DBRole { id: *role, name: name.clone(), permissions: *permissions })
+            .collect();
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut members: Vec<DBSpaceMember> = space.members.iter()
+            .filter_map(|(member, roles)| Some(DBSpaceMember { id: *member, username: username(member)?, roles: roles.iter().copied().collect() }))
+            .collect();
+        members.sort_by(|a, b| a.username.cmp(&b.username));
+        let mut banned: Vec<(Uuid, String)> = space.banned.iter().filter_map(|user| Some((*user, username(user)?))).collect();
+        banned.sort_by(|a, b| a.1.cmp(&b.1));
+
+        Some(DBSpace { id: *id, name: space.name.clone(), owner: space.owner, channels, roles, members, banned })
+    }
+
+    // store the copies of a message sent to a group or channel, numbered with `seq`
+    // how `other` is related to `user`
+    fn contact_state(&self, user: &Uuid, other: &Uuid) -> ContactState {
+        match (self.contacts.get(&(*user, *other)), self.contacts.get(&(*other, *user))) {
+            (Some(true), _) | (_, Some(true)) => ContactState::Accepted,
+            (Some(false), _) => ContactState::Outgoing,
+            (_, Some(false)) => ContactState::Incoming,
+            (None, None) => ContactState::None,
+        }
+    }
+
+    fn store_copies(&mut self, conversation: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, seq: i64, ttl: u64) {
+        for copy in copies {
+            self.history.push(StoredMsg {
+                id: copy.id,
+                sender: *sender,
+                recipient: copy.recipient,
+                message: copy.message.clone(),
+                timestamp,
+                seq,
+                group: Some(*conversation),
+                ttl,
+            });
+            self.queued.push(copy.id);
+        }
+    }
+
+    // the messages of a conversation the user can see, in sequence order
+    fn conversation<'a>(&'a self, user: &'a Uuid, conversation: &'a Conversation) -> impl DoubleEndedIterator<Item = &'a StoredMsg> + 'a {
+        self.history.iter().filter(move |msg| match conversation {
+            Conversation::Direct(other) => msg.group.is_none()
+                && ((msg.sender == *user && msg.recipient == *other) || (msg.sender == *other && msg.recipient == *user)),
+            Conversation::Group(group) | Conversation::Channel(group) => msg.group == Some(*group) && msg.recipient == *user,
+        })
+    }
+}
+
+impl Store for MemoryStore {
+    // there is no schema, a new store is always up to date
+    fn migrate(&self) -> Result<Vec<&'static Migration>, String> {
+        Ok(Vec::new())
+    }
+
+    fn store_msg(&self, id: &Uuid, sender: &Uuid, recipient: &Uuid, message: &SealedPayload, timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let mut state = self.state()?;
+        if state.history.iter().any(|msg| msg.id == *id) {
+            return Err(format!("store_msg.A message with id {} already exists", id));
+        }
+        let seq = state.conversations.entry((*sender.min(recipient), *sender.max(recipient))).or_insert(0);
+        *seq += 1;
+        let seq = *seq;
+        state.history.push(StoredMsg { id: *id, sender: *sender, recipient: *recipient, message: message.clone(), timestamp, seq, group: None, ttl });
+        state.queued.push(*id);
+        Ok(seq)
+    }
+
+    fn store_group_msg(&self, group: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let mut state = self.state()?;
+        if let Some(copy) = copies.iter().find(|copy| state.history.iter().any(|msg| msg.id == copy.id)) {
+            return Err(format!("store_group_msg.A message with id {} already exists", copy.id));
+        }
+        let Some(stored) = state.groups.get_mut(group) else {
+            return Err(format!("store_group_msg.There is no group {}", group));
+        };
+        stored.last_seq += 1;
+        let seq = stored.last_seq;
+        state.store_copies(group, sender, copies, timestamp, seq, ttl);
+        Ok(seq)
+    }
+
+    fn store_channel_msg(&self, channel: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let mut state = self.state()?;
+        if let Some(copy) = copies.iter().find(|copy| state.history.iter().any(|msg| msg.id == copy.id)) {
+            return Err(format!("store_channel_msg.A message with id {} already exists", copy.id));
+        }
+        let Some(stored) = state.channels.get_mut(channel) else {
+            return Err(format!("store_channel_msg.There is no channel {}", channel));
+        };
+        stored.last_seq += 1;
+        let seq = stored.last_seq;
+        state.store_copies(channel, sender, copies, timestamp, seq, ttl);
+        Ok(seq)
+    }
+
+    fn get_queued_msgs(&self, receiver: &Uuid) -> Result<Vec<DBMessageQuery>, String> {
+        let state = self.state()?;
+        let mut queued: Vec<&StoredMsg> = state.history.iter()
+            .filter(|msg| msg.recipient == *receiver && state.queued.contains(&msg.id))
+            .collect();
+        // group messages from several senders share the numbering of their group
+        queued.sort_by_key(|msg| (msg.group.unwrap_or(msg.sender), msg.seq));
+        queued.into_iter().map(|msg| state.query(msg)).collect()
+    }
+
+    fn delete_msg(&self, id: &Uuid, recipient: &Uuid) -> Result<bool, String> {
+        let mut state = self.state()?;
+        if !state.history.iter().any(|msg| msg.id == *id && msg.recipient == *recipient) {
+            return Ok(false);
+        }
+        let before = state.queued.len();
+        state.queued.retain(|queued| queued != id);
+        Ok(state.queued.len() < before)
+    }
+
+    fn purge_expired_msgs(&self, now: DateTime<Utc>) -> Result<usize, String> {
+        let mut state = self.state()?;
+        let expired: Vec<Uuid> = state.history.iter()
+            .filter(|msg| msg.ttl > 0 && msg.timestamp + chrono::Duration::seconds(msg.ttl as i64) <= now)
+            .map(|msg| msg.id)
+            .collect();
+        state.history.retain(|msg| !expired.contains(&msg.id));
+        state.queued.retain(|queued| !expired.contains(queued));
+        Ok(expired.len())
+    }
+
+    fn get_history(&self, user: &Uuid, conversation: &Conversation, cursor: &HistoryCursor, limit: u32) -> Result<(Vec<DBMessageQuery>, bool), String> {
+        // take one more message than needed to know if there is another page
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE) as usize;
+        let (start, backwards) = page_start(cursor)?;
+
+        let state = self.state()?;
+        // a page can only start at a message of the same conversation
+        let start_seq = match &start {
+            PageStart::Id(id) => match state.conversation(user, conversation).find(|msg| msg.id == *id) {
+                Some(msg) => Some(msg.seq),
+                None => return Err(format!("get_history.Message {} is not in the conversation", id)),
+            },
+            _ => None,
+        };
+        let past_start = |msg: &StoredMsg| -> bool {
+            match (&start, start_seq) {
+                (PageStart::Id(_), Some(seq)) => if backwards { msg.seq < seq } else { msg.seq > seq },
+                (PageStart::Time(time), _) => if backwards { msg.timestamp < *time } else { msg.timestamp > *time },
+                _ => true,
+            }
+        };
+        let page: Result<Vec<_>, String> = if backwards {
+            state.conversation(user, conversation).rev().filter(|msg| past_start(msg)).take(limit + 1).map(|msg| state.query(msg)).collect()
+        } else {
+            state.conversation(user, conversation).filter(|msg| past_start(msg)).take(limit + 1).map(|msg| state.query(msg)).collect()
+        };
+
+        Ok(finish_page(page?, limit, backwards))
+    }
+
+    fn get_history_range(&self, user: &Uuid, conversation: &Conversation, from: i64, to: i64) -> Result<(Vec<DBMessageQuery>, bool), String> {
+        let state = self.state()?;
+        let page = state.conversation(user, conversation)
+            .filter(|msg| (from..=to).contains(&msg.seq))
+            .take(MAX_HISTORY_PAGE as usize + 1)
+            .map(|msg| state.query(msg))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(finish_page(page, MAX_HISTORY_PAGE as usize, false))
+    }
+
+    fn create_group(&self, name: &str, members: &[Uuid]) -> Result<Uuid, String> {
+        let id = Uuid::new_v4();
+        self.state()?.groups.insert(id, Group { name: name.to_string(), members: members.iter().copied().collect(), last_seq: 0, timer: None });
+        Ok(id)
+    }
+
+    fn get_group(&self, group: &Uuid) -> Result<Option<DBGroup>, String> {
+        Ok(self.state()?.group(group))
+    }
+
+    fn get_user_groups(&self, user: &Uuid) -> Result<Vec<DBGroup>, String> {
+        let state = self.state()?;
+        let mut groups: Vec<DBGroup> = state.groups.iter()
+            .filter(|(_, group)| group.members.contains(user))
+            .filter_map(|(id, _)| state.group(id))
+            .collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(groups)
+    }
+
+    fn add_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String> {
+        Ok(self.state()?.groups.get_mut(group).is_some_and(|group| group.members.insert(*user)))
+    }
+
+    fn remove_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String> {
+        Ok(self.state()?.groups.get_mut(group).is_some_and(|group| group.members.remove(user)))
+    }
+
+    fn rename_group(&self, group: &Uuid, name: &str) -> Result<(), String> {
+        if let Some(group) = self.state()?.groups.get_mut(group) {
+            group.name = name.to_string();
+        }
+        Ok(())
+    }
+
+    fn create_space(&self, name: &str, owner: &Uuid, everyone: u32) -> Result<Uuid, String> {
+        let id = Uuid::new_v4();
+        self.state()?.spaces.insert(id, Space {
+            name: name.to_string(),
+            owner: *owner,
+            members: BTreeMap::from([(*owner, BTreeSet::new())]),
+            banned: BTreeSet::new(),
+            roles: HashMap::from([(id, (format!("everyone"), everyone))]),
+        });
+        Ok(id)
+    }
+
+    fn get_space(&self, space: &Uuid) -> Result<Option<DBSpace>, String> {
+        Ok(self.state()?.space(space))
+    }
+
+    fn get_user_spaces(&self, user: &Uuid) -> Result<Vec<DBSpace>, String> {
+        let state = self.state()?;
+        let mut spaces: Vec<DBSpace> = state.spaces.iter()
+            .filter(|(_, space)| space.members.contains_key(user))
+            .filter_map(|(id, _)| state.space(id))
+            .collect();
+        spaces.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(spaces)
+    }
+
+    fn add_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String> {
+        let mut state = self.state()?;
+        let Some(space) = state.spaces.get_mut(space) else {
+            return Ok(false);
+        };
+        if space.members.contains_key(user) {
+            return Ok(false);
+        }
+        space.members.insert(*user, BTreeSet::new());
+        Ok(true)
+    }
+
+    fn remove_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String> {
+        Ok(self.state()?.spaces.get_mut(space).is_some_and(|space| space.members.remove(user).is_some()))
+    }
+
+    fn set_space_ban(&self, space: &Uuid, user: &Uuid, banned: bool) -> Result<bool, String> {
+        Ok(self.state()?.spaces.get_mut(space).is_some_and(|space| {
+            if banned { space.banned.insert(*user) } else { space.banned.remove(user) }
+        }))
+    }
+
+    fn create_channel(&self, space: &Uuid, name: &str) -> Result<Uuid, String> {
+        let mut state = self.state()?;
+        if !state.spaces.contains_key(space) {
+            return Err(format!("create_channel.There is no space {}", space));
+        }
+        let id = Uuid::new_v4();
+        state.channels.insert(id, Channel { space: *space, name: name.to_string(), last_seq: 0, timer: None });
+        Ok(id)
+    }
+
+    fn delete_channel(&self, space: &Uuid, channel: &Uuid) -> Result<bool, String> {
+        let mut state = self.state()?;
+        if state.channels.get(channel).is_none_or(|stored| stored.space != *space) {
+            return Ok(false);
+        }
+        state.channels.remove(channel);
+        Ok(true)
+    }
+
+    fn get_channel_space(&self, channel: &Uuid) -> Result<Option<Uuid>, String> {
+        Ok(self.state()?.channels.get(channel).map(|channel| channel.space))
+    }
+
+    fn set_role(&self, space: &Uuid, role: &DBRole) -> Result<(), String> {
+        let mut state = self.state()?;
+        let Some(space) = state.spaces.get_mut(space) else {
+            return Err(format!("set_role.There is no space {}", space));
+        };
+        space.roles.insert(role.id, (role.name.clone(), role.permissions));
+        Ok(())
+    }
+
+    fn delete_role(&self, space: &Uuid, role: &Uuid) -> Result<bool, String> {
+        let mut state = self.state()?;
+        let Some(space) = state.spaces.get_mut(space) else {
+            return Ok(false);
+        };
+        if space.roles.remove(role).is_none() {
+            return Ok(false);
+        }
+        for roles in space.members.values_mut() {
+            roles.remove(role);
+        }
+        Ok(true)
+    }
+
+    fn assign_role(&self, space: &Uuid, role: &Uuid, user: &Uuid, assigned: bool) -> Result<bool, String> {
+        let mut state = self.state()?;
+        let Some(roles) = state.spaces.get_mut(space).and_then(|space| space.members.get_mut(user)) else {
+            return Ok(false);
+        };
+        Ok(if assigned { roles.insert(*role) } else { roles.remove(role) })
+    }
+
+    fn get_contacts(&self, user: &Uuid) -> Result<Vec<DBContact>, String> {
+        let state = self.state()?;
+        let mut contacts: Vec<DBContact> = state.contacts.keys()
+            .filter_map(|(from, to)| if from == user { Some(to) } else if to == user { Some(from) } else { None })
+            .filter_map(|other| state.users.get(other).map(|stored| DBContact {
+                id: *other,
+                username: stored.username.clone(),
+                state: state.contact_state(user, other),
+            }))
+            .collect();
+        contacts.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(contacts)
+    }
+
+    fn get_contact_state(&self, user: &Uuid, other: &Uuid) -> Result<ContactState, String> {
+        Ok(self.state()?.contact_state(user, other))
+    }
+
+    fn add_contact_request(&self, from: &Uuid, to: &Uuid) -> Result<bool, String> {
+        let mut state = self.state()?;
+        if state.contact_state(from, to) != ContactState::None {
+            return Ok(false);
+        }
+        state.contacts.insert((*from, *to), false);
+        Ok(true)
+    }
+
+    fn accept_contact_request(&self, user: &Uuid, from: &Uuid) -> Result<bool, String> {
+        let mut state = self.state()?;
+        match state.contacts.get_mut(&(*from, *user)) {
+            Some(accepted) if !*accepted => {
+                *accepted = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn remove_contact(&self, user: &Uuid, other: &Uuid) -> Result<bool, String> {
+        let mut state = self.state()?;
+        let sent = state.contacts.remove(&(*user, *other)).is_some();
+        let received = state.contacts.remove(&(*other, *user)).is_some();
+        Ok(sent || received)
+    }
+
+    fn get_blocked(&self, user: &Uuid) -> Result<Vec<String>, String> {
+        let state = self.state()?;
+        let mut blocked: Vec<String> = state.blocks.iter()
+            .filter(|(by, _)| by == user)
+            .filter_map(|(_, other)| state.users.get(other).map(|stored| stored.username.clone()))
+            .collect();
+        blocked.sort();
+        Ok(blocked)
+    }
+
+    fn get_blockers(&self, user: &Uuid) -> Result<Vec<Uuid>, String> {
+        Ok(self.state()?.blocks.iter().filter(|(_, blocked)| blocked == user).map(|(by, _)| *by).collect())
+    }
+
+    fn is_blocked(&self, user: &Uuid, by: &Uuid) -> Result<bool, String> {
+        Ok(self.state()?.blocks.contains(&(*by, *user)))
+    }
+
+    fn set_blocked(&self, user: &Uuid, other: &Uuid, blocked: bool) -> Result<bool, String> {
+        let mut state = self.state()?;
+        Ok(if blocked { state.blocks.insert((*user, *other)) } else { state.blocks.remove(&(*user, *other)) })
+    }
+
+    fn set_timer(&self, user: &Uuid, conversation: &Conversation, ttl: u64) -> Result<(), String> {
+        let mut state = self.state()?;
+        let timer = (ttl > 0).then_some((ttl, *user));
+        match conversation {
+            Conversation::Direct(other) => {
+                let key = (*user.min(other), *user.max(other));
+                match timer {
+                    Some(timer) => state.timers.insert(key, timer),
+                    None => state.timers.remove(&key),
+                };
+            }
+            Conversation::Group(group) => {
+                if let Some(group) = state.groups.get_mut(group) {
+                    group.timer = timer;
+                }
+            }
+            Conversation::Channel(channel) => {
+                if let Some(channel) = state.channels.get_mut(channel) {
+                    channel.timer = timer;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_timer(&self, user: &Uuid, conversation: &Conversation) -> Result<u64, String> {
+        let state = self.state()?;
+        let timer = match conversation {
+            Conversation::Direct(other) => state.timers.get(&(*user.min(other), *user.max(other))).copied(),
+            Conversation::Group(group) => state.groups.get(group).and_then(|group| group.timer),
+            Conversation::Channel(channel) => state.channels.get(channel).and_then(|channel| channel.timer),
+        };
+        Ok(timer.map_or(0, |(ttl, _)| ttl))
+    }
+
+    fn get_user_timers(&self, user: &Uuid) -> Result<Vec<DBTimer>, String> {
+        let state = self.state()?;
+        let direct = state.timers.iter()
+            .filter_map(|((a, b), timer)| if a == user { Some((Conversation::Direct(*b), timer)) } else if b == user { Some((Conversation::Direct(*a), timer)) } else { None });
+        let groups = state.groups.iter()
+            .filter(|(_, group)| group.members.contains(user))
+            .filter_map(|(id, group)| group.timer.as_ref().map(|timer| (Conversation::Group(*id), timer)));
+        let channels = state.channels.iter()
+            .filter(|(_, channel)| state.spaces.get(&channel.space).is_some_and(|space| space.members.contains_key(user)))
+            .filter_map(|(id, channel)| channel.timer.as_ref().map(|timer| (Conversation::Channel(*id), timer)));
+        Ok(direct.chain(groups).chain(channels)
+            .filter_map(|(conversation, (ttl, set_by))| state.users.get(set_by).map(|stored| DBTimer {
+                conversation,
+                ttl: *ttl,
+                set_by: stored.username.clone(),
+            }))
+            .collect())
+    }
+
+    fn set_identity_key(&self, id: &Uuid, key: &[u8], signing_key: &[u8]) -> Result<bool, String> {
+        let mut state = self.state()?;
+        let Some(user) = state.users.get_mut(id) else {
+            return Ok(false);
+        };
+        if user.identity_key.as_deref() == Some(key) && user.signing_key.as_deref() == Some(signing_key) {
+            return Ok(false);
+        }
+        user.identity_key = Some(key.to_vec());
+        user.signing_key = Some(signing_key.to_vec());
+        Ok(true)
+    }
+
+    fn get_identity_key(&self, username: &str) -> Result<Option<Vec<u8>>, String> {
+        let state = self.state()?;
+        match state.users.values().find(|user| user.username == username) {
+            Some(user) => Ok(user.identity_key.clone()),
+            None => Err(format!("Invalid username")),
+        }
+    }
+
+    fn get_signing_key(&self, id: &Uuid) -> Result<Option<Vec<u8>>, String> {
+        match self.state()?.users.get(id) {
+            Some(user) => Ok(user.signing_key.clone()),
+            None => Err(format!("Invalid id!")),
+        }
+    }
+
+    fn delete_prekeys(&self, id: &Uuid) -> Result<(), String> {
+        let mut state = self.state()?;
+        state.signed_prekeys.remove(id);
+        state.one_time_prekeys.remove(id);
+        Ok(())
+    }
+
+    fn set_signed_prekey(&self, id: &Uuid, prekey: &PublicPreKey, signature: &[u8]) -> Result<(), String> {
+        self.state()?.signed_prekeys.insert(*id, (prekey.clone(), signature.to_vec()));
+        Ok(())
+    }
+
+    fn add_one_time_prekeys(&self, id: &Uuid, prekeys: &[PublicPreKey]) -> Result<(), String> {
+        let mut state = self.state()?;
+        let stored = state.one_time_prekeys.entry(*id).or_default();
+        for prekey in prekeys {
+            stored.entry(prekey.id).or_insert_with(|| prekey.key.clone());
+        }
+        Ok(())
+    }
+
+    fn count_one_time_prekeys(&self, id: &Uuid) -> Result<i64, String> {
+        Ok(self.state()?.one_time_prekeys.get(id).map_or(0, |stored| stored.len() as i64))
+    }
+
+    fn take_prekey_bundle(&self, username: &str) -> Result<(Uuid, Option<PreKeyBundle>), String> {
+        let mut state = self.state()?;
+        let Some(id) = state.id_of(username) else {
+            return Err(format!("Invalid username"));
+        };
+        let user = &state.users[&id];
+        let (Some(identity_key), Some(signing_key), Some((signed_prekey, signature))) =
+            (user.identity_key.clone(), user.signing_key.clone(), state.signed_prekeys.get(&id).cloned()) else {
+            return Ok((id, None));
+        };
+
+        let one_time_prekey = state.one_time_prekeys.get_mut(&id)
+            .and_then(|stored| stored.pop_first())
+            .map(|(key_id, key)| PublicPreKey { id: key_id, key });
+
+        Ok((id, Some(PreKeyBundle { identity_key, signing_key, signed_prekey, signature, one_time_prekey })))
+    }
+
+    fn insert_user(&self, username: &str, password: &str) -> Result<Uuid, String> {
+        let mut state = self.state()?;
+        if state.id_of(username).is_some() {
+            return Err(format!("insert_user.The username {} is taken", username));
+        }
+        let id = Uuid::new_v4();
+        state.users.insert(id, User {
+            username: username.to_string(),
+            password: password.to_string(),
+            online: false,
+            identity_key: None,
+            signing_key: None,
+        });
+        Ok(id)
+    }
+
+    fn set_user_password(&self, id: &Uuid, password: &str) -> Result<(), String> {
+        if let Some(user) = self.state()?.users.get_mut(id) {
+            user.password = password.to_string();
+        }
+        Ok(())
+    }
+
+    fn user_exists(&self, username: &str) -> Result<bool, String> {
+        Ok(self.state()?.id_of(username).is_some())
+    }
+
+    fn get_username_from_id(&self, id: &Uuid) -> Result<String, String> {
+        match self.state()?.users.get(id) {
+            Some(user) => Ok(user.username.clone()),
+            None => Err(format!("Invalid id!")),
+        }
+    }
+
+    fn get_id_from_username(&self, username: &str) -> Result<Uuid, String> {
+        self.state()?.id_of(username).ok_or_else(|| format!("Invalid username"))
+    }
+
+    fn get_user_from_username(&self, username: &str) -> Result<(Uuid, String), String> {
+        let state = self.state()?;
+        match state.id_of(username) {
+            Some(id) => Ok((id, state.users[&id].password.clone())),
+            None => Err(format!("Invalid username")),
+        }
+    }
+
+    fn set_id_online(&self, id: &Uuid, online: bool) -> Result<(), String> {
+        if let Some(user) = self.state()?.users.get_mut(id) {
+            user.online = online;
+        }
+        Ok(())
+    }
+
+    fn is_username_online(&self, username: &str) -> Result<bool, String> {
+        let state = self.state()?;
+        match state.id_of(username) {
+            Some(id) => Ok(state.users[&id].online),
+            None => Err(format!("Invalid username")),
+        }
+    }
+
+    fn is_id_online(&self, user: &Uuid) -> Result<bool, String> {
+        match self.state()?.users.get(user) {
+            Some(user) => Ok(user.online),
+            None => Err(format!("Invalid username")),
+        }
+    }
+}
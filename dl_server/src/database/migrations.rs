@@ -0,0 +1,447 @@
+/// A numbered change to the schema of a database, applied once and in order
+/// every version applied is recorded in the `schema_version` table
+#[derive(Debug, PartialEq)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// The migration to apply after `current`, None if the schema is up to date
+/// fails if the schema is newer than any migration this server knows, it was made by a newer server
+pub fn next(migrations: &'static [Migration], current: i64) -> Result<Option<&'static Migration>, String> {
+    let latest = migrations.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        return Err(format!("The database schema is at version {} but this server only knows up to version {}, please update the server", current, latest));
+    }
+    Ok(migrations.iter().find(|migration| migration.version > current))
+}
+
+// databases made before migrations already have the tables of versions 1 to 4, so those must be safe to apply again
+pub const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "users and messages",
+        sql: r"
+    CREATE TABLE IF NOT EXISTS user_data (
+        id       UUID,
+        username VARCHAR UNIQUE NOT NULL,
+        password VARCHAR NOT NULL,
+        online boolean NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id UUID,
+        timestamp TIMESTAMP WITH TIME ZONE,
+        message VARCHAR,
+        sender UUID,
+        recipient UUID
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        id UUID PRIMARY KEY,
+        timestamp TIMESTAMP WITH TIME ZONE NOT NULL,
+        message VARCHAR NOT NULL,
+        sender UUID NOT NULL,
+        recipient UUID NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_conversation ON history (sender, recipient, timestamp);
+    CREATE TABLE IF NOT EXISTS conversations (
+        user_a UUID,
+        user_b UUID,
+        last_seq BIGINT NOT NULL,
+        PRIMARY KEY (user_a, user_b)
+    );
+    ",
+    },
+    Migration {
+        version: 2,
+        // number the messages stored before conversations had sequence numbers, in the order they were sent
+        name: "sequence numbers",
+        sql: r"
+    ALTER TABLE history ADD COLUMN IF NOT EXISTS seq BIGINT;
+    ALTER TABLE messages ADD COLUMN IF NOT EXISTS seq BIGINT;
+    WITH numbered AS (
+        SELECT h.id, COALESCE(c.last_seq, 0) + row_number() OVER (
+            PARTITION BY LEAST(h.sender, h.recipient), GREATEST(h.sender, h.recipient) ORDER BY h.timestamp, h.id) AS seq
+        FROM history h LEFT JOIN conversations c
+            ON c.user_a = LEAST(h.sender, h.recipient) AND c.user_b = GREATEST(h.sender, h.recipient)
+        WHERE h.seq IS NULL
+    )
+    UPDATE history h SET seq = numbered.seq FROM numbered WHERE h.id = numbered.id;
+    INSERT INTO conversations (user_a, user_b, last_seq)
+        SELECT LEAST(sender, recipient), GREATEST(sender, recipient), MAX(seq) FROM history GROUP BY 1, 2
+        ON CONFLICT (user_a, user_b) DO UPDATE SET last_seq = GREATEST(conversations.last_seq, EXCLUDED.last_seq);
+    UPDATE messages m SET seq = COALESCE((SELECT seq FROM history h WHERE h.id = m.id), 0) WHERE m.seq IS NULL;
+    ALTER TABLE history ALTER COLUMN seq SET NOT NULL;
+    ALTER TABLE messages ALTER COLUMN seq SET NOT NULL;
+    CREATE UNIQUE INDEX IF NOT EXISTS history_sequence ON history (LEAST(sender, recipient), GREATEST(sender, recipient), seq);
+    ",
+    },
+    Migration {
+        version: 3,
+        // message bodies are sealed by the clients, the plaintext column is only kept for messages sent before that
+        name: "sealed messages",
+        sql: r"
+    ALTER TABLE user_data ADD COLUMN IF NOT EXISTS identity_key BYTEA;
+    ALTER TABLE history ADD COLUMN IF NOT EXISTS payload BYTEA;
+    ALTER TABLE history ALTER COLUMN message DROP NOT NULL;
+    ALTER TABLE messages ADD COLUMN IF NOT EXISTS payload BYTEA;
+    ALTER TABLE history ADD COLUMN IF NOT EXISTS header BYTEA;
+    ALTER TABLE messages ADD COLUMN IF NOT EXISTS header BYTEA;
+    ",
+    },
+    Migration {
+        version: 4,
+        name: "prekeys",
+        sql: r"
+    ALTER TABLE user_data ADD COLUMN IF NOT EXISTS signing_key BYTEA;
+    CREATE TABLE IF NOT EXISTS signed_prekeys (
+        user_id UUID PRIMARY KEY,
+        key_id BIGINT NOT NULL,
+        key BYTEA NOT NULL,
+        signature BYTEA NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS one_time_prekeys (
+        user_id UUID NOT NULL,
+        key_id BIGINT NOT NULL,
+        key BYTEA NOT NULL,
+        PRIMARY KEY (user_id, key_id)
+    );
+    ",
+    },
+    Migration {
+        version: 5,
+        // queued messages are looked up by id when acked and by recipient on every login
+        name: "message and user keys",
+        sql: r"
+    DELETE FROM messages WHERE id IS NULL;
+    ALTER TABLE messages ADD PRIMARY KEY (id);
+    CREATE INDEX messages_recipient ON messages (recipient);
+    DELETE FROM user_data WHERE id IS NULL;
+    ALTER TABLE user_data ADD PRIMARY KEY (id);
+    ",
+    },
+    Migration {
+        version: 6,
+        // every member's copy of a group message is a row of its own, numbered by the group instead of by the two users
+        name: "groups",
+        sql: r"
+    CREATE TABLE group_chats (
+        id UUID PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        last_seq BIGINT NOT NULL
+    );
+    CREATE TABLE group_members (
+        group_id UUID NOT NULL,
+        user_id UUID NOT NULL,
+        PRIMARY KEY (group_id, user_id)
+    );
+    CREATE INDEX group_members_user ON group_members (user_id);
+    ALTER TABLE history ADD COLUMN group_id UUID;
+    ALTER TABLE messages ADD COLUMN group_id UUID;
+    DROP INDEX history_sequence;
+    CREATE UNIQUE INDEX history_sequence ON history (LEAST(sender, recipient), GREATEST(sender, recipient), seq) WHERE group_id IS NULL;
+    CREATE UNIQUE INDEX history_group_sequence ON history (group_id, recipient, seq) WHERE group_id IS NOT NULL;
+    ",
+    },
+    Migration {
+        version: 7,
+        // channel messages are stored like group messages, with the channel in group_id
+        name: "spaces",
+        sql: r"
+    CREATE TABLE spaces (
+        id UUID PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        owner UUID NOT NULL
+    );
+    CREATE TABLE space_members (
+        space_id UUID NOT NULL,
+        user_id UUID NOT NULL,
+        PRIMARY KEY (space_id, user_id)
+    );
+    CREATE INDEX space_members_user ON space_members (user_id);
+    CREATE TABLE space_bans (
+        space_id UUID NOT NULL,
+        user_id UUID NOT NULL,
+        PRIMARY KEY (space_id, user_id)
+    );
+    CREATE TABLE space_channels (
+        id UUID PRIMARY KEY,
+        space_id UUID NOT NULL,
+        name VARCHAR NOT NULL,
+        last_seq BIGINT NOT NULL
+    );
+    CREATE INDEX space_channels_space ON space_channels (space_id);
+    CREATE TABLE space_roles (
+        id UUID PRIMARY KEY,
+        space_id UUID NOT NULL,
+        name VARCHAR NOT NULL,
+        permissions BIGINT NOT NULL
+    );
+    CREATE INDEX space_roles_space ON space_roles (space_id);
+    CREATE TABLE space_member_roles (
+        space_id UUID NOT NULL,
+        user_id UUID NOT NULL,
+        role_id UUID NOT NULL,
+        PRIMARY KEY (space_id, user_id, role_id)
+    );
+    ",
+    },
+    Migration {
+        version: 8,
+        // one row for every pair of users, whoever sent the request is the requester
+        name: "contacts",
+        sql: r"
+    CREATE TABLE contacts (
+        requester UUID NOT NULL,
+        addressee UUID NOT NULL,
+        accepted BOOLEAN NOT NULL,
+        PRIMARY KEY (requester, addressee)
+    );
+    CREATE INDEX contacts_addressee ON contacts (addressee);
+    ",
+    },
+    Migration {
+        version: 9,
+        // one row for every user a user blocked
+        name: "blocks",
+        sql: r"
+    CREATE TABLE blocks (
+        blocker UUID NOT NULL,
+        blocked UUID NOT NULL,
+        PRIMARY KEY (blocker, blocked)
+    );
+    CREATE INDEX blocks_blocked ON blocks (blocked);
+    ",
+    },
+    Migration {
+        version: 10,
+        // the expiry is kept next to the ttl so purging expired messages can use an index
+        // the timer of a conversation is kept on the row that numbers its messages
+        name: "disappearing messages",
+        sql: r"
+    ALTER TABLE history ADD COLUMN ttl BIGINT NOT NULL DEFAULT 0;
+    ALTER TABLE history ADD COLUMN expires TIMESTAMP WITH TIME ZONE;
+    ALTER TABLE messages ADD COLUMN ttl BIGINT NOT NULL DEFAULT 0;
+    ALTER TABLE messages ADD COLUMN expires TIMESTAMP WITH TIME ZONE;
+    ALTER TABLE conversations ADD COLUMN ttl BIGINT NOT NULL DEFAULT 0;
+    ALTER TABLE conversations ADD COLUMN ttl_set_by UUID;
+    ALTER TABLE group_chats ADD COLUMN ttl BIGINT NOT NULL DEFAULT 0;
+    ALTER TABLE group_chats ADD COLUMN ttl_set_by UUID;
+    ALTER TABLE space_channels ADD COLUMN ttl BIGINT NOT NULL DEFAULT 0;
+    ALTER TABLE space_channels ADD COLUMN ttl_set_by UUID;
+    CREATE INDEX history_expires ON history (expires) WHERE expires IS NOT NULL;
+    CREATE INDEX messages_expires ON messages (expires) WHERE expires IS NOT NULL;
+    ",
+    },
+];
+
+// timestamps are stored as microseconds since the unix epoch, ids as their 16 bytes
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "users, messages and prekeys",
+        sql: r"
+    CREATE TABLE IF NOT EXISTS user_data (
+        id BLOB PRIMARY KEY,
+        username TEXT UNIQUE NOT NULL,
+        password TEXT NOT NULL,
+        online INTEGER NOT NULL,
+        identity_key BLOB,
+        signing_key BLOB
+    );
+    CREATE TABLE IF NOT EXISTS conversations (
+        user_a BLOB NOT NULL,
+        user_b BLOB NOT NULL,
+        last_seq INTEGER NOT NULL,
+        PRIMARY KEY (user_a, user_b)
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        id BLOB PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        sender BLOB NOT NULL,
+        recipient BLOB NOT NULL,
+        payload BLOB NOT NULL,
+        header BLOB,
+        seq INTEGER NOT NULL
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS history_sequence ON history (min(sender, recipient), max(sender, recipient), seq);
+    CREATE TABLE IF NOT EXISTS messages (
+        id BLOB PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        sender BLOB NOT NULL,
+        recipient BLOB NOT NULL,
+        payload BLOB NOT NULL,
+        header BLOB,
+        seq INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_recipient ON messages (recipient);
+    CREATE TABLE IF NOT EXISTS signed_prekeys (
+        user_id BLOB PRIMARY KEY,
+        key_id INTEGER NOT NULL,
+        key BLOB NOT NULL,
+        signature BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS one_time_prekeys (
+        user_id BLOB NOT NULL,
+        key_id INTEGER NOT NULL,
+        key BLOB NOT NULL,
+        PRIMARY KEY (user_id, key_id)
+    );
+    ",
+    },
+    Migration {
+        version: 2,
+        // every member's copy of a group message is a row of its own, numbered by the group instead of by the two users
+        name: "groups",
+        sql: r"
+    CREATE TABLE group_chats (
+        id BLOB PRIMARY KEY,
+        name TEXT NOT NULL,
+        last_seq INTEGER NOT NULL
+    );
+    CREATE TABLE group_members (
+        group_id BLOB NOT NULL,
+        user_id BLOB NOT NULL,
+        PRIMARY KEY (group_id, user_id)
+    );
+    CREATE INDEX group_members_user ON group_members (user_id);
+    ALTER TABLE history ADD COLUMN group_id BLOB;
+    ALTER TABLE messages ADD COLUMN group_id BLOB;
+    DROP INDEX history_sequence;
+    CREATE UNIQUE INDEX history_sequence ON history (min(sender, recipient), max(sender, recipient), seq) WHERE group_id IS NULL;
+    CREATE UNIQUE INDEX history_group_sequence ON history (group_id, recipient, seq) WHERE group_id IS NOT NULL;
+    ",
+    },
+    Migration {
+        version: 3,
+        // channel messages are stored like group messages, with the channel in group_id
+        name: "spaces",
+        sql: r"
+    CREATE TABLE spaces (
+        id BLOB PRIMARY KEY,
+        name TEXT NOT NULL,
+        owner BLOB NOT NULL
+    );
+    CREATE TABLE space_members (
+        space_id BLOB NOT NULL,
+        user_id BLOB NOT NULL,
+        PRIMARY KEY (space_id, user_id)
+    );
+    CREATE INDEX space_members_user ON space_members (user_id);
+    CREATE TABLE space_bans (
+        space_id BLOB NOT NULL,
+        user_id BLOB NOT NULL,
+        PRIMARY KEY (space_id, user_id)
+    );
+    CREATE TABLE space_channels (
+        id BLOB PRIMARY KEY,
+        space_id BLOB NOT NULL,
+        name TEXT NOT NULL,
+        last_seq INTEGER NOT NULL
+    );
+    CREATE INDEX space_channels_space ON space_channels (space_id);
+    CREATE TABLE space_roles (
+        id BLOB PRIMARY KEY,
+        space_id BLOB NOT NULL,
+        name TEXT NOT NULL,
+        permissions INTEGER NOT NULL
+    );
+    CREATE INDEX space_roles_space ON space_roles (space_id);
+    CREATE TABLE space_member_roles (
+        space_id BLOB NOT NULL,
+        user_id BLOB NOT NULL,
+        role_id BLOB NOT NULL,
+        PRIMARY KEY (space_id, user_id, role_id)
+    );
+    ",
+    },
+    Migration {
+        version: 4,
+        // one row for every pair of users, whoever sent the request is the requester
+        name: "contacts",
+        sql: r"
+    CREATE TABLE contacts (
+        requester BLOB NOT NULL,
+        addressee BLOB NOT NULL,
+        accepted INTEGER NOT NULL,
+        PRIMARY KEY (requester, addressee)
+    );
+    CREATE INDEX contacts_addressee ON contacts (addressee);
+    ",
+    },
+    Migration {
+        version: 5,
+        // one row for every user a user blocked
+        name: "blocks",
+        sql: r"
+    CREATE TABLE blocks (
+        blocker BLOB NOT NULL,
+        blocked BLOB NOT NULL,
+        PRIMARY KEY (blocker, blocked)
+    );
+    CREATE INDEX blocks_blocked ON blocks (blocked);
+    ",
+    },
+    Migration {
+        version: 6,
+        // the expiry is kept next to the ttl so purging expired messages can use an index
+        // the timer of a conversation is kept on the row that numbers its messages
+        name: "disappearing messages",
+        sql: r"
+    ALTER TABLE history ADD COLUMN ttl INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE history ADD COLUMN expires INTEGER;
+    ALTER TABLE messages ADD COLUMN ttl INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE messages ADD COLUMN expires INTEGER;
+    ALTER TABLE conversations ADD COLUMN ttl INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE conversations ADD COLUMN ttl_set_by BLOB;
+    ALTER TABLE group_chats ADD COLUMN ttl INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE group_chats ADD COLUMN ttl_set_by BLOB;
+    ALTER TABLE space_channels ADD COLUMN ttl INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE space_channels ADD COLUMN ttl_set_by BLOB;
+    CREATE INDEX history_expires ON history (expires) WHERE expires IS NOT NULL;
+    CREATE INDEX messages_expires ON messages (expires) WHERE expires IS NOT NULL;
+    ",
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_count_up_from_one() {
+        for migrations in [POSTGRES, SQLITE] {
+            for (index, migration) in migrations.iter().enumerate() {
+                assert_eq!(migration.version, index as i64 + 1, "{}", migration.name);
+            }
+        }
+    }
+
+    #[test]
+    fn next_migration() {
+        assert_eq!(next(POSTGRES, 0).unwrap(), POSTGRES.first());
+        assert_eq!(next(POSTGRES, 2).unwrap().map(|migration| migration.version), Some(3));
+        assert_eq!(next(POSTGRES, POSTGRES.len() as i64).unwrap(), None);
+        // a newer server already migrated the database
+        assert!(next(POSTGRES, POSTGRES.len() as i64 + 1).is_err());
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        use std::path::Path;
+        use crate::database::sqlite::SqliteStore;
+        use crate::database::Store;
+
+        let path = std::env::temp_dir().join(format!("dl_server_migrations_test_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(SqliteStore::open(&path).unwrap().migrate().unwrap().len(), SQLITE.len());
+
+        // a newer server added a migration this one does not know
+        let db = rusqlite::Connection::open(&path).unwrap();
+        db.execute("INSERT INTO schema_version(version, name, applied) VALUES (?1, 'future', 0)", [SQLITE.len() as i64 + 1]).unwrap();
+        drop(db);
+        assert!(SqliteStore::open(Path::new(&path)).unwrap().migrate().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
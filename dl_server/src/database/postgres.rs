@@ -0,0 +1,1023 @@
+use chrono::{DateTime, Utc};
+use r2d2_postgres::postgres::{Client, NoTls, Row};
+use r2d2_postgres::{PostgresConnectionManager, r2d2};
+use r2d2_postgres::r2d2::PooledConnection;
+use uuid::Uuid;
+use dl_network_common::crypto::{PreKeyBundle, PublicPreKey, SealedPayload};
+use dl_network_common::{ContactState, HistoryCursor};
+use crate::database::migrations::{self, Migration};
+use crate::database::{contact_state, finish_page, page_start, Conversation, DBContact, DBGroup, DBGroupCopy, DBInfo, DBMessageQuery, DBRole, DBSpace, DBSpaceMember, DBTimer, PageStart, Store, MAX_HISTORY_PAGE};
+
+/// Stores everything in a PostgreSQL database, through a pool of connections shared by every client handler
+pub struct PostgresStore {
+    pool: r2d2::Pool<PostgresConnectionManager<NoTls>>,
+}
+
+impl PostgresStore {
+    /// Connect to the database, its schema is brought up to date with `Store::migrate`
+    pub fn connect(info: &DBInfo) -> Result<Self, String> {
+        let config = format!("host={} port={} user={} password={}", info.ip, info.port, info.uname, info.pass).parse()
+            .map_err(|e| format!("Invalid database settings: {}", e))?;
+        let pool = r2d2::Pool::new(PostgresConnectionManager::new(config, NoTls))
+            .map_err(|e| format!("Failed to connect to the database: {}", e))?;
+        Ok(Self { pool })
+    }
+
+    fn db(&self) -> Result<PooledConnection<PostgresConnectionManager<NoTls>>, String> {
+        self.pool.get().map_err(|e| format!("Failed to get a database connection: {}", e))
+    }
+}
+
+// messages are selected with their sender's username and read back with `from_row`
+const SELECT_HISTORY: &str = "SELECT h.id, u.username, h.message, h.payload, h.timestamp, h.seq, h.header, h.group_id, h.ttl FROM history h JOIN user_data u ON u.id = h.sender";
+
+// the messages of a conversation the user can see, with the user as $1 and the other user, group or channel as $2
+fn select_conversation(conversation: &Conversation) -> (String, Uuid) {
+    match conversation {
+        Conversation::Direct(other) => (format!("{} WHERE ((h.sender=$1 AND h.recipient=$2) OR (h.sender=$2 AND h.recipient=$1)) AND h.group_id IS NULL", SELECT_HISTORY), *other),
+        Conversation::Group(group) | Conversation::Channel(group) => (format!("{} WHERE h.recipient=$1 AND h.group_id=$2", SELECT_HISTORY), *group),
+    }
+}
+
+// a group and its members, None if there is no such group
+fn select_group(db: &mut Client, id: &Uuid) -> Result<Option<DBGroup>, String> {
+    let name_query = db.query("SELECT name FROM group_chats WHERE id=$1", &[&id]);
+    if let Err(e) = name_query {
+        return Err(format!("select_group.{}", e));
+    }
+    let Some(name) = name_query.unwrap().first().map(|row| row.get::<_, String>(0)) else {
+        return Ok(None);
+    };
+    let members_query = db.query(
+        "SELECT u.id, u.username FROM group_members m JOIN user_data u ON u.id = m.user_id WHERE m.group_id=$1 ORDER BY u.username",
+        &[&id]);
+    if let Err(e) = members_query {
+        return Err(format!("select_group.{}", e));
+    }
+    let members: Vec<(Uuid, String)> = members_query.unwrap().iter().map(|row| (row.get(0), row.get(1))).collect();
+    Ok(Some(DBGroup { id: *id, name, members }))
+}
+
+// a space with its channels, roles, members and bans, None if there is no such space
+fn select_space(db: &mut Client, id: &Uuid) -> Result<Option<DBSpace>, String> {
+    let space_query = db.query("SELECT name, owner FROM spaces WHERE id=$1", &[&id]);
+    if let Err(e) = space_query {
+        return Err(format!("select_space.{}", e));
+    }
+    let Some((name, owner)) = space_query.unwrap().first().map(|row| (row.get::<_, String>(0), row.get::<_, Uuid>(1))) else {
+        return Ok(None);
+    };
+
+    let queries = [
+        "SELECT id, name FROM space_channels WHERE space_id=$1 ORDER BY name",
+        "SELECT id, name, permissions FROM space_roles WHERE space_id=$1 ORDER BY name",
+        "SELECT u.id, u.username FROM space_members m JOIN user_data u ON u.id = m.user_id WHERE m.space_id=$1 ORDER BY u.username",
+        "SELECT user_id, role_id FROM space_member_roles WHERE space_id=$1 ORDER BY role_id",
+        "SELECT u.id, u.username FROM space_bans b JOIN user_data u ON u.id = b.user_id WHERE b.space_id=$1 ORDER BY u.username",
+    ];
+    let mut rows = Vec::new();
+    for query in queries {
+        match db.query(query, &[&id]) {
+            Ok(result) => rows.push(result),
+            Err(e) => return Err(format!("select_space.{}", e)),
+        }
+    }
+
+    let channels = rows[0].iter().map(|row| (row.get(0), row.get(1))).collect();
+    // permissions are stored as a BIGINT, postgres has no unsigned integers
+    let roles = rows[1].iter().map(|row| DBRole { id: row.get(0), name: row.get(1), permissions: row.get::<_, i64>(2) as u32 }).collect();
+    let mut members: Vec<DBSpaceMember> = rows[2].iter().map(|row| DBSpaceMember { id: row.get(0), username: row.get(1), roles: Vec::new() }).collect();
+    for row in &rows[3] {
+        let user: Uuid = row.get(0);
+        if let Some(member) = members.iter_mut().find(|member| member.id == user) {
+            member.roles.push(row.get(1));
+        }
+    }
+    let banned = rows[4].iter().map(|row| (row.get(0), row.get(1))).collect();
+
+    Ok(Some(DBSpace { id: *id, name, owner, channels, roles, members, banned }))
+}
+
+// store every copy of a message sent to a group or channel, with the sequence number `next_seq` counts up to
+// returns None if there is no such group or channel, errors start with `name`
+#[allow(clippy::too_many_arguments)]
+fn store_copies(db: &mut Client, name: &str, next_seq: &str, conversation: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<Option<i64>, String> {
+    // the group or channel row stays locked until the copies are stored, like the conversation row in `store_msg`
+    let transaction = db.transaction();
+    if let Err(e) = transaction {
+        return Err(format!("{}.{}", name, e));
+    }
+    let mut transaction = transaction.unwrap();
+
+    let seq_query = transaction.query(next_seq, &[&conversation]);
+    if let Err(e) = seq_query {
+        return Err(format!("{}.{}", name, e));
+    }
+    let Some(seq) = seq_query.unwrap().first().map(|row| row.get::<_, i64>(0)) else {
+        return Ok(None);
+    };
+    let ttl = ttl as i64;
+    let expires = if ttl > 0 { Some(timestamp + chrono::Duration::seconds(ttl)) } else { None };
+
+    for copy in copies {
+        let payload = copy.message.to_bytes();
+        let header = &copy.message.header;
+        if let Err(e) = transaction.execute(
+            "INSERT INTO history(id, sender, recipient, payload, header, timestamp, seq, group_id, ttl, expires) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            &[&copy.id, &sender, &copy.recipient, &payload, header, &timestamp, &seq, &conversation, &ttl, &expires]) {
+            return Err(format!("{}.history.{}", name, e));
+        }
+        if let Err(e) = transaction.execute(
+            "INSERT INTO messages(id, sender, recipient, payload, header, timestamp, seq, group_id, ttl, expires) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            &[&copy.id, &sender, &copy.recipient, &payload, header, &timestamp, &seq, &conversation, &ttl, &expires]) {
+            return Err(format!("{}.messages.{}", name, e));
+        }
+    }
+
+    if let Err(e) = transaction.commit() {
+        return Err(format!("{}.{}", name, e));
+    }
+    Ok(Some(seq))
+}
+
+// read a row selected as (id, sender username, message, payload, timestamp, seq, header, group id, ttl)
+fn from_row(row: &Row) -> Result<DBMessageQuery, String> {
+    let message = match (row.get::<_, Option<Vec<u8>>>(3), row.get::<_, Option<String>>(2)) {
+        (Some(payload), _) => SealedPayload {
+            header: row.get::<_, Option<Vec<u8>>>(6).unwrap_or_default(),
+            ..SealedPayload::from_bytes(&payload)?
+        },
+        // sent before messages were sealed
+        (None, Some(plaintext)) => SealedPayload::plaintext(plaintext),
+        (None, None) => return Err(format!("A stored message has no body")),
+    };
+    Ok(DBMessageQuery {
+        id: row.get(0),
+        sender: row.get(1),
+        message,
+        timestamp: row.get(4),
+        seq: row.get(5),
+        group: row.get(7),
+        ttl: row.get::<_, i64>(8) as u64,
+    })
+}
+
+impl Store for PostgresStore {
+    fn migrate(&self) -> Result<Vec<&'static Migration>, String> {
+        let mut db = self.db()?;
+        if let Err(e) = db.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (version BIGINT PRIMARY KEY, name VARCHAR NOT NULL, \
+                applied TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now())") {
+            return Err(format!("migrate.{}", e));
+        }
+
+        // every migration is applied in its own transaction
+        let mut applied = Vec::new();
+        loop {
+            let mut transaction = db.transaction().map_err(|e| format!("migrate.{}", e))?;
+            // servers starting at the same time wait here for each other's migrations
+            if let Err(e) = transaction.batch_execute("LOCK TABLE schema_version IN EXCLUSIVE MODE") {
+                return Err(format!("migrate.{}", e));
+            }
+            let current: i64 = match transaction.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[]) {
+                Ok(row) => row.get(0),
+                Err(e) => return Err(format!("migrate.{}", e)),
+            };
+            let Some(migration) = migrations::next(migrations::POSTGRES, current)? else {
+                return Ok(applied);
+            };
+
+            if let Err(e) = transaction.batch_execute(migration.sql) {
+                return Err(format!("Migration {} ({}) failed: {}", migration.version, migration.name, e));
+            }
+            if let Err(e) = transaction.execute("INSERT INTO schema_version(version, name) VALUES ($1, $2)", &[&migration.version, &migration.name]) {
+                return Err(format!("migrate.{}", e));
+            }
+            if let Err(e) = transaction.commit() {
+                return Err(format!("Migration {} ({}) failed: {}", migration.version, migration.name, e));
+            }
+            applied.push(migration);
+        }
+    }
+
+    fn store_msg(&self, id: &Uuid, sender: &Uuid, recipient: &Uuid, message: &SealedPayload, timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let mut db = self.db()?;
+        // the sequence number is only used up if the message is stored,
+        // and the conversation row stays locked until then so messages are stored in sequence order
+        let transaction = db.transaction();
+        if let Err(e) = transaction {
+            return Err(format!("store_msg.{}", e));
+        }
+        let mut transaction = transaction.unwrap();
+
+        let seq_query = transaction.query_one(
+            "INSERT INTO conversations(user_a, user_b, last_seq) VALUES (LEAST($1::UUID, $2::UUID), GREATEST($1::UUID, $2::UUID), 1) \
+                ON CONFLICT (user_a, user_b) DO UPDATE SET last_seq = conversations.last_seq + 1 RETURNING last_seq",
+            &[&sender, &recipient]);
+        if let Err(e) = seq_query {
+            return Err(format!("store_msg.{}", e));
+        }
+        let seq: i64 = seq_query.unwrap().get(0);
+        let payload = message.to_bytes();
+        let header = &message.header;
+        let ttl = ttl as i64;
+        let expires = if ttl > 0 { Some(timestamp + chrono::Duration::seconds(ttl)) } else { None };
+
+        if let Err(e) = transaction.execute(
+            "INSERT INTO history(id, sender, recipient, payload, header, timestamp, seq, ttl, expires) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            &[&id, &sender, &recipient, &payload, header, &timestamp, &seq, &ttl, &expires]) {
+            return Err(format!("store_msg.history.{}", e));
+        }
+        if let Err(e) = transaction.execute(
+            "INSERT INTO messages(id, sender, recipient, payload, header, timestamp, seq, ttl, expires) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            &[&id, &sender, &recipient, &payload, header, &timestamp, &seq, &ttl, &expires]) {
+            return Err(format!("store_msg.messages.{}", e));
+        }
+
+        if let Err(e) = transaction.commit() {
+            return Err(format!("store_msg.{}", e));
+        }
+        Ok(seq)
+    }
+
+    fn store_group_msg(&self, group: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let next_seq = "UPDATE group_chats SET last_seq = last_seq + 1 WHERE id=$1 RETURNING last_seq";
+        let mut db = self.db()?;
+        match store_copies(&mut db, "store_group_msg", next_seq, group, sender, copies, timestamp, ttl)? {
+            Some(seq) => Ok(seq),
+            None => Err(format!("store_group_msg.There is no group {}", group)),
+        }
+    }
+
+    fn store_channel_msg(&self, channel: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let next_seq = "UPDATE space_channels SET last_seq = last_seq + 1 WHERE id=$1 RETURNING last_seq";
+        let mut db = self.db()?;
+        match store_copies(&mut db, "store_channel_msg", next_seq, channel, sender, copies, timestamp, ttl)? {
+            Some(seq) => Ok(seq),
+            None => Err(format!("store_channel_msg.There is no channel {}", channel)),
+        }
+    }
+
+    fn get_queued_msgs(&self, receiver: &Uuid) -> Result<Vec<DBMessageQuery>, String> {
+        let mut db = self.db()?;
+        // group messages from several senders share the numbering of their group
+        let msg_query_result = db.query(
+            "SELECT m.id, u.username, m.message, m.payload, m.timestamp, m.seq, m.header, m.group_id, m.ttl FROM messages m JOIN user_data u ON u.id = m.sender \
+                WHERE m.recipient=$1 ORDER BY COALESCE(m.group_id, m.sender), m.seq",
+            &[&receiver]);
+        if let Err(e) = msg_query_result {
+            return Err(format!("get_queued_msgs.{}", e));
+        }
+
+        msg_query_result.unwrap().iter().map(from_row).collect()
+    }
+
+    fn delete_msg(&self, id: &Uuid, recipient: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute("DELETE FROM messages WHERE id=$1 AND recipient=$2", &[&id, &recipient]) {
+            Ok(deleted) => Ok(deleted > 0),
+            Err(e) => Err(format!("A message could not be removed from the database! {}", e)),
+        }
+    }
+
+    fn purge_expired_msgs(&self, now: DateTime<Utc>) -> Result<usize, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction();
+        if let Err(e) = transaction {
+            return Err(format!("purge_expired_msgs.{}", e));
+        }
+        let mut transaction = transaction.unwrap();
+        if let Err(e) = transaction.execute("DELETE FROM messages WHERE expires <= $1", &[&now]) {
+            return Err(format!("purge_expired_msgs.messages.{}", e));
+        }
+        let purged = match transaction.execute("DELETE FROM history WHERE expires <= $1", &[&now]) {
+            Ok(purged) => purged as usize,
+            Err(e) => return Err(format!("purge_expired_msgs.history.{}", e)),
+        };
+        if let Err(e) = transaction.commit() {
+            return Err(format!("purge_expired_msgs.{}", e));
+        }
+        Ok(purged)
+    }
+
+    fn get_history(&self, user: &Uuid, conversation: &Conversation, cursor: &HistoryCursor, limit: u32) -> Result<(Vec<DBMessageQuery>, bool), String> {
+        let mut db = self.db()?;
+        // select one more row than needed to know if there is another page
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE) as i64;
+        let fetch = limit + 1;
+
+        let (conversation, other) = select_conversation(conversation);
+
+        let (start, backwards) = page_start(cursor)?;
+        let (cmp, order) = if backwards { ("<", "DESC") } else { (">", "ASC") };
+        let query_result = match start {
+            PageStart::Latest => db.query(
+                format!("{} ORDER BY h.seq DESC LIMIT $3", conversation).as_str(),
+                &[user, &other, &fetch]),
+            PageStart::Id(id) => {
+                // a page can only start at a message of the same conversation
+                let cursor = db.query(format!("{} AND h.id=$3", conversation).as_str(), &[user, &other, &id]);
+                if let Err(e) = cursor {
+                    return Err(format!("get_history.{}", e));
+                }
+                let Some(seq) = cursor.unwrap().first().map(|row| row.get::<_, i64>(5)) else {
+                    return Err(format!("get_history.Message {} is not in the conversation", id));
+                };
+                db.query(
+                    format!("{} AND h.seq {} $4 ORDER BY h.seq {} LIMIT $3", conversation, cmp, order).as_str(),
+                    &[user, &other, &fetch, &seq])
+            }
+            PageStart::Time(time) => db.query(
+                format!("{} AND h.timestamp {} $4 ORDER BY h.seq {} LIMIT $3", conversation, cmp, order).as_str(),
+                &[user, &other, &fetch, &time]),
+        };
+        if let Err(e) = query_result {
+            return Err(format!("get_history.{}", e));
+        }
+        let page = query_result.unwrap().iter().map(from_row).collect::<Result<Vec<_>, String>>()?;
+
+        Ok(finish_page(page, limit as usize, backwards))
+    }
+
+    fn get_history_range(&self, user: &Uuid, conversation: &Conversation, from: i64, to: i64) -> Result<(Vec<DBMessageQuery>, bool), String> {
+        let mut db = self.db()?;
+        let (conversation, other) = select_conversation(conversation);
+        let query_result = db.query(
+            format!("{} AND h.seq BETWEEN $3 AND $4 ORDER BY h.seq LIMIT $5", conversation).as_str(),
+            &[user, &other, &from, &to, &(MAX_HISTORY_PAGE as i64 + 1)]);
+        if let Err(e) = query_result {
+            return Err(format!("get_history_range.{}", e));
+        }
+        let page = query_result.unwrap().iter().map(from_row).collect::<Result<Vec<_>, String>>()?;
+
+        Ok(finish_page(page, MAX_HISTORY_PAGE as usize, false))
+    }
+
+    fn create_group(&self, name: &str, members: &[Uuid]) -> Result<Uuid, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction();
+        if let Err(e) = transaction {
+            return Err(format!("create_group.{}", e));
+        }
+        let mut transaction = transaction.unwrap();
+
+        let id = Uuid::new_v4();
+        if let Err(e) = transaction.execute("INSERT INTO group_chats(id, name, last_seq) VALUES ($1, $2, 0)", &[&id, &name]) {
+            return Err(format!("create_group.{}", e));
+        }
+        for member in members {
+            if let Err(e) = transaction.execute(
+                "INSERT INTO group_members(group_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", &[&id, member]) {
+                return Err(format!("create_group.{}", e));
+            }
+        }
+
+        if let Err(e) = transaction.commit() {
+            return Err(format!("create_group.{}", e));
+        }
+        Ok(id)
+    }
+
+    fn get_group(&self, group: &Uuid) -> Result<Option<DBGroup>, String> {
+        let mut db = self.db()?;
+        select_group(&mut db, group)
+    }
+
+    fn get_user_groups(&self, user: &Uuid) -> Result<Vec<DBGroup>, String> {
+        let mut db = self.db()?;
+        let ids_query = db.query(
+            "SELECT g.id FROM group_members m JOIN group_chats g ON g.id = m.group_id WHERE m.user_id=$1 ORDER BY g.name", &[&user]);
+        if let Err(e) = ids_query {
+            return Err(format!("get_user_groups.{}", e));
+        }
+
+        let mut groups = Vec::new();
+        for row in ids_query.unwrap() {
+            if let Some(group) = select_group(&mut db, &row.get::<_, Uuid>(0))? {
+                groups.push(group);
+            }
+        }
+        Ok(groups)
+    }
+
+    fn add_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute(
+            "INSERT INTO group_members(group_id, user_id) SELECT id, $2 FROM group_chats WHERE id=$1 ON CONFLICT DO NOTHING",
+            &[&group, &user]) {
+            Ok(added) => Ok(added > 0),
+            Err(e) => Err(format!("add_group_member.{}", e)),
+        }
+    }
+
+    fn remove_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute("DELETE FROM group_members WHERE group_id=$1 AND user_id=$2", &[&group, &user]) {
+            Ok(removed) => Ok(removed > 0),
+            Err(e) => Err(format!("remove_group_member.{}", e)),
+        }
+    }
+
+    fn rename_group(&self, group: &Uuid, name: &str) -> Result<(), String> {
+        let mut db = self.db()?;
+        if let Err(e) = db.execute("UPDATE group_chats SET name=$1 WHERE id=$2", &[&name, &group]) {
+            return Err(format!("rename_group.{}", e));
+        }
+        Ok(())
+    }
+
+    fn create_space(&self, name: &str, owner: &Uuid, everyone: u32) -> Result<Uuid, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction();
+        if let Err(e) = transaction {
+            return Err(format!("create_space.{}", e));
+        }
+        let mut transaction = transaction.unwrap();
+
+        let id = Uuid::new_v4();
+        if let Err(e) = transaction.execute("INSERT INTO spaces(id, name, owner) VALUES ($1, $2, $3)", &[&id, &name, &owner]) {
+            return Err(format!("create_space.{}", e));
+        }
+        if let Err(e) = transaction.execute("INSERT INTO space_members(space_id, user_id) VALUES ($1, $2)", &[&id, &owner]) {
+            return Err(format!("create_space.{}", e));
+        }
+        if let Err(e) = transaction.execute(
+            "INSERT INTO space_roles(id, space_id, name, permissions) VALUES ($1, $1, 'everyone', $2)", &[&id, &(everyone as i64)]) {
+            return Err(format!("create_space.{}", e));
+        }
+
+        if let Err(e) = transaction.commit() {
+            return Err(format!("create_space.{}", e));
+        }
+        Ok(id)
+    }
+
+    fn get_space(&self, space: &Uuid) -> Result<Option<DBSpace>, String> {
+        let mut db = self.db()?;
+        select_space(&mut db, space)
+    }
+
+    fn get_user_spaces(&self, user: &Uuid) -> Result<Vec<DBSpace>, String> {
+        let mut db = self.db()?;
+        let ids_query = db.query(
+            "SELECT s.id FROM space_members m JOIN spaces s ON s.id = m.space_id WHERE m.user_id=$1 ORDER BY s.name", &[&user]);
+        if let Err(e) = ids_query {
+            return Err(format!("get_user_spaces.{}", e));
+        }
+
+        let mut spaces = Vec::new();
+        for row in ids_query.unwrap() {
+            if let Some(space) = select_space(&mut db, &row.get::<_, Uuid>(0))? {
+                spaces.push(space);
+            }
+        }
+        Ok(spaces)
+    }
+
+    fn add_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute(
+            "INSERT INTO space_members(space_id, user_id) SELECT id, $2 FROM spaces WHERE id=$1 ON CONFLICT DO NOTHING",
+            &[&space, &user]) {
+            Ok(added) => Ok(added > 0),
+            Err(e) => Err(format!("add_space_member.{}", e)),
+        }
+    }
+
+    fn remove_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction();
+        if let Err(e) = transaction {
+            return Err(format!("remove_space_member.{}", e));
+        }
+        let mut transaction = transaction.unwrap();
+
+        let removed = transaction.execute("DELETE FROM space_members WHERE space_id=$1 AND user_id=$2", &[&space, &user]);
+        if let Err(e) = removed {
+            return Err(format!("remove_space_member.{}", e));
+        }
+        if let Err(e) = transaction.execute("DELETE FROM space_member_roles WHERE space_id=$1 AND user_id=$2", &[&space, &user]) {
+            return Err(format!("remove_space_member.{}", e));
+        }
+
+        if let Err(e) = transaction.commit() {
+            return Err(format!("remove_space_member.{}", e));
+        }
+        Ok(removed.unwrap() > 0)
+    }
+
+    fn set_space_ban(&self, space: &Uuid, user: &Uuid, banned: bool) -> Result<bool, String> {
+        let sql = if banned {
+            "INSERT INTO space_bans(space_id, user_id) SELECT id, $2 FROM spaces WHERE id=$1 ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM space_bans WHERE space_id=$1 AND user_id=$2"
+        };
+        let mut db = self.db()?;
+        match db.execute(sql, &[&space, &user]) {
+            Ok(changed) => Ok(changed > 0),
+            Err(e) => Err(format!("set_space_ban.{}", e)),
+        }
+    }
+
+    fn create_channel(&self, space: &Uuid, name: &str) -> Result<Uuid, String> {
+        let mut db = self.db()?;
+        let id = Uuid::new_v4();
+        match db.execute(
+            "INSERT INTO space_channels(id, space_id, name, last_seq) SELECT $1, id, $3, 0 FROM spaces WHERE id=$2",
+            &[&id, &space, &name]) {
+            Ok(0) => Err(format!("create_channel.There is no space {}", space)),
+            Ok(_) => Ok(id),
+            Err(e) => Err(format!("create_channel.{}", e)),
+        }
+    }
+
+    fn delete_channel(&self, space: &Uuid, channel: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute("DELETE FROM space_channels WHERE id=$1 AND space_id=$2", &[&channel, &space]) {
+            Ok(deleted) => Ok(deleted > 0),
+            Err(e) => Err(format!("delete_channel.{}", e)),
+        }
+    }
+
+    fn get_channel_space(&self, channel: &Uuid) -> Result<Option<Uuid>, String> {
+        let mut db = self.db()?;
+        match db.query("SELECT space_id FROM space_channels WHERE id=$1", &[&channel]) {
+            Ok(rows) => Ok(rows.first().map(|row| row.get(0))),
+            Err(e) => Err(format!("get_channel_space.{}", e)),
+        }
+    }
+
+    fn set_role(&self, space: &Uuid, role: &DBRole) -> Result<(), String> {
+        let mut db = self.db()?;
+        // a role of another space with the same id is left alone
+        match db.execute(
+            "INSERT INTO space_roles(id, space_id, name, permissions) SELECT $1, id, $3, $4 FROM spaces WHERE id=$2 \
+                ON CONFLICT (id) DO UPDATE SET name=EXCLUDED.name, permissions=EXCLUDED.permissions WHERE space_roles.space_id=EXCLUDED.space_id",
+            &[&role.id, &space, &role.name, &(role.permissions as i64)]) {
+            Ok(0) => Err(format!("set_role.There is no role {} in space {}", role.id, space)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("set_role.{}", e)),
+        }
+    }
+
+    fn delete_role(&self, space: &Uuid, role: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction();
+        if let Err(e) = transaction {
+            return Err(format!("delete_role.{}", e));
+        }
+        let mut transaction = transaction.unwrap();
+
+        let deleted = transaction.execute("DELETE FROM space_roles WHERE id=$1 AND space_id=$2", &[&role, &space]);
+        if let Err(e) = deleted {
+            return Err(format!("delete_role.{}", e));
+        }
+        if let Err(e) = transaction.execute("DELETE FROM space_member_roles WHERE role_id=$1 AND space_id=$2", &[&role, &space]) {
+            return Err(format!("delete_role.{}", e));
+        }
+
+        if let Err(e) = transaction.commit() {
+            return Err(format!("delete_role.{}", e));
+        }
+        Ok(deleted.unwrap() > 0)
+    }
+
+    fn assign_role(&self, space: &Uuid, role: &Uuid, user: &Uuid, assigned: bool) -> Result<bool, String> {
+        // only members can be given roles
+        let sql = if assigned {
+            "INSERT INTO space_member_roles(space_id, user_id, role_id) SELECT space_id, user_id, $3 FROM space_members \
+                WHERE space_id=$1 AND user_id=$2 ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM space_member_roles WHERE space_id=$1 AND user_id=$2 AND role_id=$3"
+        };
+        let mut db = self.db()?;
+        match db.execute(sql, &[&space, &user, &role]) {
+            Ok(changed) => Ok(changed > 0),
+            Err(e) => Err(format!("assign_role.{}", e)),
+        }
+    }
+
+    fn get_contacts(&self, user: &Uuid) -> Result<Vec<DBContact>, String> {
+        let mut db = self.db()?;
+        let contacts_query = db.query(
+            "SELECT u.id, u.username, c.accepted, c.requester=$1 FROM contacts c \
+                JOIN user_data u ON u.id = CASE WHEN c.requester=$1 THEN c.addressee ELSE c.requester END \
+                WHERE c.requester=$1 OR c.addressee=$1 ORDER BY u.username",
+            &[&user]);
+        if let Err(e) = contacts_query {
+            return Err(format!("get_contacts.{}", e));
+        }
+        Ok(contacts_query.unwrap().iter().map(|row| DBContact {
+            id: row.get(0),
+            username: row.get(1),
+            state: contact_state(row.get(2), row.get(3)),
+        }).collect())
+    }
+
+    fn get_contact_state(&self, user: &Uuid, other: &Uuid) -> Result<ContactState, String> {
+        let mut db = self.db()?;
+        match db.query(
+            "SELECT accepted, requester=$1 FROM contacts WHERE (requester=$1 AND addressee=$2) OR (requester=$2 AND addressee=$1)",
+            &[&user, &other]) {
+            Ok(rows) => Ok(rows.first().map_or(ContactState::None, |row| contact_state(row.get(0), row.get(1)))),
+            Err(e) => Err(format!("get_contact_state.{}", e)),
+        }
+    }
+
+    fn add_contact_request(&self, from: &Uuid, to: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        // a request the other way around counts as a request between them too
+        match db.execute(
+            "INSERT INTO contacts(requester, addressee, accepted) SELECT $1, $2, FALSE \
+                WHERE NOT EXISTS (SELECT 1 FROM contacts WHERE requester=$2 AND addressee=$1) ON CONFLICT DO NOTHING",
+            &[&from, &to]) {
+            Ok(added) => Ok(added > 0),
+            Err(e) => Err(format!("add_contact_request.{}", e)),
+        }
+    }
+
+    fn accept_contact_request(&self, user: &Uuid, from: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute("UPDATE contacts SET accepted=TRUE WHERE requester=$1 AND addressee=$2 AND NOT accepted", &[&from, &user]) {
+            Ok(updated) => Ok(updated > 0),
+            Err(e) => Err(format!("accept_contact_request.{}", e)),
+        }
+    }
+
+    fn remove_contact(&self, user: &Uuid, other: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute(
+            "DELETE FROM contacts WHERE (requester=$1 AND addressee=$2) OR (requester=$2 AND addressee=$1)", &[&user, &other]) {
+            Ok(deleted) => Ok(deleted > 0),
+            Err(e) => Err(format!("remove_contact.{}", e)),
+        }
+    }
+
+    fn get_blocked(&self, user: &Uuid) -> Result<Vec<String>, String> {
+        let mut db = self.db()?;
+        match db.query(
+            "SELECT u.username FROM blocks b JOIN user_data u ON u.id = b.blocked WHERE b.blocker=$1 ORDER BY u.username", &[&user]) {
+            Ok(rows) => Ok(rows.iter().map(|row| row.get(0)).collect()),
+            Err(e) => Err(format!("get_blocked.{}", e)),
+        }
+    }
+
+    fn get_blockers(&self, user: &Uuid) -> Result<Vec<Uuid>, String> {
+        let mut db = self.db()?;
+        match db.query("SELECT blocker FROM blocks WHERE blocked=$1", &[&user]) {
+            Ok(rows) => Ok(rows.iter().map(|row| row.get(0)).collect()),
+            Err(e) => Err(format!("get_blockers.{}", e)),
+        }
+    }
+
+    fn is_blocked(&self, user: &Uuid, by: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.query("SELECT 1 FROM blocks WHERE blocker=$1 AND blocked=$2", &[&by, &user]) {
+            Ok(rows) => Ok(!rows.is_empty()),
+            Err(e) => Err(format!("is_blocked.{}", e)),
+        }
+    }
+
+    fn set_blocked(&self, user: &Uuid, other: &Uuid, blocked: bool) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let sql = if blocked {
+            "INSERT INTO blocks(blocker, blocked) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM blocks WHERE blocker=$1 AND blocked=$2"
+        };
+        match db.execute(sql, &[&user, &other]) {
+            Ok(changed) => Ok(changed > 0),
+            Err(e) => Err(format!("set_blocked.{}", e)),
+        }
+    }
+
+    fn set_timer(&self, user: &Uuid, conversation: &Conversation, ttl: u64) -> Result<(), String> {
+        let mut db = self.db()?;
+        let ttl = ttl as i64;
+        let (sql, other) = match conversation {
+            // a timer can be set before the first message, so the conversation row may not exist yet
+            Conversation::Direct(other) => ("INSERT INTO conversations(user_a, user_b, last_seq, ttl, ttl_set_by) \
+                VALUES (LEAST($1::UUID, $2::UUID), GREATEST($1::UUID, $2::UUID), 0, $3, $1) \
+                ON CONFLICT (user_a, user_b) DO UPDATE SET ttl = $3, ttl_set_by = $1", other),
+            Conversation::Group(group) => ("UPDATE group_chats SET ttl = $3, ttl_set_by = $1 WHERE id=$2", group),
+            Conversation::Channel(channel) => ("UPDATE space_channels SET ttl = $3, ttl_set_by = $1 WHERE id=$2", channel),
+        };
+        match db.execute(sql, &[&user, &other, &ttl]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("set_timer.{}", e)),
+        }
+    }
+
+    fn get_timer(&self, user: &Uuid, conversation: &Conversation) -> Result<u64, String> {
+        let mut db = self.db()?;
+        let rows = match conversation {
+            Conversation::Direct(other) => db.query(
+                "SELECT ttl FROM conversations WHERE user_a = LEAST($1::UUID, $2::UUID) AND user_b = GREATEST($1::UUID, $2::UUID)", &[&user, &other]),
+            Conversation::Group(group) => db.query("SELECT ttl FROM group_chats WHERE id=$1", &[&group]),
+            Conversation::Channel(channel) => db.query("SELECT ttl FROM space_channels WHERE id=$1", &[&channel]),
+        };
+        match rows {
+            Ok(rows) => Ok(rows.first().map_or(0, |row| row.get::<_, i64>(0) as u64)),
+            Err(e) => Err(format!("get_timer.{}", e)),
+        }
+    }
+
+    fn get_user_timers(&self, user: &Uuid) -> Result<Vec<DBTimer>, String> {
+        let mut db = self.db()?;
+        // each row is (kind, the other user, group or channel, ttl, who set it), kind 0 is a direct conversation, 1 a group and 2 a channel
+        let rows = db.query(
+            "SELECT 0, CASE WHEN c.user_a=$1 THEN c.user_b ELSE c.user_a END, c.ttl, u.username FROM conversations c \
+                JOIN user_data u ON u.id = c.ttl_set_by WHERE (c.user_a=$1 OR c.user_b=$1) AND c.ttl > 0 \
+            UNION ALL SELECT 1, g.id, g.ttl, u.username FROM group_chats g JOIN group_members m ON m.group_id = g.id \
+                JOIN user_data u ON u.id = g.ttl_set_by WHERE m.user_id=$1 AND g.ttl > 0 \
+            UNION ALL SELECT 2, c.id, c.ttl, u.username FROM space_channels c JOIN space_members m ON m.space_id = c.space_id \
+                JOIN user_data u ON u.id = c.ttl_set_by WHERE m.user_id=$1 AND c.ttl > 0",
+            &[&user]);
+        if let Err(e) = rows {
+            return Err(format!("get_user_timers.{}", e));
+        }
+
+        Ok(rows.unwrap().iter().map(|row| {
+            let id: Uuid = row.get(1);
+            let conversation = match row.get::<_, i32>(0) {
+                0 => Conversation::Direct(id),
+                1 => Conversation::Group(id),
+                _ => Conversation::Channel(id),
+            };
+            DBTimer { conversation, ttl: row.get::<_, i64>(2) as u64, set_by: row.get(3) }
+        }).collect())
+    }
+
+    fn set_identity_key(&self, id: &Uuid, key: &[u8], signing_key: &[u8]) -> Result<bool, String> {
+        let mut db = self.db()?;
+        match db.execute(
+            "UPDATE user_data SET identity_key=$1, signing_key=$2 WHERE id=$3 \
+                AND (identity_key IS DISTINCT FROM $1 OR signing_key IS DISTINCT FROM $2)",
+            &[&key, &signing_key, &id]) {
+            Ok(updated) => Ok(updated > 0),
+            Err(e) => Err(format!("set_identity_key.{}", e)),
+        }
+    }
+
+    fn get_identity_key(&self, username: &str) -> Result<Option<Vec<u8>>, String> {
+        let mut db = self.db()?;
+        let key_query = db.query(
+            "SELECT identity_key FROM user_data WHERE username=$1", &[&username]);
+        if let Err(e) = key_query {
+            return Err(format!("get_identity_key.{}", e));
+        }
+        let user_rows = key_query.unwrap();
+        if user_rows.is_empty() {
+            return Err(format!("Invalid username"));
+        }
+        let row = user_rows.first().unwrap();
+
+        Ok(row.get(0))
+    }
+
+    fn get_signing_key(&self, id: &Uuid) -> Result<Option<Vec<u8>>, String> {
+        let mut db = self.db()?;
+        let key_query = db.query(
+            "SELECT signing_key FROM user_data WHERE id=$1", &[&id]);
+        if let Err(e) = key_query {
+            return Err(format!("get_signing_key.{}", e));
+        }
+        let user_rows = key_query.unwrap();
+        if user_rows.is_empty() {
+            return Err(format!("Invalid id!"));
+        }
+        let row = user_rows.first().unwrap();
+
+        Ok(row.get(0))
+    }
+
+    fn delete_prekeys(&self, id: &Uuid) -> Result<(), String> {
+        let mut db = self.db()?;
+        for table in ["signed_prekeys", "one_time_prekeys"] {
+            if let Err(e) = db.execute(format!("DELETE FROM {} WHERE user_id=$1", table).as_str(), &[&id]) {
+                return Err(format!("delete_prekeys.{}", e));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_signed_prekey(&self, id: &Uuid, prekey: &PublicPreKey, signature: &[u8]) -> Result<(), String> {
+        let mut db = self.db()?;
+        if let Err(e) = db.execute(
+            "INSERT INTO signed_prekeys(user_id, key_id, key, signature) VALUES ($1, $2, $3, $4) \
+                ON CONFLICT (user_id) DO UPDATE SET key_id=EXCLUDED.key_id, key=EXCLUDED.key, signature=EXCLUDED.signature",
+            &[&id, &(prekey.id as i64), &prekey.key, &signature]) {
+            return Err(format!("set_signed_prekey.{}", e));
+        }
+        Ok(())
+    }
+
+    fn add_one_time_prekeys(&self, id: &Uuid, prekeys: &[PublicPreKey]) -> Result<(), String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction();
+        if let Err(e) = transaction {
+            return Err(format!("add_one_time_prekeys.{}", e));
+        }
+        let mut transaction = transaction.unwrap();
+
+        for prekey in prekeys {
+            if let Err(e) = transaction.execute(
+                "INSERT INTO one_time_prekeys(user_id, key_id, key) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                &[&id, &(prekey.id as i64), &prekey.key]) {
+                return Err(format!("add_one_time_prekeys.{}", e));
+            }
+        }
+
+        if let Err(e) = transaction.commit() {
+            return Err(format!("add_one_time_prekeys.{}", e));
+        }
+        Ok(())
+    }
+
+    fn count_one_time_prekeys(&self, id: &Uuid) -> Result<i64, String> {
+        let mut db = self.db()?;
+        match db.query_one("SELECT COUNT(*) FROM one_time_prekeys WHERE user_id=$1", &[&id]) {
+            Ok(row) => Ok(row.get(0)),
+            Err(e) => Err(format!("count_one_time_prekeys.{}", e)),
+        }
+    }
+
+    fn take_prekey_bundle(&self, username: &str) -> Result<(Uuid, Option<PreKeyBundle>), String> {
+        let mut db = self.db()?;
+        let bundle_query = db.query(
+            "SELECT u.id, u.identity_key, u.signing_key, s.key_id, s.key, s.signature FROM user_data u \
+                LEFT JOIN signed_prekeys s ON s.user_id = u.id WHERE u.username=$1",
+            &[&username]);
+        if let Err(e) = bundle_query {
+            return Err(format!("take_prekey_bundle.{}", e));
+        }
+        let user_rows = bundle_query.unwrap();
+        if user_rows.is_empty() {
+            return Err(format!("Invalid username"));
+        }
+        let row = user_rows.first().unwrap();
+        let user: Uuid = row.get(0);
+
+        let (Some(identity_key), Some(signing_key), Some(key_id), Some(key), Some(signature)) =
+            (row.get(1), row.get(2), row.get::<_, Option<i64>>(3), row.get(4), row.get(5)) else {
+            return Ok((user, None));
+        };
+
+        // concurrent requests each lock and remove a different row
+        let one_time_query = db.query(
+            "DELETE FROM one_time_prekeys WHERE (user_id, key_id) = \
+                (SELECT user_id, key_id FROM one_time_prekeys WHERE user_id=$1 ORDER BY key_id LIMIT 1 FOR UPDATE SKIP LOCKED) \
+                RETURNING key_id, key",
+            &[&user]);
+        if let Err(e) = one_time_query {
+            return Err(format!("take_prekey_bundle.{}", e));
+        }
+        let one_time_prekey = one_time_query.unwrap().first().map(|row| PublicPreKey {
+            id: row.get::<_, i64>(0) as u32,
+            key: row.get(1),
+        });
+
+        Ok((user, Some(PreKeyBundle {
+            identity_key,
+            signing_key,
+            signed_prekey: PublicPreKey { id: key_id as u32, key },
+            signature,
+            one_time_prekey,
+        })))
+    }
+
+    fn insert_user(&self, username: &str, password: &str) -> Result<Uuid, String> {
+        let mut db = self.db()?;
+        let id = Uuid::new_v4();
+        if let Err(e) = db.execute("INSERT INTO user_data(id, username, password, online) VALUES ($1, $2, $3, false)",
+                                   &[&id, &username, &password]) {
+            return Err(format!("insert_user.{}", e));
+        }
+        Ok(id)
+    }
+
+    fn set_user_password(&self, id: &Uuid, password: &str) -> Result<(), String> {
+        let mut db = self.db()?;
+        if let Err(e) = db.execute("UPDATE user_data SET password=$1 WHERE id=$2;",
+                                   &[&password, &id]) {
+            return Err(format!("set_user_password.{}", e));
+        }
+        Ok(())
+    }
+
+    fn user_exists(&self, username: &str) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let query_result = db.query(
+            "SELECT id FROM user_data WHERE username=$1", &[&username]);
+        if let Err(e) = query_result {
+            return Err(format!("get_username_from_id.{}", e));
+        }
+        let user_rows = query_result.unwrap();
+
+        Ok(!user_rows.is_empty())
+    }
+
+    fn get_username_from_id(&self, id: &Uuid) -> Result<String, String> {
+        let mut db = self.db()?;
+        let query_result = db.query(
+            "SELECT username FROM user_data WHERE id=$1", &[&id]);
+        if let Err(e) = query_result {
+            return Err(format!("get_username_from_id.{}", e));
+        }
+        let user_rows = query_result.unwrap();
+        if user_rows.len() > 1 {
+            return Err(format!("Multiple users with the same username found!"));
+        }
+        if user_rows.is_empty() {
+            return Err(format!("Invalid id!"));
+        }
+        let user = user_rows.first().unwrap();
+
+        Ok(user.get(0))
+    }
+
+    fn get_id_from_username(&self, username: &str) -> Result<Uuid, String> {
+        let mut db = self.db()?;
+        let id_query = db.query(
+            format!("SELECT id FROM user_data WHERE username=$1").as_str(), &[&username]);
+        if let Err(e) = id_query {
+            return Err(format!("get_id_from_username.{}", e));
+        }
+        let user_rows = id_query.unwrap();
+        if user_rows.len() > 1 {
+            return Err(format!("Multiple users with the same username found!"));
+        }
+        if user_rows.is_empty() {
+            return Err(format!("Invalid username"));
+        }
+        let row = user_rows.first().unwrap();
+
+        Ok(row.get(0))
+    }
+
+    fn get_user_from_username(&self, username: &str) -> Result<(Uuid, String), String> {
+        let mut db = self.db()?;
+        let password_query = db.query(
+            format!("SELECT id, password FROM user_data WHERE username=$1").as_str(), &[&username]);
+        if let Err(e) = password_query {
+            return Err(format!("get_user_from_username.{}", e));
+        }
+        let user_rows = password_query.unwrap();
+        if user_rows.len() > 1 {
+            return Err(format!("Multiple users with the same username found"));
+        }
+        if user_rows.is_empty() {
+            return Err(format!("Invalid username"));
+        }
+        let row = user_rows.first().unwrap();
+
+        Ok((row.get(0), row.get(1)))
+    }
+
+    fn set_id_online(&self, id: &Uuid, online: bool) -> Result<(), String> {
+        let mut db = self.db()?;
+        if let Err(e) = db.execute("UPDATE user_data SET online=$1 WHERE id=$2;",
+                                   &[&online, &id]) {
+            return Err(format!("set_id_online.{}", e));
+        }
+        Ok(())
+    }
+
+    fn is_username_online(&self, username: &str) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let online_query = db.query(
+            format!("SELECT online FROM user_data WHERE username=$1").as_str(), &[&username]);
+        if let Err(e) = online_query {
+            return Err(format!("is_username_online.{}", e));
+        }
+        let user_rows = online_query.unwrap();
+        if user_rows.len() > 1 {
+            return Err(format!("Multiple users with the same username found!"));
+        }
+        if user_rows.is_empty() {
+            return Err(format!("Invalid username"));
+        }
+        let row = user_rows.first().unwrap();
+
+        Ok(row.get(0))
+    }
+
+    fn is_id_online(&self, user: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let online_query = db.query(
+            format!("SELECT online FROM user_data WHERE id=$1").as_str(), &[user]);
+        if let Err(e) = online_query {
+            return Err(format!("is_id_online.{}", e));
+        }
+        let user_rows = online_query.unwrap();
+        if user_rows.len() > 1 {
+            return Err(format!("Multiple users with the same username found!"));
+        }
+        if user_rows.is_empty() {
+            return Err(format!("Invalid username"));
+        }
+        let row = user_rows.first().unwrap();
+
+        Ok(row.get(0))
+    }
+}
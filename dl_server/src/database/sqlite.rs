@@ -0,0 +1,783 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use uuid::Uuid;
+use dl_network_common::crypto::{PreKeyBundle, PublicPreKey, SealedPayload};
+use dl_network_common::{ContactState, HistoryCursor};
+use crate::database::migrations::{self, Migration};
+use crate::database::{contact_state, finish_page, page_start, Conversation, DBContact, DBGroup, DBGroupCopy, DBMessageQuery, DBRole, DBSpace, DBSpaceMember, DBTimer, PageStart, Store, MAX_HISTORY_PAGE};
+
+/// Stores everything in an embedded SQLite database file, for small servers without a database server
+/// there is one connection, so queries from every client handler take turns
+pub struct SqliteStore {
+    db: Mutex<Connection>,
+}
+
+// messages are selected with their sender's username, ids and timestamps are read back with `from_row`
+const SELECT_HISTORY: &str = "SELECT h.id, u.username, h.payload, h.header, h.timestamp, h.seq, h.group_id, h.ttl FROM history h JOIN user_data u ON u.id = h.sender";
+
+// the messages of a conversation the user can see, with the user as ?1 and the other user, group or channel as ?2
+fn select_conversation(conversation: &Conversation) -> (String, Uuid) {
+    match conversation {
+        Conversation::Direct(other) => (format!("{} WHERE ((h.sender=?1 AND h.recipient=?2) OR (h.sender=?2 AND h.recipient=?1)) AND h.group_id IS NULL", SELECT_HISTORY), *other),
+        Conversation::Group(group) | Conversation::Channel(group) => (format!("{} WHERE h.recipient=?1 AND h.group_id=?2", SELECT_HISTORY), *group),
+    }
+}
+
+impl SqliteStore {
+    /// Open the database file, creating it if it does not exist, its schema is brought up to date with `Store::migrate`
+    /// `:memory:` opens a database that is gone once the store is dropped
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                return Err(format!("Failed to create the directory for {}: {}", path.display(), e));
+            }
+        }
+        let db = Connection::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    fn db(&self) -> Result<MutexGuard<'_, Connection>, String> {
+        self.db.lock().map_err(|_| format!("The database connection was poisoned"))
+    }
+}
+
+fn from_micros(micros: i64) -> Result<DateTime<Utc>, String> {
+    Utc.timestamp_opt(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1000) as u32).single()
+        .ok_or_else(|| format!("A stored message has an invalid timestamp"))
+}
+
+// read a row selected as (id, sender username, payload, header, timestamp, seq, group id, ttl)
+fn from_row(row: &Row) -> rusqlite::Result<Result<DBMessageQuery, String>> {
+    let payload: Vec<u8> = row.get(2)?;
+    let header: Option<Vec<u8>> = row.get(3)?;
+    let ttl: i64 = row.get(7)?;
+    let (id, sender, timestamp, seq, group) = (row.get(0)?, row.get(1)?, row.get(4)?, row.get(5)?, row.get(6)?);
+    Ok(SealedPayload::from_bytes(&payload).and_then(|message| Ok(DBMessageQuery {
+        id,
+        sender,
+        message: SealedPayload { header: header.unwrap_or_default(), ..message },
+        timestamp: from_micros(timestamp)?,
+        seq,
+        group,
+        ttl: ttl as u64,
+    })))
+}
+
+// run a query selecting messages, see `from_row`
+fn query_msgs<P: rusqlite::Params>(db: &Connection, sql: &str, params: P) -> Result<Vec<DBMessageQuery>, String> {
+    let mut statement = db.prepare(sql).map_err(|e| e.to_string())?;
+    let rows = statement.query_map(params, from_row).map_err(|e| e.to_string())?;
+    rows.map(|row| row.map_err(|e| e.to_string())?).collect()
+}
+
+// a group and its members, None if there is no such group
+fn select_group(db: &Connection, id: &Uuid) -> rusqlite::Result<Option<DBGroup>> {
+    let Some(name) = db.query_row("SELECT name FROM group_chats WHERE id=?1", params![id], |row| row.get(0)).optional()? else {
+        return Ok(None);
+    };
+    let mut statement = db.prepare(
+        "SELECT u.id, u.username FROM group_members m JOIN user_data u ON u.id = m.user_id WHERE m.group_id=?1 ORDER BY u.username")?;
+    let members = statement.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(Some(DBGroup { id: *id, name, members }))
+}
+
+// a space with its channels, roles, members and bans, None if there is no such space
+fn select_space(db: &Connection, id: &Uuid) -> rusqlite::Result<Option<DBSpace>> {
+    let space = db.query_row("SELECT name, owner FROM spaces WHERE id=?1", params![id], |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+    let Some((name, owner)) = space else {
+        return Ok(None);
+    };
+
+    let mut statement = db.prepare("SELECT id, name FROM space_channels WHERE space_id=?1 ORDER BY name")?;
+    let channels = statement.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut statement = db.prepare("SELECT id, name, permissions FROM space_roles WHERE space_id=?1 ORDER BY name")?;
+    let roles = statement.query_map(params![id], |row| Ok(DBRole { id: row.get(0)?, name: row.get(1)?, permissions: row.get(2)? }))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut statement = db.prepare(
+        "SELECT u.id, u.username FROM space_members m JOIN user_data u ON u.id = m.user_id WHERE m.space_id=?1 ORDER BY u.username")?;
+    let mut members = statement.query_map(params![id], |row| Ok(DBSpaceMember { id: row.get(0)?, username: row.get(1)?, roles: Vec::new() }))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut statement = db.prepare("SELECT user_id, role_id FROM space_member_roles WHERE space_id=?1 ORDER BY role_id")?;
+    let given = statement.query_map(params![id], |row| Ok((row.get::<_, Uuid>(0)?, row.get::<_, Uuid>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (user, role) in given {
+        if let Some(member) = members.iter_mut().find(|member| member.id == user) {
+            member.roles.push(role);
+        }
+    }
+    let mut statement = db.prepare(
+        "SELECT u.id, u.username FROM space_bans b JOIN user_data u ON u.id = b.user_id WHERE b.space_id=?1 ORDER BY u.username")?;
+    let banned = statement.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(Some(DBSpace { id: *id, name, owner, channels, roles, members, banned }))
+}
+
+// store every copy of a message sent to a group or channel, with the sequence number `next_seq` counts up to
+// returns None if there is no such group or channel
+fn store_copies(db: &mut Connection, next_seq: &str, conversation: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> rusqlite::Result<Option<i64>> {
+    let transaction = db.transaction()?;
+    let seq: Option<i64> = transaction.query_row(next_seq, params![conversation], |row| row.get(0)).optional()?;
+    let Some(seq) = seq else {
+        return Ok(None);
+    };
+    let ttl = ttl as i64;
+    let expires = if ttl > 0 { Some(timestamp.timestamp_micros() + ttl * 1_000_000) } else { None };
+    let timestamp = timestamp.timestamp_micros();
+
+    for copy in copies {
+        for table in ["history", "messages"] {
+            transaction.execute(
+                format!("INSERT INTO {}(id, sender, recipient, payload, header, timestamp, seq, group_id, ttl, expires) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", table).as_str(),
+                params![copy.id, sender, copy.recipient, copy.message.to_bytes(), copy.message.header, timestamp, seq, conversation, ttl, expires])?;
+        }
+    }
+
+    transaction.commit()?;
+    Ok(Some(seq))
+}
+
+impl Store for SqliteStore {
+    fn migrate(&self) -> Result<Vec<&'static Migration>, String> {
+        let mut db = self.db()?;
+        if let Err(e) = db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied INTEGER NOT NULL)") {
+            return Err(format!("migrate.{}", e));
+        }
+
+        // every migration is applied in its own transaction
+        let mut applied = Vec::new();
+        loop {
+            let transaction = db.transaction_with_behavior(TransactionBehavior::Immediate).map_err(|e| format!("migrate.{}", e))?;
+            let current: i64 = transaction.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))
+                .map_err(|e| format!("migrate.{}", e))?;
+            let Some(migration) = migrations::next(migrations::SQLITE, current)? else {
+                return Ok(applied);
+            };
+
+            if let Err(e) = transaction.execute_batch(migration.sql) {
+                return Err(format!("Migration {} ({}) failed: {}", migration.version, migration.name, e));
+            }
+            if let Err(e) = transaction.execute("INSERT INTO schema_version(version, name, applied) VALUES (?1, ?2, ?3)",
+                                                params![migration.version, migration.name, Utc::now().timestamp_micros()]) {
+                return Err(format!("migrate.{}", e));
+            }
+            if let Err(e) = transaction.commit() {
+                return Err(format!("Migration {} ({}) failed: {}", migration.version, migration.name, e));
+            }
+            applied.push(migration);
+        }
+    }
+
+    fn store_msg(&self, id: &Uuid, sender: &Uuid, recipient: &Uuid, message: &SealedPayload, timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction().map_err(|e| format!("store_msg.{}", e))?;
+
+        let seq: i64 = transaction.query_row(
+            "INSERT INTO conversations(user_a, user_b, last_seq) VALUES (min(?1, ?2), max(?1, ?2), 1) \
+                ON CONFLICT (user_a, user_b) DO UPDATE SET last_seq = last_seq + 1 RETURNING last_seq",
+            params![sender, recipient], |row| row.get(0)).map_err(|e| format!("store_msg.{}", e))?;
+        let payload = message.to_bytes();
+        let ttl = ttl as i64;
+        let expires = if ttl > 0 { Some(timestamp.timestamp_micros() + ttl * 1_000_000) } else { None };
+        let timestamp = timestamp.timestamp_micros();
+
+        for table in ["history", "messages"] {
+            if let Err(e) = transaction.execute(
+                format!("INSERT INTO {}(id, sender, recipient, payload, header, timestamp, seq, ttl, expires) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", table).as_str(),
+                params![id, sender, recipient, payload, message.header, timestamp, seq, ttl, expires]) {
+                return Err(format!("store_msg.{}.{}", table, e));
+            }
+        }
+
+        transaction.commit().map_err(|e| format!("store_msg.{}", e))?;
+        Ok(seq)
+    }
+
+    fn store_group_msg(&self, group: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let next_seq = "UPDATE group_chats SET last_seq = last_seq + 1 WHERE id=?1 RETURNING last_seq";
+        let mut db = self.db()?;
+        match store_copies(&mut db, next_seq, group, sender, copies, timestamp, ttl) {
+            Ok(Some(seq)) => Ok(seq),
+            Ok(None) => Err(format!("store_group_msg.There is no group {}", group)),
+            Err(e) => Err(format!("store_group_msg.{}", e)),
+        }
+    }
+
+    fn store_channel_msg(&self, channel: &Uuid, sender: &Uuid, copies: &[DBGroupCopy], timestamp: DateTime<Utc>, ttl: u64) -> Result<i64, String> {
+        let next_seq = "UPDATE space_channels SET last_seq = last_seq + 1 WHERE id=?1 RETURNING last_seq";
+        let mut db = self.db()?;
+        match store_copies(&mut db, next_seq, channel, sender, copies, timestamp, ttl) {
+            Ok(Some(seq)) => Ok(seq),
+            Ok(None) => Err(format!("store_channel_msg.There is no channel {}", channel)),
+            Err(e) => Err(format!("store_channel_msg.{}", e)),
+        }
+    }
+
+    fn get_queued_msgs(&self, receiver: &Uuid) -> Result<Vec<DBMessageQuery>, String> {
+        let db = self.db()?;
+        // group messages from several senders share the numbering of their group
+        query_msgs(&db,
+            "SELECT m.id, u.username, m.payload, m.header, m.timestamp, m.seq, m.group_id, m.ttl FROM messages m JOIN user_data u ON u.id = m.sender \
+                WHERE m.recipient=?1 ORDER BY COALESCE(m.group_id, m.sender), m.seq",
+            params![receiver]).map_err(|e| format!("get_queued_msgs.{}", e))
+    }
+
+    fn delete_msg(&self, id: &Uuid, recipient: &Uuid) -> Result<bool, String> {
+        match self.db()?.execute("DELETE FROM messages WHERE id=?1 AND recipient=?2", params![id, recipient]) {
+            Ok(deleted) => Ok(deleted > 0),
+            Err(e) => Err(format!("A message could not be removed from the database! {}", e)),
+        }
+    }
+
+    fn purge_expired_msgs(&self, now: DateTime<Utc>) -> Result<usize, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction().map_err(|e| format!("purge_expired_msgs.{}", e))?;
+        let now = now.timestamp_micros();
+        if let Err(e) = transaction.execute("DELETE FROM messages WHERE expires <= ?1", params![now]) {
+            return Err(format!("purge_expired_msgs.messages.{}", e));
+        }
+        let purged = transaction.execute("DELETE FROM history WHERE expires <= ?1", params![now])
+            .map_err(|e| format!("purge_expired_msgs.history.{}", e))?;
+        transaction.commit().map_err(|e| format!("purge_expired_msgs.{}", e))?;
+        Ok(purged)
+    }
+
+    fn get_history(&self, user: &Uuid, conversation: &Conversation, cursor: &HistoryCursor, limit: u32) -> Result<(Vec<DBMessageQuery>, bool), String> {
+        // select one more row than needed to know if there is another page
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE) as i64;
+        let fetch = limit + 1;
+
+        let (start, backwards) = page_start(cursor)?;
+        let (cmp, order) = if backwards { ("<", "DESC") } else { (">", "ASC") };
+        let (select, other) = select_conversation(conversation);
+        let db = self.db()?;
+        let page = match start {
+            PageStart::Latest => query_msgs(&db,
+                format!("{} ORDER BY h.seq DESC LIMIT ?3", select).as_str(),
+                params![user, other, fetch]),
+            PageStart::Id(id) => {
+                // a page can only start at a message of the same conversation
+                let cursor = query_msgs(&db, format!("{} AND h.id=?3", select).as_str(), params![user, other, id])
+                    .map_err(|e| format!("get_history.{}", e))?;
+                let Some(cursor) = cursor.first() else {
+                    return Err(format!("get_history.Message {} is not in the conversation", id));
+                };
+                query_msgs(&db,
+                    format!("{} AND h.seq {} ?4 ORDER BY h.seq {} LIMIT ?3", select, cmp, order).as_str(),
+                    params![user, other, fetch, cursor.seq])
+            }
+            PageStart::Time(time) => query_msgs(&db,
+                format!("{} AND h.timestamp {} ?4 ORDER BY h.seq {} LIMIT ?3", select, cmp, order).as_str(),
+                params![user, other, fetch, time.timestamp_micros()]),
+        }.map_err(|e| format!("get_history.{}", e))?;
+
+        Ok(finish_page(page, limit as usize, backwards))
+    }
+
+    fn get_history_range(&self, user: &Uuid, conversation: &Conversation, from: i64, to: i64) -> Result<(Vec<DBMessageQuery>, bool), String> {
+        let (select, other) = select_conversation(conversation);
+        let db = self.db()?;
+        let page = query_msgs(&db,
+            format!("{} AND h.seq BETWEEN ?3 AND ?4 ORDER BY h.seq LIMIT ?5", select).as_str(),
+            params![user, other, from, to, MAX_HISTORY_PAGE as i64 + 1]).map_err(|e| format!("get_history_range.{}", e))?;
+
+        Ok(finish_page(page, MAX_HISTORY_PAGE as usize, false))
+    }
+
+    fn create_group(&self, name: &str, members: &[Uuid]) -> Result<Uuid, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction().map_err(|e| format!("create_group.{}", e))?;
+
+        let id = Uuid::new_v4();
+        if let Err(e) = transaction.execute("INSERT INTO group_chats(id, name, last_seq) VALUES (?1, ?2, 0)", params![id, name]) {
+            return Err(format!("create_group.{}", e));
+        }
+        for member in members {
+            if let Err(e) = transaction.execute(
+                "INSERT INTO group_members(group_id, user_id) VALUES (?1, ?2) ON CONFLICT DO NOTHING", params![id, member]) {
+                return Err(format!("create_group.{}", e));
+            }
+        }
+
+        transaction.commit().map_err(|e| format!("create_group.{}", e))?;
+        Ok(id)
+    }
+
+    fn get_group(&self, group: &Uuid) -> Result<Option<DBGroup>, String> {
+        let db = self.db()?;
+        select_group(&db, group).map_err(|e| format!("get_group.{}", e))
+    }
+
+    fn get_user_groups(&self, user: &Uuid) -> Result<Vec<DBGroup>, String> {
+        let db = self.db()?;
+        let mut statement = db.prepare("SELECT g.id FROM group_members m JOIN group_chats g ON g.id = m.group_id WHERE m.user_id=?1 ORDER BY g.name")
+            .map_err(|e| format!("get_user_groups.{}", e))?;
+        let ids = statement.query_map(params![user], |row| row.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Uuid>>>())
+            .map_err(|e| format!("get_user_groups.{}", e))?;
+
+        let mut groups = Vec::new();
+        for id in ids {
+            if let Some(group) = select_group(&db, &id).map_err(|e| format!("get_user_groups.{}", e))? {
+                groups.push(group);
+            }
+        }
+        Ok(groups)
+    }
+
+    fn add_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String> {
+        match self.db()?.execute(
+            "INSERT INTO group_members(group_id, user_id) SELECT id, ?2 FROM group_chats WHERE id=?1 ON CONFLICT DO NOTHING",
+            params![group, user]) {
+            Ok(added) => Ok(added > 0),
+            Err(e) => Err(format!("add_group_member.{}", e)),
+        }
+    }
+
+    fn remove_group_member(&self, group: &Uuid, user: &Uuid) -> Result<bool, String> {
+        match self.db()?.execute("DELETE FROM group_members WHERE group_id=?1 AND user_id=?2", params![group, user]) {
+            Ok(removed) => Ok(removed > 0),
+            Err(e) => Err(format!("remove_group_member.{}", e)),
+        }
+    }
+
+    fn rename_group(&self, group: &Uuid, name: &str) -> Result<(), String> {
+        if let Err(e) = self.db()?.execute("UPDATE group_chats SET name=?1 WHERE id=?2", params![name, group]) {
+            return Err(format!("rename_group.{}", e));
+        }
+        Ok(())
+    }
+
+    fn create_space(&self, name: &str, owner: &Uuid, everyone: u32) -> Result<Uuid, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction().map_err(|e| format!("create_space.{}", e))?;
+
+        let id = Uuid::new_v4();
+        let statements = [
+            ("INSERT INTO spaces(id, name, owner) VALUES (?1, ?2, ?3)", params![id, name, owner]),
+            ("INSERT INTO space_members(space_id, user_id) VALUES (?1, ?2)", params![id, owner]),
+            ("INSERT INTO space_roles(id, space_id, name, permissions) VALUES (?1, ?1, 'everyone', ?2)", params![id, everyone]),
+        ];
+        for (sql, params) in statements {
+            if let Err(e) = transaction.execute(sql, params) {
+                return Err(format!("create_space.{}", e));
+            }
+        }
+
+        transaction.commit().map_err(|e| format!("create_space.{}", e))?;
+        Ok(id)
+    }
+
+    fn get_space(&self, space: &Uuid) -> Result<Option<DBSpace>, String> {
+        let db = self.db()?;
+        select_space(&db, space).map_err(|e| format!("get_space.{}", e))
+    }
+
+    fn get_user_spaces(&self, user: &Uuid) -> Result<Vec<DBSpace>, String> {
+        let db = self.db()?;
+        let mut statement = db.prepare("SELECT s.id FROM space_members m JOIN spaces s ON s.id = m.space_id WHERE m.user_id=?1 ORDER BY s.name")
+            .map_err(|e| format!("get_user_spaces.{}", e))?;
+        let ids = statement.query_map(params![user], |row| row.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Uuid>>>())
+            .map_err(|e| format!("get_user_spaces.{}", e))?;
+
+        let mut spaces = Vec::new();
+        for id in ids {
+            if let Some(space) = select_space(&db, &id).map_err(|e| format!("get_user_spaces.{}", e))? {
+                spaces.push(space);
+            }
+        }
+        Ok(spaces)
+    }
+
+    fn add_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String> {
+        match self.db()?.execute(
+            "INSERT INTO space_members(space_id, user_id) SELECT id, ?2 FROM spaces WHERE id=?1 ON CONFLICT DO NOTHING",
+            params![space, user]) {
+            Ok(added) => Ok(added > 0),
+            Err(e) => Err(format!("add_space_member.{}", e)),
+        }
+    }
+
+    fn remove_space_member(&self, space: &Uuid, user: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction().map_err(|e| format!("remove_space_member.{}", e))?;
+
+        let removed = transaction.execute("DELETE FROM space_members WHERE space_id=?1 AND user_id=?2", params![space, user])
+            .map_err(|e| format!("remove_space_member.{}", e))?;
+        if let Err(e) = transaction.execute("DELETE FROM space_member_roles WHERE space_id=?1 AND user_id=?2", params![space, user]) {
+            return Err(format!("remove_space_member.{}", e));
+        }
+
+        transaction.commit().map_err(|e| format!("remove_space_member.{}", e))?;
+        Ok(removed > 0)
+    }
+
+    fn set_space_ban(&self, space: &Uuid, user: &Uuid, banned: bool) -> Result<bool, String> {
+        let sql = if banned {
+            "INSERT INTO space_bans(space_id, user_id) SELECT id, ?2 FROM spaces WHERE id=?1 ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM space_bans WHERE space_id=?1 AND user_id=?2"
+        };
+        match self.db()?.execute(sql, params![space, user]) {
+            Ok(changed) => Ok(changed > 0),
+            Err(e) => Err(format!("set_space_ban.{}", e)),
+        }
+    }
+
+    fn create_channel(&self, space: &Uuid, name: &str) -> Result<Uuid, String> {
+        let id = Uuid::new_v4();
+        match self.db()?.execute(
+            "INSERT INTO space_channels(id, space_id, name, last_seq) SELECT ?1, id, ?3, 0 FROM spaces WHERE id=?2",
+            params![id, space, name]) {
+            Ok(0) => Err(format!("create_channel.There is no space {}", space)),
+            Ok(_) => Ok(id),
+            Err(e) => Err(format!("create_channel.{}", e)),
+        }
+    }
+
+    fn delete_channel(&self, space: &Uuid, channel: &Uuid) -> Result<bool, String> {
+        match self.db()?.execute("DELETE FROM space_channels WHERE id=?1 AND space_id=?2", params![channel, space]) {
+            Ok(deleted) => Ok(deleted > 0),
+            Err(e) => Err(format!("delete_channel.{}", e)),
+        }
+    }
+
+    fn get_channel_space(&self, channel: &Uuid) -> Result<Option<Uuid>, String> {
+        self.db()?.query_row("SELECT space_id FROM space_channels WHERE id=?1", params![channel], |row| row.get(0))
+            .optional().map_err(|e| format!("get_channel_space.{}", e))
+    }
+
+    fn set_role(&self, space: &Uuid, role: &DBRole) -> Result<(), String> {
+        // a role of another space with the same id is left alone
+        match self.db()?.execute(
+            "INSERT INTO space_roles(id, space_id, name, permissions) SELECT ?1, id, ?3, ?4 FROM spaces WHERE id=?2 \
+                ON CONFLICT(id) DO UPDATE SET name=excluded.name, permissions=excluded.permissions WHERE space_roles.space_id=excluded.space_id",
+            params![role.id, space, role.name, role.permissions]) {
+            Ok(0) => Err(format!("set_role.There is no role {} in space {}", role.id, space)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("set_role.{}", e)),
+        }
+    }
+
+    fn delete_role(&self, space: &Uuid, role: &Uuid) -> Result<bool, String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction().map_err(|e| format!("delete_role.{}", e))?;
+
+        let deleted = transaction.execute("DELETE FROM space_roles WHERE id=?1 AND space_id=?2", params![role, space])
+            .map_err(|e| format!("delete_role.{}", e))?;
+        if let Err(e) = transaction.execute("DELETE FROM space_member_roles WHERE role_id=?1 AND space_id=?2", params![role, space]) {
+            return Err(format!("delete_role.{}", e));
+        }
+
+        transaction.commit().map_err(|e| format!("delete_role.{}", e))?;
+        Ok(deleted > 0)
+    }
+
+    fn assign_role(&self, space: &Uuid, role: &Uuid, user: &Uuid, assigned: bool) -> Result<bool, String> {
+        // only members can be given roles
+        let sql = if assigned {
+            "INSERT INTO space_member_roles(space_id, user_id, role_id) SELECT space_id, user_id, ?3 FROM space_members \
+                WHERE space_id=?1 AND user_id=?2 ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM space_member_roles WHERE space_id=?1 AND user_id=?2 AND role_id=?3"
+        };
+        match self.db()?.execute(sql, params![space, user, role]) {
+            Ok(changed) => Ok(changed > 0),
+            Err(e) => Err(format!("assign_role.{}", e)),
+        }
+    }
+
+    fn get_contacts(&self, user: &Uuid) -> Result<Vec<DBContact>, String> {
+        let db = self.db()?;
+        let mut statement = db.prepare(
+            "SELECT u.id, u.username, c.accepted, c.requester=?1 FROM contacts c \
+                JOIN user_data u ON u.id = CASE WHEN c.requester=?1 THEN c.addressee ELSE c.requester END \
+                WHERE c.requester=?1 OR c.addressee=?1 ORDER BY u.username")
+            .map_err(|e| format!("get_contacts.{}", e))?;
+        statement.query_map(params![user], |row| Ok(DBContact { id: row.get(0)?, username: row.get(1)?, state: contact_state(row.get(2)?, row.get(3)?) }))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<DBContact>>>())
+            .map_err(|e| format!("get_contacts.{}", e))
+    }
+
+    fn get_contact_state(&self, user: &Uuid, other: &Uuid) -> Result<ContactState, String> {
+        let state = self.db()?.query_row(
+            "SELECT accepted, requester=?1 FROM contacts WHERE (requester=?1 AND addressee=?2) OR (requester=?2 AND addressee=?1)",
+            params![user, other], |row| Ok(contact_state(row.get(0)?, row.get(1)?)))
+            .optional().map_err(|e| format!("get_contact_state.{}", e))?;
+        Ok(state.unwrap_or(ContactState::None))
+    }
+
+    fn add_contact_request(&self, from: &Uuid, to: &Uuid) -> Result<bool, String> {
+        // a request the other way around counts as a request between them too
+        match self.db()?.execute(
+            "INSERT INTO contacts(requester, addressee, accepted) SELECT ?1, ?2, 0 \
+                WHERE NOT EXISTS (SELECT 1 FROM contacts WHERE requester=?2 AND addressee=?1) ON CONFLICT DO NOTHING",
+            params![from, to]) {
+            Ok(added) => Ok(added > 0),
+            Err(e) => Err(format!("add_contact_request.{}", e)),
+        }
+    }
+
+    fn accept_contact_request(&self, user: &Uuid, from: &Uuid) -> Result<bool, String> {
+        match self.db()?.execute("UPDATE contacts SET accepted=1 WHERE requester=?1 AND addressee=?2 AND accepted=0", params![from, user]) {
+            Ok(updated) => Ok(updated > 0),
+            Err(e) => Err(format!("accept_contact_request.{}", e)),
+        }
+    }
+
+    fn remove_contact(&self, user: &Uuid, other: &Uuid) -> Result<bool, String> {
+        match self.db()?.execute(
+            "DELETE FROM contacts WHERE (requester=?1 AND addressee=?2) OR (requester=?2 AND addressee=?1)", params![user, other]) {
+            Ok(deleted) => Ok(deleted > 0),
+            Err(e) => Err(format!("remove_contact.{}", e)),
+        }
+    }
+
+    fn get_blocked(&self, user: &Uuid) -> Result<Vec<String>, String> {
+        let db = self.db()?;
+        let mut statement = db.prepare(
+            "SELECT u.username FROM blocks b JOIN user_data u ON u.id = b.blocked WHERE b.blocker=?1 ORDER BY u.username")
+            .map_err(|e| format!("get_blocked.{}", e))?;
+        statement.query_map(params![user], |row| row.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>())
+            .map_err(|e| format!("get_blocked.{}", e))
+    }
+
+    fn get_blockers(&self, user: &Uuid) -> Result<Vec<Uuid>, String> {
+        let db = self.db()?;
+        let mut statement = db.prepare("SELECT blocker FROM blocks WHERE blocked=?1")
+            .map_err(|e| format!("get_blockers.{}", e))?;
+        statement.query_map(params![user], |row| row.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Uuid>>>())
+            .map_err(|e| format!("get_blockers.{}", e))
+    }
+
+    fn is_blocked(&self, user: &Uuid, by: &Uuid) -> Result<bool, String> {
+        let blocked = self.db()?.query_row("SELECT 1 FROM blocks WHERE blocker=?1 AND blocked=?2", params![by, user], |_| Ok(()))
+            .optional().map_err(|e| format!("is_blocked.{}", e))?;
+        Ok(blocked.is_some())
+    }
+
+    fn set_blocked(&self, user: &Uuid, other: &Uuid, blocked: bool) -> Result<bool, String> {
+        let sql = if blocked {
+            "INSERT INTO blocks(blocker, blocked) VALUES (?1, ?2) ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM blocks WHERE blocker=?1 AND blocked=?2"
+        };
+        match self.db()?.execute(sql, params![user, other]) {
+            Ok(changed) => Ok(changed > 0),
+            Err(e) => Err(format!("set_blocked.{}", e)),
+        }
+    }
+
+    fn set_timer(&self, user: &Uuid, conversation: &Conversation, ttl: u64) -> Result<(), String> {
+        let ttl = ttl as i64;
+        let (sql, other) = match conversation {
+            // a timer can be set before the first message, so the conversation row may not exist yet
+            Conversation::Direct(other) => ("INSERT INTO conversations(user_a, user_b, last_seq, ttl, ttl_set_by) VALUES (min(?1, ?2), max(?1, ?2), 0, ?3, ?1) \
+                ON CONFLICT (user_a, user_b) DO UPDATE SET ttl = ?3, ttl_set_by = ?1", other),
+            Conversation::Group(group) => ("UPDATE group_chats SET ttl = ?3, ttl_set_by = ?1 WHERE id=?2", group),
+            Conversation::Channel(channel) => ("UPDATE space_channels SET ttl = ?3, ttl_set_by = ?1 WHERE id=?2", channel),
+        };
+        match self.db()?.execute(sql, params![user, other, ttl]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("set_timer.{}", e)),
+        }
+    }
+
+    fn get_timer(&self, user: &Uuid, conversation: &Conversation) -> Result<u64, String> {
+        let db = self.db()?;
+        let ttl: Option<i64> = match conversation {
+            Conversation::Direct(other) => db.query_row(
+                "SELECT ttl FROM conversations WHERE user_a = min(?1, ?2) AND user_b = max(?1, ?2)", params![user, other], |row| row.get(0)),
+            Conversation::Group(group) => db.query_row("SELECT ttl FROM group_chats WHERE id=?1", params![group], |row| row.get(0)),
+            Conversation::Channel(channel) => db.query_row("SELECT ttl FROM space_channels WHERE id=?1", params![channel], |row| row.get(0)),
+        }.optional().map_err(|e| format!("get_timer.{}", e))?;
+        Ok(ttl.unwrap_or(0) as u64)
+    }
+
+    fn get_user_timers(&self, user: &Uuid) -> Result<Vec<DBTimer>, String> {
+        let db = self.db()?;
+        // each row is (kind, the other user, group or channel, ttl, who set it), kind 0 is a direct conversation, 1 a group and 2 a channel
+        let mut statement = db.prepare(
+            "SELECT 0, CASE WHEN c.user_a=?1 THEN c.user_b ELSE c.user_a END, c.ttl, u.username FROM conversations c \
+                JOIN user_data u ON u.id = c.ttl_set_by WHERE (c.user_a=?1 OR c.user_b=?1) AND c.ttl > 0 \
+            UNION ALL SELECT 1, g.id, g.ttl, u.username FROM group_chats g JOIN group_members m ON m.group_id = g.id \
+                JOIN user_data u ON u.id = g.ttl_set_by WHERE m.user_id=?1 AND g.ttl > 0 \
+            UNION ALL SELECT 2, c.id, c.ttl, u.username FROM space_channels c JOIN space_members m ON m.space_id = c.space_id \
+                JOIN user_data u ON u.id = c.ttl_set_by WHERE m.user_id=?1 AND c.ttl > 0")
+            .map_err(|e| format!("get_user_timers.{}", e))?;
+        statement.query_map(params![user], |row| {
+            let (kind, id, ttl): (i64, Uuid, i64) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            let conversation = match kind {
+                0 => Conversation::Direct(id),
+                1 => Conversation::Group(id),
+                _ => Conversation::Channel(id),
+            };
+            Ok(DBTimer { conversation, ttl: ttl as u64, set_by: row.get(3)? })
+        })
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<DBTimer>>>())
+            .map_err(|e| format!("get_user_timers.{}", e))
+    }
+
+    fn set_identity_key(&self, id: &Uuid, key: &[u8], signing_key: &[u8]) -> Result<bool, String> {
+        match self.db()?.execute(
+            "UPDATE user_data SET identity_key=?1, signing_key=?2 WHERE id=?3 \
+                AND (identity_key IS NOT ?1 OR signing_key IS NOT ?2)",
+            params![key, signing_key, id]) {
+            Ok(updated) => Ok(updated > 0),
+            Err(e) => Err(format!("set_identity_key.{}", e)),
+        }
+    }
+
+    fn get_identity_key(&self, username: &str) -> Result<Option<Vec<u8>>, String> {
+        let key = self.db()?.query_row("SELECT identity_key FROM user_data WHERE username=?1", params![username], |row| row.get(0))
+            .optional().map_err(|e| format!("get_identity_key.{}", e))?;
+        key.ok_or_else(|| format!("Invalid username"))
+    }
+
+    fn get_signing_key(&self, id: &Uuid) -> Result<Option<Vec<u8>>, String> {
+        let key = self.db()?.query_row("SELECT signing_key FROM user_data WHERE id=?1", params![id], |row| row.get(0))
+            .optional().map_err(|e| format!("get_signing_key.{}", e))?;
+        key.ok_or_else(|| format!("Invalid id!"))
+    }
+
+    fn delete_prekeys(&self, id: &Uuid) -> Result<(), String> {
+        let db = self.db()?;
+        for table in ["signed_prekeys", "one_time_prekeys"] {
+            if let Err(e) = db.execute(format!("DELETE FROM {} WHERE user_id=?1", table).as_str(), params![id]) {
+                return Err(format!("delete_prekeys.{}", e));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_signed_prekey(&self, id: &Uuid, prekey: &PublicPreKey, signature: &[u8]) -> Result<(), String> {
+        if let Err(e) = self.db()?.execute(
+            "INSERT INTO signed_prekeys(user_id, key_id, key, signature) VALUES (?1, ?2, ?3, ?4) \
+                ON CONFLICT (user_id) DO UPDATE SET key_id=excluded.key_id, key=excluded.key, signature=excluded.signature",
+            params![id, prekey.id, prekey.key, signature]) {
+            return Err(format!("set_signed_prekey.{}", e));
+        }
+        Ok(())
+    }
+
+    fn add_one_time_prekeys(&self, id: &Uuid, prekeys: &[PublicPreKey]) -> Result<(), String> {
+        let mut db = self.db()?;
+        let transaction = db.transaction().map_err(|e| format!("add_one_time_prekeys.{}", e))?;
+
+        for prekey in prekeys {
+            if let Err(e) = transaction.execute(
+                "INSERT INTO one_time_prekeys(user_id, key_id, key) VALUES (?1, ?2, ?3) ON CONFLICT DO NOTHING",
+                params![id, prekey.id, prekey.key]) {
+                return Err(format!("add_one_time_prekeys.{}", e));
+            }
+        }
+
+        transaction.commit().map_err(|e| format!("add_one_time_prekeys.{}", e))
+    }
+
+    fn count_one_time_prekeys(&self, id: &Uuid) -> Result<i64, String> {
+        self.db()?.query_row("SELECT COUNT(*) FROM one_time_prekeys WHERE user_id=?1", params![id], |row| row.get(0))
+            .map_err(|e| format!("count_one_time_prekeys.{}", e))
+    }
+
+    fn take_prekey_bundle(&self, username: &str) -> Result<(Uuid, Option<PreKeyBundle>), String> {
+        let mut db = self.db()?;
+        // the whole store is locked, so the one-time prekey can not be handed out twice
+        let transaction = db.transaction().map_err(|e| format!("take_prekey_bundle.{}", e))?;
+
+        type BundleRow = (Uuid, Option<Vec<u8>>, Option<Vec<u8>>, Option<u32>, Option<Vec<u8>>, Option<Vec<u8>>);
+        let row: Option<BundleRow> = transaction.query_row(
+            "SELECT u.id, u.identity_key, u.signing_key, s.key_id, s.key, s.signature FROM user_data u \
+                LEFT JOIN signed_prekeys s ON s.user_id = u.id WHERE u.username=?1",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))
+            .optional().map_err(|e| format!("take_prekey_bundle.{}", e))?;
+        let Some((user, identity_key, signing_key, key_id, key, signature)) = row else {
+            return Err(format!("Invalid username"));
+        };
+        let (Some(identity_key), Some(signing_key), Some(key_id), Some(key), Some(signature)) = (identity_key, signing_key, key_id, key, signature) else {
+            return Ok((user, None));
+        };
+
+        let one_time_prekey = transaction.query_row(
+            "DELETE FROM one_time_prekeys WHERE user_id=?1 AND key_id = \
+                (SELECT key_id FROM one_time_prekeys WHERE user_id=?1 ORDER BY key_id LIMIT 1) RETURNING key_id, key",
+            params![user],
+            |row| Ok(PublicPreKey { id: row.get(0)?, key: row.get(1)? }))
+            .optional().map_err(|e| format!("take_prekey_bundle.{}", e))?;
+        transaction.commit().map_err(|e| format!("take_prekey_bundle.{}", e))?;
+
+        Ok((user, Some(PreKeyBundle {
+            identity_key,
+            signing_key,
+            signed_prekey: PublicPreKey { id: key_id, key },
+            signature,
+            one_time_prekey,
+        })))
+    }
+
+    fn insert_user(&self, username: &str, password: &str) -> Result<Uuid, String> {
+        let id = Uuid::new_v4();
+        if let Err(e) = self.db()?.execute("INSERT INTO user_data(id, username, password, online) VALUES (?1, ?2, ?3, false)",
+                                           params![id, username, password]) {
+            return Err(format!("insert_user.{}", e));
+        }
+        Ok(id)
+    }
+
+    fn set_user_password(&self, id: &Uuid, password: &str) -> Result<(), String> {
+        if let Err(e) = self.db()?.execute("UPDATE user_data SET password=?1 WHERE id=?2", params![password, id]) {
+            return Err(format!("set_user_password.{}", e));
+        }
+        Ok(())
+    }
+
+    fn user_exists(&self, username: &str) -> Result<bool, String> {
+        self.db()?.query_row("SELECT EXISTS(SELECT 1 FROM user_data WHERE username=?1)", params![username], |row| row.get(0))
+            .map_err(|e| format!("user_exists.{}", e))
+    }
+
+    fn get_username_from_id(&self, id: &Uuid) -> Result<String, String> {
+        let username = self.db()?.query_row("SELECT username FROM user_data WHERE id=?1", params![id], |row| row.get(0))
+            .optional().map_err(|e| format!("get_username_from_id.{}", e))?;
+        username.ok_or_else(|| format!("Invalid id!"))
+    }
+
+    fn get_id_from_username(&self, username: &str) -> Result<Uuid, String> {
+        let id = self.db()?.query_row("SELECT id FROM user_data WHERE username=?1", params![username], |row| row.get(0))
+            .optional().map_err(|e| format!("get_id_from_username.{}", e))?;
+        id.ok_or_else(|| format!("Invalid username"))
+    }
+
+    fn get_user_from_username(&self, username: &str) -> Result<(Uuid, String), String> {
+        let user = self.db()?.query_row("SELECT id, password FROM user_data WHERE username=?1", params![username],
+                                        |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional().map_err(|e| format!("get_user_from_username.{}", e))?;
+        user.ok_or_else(|| format!("Invalid username"))
+    }
+
+    fn set_id_online(&self, id: &Uuid, online: bool) -> Result<(), String> {
+        if let Err(e) = self.db()?.execute("UPDATE user_data SET online=?1 WHERE id=?2", params![online, id]) {
+            return Err(format!("set_id_online.{}", e));
+        }
+        Ok(())
+    }
+
+    fn is_username_online(&self, username: &str) -> Result<bool, String> {
+        let online = self.db()?.query_row("SELECT online FROM user_data WHERE username=?1", params![username], |row| row.get(0))
+            .optional().map_err(|e| format!("is_username_online.{}", e))?;
+        online.ok_or_else(|| format!("Invalid username"))
+    }
+
+    fn is_id_online(&self, user: &Uuid) -> Result<bool, String> {
+        let online = self.db()?.query_row("SELECT online FROM user_data WHERE id=?1", params![user], |row| row.get(0))
+            .optional().map_err(|e| format!("is_id_online.{}", e))?;
+        online.ok_or_else(|| format!("Invalid username"))
+    }
+}
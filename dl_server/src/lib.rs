@@ -0,0 +1,15 @@
+// Delta Lima Server library, everything but reading the config and starting up
+// `format!` is used for every owned string in this project, literal or not
+#![allow(clippy::useless_format)]
+
+pub mod logging;
+pub mod database;
+pub mod config;
+pub mod password;
+pub mod router;
+pub mod server;
+pub mod tls;
+mod client;
+
+pub const ACCEPTED_CLIENT_VERSION: &str = "0.1.1";
+pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
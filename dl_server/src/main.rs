@@ -1,53 +1,149 @@
 // Delta Lima Server main file
+// `format!` is used for every owned string in this project, literal or not
+#![allow(clippy::useless_format)]
 
-use std::{io, thread};
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener};
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
 use better_term::flush_styles;
-use r2d2_postgres::postgres::NoTls;
-use r2d2_postgres::{PostgresConnectionManager, r2d2};
-use dl_network_common::{validate_ip, validate_port};
-use crate::client::chandler;
-use crate::config::{config_path, read_config};
-use crate::database::get_db_address;
-
-pub mod logging;
-pub mod database;
-pub mod config;
-mod client;
+use dl_network_common::resolve_address;
+use dl_network_common::tls::{certificate_fingerprint, server_config};
+use dl_server::{error, info, warn};
+use dl_server::config::{config_path, read_config};
+use dl_server::database::{get_db_address, open_store, Store};
+use dl_server::password::HashConfig;
+use dl_server::router::Router;
+use dl_server::server::{serve, ServerState};
+use dl_server::tls::generate_self_signed;
+
+/// Read the database config, connect and bring the schema up to date, logging any errors
+fn open_database() -> Option<Arc<dyn Store>> {
+    let database_info_result = get_db_address();
+    if let Err(e) = database_info_result {
+        error!("Failed to read database config: {}", e);
+        return None;
+    }
+    let dbinfo = database_info_result.unwrap();
 
-pub const ACCEPTED_CLIENT_VERSION: &str = "0.1.1";
-pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+    let store = match open_store(&dbinfo) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("{}", e);
+            return None;
+        }
+    };
 
-// How long the main loop should wait between checking for incoming connections to save cpu resources
-const MAIN_LOOP_WAIT_DELAY_MS: u64 = 20;
+    info!("Connected. Verifying tables...");
+    match store.migrate() {
+        Ok(applied) => {
+            for migration in applied {
+                info!("Applied database migration {}: {}", migration.version, migration.name);
+            }
+        }
+        Err(e) => {
+            error!("Failed to migrate the database: {}", e);
+            return None;
+        }
+    }
+    Some(store)
+}
 
 fn main() {
+    // `dl_server migrate` only brings the database up to date, without starting the server
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("migrate") => {
+            info!("Migrating the database...");
+            if open_database().is_some() {
+                info!("The database is up to date!");
+            }
+            flush_styles();
+            return;
+        }
+        Some(command) => {
+            error!("Unknown command `{}`, run without a command to start the server or with `migrate` to only migrate the database", command);
+            return;
+        }
+    }
+
     info!("Reading IP and Port from config file...");
     // handle configuration
     let Ok(cfg_path) = config_path("config.toml") else { return; };
     let raw_path = Path::new(&cfg_path);
     let config = read_config(raw_path, format!("\
     [server]\
-    \n# ip: the ip to listen on\
-    \n# surround with '[' and ']' for Ipv6 addresses\
+    \n# ip: the address to listen on, or a list of addresses like [\"0.0.0.0\", \"[::]\"]\
+    \n# an IPv4 address, an IPv6 address surrounded with '[' and ']', or a hostname\
+    \n# every address a hostname resolves to is listened on\
+    \n# `[::]` also accepts IPv4 connections on most systems\
     \n# defaults to 0.0.0.0 and will listen on your machines current IP\
     \nip = \"0.0.0.0\"\
     \n# port: the port to listen on\
     \n# defaults to 2277\
-    \nport = \"2277\""));
+    \nport = \"2277\"\
+    \n\
+    \n[passwords]\
+    \n# Argon2id cost parameters for hashing passwords\
+    \n# stored hashes are upgraded to these parameters the next time a user logs in\
+    \n# memory_kib: memory used by one hash in KiB, defaults to 19456\
+    \nmemory_kib = 19456\
+    \n# iterations: passes over the memory, defaults to 2\
+    \niterations = 2\
+    \n# parallelism: lanes hashed in parallel, defaults to 1\
+    \nparallelism = 1\
+    \n\
+    \n[tls]\
+    \n# enabled: encrypt connections with TLS, clients must connect with TLS too\
+    \n# defaults to true\
+    \nenabled = true\
+    \n# cert: PEM file with the certificate chain, defaults to config/cert.pem\
+    \n# key: PEM file with the private key, defaults to config/key.pem\
+    \n# if neither exists a self-signed certificate for local testing is generated\
+    \ncert = \"config/cert.pem\"\
+    \nkey = \"config/key.pem\""));
 
     // set default values for the config
-    let mut ip = format!("0.0.0.0");
+    let mut ips = vec![format!("0.0.0.0")];
     let mut port = format!("2277");
 
+    let mut hash_config = HashConfig::default();
+
+    let mut tls_enabled = true;
+    let mut tls_cert = format!("config/cert.pem");
+    let mut tls_key = format!("config/key.pem");
+
     // if the configuration values are set, override defaults
+    if let Some(passwords) = config.passwords {
+        if let Some(memory_kib) = passwords.memory_kib {
+            hash_config.memory_kib = memory_kib;
+        }
+        if let Some(iterations) = passwords.iterations {
+            hash_config.iterations = iterations;
+        }
+        if let Some(parallelism) = passwords.parallelism {
+            hash_config.parallelism = parallelism;
+        }
+    }
+    if let Some(tls) = config.tls {
+        if let Some(enabled) = tls.enabled {
+            tls_enabled = enabled;
+        }
+        if let Some(cert) = tls.cert {
+            tls_cert = cert;
+        }
+        if let Some(key) = tls.key {
+            tls_key = key;
+        }
+    }
+    if let Err(e) = hash_config.validate() {
+        error!("{} in `~/config/config.toml`!", e);
+        return;
+    }
+
     if let Some(server_conf) = config.server {
         if let Some(cfg_ip) = server_conf.ip {
-            ip = cfg_ip;
+            ips = cfg_ip.into_vec();
         } else {
             warn!("Failed to read ip value from `~/config/config.toml`");
         }
@@ -58,74 +154,72 @@ fn main() {
         }
     }
 
-    if !validate_ip(ip.clone()) {
-        error!("Invalid IP found in `~/config/config.toml`! If this issue persists, try deleting config.toml and re-running the program.");
+    if ips.is_empty() {
+        error!("No IP to listen on in `~/config/config.toml`! If this issue persists, try deleting config.toml and re-running the program.");
         return;
     }
 
-    if !validate_port(port.clone()) {
-        error!("Invalid Port found in `~/config/config.toml`! If this issue persists, try deleting config.toml and re-running the program.");
-        return;
+    let mut addresses: Vec<SocketAddr> = Vec::new();
+    for ip in &ips {
+        match resolve_address(ip, &port) {
+            Ok(resolved) => {
+                // two names can resolve to the same address, which can only be bound once
+                for address in resolved {
+                    if !addresses.contains(&address) {
+                        addresses.push(address);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Invalid address found in `~/config/config.toml`: {}! If this issue persists, try deleting config.toml and re-running the program.", e);
+                return;
+            }
+        }
     }
 
-    let full_ip = format!("{}:{}", ip, port);
-
-    // Create the listener for incoming connection attempts
+    // Create a listener for incoming connection attempts on every address
     info!("Done! Starting server...");
-    let listener_result = TcpListener::bind(full_ip.clone());
-    let Ok(listener) = listener_result else {
-        error!("Failed to bind listener to {}!", full_ip);
-        return;
-    };
-
-    // set the listener to non-blocking to ensure safe exiting of the server
-    if let Err(e) = listener.set_nonblocking(true) {
-        error!("Failed to set the connection listener to non-blocking mode; safely exiting would not be possible.\n  Error: {}", e);
-        return;
+    let mut listeners = Vec::new();
+    for address in &addresses {
+        match TcpListener::bind(address) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                error!("Failed to bind listener to {}: {}", address, e);
+                return;
+            }
+        }
     }
 
-    info!("Connecting to and setting up the database...");
+    let tls_config = if tls_enabled {
+        let (cert_path, key_path) = (Path::new(&tls_cert), Path::new(&tls_key));
+        if !cert_path.exists() && !key_path.exists() {
+            info!("No TLS certificate found, generating a self-signed one for local testing...");
+            if let Err(e) = generate_self_signed(cert_path, key_path) {
+                error!("{}", e);
+                return;
+            }
+        }
+        let tls_config = match server_config(cert_path, key_path) {
+            Ok(tls_config) => tls_config,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        match certificate_fingerprint(cert_path) {
+            Ok(fingerprint) => info!("TLS enabled. Clients can pin the certificate with the fingerprint {}", fingerprint),
+            Err(e) => warn!("TLS enabled, but the certificate fingerprint could not be read: {}", e),
+        }
+        Some(tls_config)
+    } else {
+        warn!("TLS is disabled in `~/config/config.toml`, passwords will be sent in cleartext!");
+        None
+    };
 
-    let database_info_result = get_db_address();
-    if let Err(e) = database_info_result {
-        error!("Failed to read database config: {}", e);
+    info!("Connecting to and setting up the database...");
+    let Some(store) = open_database() else {
         return;
-    }
-    let dbinfo = database_info_result.unwrap();
-
-    // create an r2d2 connection pool
-    let db_manager = PostgresConnectionManager::new(
-        format!("host={} port={} user={} password={}", dbinfo.ip, dbinfo.port, dbinfo.uname, dbinfo.pass).parse().unwrap(),
-        NoTls
-    );
-    let pool = r2d2::Pool::new(db_manager).unwrap();
-
-    let mut db_client = pool.get().unwrap();
-
-    info!("Connected. Verifying tables...");
-
-    // ensure the correct tables are created on the database
-    db_client.execute(
-        r"
-    CREATE TABLE IF NOT EXISTS user_data (
-        id       UUID,
-        username VARCHAR UNIQUE NOT NULL,
-        password VARCHAR NOT NULL,
-        online boolean NOT NULL
-    );", &[]).expect("Failed to create database user_data table!");
-    db_client.execute(
-        r"
-    CREATE TABLE IF NOT EXISTS messages (
-        id UUID,
-        timestamp TIMESTAMP WITH TIME ZONE,
-        message VARCHAR,
-        sender UUID,
-        recipient UUID
-    );", &[]).expect("Failed to create database unsent_msgs table!");
-
-    // this might not be needed later:
-    db_client.execute(
-        "SET timezone = \"America/Chicago\"", &[]).expect("Failed to set database timezone!");
+    };
 
     info!("Verified! Setting things up...");
 
@@ -142,62 +236,15 @@ fn main() {
         return;
     }
 
-    let mut handlers = Vec::new();
-
-    info!("Done! Listening on {}:{}", ip, port);
+    let listening: Vec<String> = addresses.iter().map(|address| format!("{}", address)).collect();
+    info!("Done! Listening on {}", listening.join(", "));
 
-    // listen for incoming connections
-    for stream in listener.incoming() {
-        match stream {
-            Ok(s) => {
-                info!("New connection!");
-                // create db reference and termination reference
-                let tarc = Arc::clone(&terminate);
-                let pool = pool.clone();
-
-                handlers.push(thread::spawn(move || {
-                    chandler(s, pool, tarc);
-                }));
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // handle if the program needs to exit
-                if terminate.load(Ordering::SeqCst) {
-                    info!("Safely shutting down server...");
-                    break;
-                }
-
-                // handle handlers no longer in use
-                handlers.retain(|h| {
-                    info!("Dropped a thread because it was finished.");
-                    h.is_finished()
-                });
-
-                // save CPU resources with a sleep call
-                thread::sleep(Duration::from_millis(MAIN_LOOP_WAIT_DELAY_MS));
-                continue;
-            }
-            Err(e) => {
-                error!("Encountered an error when polling for connections: {}", e);
-                // safely exit
-                break;
-            }
-        }
-    }
-
-    terminate.store(true, Ordering::SeqCst);
-
-    info!("Shutting down all active connections...");
-
-    for h in handlers {
-        if let Err(_) = h.join() {
-            warn!("A thread was unavailable when shutting down, this means a possible memory leak. Please report this alongside all other log messages!\n\
-            (This will not harm your computer, but means the program is operating inefficiently)");
-        }
-    }
-
-    // stop the listener
-    drop(listener);
-
-    info!("Server shut down!");
+    let state = ServerState {
+        store,
+        router: Arc::new(Router::new()),
+        hash_config,
+        tls: tls_config,
+    };
+    serve(listeners, state, terminate);
     flush_styles();
 }
@@ -0,0 +1,126 @@
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use subtle::ConstantTimeEq;
+
+/// Argon2id cost parameters used when hashing passwords
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashConfig {
+    /// memory used by one hash, in KiB
+    pub memory_kib: u32,
+    /// number of passes over the memory
+    pub iterations: u32,
+    /// number of lanes hashed in parallel
+    pub parallelism: u32,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl HashConfig {
+    /// Ensure the parameters are accepted by Argon2
+    pub fn validate(&self) -> Result<(), String> {
+        self.params().map(|_| ())
+    }
+
+    fn params(&self) -> Result<Params, String> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| format!("Invalid password hashing parameters: {}", e))
+    }
+
+    fn hasher(&self) -> Result<Argon2<'static>, String> {
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params()?))
+    }
+}
+
+/// The result of checking a password against what is stored for a user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verified {
+    /// The password does not match
+    Invalid,
+    /// The password matches
+    Valid,
+    /// The password matches, but the stored value is plaintext or was hashed with different parameters
+    /// and should be replaced with a new hash
+    ValidNeedsRehash,
+}
+
+/// Hash a password with a new random salt, returning it as a PHC string
+pub fn hash_password(password: &str, config: &HashConfig) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    match config.hasher()?.hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(format!("hash_password.{}", e)),
+    }
+}
+
+/// Check a password against the value stored in the database
+/// stored values that are not Argon2 hashes are plaintext passwords from before hashing was added
+pub fn verify_password(password: &str, stored: &str, config: &HashConfig) -> Verified {
+    let hash = match PasswordHash::new(stored) {
+        Ok(hash) if Algorithm::new(hash.algorithm.as_str()).is_ok() => hash,
+        _ => {
+            // legacy plaintext password, compared in constant time
+            return if bool::from(password.as_bytes().ct_eq(stored.as_bytes())) {
+                Verified::ValidNeedsRehash
+            } else {
+                Verified::Invalid
+            };
+        }
+    };
+
+    // the parameters are read from the stored hash, so any Argon2 variant and cost will verify
+    if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+        return Verified::Invalid;
+    }
+
+    let current = hash.algorithm == Algorithm::Argon2id.ident()
+        && Params::try_from(&hash).is_ok_and(|params| {
+            params.m_cost() == config.memory_kib && params.t_cost() == config.iterations && params.p_cost() == config.parallelism
+        });
+
+    if current { Verified::Valid } else { Verified::ValidNeedsRehash }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // small parameters so the tests run quickly
+    const CHEAP: HashConfig = HashConfig { memory_kib: 256, iterations: 1, parallelism: 1 };
+
+    #[test]
+    fn hash_and_verify() {
+        let hash = hash_password("hunter2", &CHEAP).unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_eq!(verify_password("hunter2", &hash, &CHEAP), Verified::Valid);
+        assert_eq!(verify_password("hunter3", &hash, &CHEAP), Verified::Invalid);
+    }
+
+    #[test]
+    fn salted() {
+        assert_ne!(hash_password("hunter2", &CHEAP).unwrap(), hash_password("hunter2", &CHEAP).unwrap());
+    }
+
+    #[test]
+    fn legacy_plaintext() {
+        assert_eq!(verify_password("test", "test", &CHEAP), Verified::ValidNeedsRehash);
+        assert_eq!(verify_password("test", "tset", &CHEAP), Verified::Invalid);
+        assert_eq!(verify_password("test", "test2", &CHEAP), Verified::Invalid);
+    }
+
+    #[test]
+    fn changed_parameters() {
+        let hash = hash_password("hunter2", &CHEAP).unwrap();
+        let stronger = HashConfig { iterations: 2, ..CHEAP };
+        assert_eq!(verify_password("hunter2", &hash, &stronger), Verified::ValidNeedsRehash);
+        assert_eq!(verify_password("hunter3", &hash, &stronger), Verified::Invalid);
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use uuid::Uuid;
+use dl_network_common::Packet;
+
+// the channels of every online session of a user, tagged with the session's id
+type UserSessions = Vec<(u64, Sender<Packet>)>;
+
+/// Routes packets between the sessions of online users without going through the database
+/// every logged in connection registers a channel that its chandler writes to the client
+#[derive(Default)]
+pub struct Router {
+    sessions: Mutex<HashMap<Uuid, UserSessions>>,
+    next_session: AtomicU64,
+}
+
+/// An online session registered with the router, removed from the router when dropped
+pub struct Session {
+    router: Arc<Router>,
+    user: Uuid,
+    id: u64,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new online session for a user
+    /// returns the session and the receiving end of its channel
+    pub fn register(router: &Arc<Router>, user: Uuid) -> (Session, Receiver<Packet>) {
+        let (tx, rx) = channel();
+        let id = router.next_session.fetch_add(1, Ordering::Relaxed);
+        router.sessions.lock().unwrap().entry(user).or_default().push((id, tx));
+        (Session { router: Arc::clone(router), user, id }, rx)
+    }
+
+    /// Send a packet to every online session of a user
+    /// returns false if the user has no online sessions, meaning the packet was not delivered
+    pub fn deliver(&self, user: &Uuid, packet: Packet) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(user_sessions) = sessions.get_mut(user) else {
+            return false;
+        };
+
+        // sessions whose chandler already stopped receiving are dropped
+        user_sessions.retain(|(_, tx)| tx.send(packet.clone()).is_ok());
+        if user_sessions.is_empty() {
+            sessions.remove(user);
+            return false;
+        }
+        true
+    }
+
+    /// Check if a user has any online sessions
+    pub fn is_online(&self, user: &Uuid) -> bool {
+        self.sessions.lock().unwrap().contains_key(user)
+    }
+
+    fn unregister(&self, user: &Uuid, session: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(user_sessions) = sessions.get_mut(user) {
+            user_sessions.retain(|(id, _)| *id != session);
+            if user_sessions.is_empty() {
+                sessions.remove(user);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.router.unregister(&self.user, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(text: &str) -> Packet {
+        Packet::Error { should_disconnect: false, error: text.to_string() }
+    }
+
+    #[test]
+    fn offline_users_are_not_delivered_to() {
+        let router = Arc::new(Router::new());
+        let user = Uuid::new_v4();
+        assert!(!router.is_online(&user));
+        assert!(!router.deliver(&user, packet("lost")));
+    }
+
+    #[test]
+    fn delivers_to_every_session() {
+        let router = Arc::new(Router::new());
+        let user = Uuid::new_v4();
+        let (_first, first_rx) = Router::register(&router, user);
+        let (_second, second_rx) = Router::register(&router, user);
+
+        assert!(router.deliver(&user, packet("hello")));
+        assert_eq!(first_rx.try_recv().unwrap(), packet("hello"));
+        assert_eq!(second_rx.try_recv().unwrap(), packet("hello"));
+    }
+
+    #[test]
+    fn dropped_sessions_go_offline() {
+        let router = Arc::new(Router::new());
+        let user = Uuid::new_v4();
+        let (first, _first_rx) = Router::register(&router, user);
+        let (second, second_rx) = Router::register(&router, user);
+
+        drop(first);
+        assert!(router.is_online(&user));
+        assert!(router.deliver(&user, packet("still here")));
+        assert_eq!(second_rx.try_recv().unwrap(), packet("still here"));
+
+        drop(second);
+        assert!(!router.is_online(&user));
+    }
+}
@@ -0,0 +1,116 @@
+use std::{io, thread};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use dl_network_common::Connection;
+use dl_network_common::tls::{ServerConfig, TlsStream};
+use dl_network_common::transport::Listener;
+use crate::client::chandler;
+use crate::database::Store;
+use crate::password::HashConfig;
+use crate::router::Router;
+use crate::{error, info, warn};
+
+// How long the main loop should wait between checking for incoming connections to save cpu resources
+const MAIN_LOOP_WAIT_DELAY_MS: u64 = 20;
+
+// How often disappearing messages whose timer ran out are purged from the database
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything the client handlers share
+#[derive(Clone)]
+pub struct ServerState {
+    pub store: Arc<dyn Store>,
+    /// routes messages between the sessions of online users
+    pub router: Arc<Router>,
+    pub hash_config: HashConfig,
+    /// accepted connections must start a TLS session when set
+    pub tls: Option<Arc<ServerConfig>>,
+}
+
+/// Accept clients until `terminate` is set, then disconnect every client and wait for their handlers to finish
+pub fn serve<L: Listener>(listener: L, state: ServerState, terminate: Arc<AtomicBool>) where L::Transport: 'static {
+    // set the listener to non-blocking to ensure safe exiting of the server
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Failed to set the connection listener to non-blocking mode; safely exiting would not be possible.\n  Error: {}", e);
+        return;
+    }
+
+    let mut handlers = Vec::new();
+    let mut last_purge = Instant::now();
+
+    // listen for incoming connections
+    loop {
+        // accepted streams are always blocking, even though the listener is not
+        match listener.accept() {
+            Ok(s) => {
+                info!("New connection!");
+                // create db reference and termination reference
+                let tarc = Arc::clone(&terminate);
+                let state = state.clone();
+
+                handlers.push(thread::spawn(move || {
+                    match state.tls {
+                        Some(tls_config) => match TlsStream::accept(tls_config, s) {
+                            Ok(stream) => chandler(Connection::new(stream), state.store, state.router, state.hash_config, tarc),
+                            Err(e) => warn!("Failed to accept a TLS connection: {}", e),
+                        },
+                        None => chandler(Connection::new(s), state.store, state.router, state.hash_config, tarc),
+                    }
+                }));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // handle if the program needs to exit
+                if terminate.load(Ordering::SeqCst) {
+                    info!("Safely shutting down server...");
+                    break;
+                }
+
+                // handle handlers no longer in use
+                handlers.retain(|h| {
+                    if h.is_finished() {
+                        info!("Dropped a thread because it was finished.");
+                        return false;
+                    }
+                    true
+                });
+
+                // purge disappearing messages between connections, so nobody waits on it
+                if last_purge.elapsed() >= PURGE_INTERVAL {
+                    last_purge = Instant::now();
+                    match state.store.purge_expired_msgs(Utc::now()) {
+                        Ok(0) => {}
+                        Ok(purged) => info!("Purged {} expired messages.", purged),
+                        Err(e) => warn!("Failed to purge expired messages: {}", e),
+                    }
+                }
+
+                // save CPU resources with a sleep call
+                thread::sleep(Duration::from_millis(MAIN_LOOP_WAIT_DELAY_MS));
+                continue;
+            }
+            Err(e) => {
+                error!("Encountered an error when polling for connections: {}", e);
+                // safely exit
+                break;
+            }
+        }
+    }
+
+    terminate.store(true, Ordering::SeqCst);
+
+    info!("Shutting down all active connections...");
+
+    for h in handlers {
+        if h.join().is_err() {
+            warn!("A thread was unavailable when shutting down, this means a possible memory leak. Please report this alongside all other log messages!\n\
+            (This will not harm your computer, but means the program is operating inefficiently)");
+        }
+    }
+
+    // stop the listener
+    drop(listener);
+
+    info!("Server shut down!");
+}
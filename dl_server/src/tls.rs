@@ -0,0 +1,25 @@
+use std::fs;
+use std::path::Path;
+
+/// Create a self-signed certificate for local testing, for connections to localhost
+/// clients must pin its fingerprint, no certificate authority will vouch for it
+pub fn generate_self_signed(cert: &Path, key: &Path) -> Result<(), String> {
+    let names = vec![format!("localhost"), format!("127.0.0.1"), format!("::1")];
+    let certified = rcgen::generate_simple_self_signed(names).map_err(|e| format!("Failed to generate certificate: {}", e))?;
+
+    if let Err(e) = fs::write(cert, certified.cert.pem()) {
+        return Err(format!("Failed to save certificate to {}: {}", cert.display(), e));
+    }
+    if let Err(e) = fs::write(key, certified.key_pair.serialize_pem()) {
+        return Err(format!("Failed to save private key to {}: {}", key.display(), e));
+    }
+    // only the server should be able to read its private key
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(key, fs::Permissions::from_mode(0o600)) {
+            return Err(format!("Failed to restrict permissions of {}: {}", key.display(), e));
+        }
+    }
+    Ok(())
+}
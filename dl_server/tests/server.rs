@@ -0,0 +1,659 @@
+// Boots a real server on an ephemeral port and drives it with scripted clients
+// `format!` is used for every owned string in this project, literal or not
+#![allow(clippy::useless_format)]
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use dl_network_common::{permissions, Connection, Contact, ContactState, Group, HistoryCursor, Packet, Role, SealedCopy, Space, Timer, MAX_MESSAGE_TTL, MAX_PREKEYS};
+use dl_network_common::crypto::{open, seal, IdentityKey, PublicPreKey, KEY_LEN};
+use dl_network_common::tls::{certificate_fingerprint, client_config, server_config, ServerConfig, TlsStream};
+use dl_network_common::transport::Transport;
+use dl_server::ACCEPTED_CLIENT_VERSION;
+use dl_server::database::memory::MemoryStore;
+use dl_server::password::HashConfig;
+use dl_server::router::Router;
+use dl_server::server::{serve, ServerState};
+use dl_server::tls::generate_self_signed;
+
+// small parameters so signing up and logging in are quick
+const CHEAP: HashConfig = HashConfig { memory_kib: 256, iterations: 1, parallelism: 1 };
+
+// how long a client waits for a packet before the test fails
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server with an empty in-memory store, shut down when dropped
+struct TestServer {
+    addr: SocketAddr,
+    terminate: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        Self::start_with_tls(None)
+    }
+
+    fn start_with_tls(tls: Option<Arc<ServerConfig>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState {
+            store: Arc::new(MemoryStore::new()),
+            router: Arc::new(Router::new()),
+            hash_config: CHEAP,
+            tls,
+        };
+        let terminate = Arc::new(AtomicBool::new(false));
+        let tarc = Arc::clone(&terminate);
+        let handle = thread::spawn(move || serve(listener, state, tarc));
+        Self { addr, terminate, handle: Some(handle) }
+    }
+
+    /// Ask the server to shut down, it finishes once every client has hung up
+    fn shut_down(&self) {
+        self.terminate.store(true, Ordering::SeqCst);
+    }
+
+    fn stream(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        stream
+    }
+
+    /// A client that has pinged the server
+    fn connect(&self) -> TestClient {
+        let mut client = TestClient { connection: Connection::new(self.stream()) };
+        assert!(client.ping(ACCEPTED_CLIENT_VERSION, false));
+        client
+    }
+
+    /// A client logged in as a new user
+    fn signup(&self, username: &str) -> TestClient {
+        let mut client = self.connect();
+        assert_eq!(client.login(username, "hunter2", true), accepted());
+        client.expect_prekeys_low();
+        client
+    }
+
+    fn login(&self, username: &str) -> TestClient {
+        let mut client = self.connect();
+        assert_eq!(client.login(username, "hunter2", false), accepted());
+        client.expect_prekeys_low();
+        client
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shut_down();
+        if let Some(handle) = self.handle.take() {
+            // don't hide the reason a test failed behind a second panic
+            if handle.join().is_err() && !thread::panicking() {
+                panic!("The server panicked");
+            }
+        }
+    }
+}
+
+struct TestClient<T: Transport = TcpStream> {
+    connection: Connection<T>,
+}
+
+impl<T: Transport> TestClient<T> {
+    fn send(&mut self, packet: Packet) {
+        self.connection.send(packet).unwrap();
+    }
+
+    fn recv(&mut self) -> Packet {
+        self.connection.recv().unwrap()
+    }
+
+    /// Send a packet and read the reply
+    fn request(&mut self, packet: Packet) -> Packet {
+        self.send(packet);
+        self.recv()
+    }
+
+    /// @return: if the server accepts the version
+    fn ping(&mut self, version: &str, disconnecting: bool) -> bool {
+        match self.request(Packet::Ping { version: version.to_string(), disconnecting }) {
+            Packet::PingResponse { valid, accepted_version } => {
+                assert_eq!(accepted_version, ACCEPTED_CLIENT_VERSION);
+                valid
+            }
+            packet => panic!("Expected a PingResponse, got {:?}", packet),
+        }
+    }
+
+    fn login(&mut self, username: &str, password: &str, signup: bool) -> Packet {
+        self.request(Packet::LoginRequest { username: username.to_string(), password: password.to_string(), signup })
+    }
+
+    // users without one-time prekeys are asked for more after logging in
+    fn expect_prekeys_low(&mut self) {
+        assert_eq!(self.recv(), Packet::PreKeysLow { remaining: 0 });
+    }
+
+    /// Send a sealed message, returning the seq the server gave it
+    fn message(&mut self, to: &str, text: &str, sender: &IdentityKey, recipient: &IdentityKey) -> u64 {
+        let message = seal(text.as_bytes(), sender, &recipient.public()).unwrap();
+        match self.request(Packet::Message {
+            id: String::new(), seq: 0, message, sender: String::new(), recipient: to.to_string(), group: None, timestamp: String::new(), ttl: 0,
+        }) {
+            Packet::MessageReceipt { recipient, seq, .. } => {
+                assert_eq!(recipient, to);
+                seq
+            }
+            packet => panic!("Expected a MessageReceipt, got {:?}", packet),
+        }
+    }
+
+    /// Read a message, returning its id, seq, sender and text
+    fn expect_message(&mut self, recipient: &IdentityKey, sender: &IdentityKey) -> (String, u64, String, String) {
+        match self.recv() {
+            Packet::Message { id, seq, message, sender: from, .. } => {
+                let text = open(&message, recipient, &sender.public()).unwrap();
+                (id, seq, from, String::from_utf8(text).unwrap())
+            }
+            packet => panic!("Expected a Message, got {:?}", packet),
+        }
+    }
+
+    /// Send a packet that changes a group, returning the group the server answered with
+    fn group(&mut self, packet: Packet) -> Group {
+        match self.request(packet) {
+            Packet::GroupInfo { group } => group,
+            packet => panic!("Expected a GroupInfo, got {:?}", packet),
+        }
+    }
+
+    /// Set the disappearing message timer of a conversation, returning the timer the server answered with
+    fn timer(&mut self, conversation: &str, ttl: u64) -> Timer {
+        match self.request(Packet::TimerSet { conversation: conversation.to_string(), ttl }) {
+            Packet::TimerInfo { timer } => timer,
+            packet => panic!("Expected a TimerInfo, got {:?}", packet),
+        }
+    }
+
+    /// Send a packet that changes a space, returning the space the server answered with
+    fn space(&mut self, packet: Packet) -> Space {
+        match self.request(packet) {
+            Packet::SpaceInfo { space } => space,
+            packet => panic!("Expected a SpaceInfo, got {:?}", packet),
+        }
+    }
+
+    fn user_online(&mut self, username: &str) -> bool {
+        match self.request(Packet::UserOnlineRequest { username: username.to_string() }) {
+            Packet::UserResponse { response } => response,
+            packet => panic!("Expected a UserResponse, got {:?}", packet),
+        }
+    }
+
+    fn user_exists(&mut self, username: &str) -> bool {
+        match self.request(Packet::UserExistsRequest { username: username.to_string() }) {
+            Packet::UserResponse { response } => response,
+            packet => panic!("Expected a UserResponse, got {:?}", packet),
+        }
+    }
+}
+
+fn accepted() -> Packet {
+    Packet::LoginResponse { valid: true, error: None }
+}
+
+fn rejected(error: &str) -> Packet {
+    Packet::LoginResponse { valid: false, error: Some(error.to_string()) }
+}
+
+#[test]
+fn ping_checks_the_version() {
+    let server = TestServer::start();
+
+    let mut client = TestClient { connection: Connection::new(server.stream()) };
+    assert!(!client.ping("0.0.0", true));
+    // the server hangs up after a disconnecting ping
+    assert!(client.connection.recv().is_err());
+
+    let mut client = TestClient { connection: Connection::new(server.stream()) };
+    assert!(client.ping(ACCEPTED_CLIENT_VERSION, true));
+}
+
+#[test]
+fn signup_and_login() {
+    let server = TestServer::start();
+    drop(server.signup("alice"));
+
+    let mut client = server.connect();
+    assert_eq!(client.login("alice", "other", true), rejected("Username is taken"));
+    assert_eq!(client.login("alice", "wrong", false), rejected("Invalid login credentials"));
+    assert_eq!(client.login("alice", "hunter2", false), accepted());
+    client.expect_prekeys_low();
+}
+
+#[test]
+fn key_uploads_are_not_replied_to() {
+    let server = TestServer::start();
+    let mut alice = server.signup("alice");
+
+    // prekeys past the limit are dropped quietly, an error would be taken as the reply to the next request
+    let prekeys = (0..MAX_PREKEYS + 5).map(|id| PublicPreKey { id, key: vec![7; KEY_LEN] }).collect();
+    alice.send(Packet::PreKeysUpload { prekeys });
+    assert_eq!(alice.request(Packet::UserExistsRequest { username: format!("alice") }), Packet::UserResponse { response: true });
+
+    // keys nobody could use end the session
+    alice.send(Packet::IdentityKeyUpload { key: vec![1; 3], signing_key: vec![1; 3] });
+    assert!(matches!(alice.recv(), Packet::Error { should_disconnect: true, .. }));
+}
+
+#[test]
+fn messages_are_delivered_live() {
+    let server = TestServer::start();
+    let (alice_key, bob_key) = (IdentityKey::generate(), IdentityKey::generate());
+    let mut alice = server.signup("alice");
+    let mut bob = server.signup("bob");
+
+    assert_eq!(alice.message("bob", "hello bob", &alice_key, &bob_key), 1);
+    let (_, seq, sender, text) = bob.expect_message(&bob_key, &alice_key);
+    assert_eq!((seq, sender.as_str(), text.as_str()), (1, "alice", "hello bob"));
+
+    assert_eq!(bob.message("alice", "hi alice", &bob_key, &alice_key), 2);
+    let (_, seq, sender, text) = alice.expect_message(&alice_key, &bob_key);
+    assert_eq!((seq, sender.as_str(), text.as_str()), (2, "bob", "hi alice"));
+}
+
+#[test]
+fn messages_are_queued_until_acked() {
+    let server = TestServer::start();
+    let (alice_key, bob_key) = (IdentityKey::generate(), IdentityKey::generate());
+    drop(server.signup("bob"));
+    let mut alice = server.signup("alice");
+
+    alice.message("bob", "first", &alice_key, &bob_key);
+    alice.message("bob", "second", &alice_key, &bob_key);
+
+    // bob was offline, so both are sent when he logs in, in order
+    let mut bob = server.login("bob");
+    let (first, seq, _, text) = bob.expect_message(&bob_key, &alice_key);
+    assert_eq!((seq, text.as_str()), (1, "first"));
+    let (_, seq, _, text) = bob.expect_message(&bob_key, &alice_key);
+    assert_eq!((seq, text.as_str()), (2, "second"));
+
+    // only the acked message stops being redelivered
+    bob.send(Packet::MessageAck { id: first });
+    // packets are handled in order, so the ack is stored once this is answered
+    assert!(bob.user_exists("alice"));
+    drop(bob);
+    let mut bob = server.login("bob");
+    let (_, seq, _, text) = bob.expect_message(&bob_key, &alice_key);
+    assert_eq!((seq, text.as_str()), (2, "second"));
+}
+
+#[test]
+fn disappearing_messages_are_purged_from_the_queue() {
+    let server = TestServer::start();
+    let (alice_key, bob_key) = (IdentityKey::generate(), IdentityKey::generate());
+    drop(server.signup("bob"));
+    let mut alice = server.signup("alice");
+
+    alice.timer("bob", 1);
+    alice.message("bob", "gone soon", &alice_key, &bob_key);
+    alice.timer("bob", 3600);
+    alice.message("bob", "still here", &alice_key, &bob_key);
+
+    // the first message expired while bob was offline, so he only gets the second, with its timer
+    thread::sleep(Duration::from_millis(1100));
+    let mut bob = server.login("bob");
+    match bob.recv() {
+        Packet::Message { message, seq, ttl, .. } => {
+            assert_eq!((seq, ttl), (2, 3600));
+            assert_eq!(open(&message, &bob_key, &alice_key.public()).unwrap(), b"still here");
+        }
+        packet => panic!("Expected a Message, got {:?}", packet),
+    }
+    // nothing else was queued, so the next packet is the reply
+    assert!(bob.user_exists("alice"));
+    match bob.request(Packet::MsgHistoryRequest { username: format!("alice"), cursor: HistoryCursor::Latest, limit: 10 }) {
+        Packet::MsgHistory { history, .. } => assert_eq!(history.iter().map(|msg| (msg.seq, msg.ttl)).collect::<Vec<_>>(), vec![(2, 3600)]),
+        packet => panic!("Expected a MsgHistory, got {:?}", packet),
+    }
+    // the timer changed while bob was offline, so he asks for it
+    assert_eq!(bob.request(Packet::TimerListRequest), Packet::TimerList { timers: vec![
+        Timer { conversation: format!("alice"), ttl: 3600, set_by: format!("alice") },
+    ] });
+}
+
+#[test]
+fn timers_are_shared_with_the_conversation() {
+    let server = TestServer::start();
+    let (alice_key, bob_key) = (IdentityKey::generate(), IdentityKey::generate());
+    let mut alice = server.signup("alice");
+    let mut bob = server.signup("bob");
+    let timer = |conversation: &str, ttl, set_by: &str| Timer { conversation: conversation.to_string(), ttl, set_by: set_by.to_string() };
+
+    // the other user is told, and messages disappear after the timer whatever ttl the sender asked for
+    assert_eq!(alice.timer("bob", 60), timer("bob", 60, "alice"));
+    assert_eq!(bob.recv(), Packet::TimerUpdate { timer: timer("alice", 60, "alice") });
+    alice.message("bob", "hello", &alice_key, &bob_key);
+    match bob.recv() {
+        Packet::Message { ttl, .. } => assert_eq!(ttl, 60),
+        packet => panic!("Expected a Message, got {:?}", packet),
+    }
+    // either user can turn it off
+    assert_eq!(bob.timer("alice", 0), timer("alice", 0, "bob"));
+    assert_eq!(alice.recv(), Packet::TimerUpdate { timer: timer("bob", 0, "bob") });
+    assert_eq!(alice.request(Packet::TimerListRequest), Packet::TimerList { timers: Vec::new() });
+    assert_eq!(alice.request(Packet::TimerSet { conversation: format!("bob"), ttl: MAX_MESSAGE_TTL + 1 }),
+               Packet::Error { should_disconnect: false, error: format!("Messages can disappear after at most {} seconds", MAX_MESSAGE_TTL) });
+    assert_eq!(alice.request(Packet::TimerSet { conversation: format!("alice"), ttl: 60 }),
+               Packet::Error { should_disconnect: false, error: format!("You can not set a timer with yourself") });
+
+    // every copy of a group message disappears after the timer of the group
+    let group = alice.group(Packet::GroupCreate { name: format!("friends"), members: vec![format!("bob")] });
+    assert_eq!(bob.recv(), Packet::GroupUpdate { group: group.clone() });
+    assert_eq!(alice.timer(&group.id, 3600), timer(&group.id, 3600, "alice"));
+    assert_eq!(bob.recv(), Packet::TimerUpdate { timer: timer(&group.id, 3600, "alice") });
+    let copies = vec![SealedCopy { recipient: format!("bob"), message: seal(b"hi all", &alice_key, &bob_key.public()).unwrap() }];
+    match alice.request(Packet::GroupMessage { group: group.id.clone(), copies }) {
+        Packet::MessageReceipt { .. } => {}
+        packet => panic!("Expected a MessageReceipt, got {:?}", packet),
+    }
+    match bob.recv() {
+        Packet::Message { group: in_group, ttl, .. } => assert_eq!((in_group, ttl), (Some(group.id.clone()), 3600)),
+        packet => panic!("Expected a Message, got {:?}", packet),
+    }
+    assert_eq!(bob.request(Packet::TimerListRequest), Packet::TimerList { timers: vec![timer(&group.id, 3600, "alice")] });
+
+    // changing the timer of a channel needs MANAGE_CHANNELS
+    let space = alice.space(Packet::SpaceCreate { name: format!("club") });
+    let space = alice.space(Packet::SpaceInvite { space: space.id.clone(), username: format!("bob") });
+    assert_eq!(bob.recv(), Packet::SpaceUpdate { space: space.clone() });
+    let general = space.channels[0].id.clone();
+    assert_eq!(bob.request(Packet::TimerSet { conversation: general.clone(), ttl: 60 }),
+               Packet::Error { should_disconnect: false, error: format!("You do not have permission to do that in this space") });
+    assert_eq!(alice.timer(&general, 60), timer(&general, 60, "alice"));
+    assert_eq!(bob.recv(), Packet::TimerUpdate { timer: timer(&general, 60, "alice") });
+}
+
+#[test]
+fn messages_to_unknown_users_are_refused() {
+    let server = TestServer::start();
+    let alice_key = IdentityKey::generate();
+    let mut alice = server.signup("alice");
+
+    let message = seal(b"hello?", &alice_key, &IdentityKey::generate().public()).unwrap();
+    let reply = alice.request(Packet::Message {
+        id: String::new(), seq: 0, message, sender: String::new(), recipient: format!("nobody"), group: None, timestamp: String::new(), ttl: 0,
+    });
+    assert_eq!(reply, Packet::Error { should_disconnect: false, error: format!("Invalid recipient") });
+}
+
+#[test]
+fn group_messages_fan_out_to_every_member() {
+    let server = TestServer::start();
+    let (alice_key, bob_key, carol_key) = (IdentityKey::generate(), IdentityKey::generate(), IdentityKey::generate());
+    drop(server.signup("carol"));
+    let mut alice = server.signup("alice");
+    let mut bob = server.signup("bob");
+
+    // the other members are told about every change
+    let group = alice.group(Packet::GroupCreate { name: format!("friends"), members: vec![format!("bob")] });
+    assert_eq!(group.members, vec![format!("alice"), format!("bob")]);
+    assert_eq!(bob.recv(), Packet::GroupUpdate { group: group.clone() });
+    let group = alice.group(Packet::GroupInvite { group: group.id.clone(), username: format!("carol") });
+    assert_eq!(bob.recv(), Packet::GroupUpdate { group: group.clone() });
+
+    // every other member needs a copy
+    let copy = |recipient: &str, key: &IdentityKey| SealedCopy { recipient: recipient.to_string(), message: seal(b"hi all", &alice_key, &key.public()).unwrap() };
+    let copies = vec![copy("bob", &bob_key), copy("carol", &carol_key)];
+    assert_eq!(alice.request(Packet::GroupMessage { group: group.id.clone(), copies: copies[..1].to_vec() }),
+               Packet::Error { should_disconnect: false, error: format!("The message must be sealed for every other member of the group") });
+    match alice.request(Packet::GroupMessage { group: group.id.clone(), copies }) {
+        Packet::MessageReceipt { recipient, seq, .. } => assert_eq!((recipient, seq), (group.id.clone(), 1)),
+        packet => panic!("Expected a MessageReceipt, got {:?}", packet),
+    }
+
+    // bob is online and gets his copy right away, carol gets hers when she logs in
+    match bob.recv() {
+        Packet::Message { message, sender, group: in_group, seq, .. } => {
+            assert_eq!((sender.as_str(), in_group, seq), ("alice", Some(group.id.clone()), 1));
+            assert_eq!(open(&message, &bob_key, &alice_key.public()).unwrap(), b"hi all");
+        }
+        packet => panic!("Expected a Message, got {:?}", packet),
+    }
+    let mut carol = server.login("carol");
+    let (_, seq, sender, text) = carol.expect_message(&carol_key, &alice_key);
+    assert_eq!((seq, sender.as_str(), text.as_str()), (1, "alice", "hi all"));
+
+    // the history of a group is asked for by its id
+    match bob.request(Packet::MsgHistoryRequest { username: group.id.clone(), cursor: HistoryCursor::Latest, limit: 10 }) {
+        Packet::MsgHistory { history, more } => assert_eq!((history.len(), history[0].seq, more), (1, 1, false)),
+        packet => panic!("Expected a MsgHistory, got {:?}", packet),
+    }
+
+    // removed members are told, and can not send to the group anymore
+    let group = alice.group(Packet::GroupRemove { group: group.id.clone(), username: format!("bob") });
+    assert_eq!(group.members, vec![format!("alice"), format!("carol")]);
+    assert_eq!(bob.recv(), Packet::GroupUpdate { group: group.clone() });
+    assert_eq!(carol.recv(), Packet::GroupUpdate { group: group.clone() });
+    assert_eq!(bob.request(Packet::GroupMessage { group: group.id.clone(), copies: Vec::new() }),
+               Packet::Error { should_disconnect: false, error: format!("You are not in this group") });
+    assert_eq!(bob.request(Packet::GroupListRequest), Packet::GroupList { groups: Vec::new() });
+}
+
+#[test]
+fn space_permissions_are_checked() {
+    let server = TestServer::start();
+    let (alice_key, bob_key) = (IdentityKey::generate(), IdentityKey::generate());
+    let mut alice = server.signup("alice");
+    let mut bob = server.signup("bob");
+    let denied = Packet::Error { should_disconnect: false, error: format!("You do not have permission to do that in this space") };
+
+    // a new space has a general channel everyone can send in
+    let space = alice.space(Packet::SpaceCreate { name: format!("club") });
+    assert_eq!((space.owner.as_str(), space.channels[0].name.as_str()), ("alice", "general"));
+    assert_eq!(space.permissions("alice"), permissions::ALL);
+    let space = alice.space(Packet::SpaceInvite { space: space.id.clone(), username: format!("bob") });
+    assert_eq!(bob.recv(), Packet::SpaceUpdate { space: space.clone() });
+    assert_eq!(space.permissions("bob"), permissions::SEND);
+
+    // members can only do what their roles allow
+    assert_eq!(bob.request(Packet::ChannelCreate { space: space.id.clone(), name: format!("off-topic") }), denied);
+    assert_eq!(bob.request(Packet::SpaceKick { space: space.id.clone(), username: format!("alice") }), denied);
+    let mods = Role { id: String::new(), name: format!("mods"), permissions: permissions::MANAGE_CHANNELS | permissions::MANAGE_ROLES };
+    let space = alice.space(Packet::RoleSet { space: space.id.clone(), role: mods });
+    assert_eq!(bob.recv(), Packet::SpaceUpdate { space: space.clone() });
+    let mods = space.roles.iter().find(|role| role.name == "mods").unwrap().id.clone();
+    let space = alice.space(Packet::RoleAssign { space: space.id.clone(), role: mods.clone(), username: format!("bob"), assigned: true });
+    assert_eq!(bob.recv(), Packet::SpaceUpdate { space: space.clone() });
+    let space = bob.space(Packet::ChannelCreate { space: space.id.clone(), name: format!("off-topic") });
+    assert_eq!(alice.recv(), Packet::SpaceUpdate { space: space.clone() });
+    // roles can not hand out more than their holder has
+    let kickers = Role { id: mods, name: format!("mods"), permissions: permissions::KICK };
+    assert_eq!(bob.request(Packet::RoleSet { space: space.id.clone(), role: kickers }),
+               Packet::Error { should_disconnect: false, error: format!("You can only give out permissions you have") });
+
+    // channel messages go to every other member of the space
+    let general = space.channels.iter().find(|channel| channel.name == "general").unwrap().id.clone();
+    let copies = vec![SealedCopy { recipient: format!("bob"), message: seal(b"welcome", &alice_key, &bob_key.public()).unwrap() }];
+    match alice.request(Packet::ChannelMessage { channel: general.clone(), copies }) {
+        Packet::MessageReceipt { recipient, seq, .. } => assert_eq!((recipient, seq), (general.clone(), 1)),
+        packet => panic!("Expected a MessageReceipt, got {:?}", packet),
+    }
+    match bob.recv() {
+        Packet::Message { message, group, .. } => {
+            assert_eq!(group, Some(general.clone()));
+            assert_eq!(open(&message, &bob_key, &alice_key.public()).unwrap(), b"welcome");
+        }
+        packet => panic!("Expected a Message, got {:?}", packet),
+    }
+
+    // banned members are removed, told, and can not be invited back
+    let space = alice.space(Packet::SpaceBan { space: space.id.clone(), username: format!("bob"), banned: true });
+    assert_eq!(space.banned, vec![format!("bob")]);
+    assert_eq!(bob.recv(), Packet::SpaceUpdate { space: space.clone() });
+    assert_eq!(alice.request(Packet::SpaceInvite { space: space.id.clone(), username: format!("bob") }),
+               Packet::Error { should_disconnect: false, error: format!("bob is banned from the space") });
+    assert_eq!(bob.request(Packet::ChannelMessage { channel: general, copies: Vec::new() }),
+               Packet::Error { should_disconnect: false, error: format!("You are not in this space") });
+    assert_eq!(bob.request(Packet::SpaceListRequest), Packet::SpaceList { spaces: Vec::new() });
+}
+
+#[test]
+fn contact_requests() {
+    let server = TestServer::start();
+    let contact = |username: &str, state| Contact { username: username.to_string(), state };
+    drop(server.signup("bob"));
+    let mut alice = server.signup("alice");
+    let mut carol = server.signup("carol");
+
+    // bob is offline, so he is told about the request when he logs in
+    assert_eq!(alice.request(Packet::ContactRequest { username: format!("bob") }),
+               Packet::ContactList { contacts: vec![contact("bob", ContactState::Outgoing)] });
+    assert_eq!(alice.request(Packet::ContactRequest { username: format!("bob") }),
+               Packet::Error { should_disconnect: false, error: format!("You already sent bob a request") });
+    let mut bob = server.login("bob");
+    assert_eq!(bob.recv(), Packet::ContactUpdate { contact: contact("alice", ContactState::Incoming) });
+    assert_eq!(bob.request(Packet::ContactRespond { username: format!("alice"), accept: true }),
+               Packet::ContactList { contacts: vec![contact("alice", ContactState::Accepted)] });
+    assert_eq!(alice.recv(), Packet::ContactUpdate { contact: contact("bob", ContactState::Accepted) });
+
+    // carol is online and told straight away, and alice is told when she declines
+    alice.request(Packet::ContactRequest { username: format!("carol") });
+    assert_eq!(carol.recv(), Packet::ContactUpdate { contact: contact("alice", ContactState::Incoming) });
+    assert_eq!(carol.request(Packet::ContactRespond { username: format!("alice"), accept: false }), Packet::ContactList { contacts: Vec::new() });
+    assert_eq!(alice.recv(), Packet::ContactUpdate { contact: contact("carol", ContactState::None) });
+    assert_eq!(carol.request(Packet::ContactRespond { username: format!("alice"), accept: true }),
+               Packet::Error { should_disconnect: false, error: format!("alice did not send you a request") });
+
+    assert_eq!(bob.request(Packet::ContactRemove { username: format!("alice") }), Packet::ContactList { contacts: Vec::new() });
+    assert_eq!(alice.recv(), Packet::ContactUpdate { contact: contact("bob", ContactState::None) });
+    assert_eq!(alice.request(Packet::ContactListRequest), Packet::ContactList { contacts: Vec::new() });
+}
+
+#[test]
+fn blocked_users_are_ignored() {
+    let server = TestServer::start();
+    let (alice_key, bob_key, carol_key) = (IdentityKey::generate(), IdentityKey::generate(), IdentityKey::generate());
+    let mut alice = server.signup("alice");
+    let mut bob = server.signup("bob");
+    let mut carol = server.signup("carol");
+
+    // blocking ends the request bob sent, and he is told like with any other removal
+    bob.request(Packet::ContactRequest { username: format!("alice") });
+    assert_eq!(alice.recv(), Packet::ContactUpdate { contact: Contact { username: format!("bob"), state: ContactState::Incoming } });
+    assert_eq!(alice.request(Packet::Block { username: format!("bob") }), Packet::BlockList { usernames: vec![format!("bob")] });
+    assert_eq!(bob.recv(), Packet::ContactUpdate { contact: Contact { username: format!("alice"), state: ContactState::None } });
+    assert_eq!(alice.request(Packet::Block { username: format!("bob") }),
+               Packet::Error { should_disconnect: false, error: format!("bob is already blocked") });
+    assert_eq!(alice.request(Packet::Block { username: format!("alice") }),
+               Packet::Error { should_disconnect: false, error: format!("You can not block yourself") });
+
+    // to bob alice seems offline, his requests go nowhere and his messages are refused
+    assert!(!bob.user_online("alice"));
+    assert!(carol.user_online("alice"));
+    assert_eq!(bob.request(Packet::ContactRequest { username: format!("alice") }), Packet::ContactList { contacts: Vec::new() });
+    assert_eq!(alice.request(Packet::ContactRequest { username: format!("bob") }),
+               Packet::Error { should_disconnect: false, error: format!("Unblock bob first") });
+    let message = seal(b"hello?", &bob_key, &alice_key.public()).unwrap();
+    assert_eq!(bob.request(Packet::Message {
+        id: String::new(), seq: 0, message, sender: String::new(), recipient: format!("alice"), group: None, timestamp: String::new(), ttl: 0,
+    }), Packet::Error { should_disconnect: false, error: format!("alice is not accepting your messages") });
+
+    // in a group only alice's copy is dropped
+    let group = bob.group(Packet::GroupCreate { name: format!("club"), members: vec![format!("alice"), format!("carol")] });
+    assert_eq!(alice.recv(), Packet::GroupUpdate { group: group.clone() });
+    assert_eq!(carol.recv(), Packet::GroupUpdate { group: group.clone() });
+    let copy = |recipient: &str, key: &IdentityKey| SealedCopy { recipient: recipient.to_string(), message: seal(b"hi all", &bob_key, &key.public()).unwrap() };
+    match bob.request(Packet::GroupMessage { group: group.id.clone(), copies: vec![copy("alice", &alice_key), copy("carol", &carol_key)] }) {
+        Packet::MessageReceipt { seq, .. } => assert_eq!(seq, 1),
+        packet => panic!("Expected a MessageReceipt, got {:?}", packet),
+    }
+    let (_, _, sender, text) = carol.expect_message(&carol_key, &bob_key);
+    assert_eq!((sender.as_str(), text.as_str()), ("bob", "hi all"));
+    // the message was routed before bob's receipt, so it would have come before this reply
+    assert_eq!(alice.request(Packet::BlockListRequest), Packet::BlockList { usernames: vec![format!("bob")] });
+
+    assert_eq!(alice.request(Packet::Unblock { username: format!("bob") }), Packet::BlockList { usernames: Vec::new() });
+    assert_eq!(alice.request(Packet::Unblock { username: format!("bob") }),
+               Packet::Error { should_disconnect: false, error: format!("bob is not blocked") });
+    assert!(bob.user_online("alice"));
+}
+
+#[test]
+fn users_go_offline_when_they_disconnect() {
+    let server = TestServer::start();
+    let mut alice = server.signup("alice");
+    let bob = server.signup("bob");
+
+    assert!(alice.user_exists("bob"));
+    assert!(!alice.user_exists("carol"));
+    assert!(alice.user_online("bob"));
+
+    drop(bob);
+    // the server notices the closed connection on its own time
+    let deadline = Instant::now() + READ_TIMEOUT;
+    while alice.user_online("bob") {
+        assert!(Instant::now() < deadline, "bob is still online after disconnecting");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn clients_are_disconnected_on_shutdown() {
+    let server = TestServer::start();
+    let mut alice = server.signup("alice");
+    server.shut_down();
+    assert_eq!(alice.recv(), Packet::Disconnect);
+    drop(alice);
+}
+
+#[test]
+fn tls_connections() {
+    let dir = std::env::temp_dir().join(format!("dl_server_tls_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    generate_self_signed(&cert, &key).unwrap();
+    let fingerprint = certificate_fingerprint(&cert).unwrap();
+    let server = TestServer::start_with_tls(Some(server_config(&cert, &key).unwrap()));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let stream = TlsStream::connect(client_config(Some(&fingerprint)).unwrap(), "localhost", server.stream()).unwrap();
+    let mut client = TestClient { connection: Connection::new(stream) };
+    assert!(client.ping(ACCEPTED_CLIENT_VERSION, false));
+    assert_eq!(client.login("alice", "hunter2", true), accepted());
+    client.expect_prekeys_low();
+    assert!(client.user_online("alice"));
+}
+
+#[test]
+fn serves_every_listener() {
+    let listeners = vec![TcpListener::bind("127.0.0.1:0").unwrap(), TcpListener::bind("127.0.0.1:0").unwrap()];
+    let addrs: Vec<SocketAddr> = listeners.iter().map(|listener| listener.local_addr().unwrap()).collect();
+    let state = ServerState {
+        store: Arc::new(MemoryStore::new()),
+        router: Arc::new(Router::new()),
+        hash_config: CHEAP,
+        tls: None,
+    };
+    let terminate = Arc::new(AtomicBool::new(false));
+    let tarc = Arc::clone(&terminate);
+    let handle = thread::spawn(move || serve(listeners, state, tarc));
+
+    // a user signed up through one address can be found through the other
+    let mut alice = TestClient { connection: Connection::new(TcpStream::connect(addrs[0]).unwrap()) };
+    assert!(alice.ping(ACCEPTED_CLIENT_VERSION, false));
+    assert_eq!(alice.login("alice", "hunter2", true), accepted());
+    alice.expect_prekeys_low();
+    let mut bob = TestClient { connection: Connection::new(TcpStream::connect(addrs[1]).unwrap()) };
+    assert!(bob.ping(ACCEPTED_CLIENT_VERSION, false));
+    assert_eq!(bob.login("bob", "hunter2", true), accepted());
+    bob.expect_prekeys_low();
+    assert!(bob.user_online("alice"));
+
+    drop((alice, bob));
+    terminate.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+}